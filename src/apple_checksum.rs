@@ -0,0 +1,105 @@
+// Verification against the `checksum` iCloud reports for each derivative.
+//
+// These values are MMCS file signatures: one type byte followed by a digest.
+// Type 0x01 is the SHA-1 of the complete file, which is what shared-album
+// derivatives carry in practice (42 hex characters starting with `01`), and
+// the only form that has been confirmed against downloaded files. Anything
+// else (other types such as the chunked signatures used for some large
+// files, other lengths or encodings) is reported as unverifiable instead of
+// guessed at.
+
+use sha1::{Digest, Sha1};
+use std::io::Read;
+use std::path::Path;
+
+const SHA1_SIGNATURE: u8 = 0x01;
+
+/// `Some(true)` if `content` matches, `Some(false)` if it doesn't, and `None`
+/// if the checksum uses a scheme we can't check.
+pub fn verify_apple_checksum(content: &[u8], checksum: &str) -> Option<bool> {
+    let digest = sha1_digest(checksum)?;
+    Some(Sha1::digest(content).as_slice() == digest)
+}
+
+/// Like `verify_apple_checksum`, for a file on disk, read a piece at a time.
+pub fn verify_file(path: &Path, checksum: &str) -> std::io::Result<Option<bool>> {
+    let Some(digest) = sha1_digest(checksum) else {
+        return Ok(None);
+    };
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(Some(hasher.finalize().as_slice() == digest))
+}
+
+/// The SHA-1 digest of a type 0x01 signature in hex, the one confirmed form.
+fn sha1_digest(checksum: &str) -> Option<[u8; 20]> {
+    let hex = checksum.trim();
+    if hex.len() != 42 || !hex.is_char_boundary(2) {
+        return None;
+    }
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    match bytes.split_first() {
+        Some((&SHA1_SIGNATURE, digest)) => digest.try_into().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-1 test vectors from FIPS 180-2, as type 0x01 signatures.
+    const EMPTY: &str = "01da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const ABC: &str = "01a9993e364706816aba3e25717850c26c9cd0d89d";
+    const MILLION_A: &str = "0134AA973CD4C4DAA4F61EEB2BDBAD27316534016F";
+
+    #[test]
+    fn known_files_match_their_checksums() {
+        assert_eq!(verify_apple_checksum(b"", EMPTY), Some(true));
+        assert_eq!(verify_apple_checksum(b"abc", ABC), Some(true));
+        assert_eq!(verify_apple_checksum(&vec![b'a'; 1_000_000], MILLION_A), Some(true));
+        assert_eq!(verify_apple_checksum(b"abc", &format!(" {}\n", ABC)), Some(true));
+    }
+
+    #[test]
+    fn changed_content_does_not_match() {
+        assert_eq!(verify_apple_checksum(b"abd", ABC), Some(false));
+        assert_eq!(verify_apple_checksum(b"", ABC), Some(false));
+    }
+
+    #[test]
+    fn unconfirmed_formats_are_unverifiable() {
+        // Another signature type with a SHA-1-sized digest
+        assert_eq!(verify_apple_checksum(b"abc", &format!("02{}", &ABC[2..])), None);
+        // A bare SHA-1, without the type byte
+        assert_eq!(verify_apple_checksum(b"abc", &ABC[2..]), None);
+        // The same signature in base64
+        assert_eq!(verify_apple_checksum(b"abc", "AamZPjZHBoFqPiVxeFDCbJzQ2J0="), None);
+        // Longer chunked signatures
+        assert_eq!(verify_apple_checksum(b"abc", &format!("{}00112233", ABC)), None);
+        assert_eq!(verify_apple_checksum(b"abc", "01zz993e364706816aba3e25717850c26c9cd0d89d"), None);
+        assert_eq!(verify_apple_checksum(b"abc", "01a9993e364706816aba3e25717850c26c9cd0d8\u{e9}"), None);
+        assert_eq!(verify_apple_checksum(b"abc", ""), None);
+    }
+
+    #[test]
+    fn files_are_verified_in_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        std::fs::write(&path, vec![b'a'; 1_000_000]).unwrap();
+        assert_eq!(verify_file(&path, MILLION_A).unwrap(), Some(true));
+        assert_eq!(verify_file(&path, ABC).unwrap(), Some(false));
+        assert_eq!(verify_file(&path, "unknown").unwrap(), None);
+    }
+}
@@ -0,0 +1,85 @@
+// --tar: write the album as a tar stream instead of files, to a file or to
+// stdout (`--tar -`) for piping into another process, e.g.
+// `... --tar - | ssh host 'tar -x -C /backup'`.
+//
+// A tar stream is strictly sequential, so downloads still run concurrently
+// but each finished file is written as one whole entry under a lock. Files
+// are held in memory until their entry is written; nothing touches the disk.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+type Sink = tar::Builder<Box<dyn Write + Send>>;
+
+#[derive(Clone)]
+pub struct TarArchive {
+    builder: Arc<Mutex<Option<Sink>>>,
+    prefix: String,
+}
+
+impl TarArchive {
+    /// Opens `target` for writing, or stdout for `-`.
+    pub fn create(target: &str) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = if target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(File::create(target).with_context(|| format!("Failed to create {}", target))?)
+        };
+
+        let mut builder = tar::Builder::new(writer);
+        builder.mode(tar::HeaderMode::Deterministic);
+        Ok(Self {
+            builder: Arc::new(Mutex::new(Some(builder))),
+            prefix: String::new(),
+        })
+    }
+
+    /// The same archive, with entries placed under `prefix/` (one directory
+    /// per album when downloading several).
+    pub fn with_prefix(&self, prefix: &str) -> Self {
+        Self { builder: Arc::clone(&self.builder), prefix: prefix.to_string() }
+    }
+
+    /// Writes one file as a complete entry. Entries never interleave.
+    pub async fn append(&self, name: &str, content: Bytes, modified: Option<DateTime<Utc>>) -> Result<()> {
+        let path = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        };
+        let builder = Arc::clone(&self.builder);
+
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(modified.unwrap_or_else(Utc::now).timestamp().max(0) as u64);
+
+            let mut builder = builder.lock().unwrap();
+            let builder = builder.as_mut().context("The archive is already closed")?;
+            builder
+                .append_data(&mut header, &path, content.as_ref())
+                .with_context(|| format!("Failed to write {} to the archive", path))
+        })
+        .await
+        .context("Archive writer panicked")?
+    }
+
+    /// Writes the end-of-archive marker and flushes.
+    pub async fn finish(&self) -> Result<()> {
+        let builder = Arc::clone(&self.builder);
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            if let Some(builder) = builder.lock().unwrap().take() {
+                let mut writer = builder.into_inner().context("Failed to finish the archive")?;
+                writer.flush().context("Failed to flush the archive")?;
+            }
+            Ok(())
+        })
+        .await
+        .context("Archive writer panicked")?
+    }
+}
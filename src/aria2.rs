@@ -0,0 +1,114 @@
+// `--failures-aria2`: hands downloads that failed to aria2c. At the end of
+// each album the failed files get fresh download URLs (the ones from the run
+// have often expired by then) and are added to an aria2 input file, which is
+// rewritten in one step so a crash can't leave half an entry in it:
+//
+//     https://cvws.icloud-content.com/...
+//       dir=/home/me/photos
+//       out=IMG_1234.JPG
+//
+// Run it with `aria2c --input-file <path>`.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::atomic::atomic_write;
+use crate::http::HttpClient;
+use crate::{fetch_asset_urls_batch, DerivativeSelection, DownloadInfo, Photo};
+
+/// Photos per webasseturls request, as in the main URL fetch.
+const REFRESH_BATCH_SIZE: usize = 25;
+
+struct FailedDownload {
+    photo_guid: String,
+    checksum: String,
+    filename: String,
+    url: String,
+}
+
+pub struct Aria2Export {
+    path: PathBuf,
+    failed: Mutex<Vec<FailedDownload>>,
+}
+
+impl Aria2Export {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, failed: Mutex::new(Vec::new()) }
+    }
+
+    /// Removes the file left by a previous run; each album of this run then appends to it.
+    pub fn remove_stale(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove old {}", path.display())),
+        }
+    }
+
+    pub fn record(&self, info: &DownloadInfo) {
+        self.failed.lock().unwrap().push(FailedDownload {
+            photo_guid: info.photo_guid.clone(),
+            checksum: info.checksum.clone(),
+            filename: info.filename.clone(),
+            url: info.download_url.clone(),
+        });
+    }
+
+    /// Re-fetches URLs for the recorded failures and appends them to the
+    /// file. Returns how many entries were written.
+    pub async fn write(
+        &self,
+        client: &impl HttpClient,
+        hash: &str,
+        photos: &[Photo],
+        selection: &DerivativeSelection,
+        output_dir: &str,
+    ) -> Result<usize> {
+        let failed = std::mem::take(&mut *self.failed.lock().unwrap());
+        if failed.is_empty() {
+            return Ok(0);
+        }
+
+        let failed_guids: Vec<&str> = failed.iter().map(|f| f.photo_guid.as_str()).collect();
+        let failed_photos: Vec<&Photo> = photos
+            .iter()
+            .filter(|photo| failed_guids.contains(&photo.photo_guid.as_str()))
+            .collect();
+
+        let mut fresh_urls: HashMap<String, String> = HashMap::new();
+        for batch in failed_photos.chunks(REFRESH_BATCH_SIZE) {
+            let batch: Vec<Photo> = batch.iter().map(|photo| (*photo).clone()).collect();
+            match fetch_asset_urls_batch(client, hash, &batch, selection).await {
+                Ok(infos) => fresh_urls.extend(infos.into_iter().map(|info| (info.checksum, info.download_url))),
+                Err(e) => eprintln!("⚠️  Could not refresh URLs for the aria2 file, using the old ones: {:#}", e),
+            }
+        }
+
+        let dir = fs::canonicalize(output_dir).unwrap_or_else(|_| PathBuf::from(output_dir));
+        let mut entries = String::new();
+        for failure in &failed {
+            let url = fresh_urls.get(&failure.checksum).unwrap_or(&failure.url);
+            entries.push_str(&format!("{}\n  dir={}\n  out={}\n", url, dir.display(), failure.filename));
+        }
+
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let mut contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+        contents.extend_from_slice(entries.as_bytes());
+        atomic_write(&self.path, &contents)?;
+
+        Ok(failed.len())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,185 @@
+// Crash-safe writes of the files the tool keeps for itself: the manifest and
+// its exports, checksums, the gallery, progress and failures files. A file
+// that's rewritten is written in full to a temporary file next to it, synced
+// and renamed over the old one, so a reader only ever sees the old or the new
+// version, never half of one. Files that only grow (snapshot history, stats)
+// get whole lines appended, and a line cut short by an earlier crash is
+// closed off first so it can't run into the next one.
+//
+// Downloads are written the same way, through a `PendingFile`: at a
+// temporary path that is renamed into place once complete, and removed if
+// the write is abandoned, so no half-written photo ever sits where a later
+// run would take it for a finished one.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Replaces `path` with `contents` in one step.
+pub fn atomic_write(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = temporary_path(path);
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+    }
+    sync_parent(path);
+    Ok(())
+}
+
+/// Appends `line` (which ends in a newline) to `path`, creating it if needed.
+pub fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut contents = String::new();
+    if ends_mid_line(&mut file).with_context(|| format!("Failed to read {}", path.display()))? {
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// A file being written at a temporary path. It's removed when dropped
+/// unless `persist` moved it into place, so a write abandoned halfway (an
+/// error, or a download dropped by --per-file-timeout) leaves nothing behind.
+pub struct PendingFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl PendingFile {
+    /// Takes charge of `path`, which the caller is about to create.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, persisted: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the file to `destination`, which must be on the same file system.
+    pub async fn persist(mut self, destination: &Path) -> Result<()> {
+        tokio::fs::rename(&self.path, destination)
+            .await
+            .with_context(|| format!("Failed to move the download to {}", destination.display()))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A hidden name next to `path` for writing its new contents, unique to
+/// this process so concurrent runs don't write into each other's.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Whether the file is non-empty and its last byte isn't a newline.
+fn ends_mid_line(file: &mut File) -> std::io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0u8];
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Makes the rename itself durable. Not possible on every platform; the
+/// contents are synced either way.
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        let _ = File::open(parent).and_then(|dir| dir.sync_all());
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readers_only_ever_see_a_whole_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        let versions = [vec![b'a'; 256 * 1024], vec![b'b'; 512 * 1024]];
+        atomic_write(&path, &versions[0]).unwrap();
+
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                for i in 0..50 {
+                    atomic_write(&path, &versions[i % 2]).unwrap();
+                }
+            });
+            while !writer.is_finished() {
+                let seen = fs::read(&path).unwrap();
+                assert!(versions.contains(&seen), "read a partial file of {} bytes", seen.len());
+            }
+        });
+    }
+
+    #[test]
+    fn a_temporary_file_left_by_a_crash_never_replaces_the_real_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        atomic_write(&path, b"[{\"filename\":\"IMG_0001.JPG\"}]").unwrap();
+
+        // A write killed halfway leaves a truncated temporary file behind
+        fs::write(temporary_path(&path), b"[{\"filena").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"[{\"filename\":\"IMG_0001.JPG\"}]");
+
+        atomic_write(&path, b"[]").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"[]");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn a_line_cut_short_is_closed_off_before_the_next() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.jsonl");
+        fs::write(&path, "{\"whole\":1}\n{\"cut").unwrap();
+
+        append_line(&path, "{\"next\":2}\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"whole\":1}\n{\"cut\n{\"next\":2}\n");
+    }
+
+    #[tokio::test]
+    async fn a_pending_file_is_removed_unless_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("photo.jpg");
+
+        let pending = PendingFile::new(temporary_path(&destination));
+        fs::write(pending.path(), b"half a pho").unwrap();
+        drop(pending);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let pending = PendingFile::new(temporary_path(&destination));
+        fs::write(pending.path(), b"a whole photo").unwrap();
+        pending.persist(&destination).await.unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), b"a whole photo");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
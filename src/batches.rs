@@ -0,0 +1,59 @@
+// `--group-by-batch`: each file goes into a folder named after the upload
+// batch its photo belongs to (`batchGuid`).
+//
+// In a shared album a batch is one post: the photos and videos a member
+// added to the album in one go, which the iCloud web page shows together
+// under one "added N items" entry. Photos in a batch share the batch's
+// `batchGuid` and `batchDateCreated`, however far apart they were taken. A
+// batch doesn't group the parts of a Live Photo: those are derivatives of a
+// single photo and always share its name already. Nor does it group burst
+// shots as such, unless they were posted together.
+
+use std::collections::HashMap;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::{DownloadInfo, Photo, MAX_FILENAME_BYTES};
+
+pub struct BatchFolders {
+    /// Photo GUID to the folder of its batch.
+    folders: HashMap<String, String>,
+}
+
+impl BatchFolders {
+    /// Photos without a batch GUID aren't put in a folder.
+    pub fn new(photos: &[Photo]) -> Self {
+        let folders = photos
+            .iter()
+            .filter_map(|photo| {
+                let batch = photo.batch_guid.as_deref()?;
+                let folder = render_caption(batch, CaptionContext::Filename { max_bytes: MAX_FILENAME_BYTES });
+                (!folder.is_empty()).then(|| (photo.photo_guid.clone(), folder))
+            })
+            .collect();
+        Self { folders }
+    }
+
+    pub fn apply(&self, info: &mut DownloadInfo) {
+        if let Some(folder) = self.folders.get(&info.photo_guid) {
+            info.filename = format!("{}/{}", folder, info.filename);
+        }
+    }
+
+    /// Reports how the album's photos are grouped.
+    pub fn report(&self, photo_count: usize) {
+        let mut sizes: HashMap<&str, usize> = HashMap::new();
+        for folder in self.folders.values() {
+            *sizes.entry(folder).or_default() += 1;
+        }
+        status!(
+            "🗂️  Grouping {} photos into {} upload batches ({} with more than one photo)",
+            self.folders.len(),
+            sizes.len(),
+            sizes.values().filter(|&&size| size > 1).count()
+        );
+        let unbatched = photo_count - self.folders.len();
+        if unbatched > 0 {
+            status!("   {} photos have no batch and stay at the top level", unbatched);
+        }
+    }
+}
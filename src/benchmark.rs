@@ -0,0 +1,194 @@
+// `--benchmark`: downloads an album without writing anything and reports how
+// fast it went, to tune --concurrent and --parallel-parts or spot a
+// regression. Files go through the normal download path (retries, size
+// checks, --verify, type detection) and are then dropped instead of saved,
+// so the disk doesn't skew the numbers.
+//
+// With --benchmark-concurrency and/or --benchmark-parts every combination is
+// run in turn, each fetching fresh URLs, and the results are compared in a
+// table. Later runs may be served from warmer CDN caches than the first.
+
+use anyhow::{anyhow, Result};
+use comfy_table::presets::UTF8_FULL_CONDENSED;
+use comfy_table::{ContentArrangement, Table};
+use futures::stream::{self, StreamExt};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::http::HttpClient;
+use crate::size::format_size;
+use crate::{
+    download_single_photo, fetch_asset_urls_batch, fetch_webstream, resolve_album_hash, Args, DerivativeSelection,
+    DownloadOptions, URL_BATCH_SIZE,
+};
+
+struct Settings {
+    concurrent: usize,
+    parallel_parts: usize,
+}
+
+struct Measurement {
+    files: usize,
+    failed: usize,
+    bytes: u64,
+    url_fetch: Duration,
+    download: Duration,
+    /// Process CPU time (user and system) over the run; `None` where it can't be read.
+    cpu: Option<Duration>,
+    /// Peak resident memory; `None` where it can't be read.
+    peak_memory: Option<u64>,
+}
+
+pub async fn run(client: &impl HttpClient, args: &Args) -> Result<()> {
+    let [url] = args.url.as_slice() else {
+        return Err(anyhow!("--benchmark takes exactly one --url"));
+    };
+    let hash = resolve_album_hash(client, url).await?;
+    let mut webstream = fetch_webstream(client, &hash).await?;
+    if let Some(range) = &args.range {
+        let bounds = range.bounds(webstream.photos.len())?;
+        webstream.photos.truncate(bounds.end);
+        webstream.photos.drain(..bounds.start);
+    }
+    if webstream.photos.is_empty() {
+        return Err(anyhow!("The album has no photos to benchmark with"));
+    }
+    let selection = match &args.derivatives {
+        Some(names) => DerivativeSelection::Named(names.clone()),
+        None => DerivativeSelection::Best,
+    };
+
+    let concurrency = if args.benchmark_concurrency.is_empty() {
+        vec![args.concurrent]
+    } else {
+        args.benchmark_concurrency.iter().map(|&n| n as usize).collect()
+    };
+    let parts = if args.benchmark_parts.is_empty() { vec![args.parallel_parts] } else { args.benchmark_parts.clone() };
+    let matrix: Vec<Settings> = concurrency
+        .iter()
+        .flat_map(|&concurrent| {
+            parts.iter().map(move |&parts| Settings { concurrent, parallel_parts: parts as usize })
+        })
+        .collect();
+
+    status!("\n⏱️  Benchmarking {} photos, discarding the downloads", webstream.photos.len());
+    let mut results = Vec::new();
+    for (i, settings) in matrix.iter().enumerate() {
+        status!(
+            "   [{}/{}] --concurrent {} --parallel-parts {}",
+            i + 1,
+            matrix.len(),
+            settings.concurrent,
+            settings.parallel_parts
+        );
+        let options = DownloadOptions {
+            max_concurrent: settings.concurrent,
+            parallel_parts: settings.parallel_parts,
+            discard: true,
+            dashboard: false,
+            disk_space: None,
+            post_download: None,
+            content_store: None,
+            ..DownloadOptions::from_args(args)
+        };
+        results.push(measure(client, &hash, &webstream.photos, &selection, &options).await?);
+    }
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["Concurrent", "Parts", "Files", "Failed", "Data", "URL fetch", "Download", "Throughput", "CPU", "Peak memory"]);
+    for (settings, result) in matrix.iter().zip(&results) {
+        let throughput = result.bytes as f64 / result.download.as_secs_f64().max(0.001);
+        table.add_row(vec![
+            settings.concurrent.to_string(),
+            settings.parallel_parts.to_string(),
+            result.files.to_string(),
+            result.failed.to_string(),
+            format_size(result.bytes),
+            format!("{:.1}s", result.url_fetch.as_secs_f64()),
+            format!("{:.1}s", result.download.as_secs_f64()),
+            format!("{}/s", format_size(throughput as u64)),
+            result.cpu.map_or("?".to_string(), |cpu| format!("{:.1}s", cpu.as_secs_f64())),
+            result.peak_memory.map_or("?".to_string(), format_size),
+        ]);
+    }
+    status!("\n{}", table);
+    Ok(())
+}
+
+async fn measure(
+    client: &impl HttpClient,
+    hash: &str,
+    photos: &[crate::Photo],
+    selection: &DerivativeSelection,
+    options: &DownloadOptions,
+) -> Result<Measurement> {
+    reset_peak_memory();
+    let cpu_start = cpu_time();
+
+    let started = Instant::now();
+    let mut infos = Vec::new();
+    for batch in photos.chunks(URL_BATCH_SIZE) {
+        infos.extend(fetch_asset_urls_batch(client, hash, batch, selection).await?);
+    }
+    let url_fetch = started.elapsed();
+
+    let files = infos.len();
+    let failed = AtomicUsize::new(0);
+    let bytes = AtomicU64::new(0);
+    let started = Instant::now();
+    stream::iter(infos)
+        .for_each_concurrent(options.max_concurrent, |info| {
+            let (failed, bytes) = (&failed, &bytes);
+            async move {
+                match download_single_photo(client, &info, "", options, None).await {
+                    Ok(saved) => {
+                        bytes.fetch_add(saved.size, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        eprintln!("❌ {}: {:#}", info.filename, e);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        })
+        .await;
+    let download = started.elapsed();
+
+    Ok(Measurement {
+        files,
+        failed: failed.into_inner(),
+        bytes: bytes.into_inner(),
+        url_fetch,
+        download,
+        cpu: cpu_start.zip(cpu_time()).map(|(start, end)| end.saturating_sub(start)),
+        peak_memory: peak_memory(),
+    })
+}
+
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let time = rustix::time::clock_gettime(rustix::time::ClockId::ProcessCPUTime);
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+/// VmHWM from /proc, so Linux only.
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Starts a new peak for `peak_memory`, so each run of a matrix reports its
+/// own. Where that isn't possible the peak is the whole process's so far.
+fn reset_peak_memory() {
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
@@ -0,0 +1,273 @@
+// Circuit breaker for the download phase. When most recent downloads fail,
+// iCloud is usually down or rate-limiting us, and letting every remaining
+// file fail on its own just adds to the pile. Instead new downloads are held
+// back for a while, then a single probe download decides whether to resume
+// or to back off for longer. After `MAX_FAILED_PROBES` failed probes in a
+// row the run stops.
+//
+// Only failures that say something about the service count: connection and
+// transport errors, 429 and 5xx. A file that fails for a reason of its own
+// (an expired URL, a 404, a checksum mismatch, a failing hook) shows the
+// service answered, and counts like a success.
+
+use anyhow::Error;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::errors::DownloadStatus;
+use crate::integrity::TruncatedBody;
+use crate::Args;
+
+/// Backoff doubles after every failed probe, up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Failed probes in a row after which the run gives up.
+pub const MAX_FAILED_PROBES: u32 = 5;
+
+/// How often downloads held back behind a probe check whether it finished.
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct CircuitBreaker {
+    window: usize,
+    threshold: f64,
+    base_backoff: Duration,
+    state: Mutex<State>,
+    gave_up: Notify,
+}
+
+struct State {
+    /// Outcomes of the most recent downloads, `true` for a failure.
+    recent: VecDeque<bool>,
+    mode: Mode,
+    backoff: Duration,
+    failed_probes: u32,
+}
+
+enum Mode {
+    Closed,
+    Open { until: Instant },
+    Probing,
+    /// Too many probes failed; nothing more is let through.
+    GaveUp,
+}
+
+/// Whether a download was let through normally or as the probe.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Normal,
+    Probe,
+}
+
+impl CircuitBreaker {
+    /// `None` with --no-circuit-breaker.
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.no_circuit_breaker {
+            return None;
+        }
+        let base_backoff = Duration::from_secs_f64(args.breaker_backoff);
+        Some(Self {
+            window: args.breaker_window.max(1),
+            threshold: args.breaker_threshold,
+            base_backoff,
+            state: Mutex::new(State { recent: VecDeque::new(), mode: Mode::Closed, backoff: base_backoff, failed_probes: 0 }),
+            gave_up: Notify::new(),
+        })
+    }
+
+    /// Waits until a download may start. After giving up, never returns;
+    /// `gave_up` ends the run instead.
+    pub async fn admit(&self) -> Admission {
+        loop {
+            let wait_until = {
+                let mut state = self.lock();
+                match state.mode {
+                    Mode::Closed => return Admission::Normal,
+                    Mode::Open { until } if Instant::now() >= until => {
+                        state.mode = Mode::Probing;
+                        return Admission::Probe;
+                    }
+                    Mode::Open { until } => Some(until),
+                    Mode::Probing => Some(Instant::now() + PROBE_POLL_INTERVAL),
+                    Mode::GaveUp => None,
+                }
+            };
+            match wait_until {
+                Some(wait_until) => tokio::time::sleep_until(wait_until).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Records how an admitted download went, given its error if it failed.
+    /// Returns a message to show when the breaker opens, re-opens, closes
+    /// again or gives up.
+    pub fn record(&self, admission: Admission, error: Option<&Error>) -> Option<String> {
+        let failed = error.is_some_and(is_service_failure);
+        let mut state = self.lock();
+
+        if admission == Admission::Probe {
+            if failed {
+                state.failed_probes += 1;
+                if state.failed_probes >= MAX_FAILED_PROBES {
+                    state.mode = Mode::GaveUp;
+                    self.gave_up.notify_one();
+                    return Some(format!("🛑 {} probe downloads in a row failed; giving up", state.failed_probes));
+                }
+                state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+                state.mode = Mode::Open { until: Instant::now() + state.backoff };
+                return Some(format!(
+                    "🛑 Probe download failed too; backing off for {}s",
+                    state.backoff.as_secs()
+                ));
+            }
+            state.mode = Mode::Closed;
+            state.recent.clear();
+            state.backoff = self.base_backoff;
+            state.failed_probes = 0;
+            return Some("✅ Probe download succeeded, resuming downloads".to_string());
+        }
+
+        if state.recent.len() == self.window {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(failed);
+
+        // Downloads that were already running when the breaker opened
+        // finish normally and don't re-trip it
+        if !matches!(state.mode, Mode::Closed) || state.recent.len() < self.window {
+            return None;
+        }
+
+        let failures = state.recent.iter().filter(|failed| **failed).count();
+        if (failures as f64) < self.threshold * self.window as f64 {
+            return None;
+        }
+
+        state.mode = Mode::Open { until: Instant::now() + state.backoff };
+        Some(format!(
+            "🛑 {} of the last {} downloads failed; iCloud appears to be down or rate-limiting, backing off for {}s",
+            failures,
+            self.window,
+            state.backoff.as_secs()
+        ))
+    }
+
+    /// Resolves once the breaker gave up on the run.
+    pub async fn gave_up(&self) {
+        self.gave_up.notified().await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether a download's error suggests the service itself is in trouble:
+/// no connection, a transfer cut off, a 429 or a 5xx.
+pub fn is_service_failure(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_connect() || e.is_timeout() || e.is_request() || e.is_body();
+        }
+        if let Some(e) = cause.downcast_ref::<DownloadStatus>() {
+            return e.status == reqwest::StatusCode::TOO_MANY_REQUESTS || e.status.is_server_error();
+        }
+        cause.is::<TruncatedBody>()
+    })
+}
+
+/// Parses a failure rate like `0.5` or `50%`.
+pub fn parse_threshold(value: &str) -> Result<f64, String> {
+    let trimmed = value.trim();
+    let rate = match trimmed.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok().map(|p| p / 100.0),
+        None => trimmed.parse::<f64>().ok(),
+    };
+    rate.filter(|rate| *rate > 0.0 && *rate <= 1.0)
+        .ok_or_else(|| format!("'{}' is not a failure rate between 0 and 1 (or 1% to 100%)", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+
+    fn breaker(window: usize) -> CircuitBreaker {
+        let base_backoff = Duration::from_millis(1);
+        CircuitBreaker {
+            window,
+            threshold: 0.5,
+            base_backoff,
+            state: Mutex::new(State { recent: VecDeque::new(), mode: Mode::Closed, backoff: base_backoff, failed_probes: 0 }),
+            gave_up: Notify::new(),
+        }
+    }
+
+    fn status(code: u16) -> Error {
+        let status = StatusCode::from_u16(code).unwrap();
+        DownloadStatus { request: "Download".to_string(), status, trace: String::new() }.into()
+    }
+
+    #[test]
+    fn only_service_failures_count() {
+        assert!(is_service_failure(&status(503)));
+        assert!(is_service_failure(&status(429)));
+        assert!(is_service_failure(&Error::from(TruncatedBody { received: 1, expected: 2 })));
+        assert!(is_service_failure(&status(500).context("Failed to download")));
+        assert!(!is_service_failure(&status(403)));
+        assert!(!is_service_failure(&status(404)));
+        assert!(!is_service_failure(&anyhow!("Checksum mismatch")));
+    }
+
+    #[tokio::test]
+    async fn files_failing_for_their_own_reasons_dont_open_it() {
+        let breaker = breaker(4);
+        for _ in 0..8 {
+            let admission = breaker.admit().await;
+            assert!(breaker.record(admission, Some(&status(403))).is_none());
+        }
+        assert!(matches!(breaker.lock().mode, Mode::Closed));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_failed_probes() {
+        let breaker = breaker(1);
+        let admission = breaker.admit().await;
+        assert!(breaker.record(admission, Some(&status(503))).is_some());
+
+        let mut probes = 0;
+        let run = async {
+            loop {
+                let admission = breaker.admit().await;
+                assert!(admission == Admission::Probe);
+                probes += 1;
+                breaker.record(admission, Some(&status(503)));
+            }
+        };
+        tokio::select! {
+            _ = run => unreachable!(),
+            _ = breaker.gave_up() => {}
+        }
+        assert_eq!(probes, MAX_FAILED_PROBES);
+    }
+
+    #[tokio::test]
+    async fn a_successful_probe_resets_the_count() {
+        let breaker = breaker(1);
+        let admission = breaker.admit().await;
+        breaker.record(admission, Some(&status(503)));
+        for _ in 0..MAX_FAILED_PROBES - 1 {
+            let admission = breaker.admit().await;
+            breaker.record(admission, Some(&status(503)));
+        }
+        let admission = breaker.admit().await;
+        assert!(admission == Admission::Probe);
+        breaker.record(admission, None);
+        assert!(matches!(breaker.lock().mode, Mode::Closed));
+        assert_eq!(breaker.lock().failed_probes, 0);
+    }
+}
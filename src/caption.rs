@@ -0,0 +1,141 @@
+// Caption normalization for every place a caption can end up.
+//
+// Captions come straight from the album owner and may contain newlines, emoji,
+// RTL text and invisible bidi controls. Each output has its own rules, so
+// callers pick a context and get back a string that is safe for it.
+
+/// Where a caption is going to be written.
+#[derive(Clone, Copy, Debug)]
+pub enum CaptionContext {
+    /// Single line for terminal or table output.
+    Display,
+    /// Part of a filename, limited to `max_bytes` bytes of UTF-8.
+    Filename { max_bytes: usize },
+    /// Text content or attribute value inside an XML document (XMP sidecars, HTML).
+    Xml,
+}
+
+pub fn render_caption(caption: &str, context: CaptionContext) -> String {
+    let normalized = normalize(caption);
+    match context {
+        CaptionContext::Display => normalized,
+        CaptionContext::Filename { max_bytes } => filename_safe(&normalized, max_bytes),
+        CaptionContext::Xml => xml_escape(&normalized),
+    }
+}
+
+/// Collapses newlines and runs of whitespace into single spaces, drops control
+/// and bidi override characters, and trims the result.
+fn normalize(caption: &str) -> String {
+    let mut out = String::with_capacity(caption.len());
+    let mut pending_space = false;
+
+    for c in caption.chars() {
+        if c.is_whitespace() {
+            pending_space = !out.is_empty();
+            continue;
+        }
+        if c.is_control() || is_bidi_control(c) {
+            continue;
+        }
+        if pending_space {
+            out.push(' ');
+            pending_space = false;
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+fn filename_safe(normalized: &str, max_bytes: usize) -> String {
+    let replaced: String = normalized
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect();
+
+    // Leading dots would hide the file; trailing dots and spaces are stripped by Windows
+    let trimmed = replaced.trim_start_matches('.');
+    truncate_at_char_boundary(trimmed, max_bytes)
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
+
+/// Returns the longest prefix of `s` that fits in `max_bytes` without
+/// splitting a multi-byte character.
+pub fn truncate_at_char_boundary(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiline_captions_become_one_line() {
+        let caption = "  Beach day\n\nwith  the\r\nkids\t ";
+        assert_eq!(render_caption(caption, CaptionContext::Display), "Beach day with the kids");
+        let filename = render_caption(caption, CaptionContext::Filename { max_bytes: 255 });
+        assert_eq!(filename, "Beach day with the kids");
+    }
+
+    #[test]
+    fn emoji_are_kept_and_never_cut_in_half() {
+        let caption = "Sunset \u{1F305} at the \u{1F3D6}\u{FE0F} beach";
+        assert_eq!(render_caption(caption, CaptionContext::Display), caption);
+        assert_eq!(render_caption(caption, CaptionContext::Filename { max_bytes: 255 }), caption);
+        // The cut falls inside the 4-byte sunrise emoji
+        assert_eq!(render_caption(caption, CaptionContext::Filename { max_bytes: 9 }), "Sunset");
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(render_caption(family, CaptionContext::Xml), family);
+    }
+
+    #[test]
+    fn controls_and_bidi_overrides_are_dropped() {
+        let caption = "gpj.\u{202E}evil\u{7}\u{200F} photo";
+        assert_eq!(render_caption(caption, CaptionContext::Display), "gpj.evil photo");
+    }
+
+    #[test]
+    fn filenames_lose_separators_and_leading_dots() {
+        let caption = "..a/b\\c: what? <yes> \"ok\" | * .";
+        let filename = render_caption(caption, CaptionContext::Filename { max_bytes: 255 });
+        assert_eq!(filename, "a_b_c_ what_ _yes_ _ok_ _ _");
+    }
+
+    #[test]
+    fn xml_special_characters_are_escaped() {
+        let caption = "Tom & Jerry's <\"party\">";
+        let escaped = render_caption(caption, CaptionContext::Xml);
+        assert_eq!(escaped, "Tom &amp; Jerry&apos;s &lt;&quot;party&quot;&gt;");
+    }
+}
@@ -0,0 +1,59 @@
+// Server-relative time for URL expiry math.
+//
+// Signed URL expiry times come from Apple's clock, so comparing them with a
+// badly set local clock would refresh URLs far too early or not at all. The
+// first API response's `Date` header gives the offset between the two, and
+// `server_now` applies it.
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+/// Skew beyond this is worth telling the user about.
+const SKEW_WARNING_SECS: i64 = 120;
+
+static OFFSET_MS: AtomicI64 = AtomicI64::new(0);
+static OBSERVED: AtomicBool = AtomicBool::new(false);
+
+/// Records the offset to the server clock from a response's `Date` header.
+/// Only the first response counts; later ones are ignored.
+pub fn observe_server_date(headers: &HeaderMap) {
+    if OBSERVED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(server_time) = headers
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+    else {
+        return;
+    };
+    if OBSERVED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let offset = server_time.with_timezone(&Utc) - Utc::now();
+    OFFSET_MS.store(offset.num_milliseconds(), Ordering::Relaxed);
+
+    if offset.num_seconds().abs() > SKEW_WARNING_SECS {
+        eprintln!(
+            "⚠️  This machine's clock is {} {} iCloud's; URL expiry times are judged by iCloud's clock instead",
+            describe(offset.abs()),
+            if offset > Duration::zero() { "behind" } else { "ahead of" }
+        );
+    }
+}
+
+/// The current time by the server's clock, or the local clock if no server
+/// time has been seen.
+pub fn server_now() -> DateTime<Utc> {
+    Utc::now() + Duration::milliseconds(OFFSET_MS.load(Ordering::Relaxed))
+}
+
+fn describe(offset: Duration) -> String {
+    if offset.num_hours() > 0 {
+        format!("{}h {}m", offset.num_hours(), offset.num_minutes() % 60)
+    } else {
+        format!("{}m {}s", offset.num_minutes(), offset.num_seconds() % 60)
+    }
+}
@@ -0,0 +1,383 @@
+// `--on-conflict`: what happens when two photos end up with the same file
+// name, either within a run (two cameras both counting up from IMG_0001) or
+// because the name is already taken on disk by a file the manifest says
+// belongs to another photo. Names are compared ignoring case and Unicode
+// normalization, as they'd clash on macOS and Windows filesystems.
+//
+// Photos are resolved in album order, the first one keeping the name, and
+// names the manifest gives to other photos count as taken. That makes the
+// outcome the same on every run, so incremental runs keep finding their
+// files under the names they got the first time. A renamed photo's files all
+// get the same suffix, before the extension, so a Live Photo's still and
+// video stay paired: `IMG_0001 (1).JPG` and `IMG_0001 (1).MOV`.
+//
+// With --xmp-sidecars names are compared without their extension, as
+// `IMG_0001.JPG` and `IMG_0001.MOV` of two photos would share a sidecar, and
+// Photos would take them for one Live Photo.
+//
+// Existing files of the same photo aren't conflicts; --overwrite-policy
+// decides about those.
+
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::caption::truncate_at_char_boundary;
+use crate::manifest::Manifest;
+use crate::normalize::folded;
+use crate::outcomes::OutcomeTable;
+use crate::{file_stem, record_skip, DownloadInfo, MAX_FILENAME_BYTES};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictStrategy {
+    /// Leave the later photo out
+    Skip,
+    /// Write the later photo over the earlier one
+    Overwrite,
+    /// Add ` (1)`, ` (2)`, ... to the later photo's name
+    Rename,
+    /// Add the later photo's GUID to its name
+    #[default]
+    GuidSuffix,
+    /// Stop the run
+    Fail,
+}
+
+pub struct NameConflicts<'a> {
+    strategy: ConflictStrategy,
+    /// Whether names clash regardless of their extension.
+    by_stem: bool,
+    /// Folded name to the GUID of the photo it went to this run.
+    claimed: HashMap<String, String>,
+    /// Folded name to the GUID of the photo the manifest has it for.
+    on_disk: HashMap<String, String>,
+    /// Photo GUID to the suffix its files got, for files of the photo that
+    /// come later (recovered derivatives).
+    suffixes: HashMap<String, String>,
+    /// With `overwrite`: folded names of other photos' files to replace.
+    overwrites: HashSet<String>,
+    outcome_table: Option<&'a OutcomeTable>,
+    renamed: usize,
+    skipped: usize,
+    overwritten: usize,
+}
+
+impl<'a> NameConflicts<'a> {
+    pub fn new(
+        strategy: ConflictStrategy,
+        by_stem: bool,
+        manifest: Option<&Manifest>,
+        outcome_table: Option<&'a OutcomeTable>,
+    ) -> Result<Self> {
+        let on_disk = match manifest {
+            Some(manifest) => manifest
+                .entries()?
+                .into_iter()
+                .map(|entry| (name_key(by_stem, &entry.filename), entry.photo_guid))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            strategy,
+            by_stem,
+            claimed: HashMap::new(),
+            on_disk,
+            suffixes: HashMap::new(),
+            overwrites: HashSet::new(),
+            outcome_table,
+            renamed: 0,
+            skipped: 0,
+            overwritten: 0,
+        })
+    }
+
+    /// Resolves the conflicts of freshly named downloads, which come in album
+    /// order with each photo's files next to each other. With `overwrite`
+    /// this must be the whole album at once.
+    pub fn resolve(&mut self, infos: Vec<DownloadInfo>) -> Result<Vec<DownloadInfo>> {
+        if self.strategy == ConflictStrategy::Overwrite {
+            return Ok(self.overwrite(infos));
+        }
+
+        let mut resolved = Vec::with_capacity(infos.len());
+        let mut infos = infos.into_iter().peekable();
+        while let Some(first) = infos.next() {
+            let mut photo = vec![first];
+            while let Some(next) = infos.next_if(|next| next.photo_guid == photo[0].photo_guid) {
+                photo.push(next);
+            }
+
+            let guid = photo[0].photo_guid.clone();
+            if let Some(suffix) = self.suffixes.get(&guid) {
+                for info in &mut photo {
+                    info.filename = with_suffix(&info.filename, suffix);
+                }
+            }
+            if let Some((name, owner)) = self.taken(&photo, &guid, None) {
+                match self.strategy {
+                    ConflictStrategy::Skip => {
+                        for info in &photo {
+                            record_skip(self.outcome_table, info, "name taken by another photo");
+                        }
+                        self.skipped += 1;
+                        continue;
+                    }
+                    ConflictStrategy::Fail => {
+                        return Err(anyhow!(
+                            "Photos {} and {} would both be saved as '{}' (--on-conflict fail)",
+                            owner,
+                            guid,
+                            name
+                        ));
+                    }
+                    _ => {
+                        let suffix = (1..)
+                            .map(|n| self.suffix(&guid, n))
+                            .find(|suffix| self.taken(&photo, &guid, Some(suffix)).is_none())
+                            .expect("some suffix is free");
+                        for info in &mut photo {
+                            info.filename = with_suffix(&info.filename, &suffix);
+                        }
+                        self.suffixes.entry(guid.clone()).or_default().push_str(&suffix);
+                        self.renamed += 1;
+                    }
+                }
+            }
+
+            for info in photo {
+                self.claimed.insert(name_key(self.by_stem, &info.filename), guid.clone());
+                resolved.push(info);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// `overwrite`: every name goes to the last photo in album order that
+    /// has it; earlier photos' files under that name are left out.
+    fn overwrite(&mut self, infos: Vec<DownloadInfo>) -> Vec<DownloadInfo> {
+        let last_owner: HashMap<String, String> = infos
+            .iter()
+            .map(|info| (name_key(self.by_stem, &info.filename), info.photo_guid.clone()))
+            .collect();
+        infos
+            .into_iter()
+            .filter(|info| {
+                let key = name_key(self.by_stem, &info.filename);
+                if last_owner[&key] != info.photo_guid {
+                    record_skip(self.outcome_table, info, "name taken by a later photo");
+                    self.skipped += 1;
+                    return false;
+                }
+                if self.on_disk.get(&key).is_some_and(|owner| *owner != info.photo_guid) {
+                    self.overwritten += 1;
+                    self.overwrites.insert(folded(&info.filename));
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// The first of the photo's names, with `suffix` added, that another
+    /// photo has, and that photo's GUID.
+    fn taken(&self, photo: &[DownloadInfo], guid: &str, suffix: Option<&String>) -> Option<(String, String)> {
+        photo.iter().find_map(|info| {
+            let name = match suffix {
+                Some(suffix) => with_suffix(&info.filename, suffix),
+                None => info.filename.clone(),
+            };
+            let key = name_key(self.by_stem, &name);
+            let owner = self.claimed.get(&key).or_else(|| self.on_disk.get(&key))?;
+            (owner != guid).then(|| (name, owner.clone()))
+        })
+    }
+
+    /// The `n`th suffix to try for a photo.
+    fn suffix(&self, guid: &str, n: usize) -> String {
+        match (self.strategy, n) {
+            (ConflictStrategy::GuidSuffix, 1) => format!("_{}", guid),
+            (ConflictStrategy::GuidSuffix, n) => format!("_{} ({})", guid, n - 1),
+            (_, n) => format!(" ({})", n),
+        }
+    }
+
+    /// With `overwrite`: the folded names of other photos' files that are
+    /// to be replaced whatever --overwrite-policy says.
+    pub fn take_overwrites(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.overwrites)
+    }
+
+    pub fn report(&self) {
+        if self.renamed > 0 {
+            status!("🔀 Renamed {} photos whose names another photo has", self.renamed);
+        }
+        if self.skipped > 0 {
+            status!("🔀 Skipping {} files whose names another photo has", self.skipped);
+        }
+        if self.overwritten > 0 {
+            status!("🔀 Overwriting {} files of other photos with the same name", self.overwritten);
+        }
+    }
+}
+
+/// What `filename` is compared by.
+fn name_key(by_stem: bool, filename: &str) -> String {
+    if by_stem {
+        folded(file_stem(filename))
+    } else {
+        folded(filename)
+    }
+}
+
+/// `filename` with `suffix` added before the extension of its last
+/// component, shortening the name if need be so the suffix survives.
+fn with_suffix(filename: &str, suffix: &str) -> String {
+    let (dir, name) = match filename.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, filename),
+    };
+    let (stem, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name, ""),
+    };
+    let stem = truncate_at_char_boundary(stem, MAX_FILENAME_BYTES.saturating_sub(suffix.len() + ext.len()));
+    let name = format!("{}{}{}", stem, suffix, ext);
+    match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{ManifestExport, ManifestFormat};
+    use crate::testing::download_info;
+    use crate::{AssetKind, SavedFile};
+    use std::path::Path;
+
+    /// A manifest in `dir` recording each `(guid, filename)`.
+    fn manifest(dir: &Path, files: &[(&str, &str)]) -> Manifest {
+        let export = ManifestExport {
+            format: ManifestFormat::Json,
+            database: dir.join("manifest.sqlite"),
+            album_hash: "album".to_string(),
+            album_name: None,
+        };
+        let manifest = Manifest::open(dir.to_path_buf(), export).unwrap();
+        for (guid, filename) in files {
+            let saved = SavedFile {
+                filename: filename.to_string(),
+                size: 3,
+                size_mismatch: None,
+                verified: None,
+                format: None,
+            };
+            manifest.record(&download_info(guid, filename, Some(3)), &saved);
+        }
+        manifest.compact().unwrap();
+        manifest
+    }
+
+    /// Two photos from different cameras that both named a file IMG_0001.
+    fn clashing() -> Vec<DownloadInfo> {
+        vec![download_info("A", "IMG_0001.JPG", None), download_info("B", "img_0001.jpg", None)]
+    }
+
+    fn resolve(strategy: ConflictStrategy, manifest: Option<&Manifest>, infos: Vec<DownloadInfo>) -> Result<Vec<String>> {
+        let mut conflicts = NameConflicts::new(strategy, false, manifest, None)?;
+        let resolved = conflicts.resolve(infos)?;
+        Ok(resolved.into_iter().map(|info| format!("{}:{}", info.photo_guid, info.filename)).collect())
+    }
+
+    #[test]
+    fn skip_leaves_the_later_photo_out() {
+        assert_eq!(resolve(ConflictStrategy::Skip, None, clashing()).unwrap(), ["A:IMG_0001.JPG"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("OLD", "IMG_0001.JPG")]);
+        let resolved = resolve(ConflictStrategy::Skip, Some(&manifest), vec![download_info("B", "IMG_0001.JPG", None)]);
+        assert!(resolved.unwrap().is_empty());
+    }
+
+    #[test]
+    fn overwrite_gives_the_name_to_the_last_photo() {
+        assert_eq!(resolve(ConflictStrategy::Overwrite, None, clashing()).unwrap(), ["B:img_0001.jpg"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("OLD", "IMG_0001.JPG")]);
+        let mut conflicts = NameConflicts::new(ConflictStrategy::Overwrite, false, Some(&manifest), None).unwrap();
+        let resolved = conflicts.resolve(vec![download_info("B", "IMG_0001.JPG", None)]).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(conflicts.take_overwrites(), HashSet::from(["img_0001.jpg".to_string()]));
+    }
+
+    #[test]
+    fn rename_counts_up() {
+        let mut infos = clashing();
+        infos.push(download_info("C", "IMG_0001.JPG", None));
+        assert_eq!(
+            resolve(ConflictStrategy::Rename, None, infos).unwrap(),
+            ["A:IMG_0001.JPG", "B:img_0001 (1).jpg", "C:IMG_0001 (2).JPG"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("OLD", "IMG_0001.JPG")]);
+        let resolved = resolve(ConflictStrategy::Rename, Some(&manifest), vec![download_info("B", "IMG_0001.JPG", None)]);
+        assert_eq!(resolved.unwrap(), ["B:IMG_0001 (1).JPG"]);
+    }
+
+    #[test]
+    fn guid_suffix_adds_the_later_photos_guid() {
+        assert_eq!(
+            resolve(ConflictStrategy::GuidSuffix, None, clashing()).unwrap(),
+            ["A:IMG_0001.JPG", "B:img_0001_B.jpg"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("OLD", "IMG_0001.JPG")]);
+        let resolved =
+            resolve(ConflictStrategy::GuidSuffix, Some(&manifest), vec![download_info("B", "IMG_0001.JPG", None)]);
+        assert_eq!(resolved.unwrap(), ["B:IMG_0001_B.JPG"]);
+    }
+
+    #[test]
+    fn fail_stops_the_run() {
+        let error = resolve(ConflictStrategy::Fail, None, clashing()).unwrap_err().to_string();
+        assert!(error.contains("A and B"), "{}", error);
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("OLD", "IMG_0001.JPG")]);
+        let resolved = resolve(ConflictStrategy::Fail, Some(&manifest), vec![download_info("B", "IMG_0001.JPG", None)]);
+        assert!(resolved.is_err());
+    }
+
+    #[test]
+    fn a_photos_own_files_are_not_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &[("A", "IMG_0001.JPG")]);
+        let resolved = resolve(ConflictStrategy::Fail, Some(&manifest), vec![download_info("A", "IMG_0001.JPG", None)]);
+        assert_eq!(resolved.unwrap(), ["A:IMG_0001.JPG"]);
+    }
+
+    #[test]
+    fn a_live_photos_files_get_the_same_suffix() {
+        let mut motion = download_info("B", "IMG_0001.MOV", None);
+        motion.kind = AssetKind::LiveMotion;
+        let infos = vec![download_info("A", "IMG_0001.JPG", None), download_info("B", "IMG_0001.JPG", None), motion];
+        assert_eq!(
+            resolve(ConflictStrategy::Rename, None, infos).unwrap(),
+            ["A:IMG_0001.JPG", "B:IMG_0001 (1).JPG", "B:IMG_0001 (1).MOV"]
+        );
+    }
+
+    #[test]
+    fn a_second_run_gives_every_photo_the_same_name() {
+        let first = resolve(ConflictStrategy::Rename, None, clashing()).unwrap();
+
+        let downloaded: Vec<(&str, &str)> = first.iter().map(|entry| entry.split_once(':').unwrap()).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = manifest(dir.path(), &downloaded);
+        let second = resolve(ConflictStrategy::Rename, Some(&manifest), clashing()).unwrap();
+
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,217 @@
+// `--select`: download a curated subset of a burst-heavy album instead of
+// every near-duplicate. `--cover-only`: just the album's cover photo. Both
+// work on the photo list before any download URLs are requested, so the
+// skipped photos cost nothing.
+//
+// Hidden and recently deleted photos are left out the same way, when the
+// metadata marks them. Shared-album webstreams haven't been seen to: a photo
+// removed from a shared album just disappears from it. The markers below are
+// the ones iCloud Photos uses elsewhere, checked in case that changes.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use std::collections::HashMap;
+
+use crate::dates::{self, DateTimezone};
+use crate::{select_derivative, Photo};
+
+#[derive(Clone, Copy, Debug)]
+pub enum SelectStrategy {
+    /// The highest-resolution photo of each day
+    BestPerDay,
+    /// The earliest photo of each day
+    FirstPerDay,
+    /// The N highest-resolution photos of the album
+    Largest(usize),
+}
+
+impl std::fmt::Display for SelectStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectStrategy::BestPerDay => write!(f, "best-per-day"),
+            SelectStrategy::FirstPerDay => write!(f, "first-per-day"),
+            SelectStrategy::Largest(n) => write!(f, "largest-{}", n),
+        }
+    }
+}
+
+pub fn parse_select(value: &str) -> Result<SelectStrategy, String> {
+    match value.trim() {
+        "best-per-day" => Ok(SelectStrategy::BestPerDay),
+        "first-per-day" => Ok(SelectStrategy::FirstPerDay),
+        other => other
+            .strip_prefix("largest-")
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n > 0)
+            .map(SelectStrategy::Largest)
+            .ok_or_else(|| {
+                format!("'{}' is not a selection; use best-per-day, first-per-day or largest-<N> (e.g. largest-50)", value)
+            }),
+    }
+}
+
+/// Keeps the photos the strategy picks, in album order. Photos without a
+/// capture date can't be grouped by day, so the per-day strategies keep them
+/// all. Returns how many of them there were.
+pub fn select_photos(photos: &mut Vec<Photo>, strategy: SelectStrategy, timezone: DateTimezone) -> usize {
+    let mut keep = vec![false; photos.len()];
+    let mut undated = 0;
+
+    match strategy {
+        SelectStrategy::BestPerDay | SelectStrategy::FirstPerDay => {
+            // Index and capture time of the current pick for each day
+            let mut picks: HashMap<NaiveDate, (usize, DateTime<Utc>)> = HashMap::new();
+            for (i, photo) in photos.iter().enumerate() {
+                let Some(date) = photo.date_created.as_deref().and_then(dates::parse_date_created) else {
+                    keep[i] = true;
+                    undated += 1;
+                    continue;
+                };
+                let day = match timezone {
+                    DateTimezone::Utc => date.date_naive(),
+                    DateTimezone::Local => date.with_timezone(&Local).date_naive(),
+                };
+                let better = match picks.get(&day) {
+                    None => true,
+                    Some(&(current, current_date)) => match strategy {
+                        SelectStrategy::BestPerDay => resolution(photo) > resolution(&photos[current]),
+                        _ => date < current_date,
+                    },
+                };
+                if better {
+                    picks.insert(day, (i, date));
+                }
+            }
+            for (i, _) in picks.into_values() {
+                keep[i] = true;
+            }
+        }
+        SelectStrategy::Largest(n) => {
+            let mut by_resolution: Vec<usize> = (0..photos.len()).collect();
+            by_resolution.sort_by_key(|&i| std::cmp::Reverse(resolution(&photos[i])));
+            for i in by_resolution.into_iter().take(n) {
+                keep[i] = true;
+            }
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    photos.retain(|_| keep.next().unwrap_or(false));
+    undated
+}
+
+/// Album fields that may name the cover photo. The webstream has no
+/// documented one, so these are the names Apple uses for key photos elsewhere.
+const COVER_KEYS: [&str; 4] = ["coverPhotoGuid", "keyPhotoGuid", "coverAssetGuid", "keyAssetGuid"];
+
+/// Where `keep_cover` found the cover.
+pub enum CoverSource {
+    /// Named by the album metadata.
+    Designated,
+    /// The album names none, so its first photo stands in.
+    FirstPhoto,
+}
+
+/// Keeps only the album's cover photo. Without a cover in the metadata the
+/// first photo is used, unless `fallback` is off.
+pub fn keep_cover(
+    photos: &mut Vec<Photo>,
+    album_extra: &HashMap<String, serde_json::Value>,
+    fallback: bool,
+) -> Result<CoverSource> {
+    let designated = COVER_KEYS
+        .iter()
+        .filter_map(|key| album_extra.get(*key).and_then(|value| value.as_str()))
+        .find_map(|guid| photos.iter().position(|photo| photo.photo_guid == guid));
+
+    let (index, source) = match designated {
+        Some(index) => (index, CoverSource::Designated),
+        None if fallback && !photos.is_empty() => (0, CoverSource::FirstPhoto),
+        None => return Err(anyhow!(
+            "The album metadata doesn't name a cover photo (without --no-cover-fallback its first photo is used)"
+        )),
+    };
+    let cover = photos.swap_remove(index);
+    *photos = vec![cover];
+    Ok(source)
+}
+
+const HIDDEN_KEYS: [&str; 2] = ["isHidden", "hidden"];
+const DELETED_KEYS: [&str; 3] = ["isDeleted", "deleted", "isExpunged"];
+
+/// What `exclude_hidden_and_deleted` found.
+pub struct Exclusions {
+    pub hidden: usize,
+    pub deleted: usize,
+    /// Whether any photo carried a hidden or deleted field at all.
+    pub markers_seen: bool,
+}
+
+/// Drops hidden and recently deleted photos, unless they are to be included.
+pub fn exclude_hidden_and_deleted(photos: &mut Vec<Photo>, include_hidden: bool, include_deleted: bool) -> Exclusions {
+    let mut exclusions = Exclusions { hidden: 0, deleted: 0, markers_seen: false };
+    photos.retain(|photo| {
+        let hidden = marker(photo, &HIDDEN_KEYS);
+        let deleted = marker(photo, &DELETED_KEYS);
+        exclusions.markers_seen |= hidden.is_some() || deleted.is_some();
+        if deleted == Some(true) && !include_deleted {
+            exclusions.deleted += 1;
+            return false;
+        }
+        if hidden == Some(true) && !include_hidden {
+            exclusions.hidden += 1;
+            return false;
+        }
+        true
+    });
+    exclusions
+}
+
+/// How many photos each filter on the photo list left out, so a run left
+/// with no photos can say which filters did it rather than look like an
+/// empty album.
+pub struct Funnel {
+    total: usize,
+    removed: Vec<(&'static str, usize)>,
+}
+
+impl Funnel {
+    pub fn new(total: usize) -> Self {
+        Self { total, removed: Vec::new() }
+    }
+
+    /// Records that `filter` left out `count` photos.
+    pub fn record(&mut self, filter: &'static str, count: usize) {
+        if count > 0 {
+            self.removed.push((filter, count));
+        }
+    }
+
+    /// Explains a photo list that the filters emptied.
+    pub fn report_nothing_left(&self) {
+        status!("🚫 None of the album's {} photos passed the filters, nothing to download:", self.total);
+        for (filter, count) in &self.removed {
+            status!("   {}: {} photos left out", filter, count);
+        }
+    }
+}
+
+/// The first of `keys` present on the photo, as a flag. Apple sends booleans
+/// as strings ("1", "true") as often as not.
+fn marker(photo: &Photo, keys: &[&str]) -> Option<bool> {
+    keys.iter().find_map(|key| match photo.extra.get(*key)? {
+        serde_json::Value::Bool(flag) => Some(*flag),
+        serde_json::Value::Number(n) => Some(n.as_i64() != Some(0)),
+        serde_json::Value::String(s) => Some(matches!(s.to_ascii_lowercase().as_str(), "1" | "true" | "yes")),
+        _ => None,
+    })
+}
+
+/// Pixel count of the photo's best rendition, then its file size as a tiebreak.
+fn resolution(photo: &Photo) -> (u64, u64) {
+    let best = select_derivative(photo).map(|(_, derivative)| derivative);
+    let width = best.and_then(|d| d.width).or(photo.width).unwrap_or(0);
+    let height = best.and_then(|d| d.height).or(photo.height).unwrap_or(0);
+    let file_size = best.and_then(|d| d.file_size_bytes()).unwrap_or(0);
+    (width as u64 * height as u64, file_size)
+}
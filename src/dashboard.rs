@@ -0,0 +1,342 @@
+// Full-screen live dashboard for `--tui`: overall progress, transfer speed,
+// ETA, the files currently downloading and the most recent failures.
+//
+// Downloads report into a shared `Dashboard`; `Screen` redraws it a few
+// times per second on the alternate screen until it is closed. Raw mode is
+// deliberately left off so Ctrl-C still reaches the normal interrupt handler.
+
+use anyhow::{Context, Result};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Stdout;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::size::format_size;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+const MAX_RECENT_FAILURES: usize = 5;
+const BAR_WIDTH: usize = 20;
+
+/// Progress shared between the download tasks and the screen.
+pub struct Dashboard {
+    total_files: usize,
+    expected_bytes: Option<u64>,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    succeeded: usize,
+    failed: usize,
+    received_bytes: u64,
+    next_id: u64,
+    active: BTreeMap<u64, ActiveTransfer>,
+    recent_failures: VecDeque<String>,
+}
+
+struct ActiveTransfer {
+    name: String,
+    received: u64,
+    total: Option<u64>,
+}
+
+impl Dashboard {
+    /// `expected_bytes` is the size of the whole batch when every file's
+    /// size is known up front; it makes the ETA byte-based instead of
+    /// file-based.
+    pub fn new(total_files: usize, expected_bytes: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            total_files,
+            expected_bytes,
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Registers a file as in flight. It is listed until the returned
+    /// guard is dropped.
+    pub fn start(&self, name: &str, total: Option<u64>) -> Transfer<'_> {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.active.insert(id, ActiveTransfer { name: name.to_string(), received: 0, total });
+        Transfer { dashboard: self, id }
+    }
+
+    pub fn record_success(&self) {
+        self.lock().succeeded += 1;
+    }
+
+    pub fn record_failure(&self, message: String) {
+        self.lock().failed += 1;
+        self.record_warning(message);
+    }
+
+    /// Lists a problem under recent failures without counting the file as failed.
+    pub fn record_warning(&self, message: String) {
+        let mut state = self.lock();
+        if state.recent_failures.len() == MAX_RECENT_FAILURES {
+            state.recent_failures.pop_front();
+        }
+        state.recent_failures.push_back(message);
+    }
+
+    #[cfg(test)]
+    pub fn received_bytes(&self) -> u64 {
+        self.lock().received_bytes
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One file being downloaded; see `Dashboard::start`.
+pub struct Transfer<'a> {
+    dashboard: &'a Dashboard,
+    id: u64,
+}
+
+impl Transfer<'_> {
+    pub fn set_total(&self, total: u64) {
+        if let Some(transfer) = self.dashboard.lock().active.get_mut(&self.id) {
+            transfer.total = Some(total);
+        }
+    }
+
+    pub fn advance(&self, bytes: usize) {
+        let mut state = self.dashboard.lock();
+        state.received_bytes += bytes as u64;
+        if let Some(transfer) = state.active.get_mut(&self.id) {
+            transfer.received += bytes as u64;
+        }
+    }
+
+    /// Takes back bytes that were received but thrown away, e.g. by a
+    /// download cut short that starts over.
+    pub fn rewind(&self, bytes: u64) {
+        let mut state = self.dashboard.lock();
+        state.received_bytes = state.received_bytes.saturating_sub(bytes);
+        if let Some(transfer) = state.active.get_mut(&self.id) {
+            transfer.received = transfer.received.saturating_sub(bytes);
+        }
+    }
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        self.dashboard.lock().active.remove(&self.id);
+    }
+}
+
+/// The dashboard drawn on the alternate screen by a background task.
+pub struct Screen {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+    _guard: AlternateScreen,
+}
+
+impl Screen {
+    pub fn show(dashboard: Arc<Dashboard>) -> Result<Self> {
+        let guard = AlternateScreen::enter()?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+            .context("Failed to initialise the terminal dashboard")?;
+        terminal.clear()?;
+
+        let (stop, mut stopped) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            let mut speed = SpeedMeter::default();
+            let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = stopped.changed() => break,
+                }
+                draw(&mut terminal, &dashboard, &mut speed);
+            }
+            // Final frame so the last completions are visible before the screen closes
+            draw(&mut terminal, &dashboard, &mut speed);
+        });
+
+        Ok(Self { stop, task, _guard: guard })
+    }
+
+    /// Stops redrawing and returns to the normal screen.
+    pub async fn close(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Leaves the alternate screen when dropped, including on early returns.
+struct AlternateScreen;
+
+impl AlternateScreen {
+    fn enter() -> Result<Self> {
+        execute!(std::io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)
+            .context("Failed to switch to the alternate screen")?;
+        Ok(Self)
+    }
+}
+
+impl Drop for AlternateScreen {
+    fn drop(&mut self) {
+        let _ = execute!(std::io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+    }
+}
+
+/// Transfer speed averaged over the last few seconds.
+#[derive(Default)]
+struct SpeedMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl SpeedMeter {
+    fn sample(&mut self, received_bytes: u64) -> f64 {
+        let now = Instant::now();
+        self.samples.push_back((now, received_bytes));
+        while self.samples.len() > 2 && now.duration_since(self.samples[0].0) > SPEED_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (oldest_at, oldest_bytes) = self.samples[0];
+        let elapsed = now.duration_since(oldest_at).as_secs_f64();
+        if elapsed > 0.0 {
+            received_bytes.saturating_sub(oldest_bytes) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+}
+
+fn draw(terminal: &mut Terminal<CrosstermBackend<Stdout>>, dashboard: &Dashboard, speed: &mut SpeedMeter) {
+    let state = dashboard.lock();
+    let bytes_per_sec = speed.sample(state.received_bytes);
+    // Drawing errors (e.g. a closed terminal) must not abort the downloads
+    let _ = terminal.draw(|frame| render(frame, dashboard, &state, bytes_per_sec));
+}
+
+fn render(frame: &mut Frame, dashboard: &Dashboard, state: &State, bytes_per_sec: f64) {
+    let [overall_area, summary_area, active_area, failures_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(MAX_RECENT_FAILURES as u16 + 2),
+    ])
+    .areas(frame.area());
+
+    let finished = state.succeeded + state.failed;
+    let ratio = if dashboard.total_files == 0 {
+        1.0
+    } else {
+        finished as f64 / dashboard.total_files as f64
+    };
+    let overall = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(" iCloud Photo Album Downloader "))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio.clamp(0.0, 1.0))
+        .label(format!("{}/{} files", finished, dashboard.total_files));
+    frame.render_widget(overall, overall_area);
+
+    let elapsed = dashboard.started.elapsed();
+    let summary = format!(
+        "Speed: {}/s   Downloaded: {}   Failed: {}   Elapsed: {}   ETA: {}",
+        format_size(bytes_per_sec as u64),
+        format_size(state.received_bytes),
+        state.failed,
+        format_duration(elapsed),
+        eta(dashboard, state, bytes_per_sec, elapsed).map_or("--:--:--".to_string(), format_duration),
+    );
+    frame.render_widget(
+        Paragraph::new(summary).block(Block::default().borders(Borders::ALL)),
+        summary_area,
+    );
+
+    let name_width = (active_area.width as usize).saturating_sub(BAR_WIDTH + 30).max(10);
+    let active: Vec<ListItem> = state
+        .active
+        .values()
+        .map(|transfer| ListItem::new(transfer_line(transfer, name_width)))
+        .collect();
+    frame.render_widget(
+        List::new(active).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" Active ({}) ", state.active.len())),
+        ),
+        active_area,
+    );
+
+    let failures: Vec<ListItem> = state
+        .recent_failures
+        .iter()
+        .rev()
+        .map(|message| ListItem::new(Line::styled(message.as_str(), Style::default().fg(Color::Red))))
+        .collect();
+    frame.render_widget(
+        List::new(failures).block(Block::default().borders(Borders::ALL).title(" Recent failures ")),
+        failures_area,
+    );
+}
+
+fn transfer_line(transfer: &ActiveTransfer, name_width: usize) -> String {
+    let name: String = if transfer.name.chars().count() > name_width {
+        let mut short: String = transfer.name.chars().take(name_width.saturating_sub(1)).collect();
+        short.push('…');
+        short
+    } else {
+        transfer.name.clone()
+    };
+
+    match transfer.total {
+        Some(total) if total > 0 => {
+            let ratio = (transfer.received as f64 / total as f64).min(1.0);
+            let filled = (ratio * BAR_WIDTH as f64) as usize;
+            format!(
+                "{:<width$} [{}{}] {} / {}",
+                name,
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled),
+                format_size(transfer.received),
+                format_size(total),
+                width = name_width,
+            )
+        }
+        _ => format!("{:<width$} {}", name, format_size(transfer.received), width = name_width),
+    }
+}
+
+/// Time left, from bytes when the batch size is known and otherwise from
+/// the average time per finished file.
+fn eta(dashboard: &Dashboard, state: &State, bytes_per_sec: f64, elapsed: Duration) -> Option<Duration> {
+    if let Some(expected) = dashboard.expected_bytes {
+        if bytes_per_sec <= 0.0 {
+            return None;
+        }
+        let remaining = expected.saturating_sub(state.received_bytes);
+        return Some(Duration::from_secs_f64(remaining as f64 / bytes_per_sec));
+    }
+
+    let finished = state.succeeded + state.failed;
+    if finished == 0 {
+        return None;
+    }
+    let remaining = dashboard.total_files.saturating_sub(finished);
+    Some(elapsed.mul_f64(remaining as f64 / finished as f64))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
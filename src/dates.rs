@@ -0,0 +1,235 @@
+// Capture dates in filenames and folder names (--date-prefix, --folder-by-date).
+//
+// `dateCreated` comes from iCloud as an RFC 3339 timestamp in UTC. It is
+// rendered with a strftime pattern (--date-format) in either UTC or the
+// machine's local time zone (--timezone). Photos without a usable date get no
+// prefix and go into the --undated-folder.
+//
+// With --burst-index, photos whose prefixes come out identical (bursts, at
+// the resolution of the pattern) are numbered in album order after the date,
+// `2023-06-01_120000_01_IMG_0001.JPG`, so the prefix alone orders them.
+
+use chrono::format::StrftimeItems;
+use chrono::{DateTime, Local, Utc};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::{limit_filename_length, DownloadInfo, MAX_FILENAME_BYTES};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DateTimezone {
+    /// Render dates in UTC
+    Utc,
+    /// Render dates in this machine's time zone
+    Local,
+}
+
+/// Rejects patterns chrono can't format, so a typo fails up front rather
+/// than at the first photo.
+pub fn parse_date_format(value: &str) -> Result<String, String> {
+    StrftimeItems::new(value)
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid strftime pattern", value))?;
+    Ok(value.to_string())
+}
+
+pub fn parse_date_created(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+pub struct DateNaming {
+    pub format: String,
+    pub timezone: DateTimezone,
+    pub prefix: bool,
+    pub burst_index: bool,
+    pub folders: bool,
+    pub undated_folder: String,
+}
+
+impl DateNaming {
+    pub fn is_enabled(&self) -> bool {
+        self.prefix || self.folders
+    }
+
+    fn render(&self, date: DateTime<Utc>) -> String {
+        match self.timezone {
+            DateTimezone::Utc => date.format(&self.format).to_string(),
+            DateTimezone::Local => date.with_timezone(&Local).format(&self.format).to_string(),
+        }
+    }
+
+    /// Rewrites each filename to carry the date prefix and/or date folder.
+    /// `album_order` gives each photo GUID's position in the album, which
+    /// numbers bursts the same way on every run.
+    pub fn apply_all(&self, infos: &mut [DownloadInfo], album_order: &HashMap<&str, usize>) {
+        let burst_indexes = if self.prefix && self.burst_index {
+            self.burst_indexes(infos, album_order)
+        } else {
+            HashMap::new()
+        };
+        for info in infos {
+            let burst_index = burst_indexes.get(&info.photo_guid).copied();
+            self.apply(info, burst_index);
+        }
+    }
+
+    /// 1-based index of each photo within the group sharing its rendered
+    /// prefix, for groups of more than one photo. The files of a live photo
+    /// share their photo's index.
+    fn burst_indexes(&self, infos: &[DownloadInfo], album_order: &HashMap<&str, usize>) -> HashMap<String, usize> {
+        let mut groups: HashMap<String, BTreeSet<(usize, &str)>> = HashMap::new();
+        for info in infos {
+            if let Some(date) = info.date_created {
+                let position = album_order.get(info.photo_guid.as_str()).copied().unwrap_or(usize::MAX);
+                groups.entry(self.render(date)).or_default().insert((position, &info.photo_guid));
+            }
+        }
+
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .flat_map(|group| {
+                group.into_iter().enumerate().map(|(i, (_, guid))| (guid.to_string(), i + 1))
+            })
+            .collect()
+    }
+
+    /// A `/` in the pattern makes nested folders; elsewhere it is replaced.
+    fn apply(&self, info: &mut DownloadInfo, burst_index: Option<usize>) {
+        let rendered = info.date_created.map(|date| self.render(date));
+
+        if self.prefix {
+            if let Some(rendered) = &rendered {
+                let mut prefix = sanitize_component(&rendered.replace('/', "-"));
+                if let Some(index) = burst_index {
+                    prefix.push_str(&format!("_{:02}", index));
+                }
+                info.filename = limit_filename_length(&format!("{}_{}", prefix, info.filename));
+            }
+        }
+
+        if self.folders {
+            let folder = match &rendered {
+                Some(rendered) => rendered
+                    .split('/')
+                    .map(sanitize_component)
+                    .filter(|component| !component.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                None => sanitize_component(&self.undated_folder),
+            };
+            if !folder.is_empty() {
+                info.filename = format!("{}/{}", folder, info.filename);
+            }
+        }
+    }
+}
+
+/// Makes one path component safe; leading dots are stripped, so it can't be `..`.
+fn sanitize_component(component: &str) -> String {
+    render_caption(component, CaptionContext::Filename { max_bytes: MAX_FILENAME_BYTES })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::download_info;
+    use crate::AssetKind;
+
+    fn naming(burst_index: bool) -> DateNaming {
+        DateNaming {
+            format: "%Y-%m-%d_%H%M%S".to_string(),
+            timezone: DateTimezone::Utc,
+            prefix: true,
+            burst_index,
+            folders: false,
+            undated_folder: "undated".to_string(),
+        }
+    }
+
+    fn taken(guid: &str, filename: &str, date: &str) -> DownloadInfo {
+        let mut info = download_info(guid, filename, None);
+        info.date_created = parse_date_created(date);
+        info
+    }
+
+    fn names(infos: &[DownloadInfo]) -> Vec<&str> {
+        infos.iter().map(|info| info.filename.as_str()).collect()
+    }
+
+    #[test]
+    fn burst_is_numbered_in_album_order() {
+        let burst = "2023-06-01T12:00:00Z";
+        // Listed out of album order, as they may be after batching.
+        let mut infos = vec![
+            taken("c", "IMG_0003.JPG", burst),
+            taken("a", "IMG_0001.JPG", burst),
+            taken("d", "IMG_0004.JPG", "2023-06-01T12:00:05Z"),
+            taken("b", "IMG_0002.JPG", burst),
+        ];
+        let album_order = HashMap::from([("a", 0), ("b", 1), ("c", 2), ("d", 3)]);
+
+        naming(true).apply_all(&mut infos, &album_order);
+
+        assert_eq!(
+            names(&infos),
+            [
+                "2023-06-01_120000_03_IMG_0003.JPG",
+                "2023-06-01_120000_01_IMG_0001.JPG",
+                "2023-06-01_120005_IMG_0004.JPG",
+                "2023-06-01_120000_02_IMG_0002.JPG",
+            ]
+        );
+    }
+
+    #[test]
+    fn burst_numbering_does_not_depend_on_listing_order() {
+        let burst = "2023-06-01T12:00:00Z";
+        let album_order = HashMap::from([("a", 0), ("b", 1), ("c", 2)]);
+        let mut forwards = vec![taken("a", "A.JPG", burst), taken("b", "B.JPG", burst), taken("c", "C.JPG", burst)];
+        let mut backwards = vec![taken("c", "C.JPG", burst), taken("b", "B.JPG", burst), taken("a", "A.JPG", burst)];
+
+        naming(true).apply_all(&mut forwards, &album_order);
+        naming(true).apply_all(&mut backwards, &album_order);
+
+        let mut forwards = names(&forwards);
+        let mut backwards = names(&backwards);
+        forwards.sort();
+        backwards.sort();
+        assert_eq!(forwards, backwards);
+        assert_eq!(forwards[0], "2023-06-01_120000_01_A.JPG");
+    }
+
+    #[test]
+    fn live_photo_files_share_their_photo_index() {
+        let burst = "2023-06-01T12:00:00Z";
+        let mut motion = taken("b", "IMG_0002.MOV", burst);
+        motion.kind = AssetKind::LiveMotion;
+        let mut infos = vec![taken("a", "IMG_0001.JPG", burst), taken("b", "IMG_0002.JPG", burst), motion];
+        let album_order = HashMap::from([("a", 0), ("b", 1)]);
+
+        naming(true).apply_all(&mut infos, &album_order);
+
+        assert_eq!(
+            names(&infos),
+            [
+                "2023-06-01_120000_01_IMG_0001.JPG",
+                "2023-06-01_120000_02_IMG_0002.JPG",
+                "2023-06-01_120000_02_IMG_0002.MOV",
+            ]
+        );
+    }
+
+    #[test]
+    fn without_burst_index_bursts_keep_the_plain_prefix() {
+        let burst = "2023-06-01T12:00:00Z";
+        let mut infos = vec![taken("a", "IMG_0001.JPG", burst), taken("b", "IMG_0002.JPG", burst)];
+        let album_order = HashMap::from([("a", 0), ("b", 1)]);
+
+        naming(false).apply_all(&mut infos, &album_order);
+
+        assert_eq!(names(&infos), ["2023-06-01_120000_IMG_0001.JPG", "2023-06-01_120000_IMG_0002.JPG"]);
+    }
+}
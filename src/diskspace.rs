@@ -0,0 +1,100 @@
+// `--min-free-space`: keeps a long unattended run from filling the disk.
+// Free space on the output filesystem is checked before each download; below
+// the threshold, new downloads either wait for space to be freed
+// (`--on-low-space wait`) or the run stops cleanly (`abort`), leaving
+// downloaded files in place for `--repair` to pick up from. Without it a
+// full disk shows up as a cascade of confusing write failures.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::size::format_size;
+use crate::Args;
+
+/// How often a paused run checks whether space has been freed.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LowSpaceAction {
+    /// Pause new downloads until enough space is free again
+    Wait,
+    /// Stop the run; re-run with --repair once space is freed
+    Abort,
+}
+
+pub struct DiskSpaceGuard {
+    min_free: u64,
+    action: LowSpaceAction,
+    paused: AtomicBool,
+    stopped: Notify,
+}
+
+impl DiskSpaceGuard {
+    /// `None` without --min-free-space, or where free space can't be queried.
+    pub fn from_args(args: &Args) -> Option<Self> {
+        let min_free = args.min_free_space?;
+        if !cfg!(unix) {
+            eprintln!("⚠️  --min-free-space is only supported on Unix and is ignored here");
+            return None;
+        }
+        Some(Self { min_free, action: args.on_low_space, paused: AtomicBool::new(false), stopped: Notify::new() })
+    }
+
+    /// Waits until there's enough free space in `dir` to start another
+    /// download. With `abort`, fails instead and wakes `stopped`. Pausing and
+    /// resuming are reported through `report`.
+    pub async fn admit(&self, dir: &Path, report: impl Fn(String)) -> Result<()> {
+        loop {
+            // A filesystem that can't be queried isn't worth failing downloads over
+            let Some(free) = available_space(dir) else {
+                return Ok(());
+            };
+            if free >= self.min_free {
+                if self.paused.swap(false, Ordering::Relaxed) {
+                    report(format!("💾 {} free again, resuming downloads", format_size(free)));
+                }
+                return Ok(());
+            }
+
+            match self.action {
+                LowSpaceAction::Abort => {
+                    self.stopped.notify_one();
+                    return Err(anyhow!(
+                        "only {} free on the output disk (--min-free-space {})",
+                        format_size(free),
+                        format_size(self.min_free)
+                    ));
+                }
+                LowSpaceAction::Wait => {
+                    if !self.paused.swap(true, Ordering::Relaxed) {
+                        report(format!(
+                            "💾 Only {} free on the output disk, pausing downloads until {} is free",
+                            format_size(free),
+                            format_size(self.min_free)
+                        ));
+                    }
+                    tokio::time::sleep(RECHECK_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Resolves once a download was refused with `--on-low-space abort`.
+    pub async fn stopped(&self) {
+        self.stopped.notified().await
+    }
+}
+
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    let stat = rustix::fs::statvfs(dir).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
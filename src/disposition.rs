@@ -0,0 +1,267 @@
+// `--naming content-disposition`: names files after the `filename` in the
+// `Content-Disposition` header of their download, which can carry the
+// original name where the URL path has only a token.
+//
+// Everything that decides where a file goes (date prefixes, --on-conflict,
+// skipping files already on disk) needs the names before the downloads
+// start, so the header is read from a HEAD request per photo up front, like
+// --head-check does. The photo's other files (the motion video of a Live
+// Photo, other --derivatives) take on the new name too, so they stay
+// together. Without a usable header a file keeps its usual name.
+
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+
+use crate::headers::RequestKind;
+use crate::http::HttpClient;
+use crate::{file_stem, limit_filename_length, percent_decode_bytes, safe_filename, AssetKind, DownloadInfo};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NamingSource {
+    /// The original file name iCloud lists, else the last part of the download URL
+    #[default]
+    Listed,
+    /// The file name in the download's Content-Disposition header, else as `listed`
+    ContentDisposition,
+}
+
+/// What `apply` did.
+pub struct Renamed {
+    /// Photos whose files got a new name.
+    pub renamed: usize,
+    /// Photos whose response had no usable header, or whose HEAD request failed.
+    pub unnamed: usize,
+}
+
+impl Renamed {
+    pub fn report(&self) {
+        if self.renamed > 0 {
+            status!("📛 Named the files of {} photos after their Content-Disposition", self.renamed);
+        }
+        if self.unnamed > 0 {
+            status!("   {} photos had no file name in their response and keep their usual name", self.unnamed);
+        }
+    }
+}
+
+/// Renames each photo's files after the header of its main file, asking
+/// at most `concurrency` HEAD requests at a time.
+pub async fn apply(client: &impl HttpClient, infos: &mut [DownloadInfo], concurrency: usize) -> Renamed {
+    let mut files_by_photo: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, info) in infos.iter().enumerate() {
+        files_by_photo.entry(info.photo_guid.as_str()).or_default().push(i);
+    }
+    // Each photo's main file (its still or video) and all of its files
+    let photos: Vec<(usize, Vec<usize>)> = files_by_photo
+        .into_values()
+        .map(|files| {
+            let main = files.iter().copied().find(|&i| infos[i].kind != AssetKind::LiveMotion).unwrap_or(files[0]);
+            (main, files)
+        })
+        .collect();
+
+    let names: Vec<(usize, Option<String>)> = stream::iter(photos.iter().enumerate())
+        .map(|(p, (main, _))| {
+            let url = &infos[*main].download_url;
+            async move { (p, head_filename(client, url).await) }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut renamed = Renamed { renamed: 0, unnamed: 0 };
+    for (p, name) in names {
+        let Some(name) = name else {
+            renamed.unnamed += 1;
+            continue;
+        };
+        let (main, files) = &photos[p];
+        if infos[*main].filename == name {
+            continue;
+        }
+        rename_photo(infos, *main, files, &name);
+        renamed.renamed += 1;
+    }
+    renamed
+}
+
+/// Gives the photo's main file `name`, and its other files `name`'s stem in
+/// place of the old one, keeping their suffix and extension.
+fn rename_photo(infos: &mut [DownloadInfo], main: usize, files: &[usize], name: &str) {
+    let new_stem = file_stem(name).to_string();
+    for &i in files {
+        let info = &mut infos[i];
+        // A file of several --derivatives carries a suffix after the stem
+        let suffixed = file_stem(&info.filename) != info.photo_stem;
+        let renamed = if i == main && !suffixed {
+            name.to_string()
+        } else {
+            let Some(rest) = info.filename.strip_prefix(info.photo_stem.as_str()) else {
+                continue;
+            };
+            limit_filename_length(&format!("{}{}", new_stem, rest))
+        };
+        info.filename = renamed;
+        info.photo_stem = new_stem.clone();
+    }
+}
+
+async fn head_filename(client: &impl HttpClient, url: &str) -> Option<String> {
+    let response = client.head(url, RequestKind::Download).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let value = response.headers().get(reqwest::header::CONTENT_DISPOSITION)?;
+    filename_from_header(&String::from_utf8_lossy(value.as_bytes()))
+}
+
+/// The file name in a `Content-Disposition` value, as a safe plain file
+/// name. The RFC 5987 `filename*=UTF-8''...` form wins over `filename=`.
+fn filename_from_header(value: &str) -> Option<String> {
+    let params = parameters(value);
+    let param = |wanted: &str| {
+        params
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    };
+    let name = param("filename*")
+        .and_then(decode_extended)
+        .or_else(|| param("filename").map(unquote))?;
+    safe_filename(&name)
+}
+
+/// The `name=value` parameters after the disposition type, split on `;`
+/// outside quoted strings.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                segments.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    segments.push(current);
+
+    segments
+        .iter()
+        .skip(1)
+        .filter_map(|segment| {
+            let (name, value) = segment.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// A token or quoted string, without the quotes and backslash escapes.
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+/// `charset'language'percent-encoded` (RFC 5987). UTF-8 and ISO-8859-1 are
+/// the only charsets it allows.
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes = percent_decode_bytes(encoded);
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, download_info, FakeClient};
+    use reqwest::header::{HeaderValue, CONTENT_DISPOSITION};
+
+    #[test]
+    fn plain_and_quoted_names_are_read() {
+        assert_eq!(filename_from_header("attachment; filename=IMG_0001.HEIC").as_deref(), Some("IMG_0001.HEIC"));
+        assert_eq!(filename_from_header("attachment; filename=\"IMG 0001.HEIC\"").as_deref(), Some("IMG 0001.HEIC"));
+        // Semicolons and escaped quotes inside the quotes belong to the name
+        assert_eq!(
+            filename_from_header(r#"attachment; filename="a;b \"c\".jpg"; size=3"#).as_deref(),
+            Some(r#"a;b "c".jpg"#)
+        );
+        assert_eq!(filename_from_header("ATTACHMENT; FileName=IMG_0002.MOV").as_deref(), Some("IMG_0002.MOV"));
+    }
+
+    #[test]
+    fn rfc_5987_names_win_over_plain_ones() {
+        let value = "attachment; filename=\"fallback.jpg\"; filename*=UTF-8''Caf%C3%A9%20%F0%9F%8C%85.jpg";
+        assert_eq!(filename_from_header(value).as_deref(), Some("Café 🌅.jpg"));
+        assert_eq!(filename_from_header("attachment; filename*=iso-8859-1'en'%E9t%E9.png").as_deref(), Some("été.png"));
+        // An unusable extended name falls back to the plain one
+        let value = "attachment; filename*=UTF-8''%FF%FE.jpg; filename=plain.jpg";
+        assert_eq!(filename_from_header(value).as_deref(), Some("plain.jpg"));
+        assert_eq!(filename_from_header("attachment; filename*=KOI8-R''%C1.jpg"), None);
+    }
+
+    #[test]
+    fn names_cant_leave_the_output_directory() {
+        assert_eq!(filename_from_header("attachment; filename=\"../../etc/passwd\"").as_deref(), Some("passwd"));
+        assert_eq!(
+            filename_from_header("attachment; filename*=UTF-8''..%2F..%2F.ssh%2Fauthorized_keys").as_deref(),
+            Some("authorized_keys")
+        );
+        assert_eq!(filename_from_header(r#"attachment; filename="C:\\Windows\\x.jpg""#).as_deref(), Some("x.jpg"));
+        assert_eq!(filename_from_header("attachment; filename=\"..\""), None);
+        assert_eq!(filename_from_header("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn headers_without_a_name_give_none() {
+        assert_eq!(filename_from_header("attachment"), None);
+        assert_eq!(filename_from_header("inline; size=100"), None);
+        assert_eq!(filename_from_header(""), None);
+    }
+
+    #[tokio::test]
+    async fn a_photos_files_take_the_name_from_its_header() {
+        let client = FakeClient::new(|request| {
+            let response = testing::file(&request.url, b"", 0);
+            match request.url.contains("/ckP1/") {
+                true => response.with_header(
+                    CONTENT_DISPOSITION,
+                    HeaderValue::from_static("attachment; filename*=UTF-8''IMG_0001.HEIC"),
+                ),
+                false => response,
+            }
+        });
+        let mut motion = download_info("P1", "01a2b3.mov", None);
+        motion.kind = AssetKind::LiveMotion;
+        motion.download_url = "https://files.test/ckP1motion/01a2b3.mov".to_string();
+        let mut infos = vec![download_info("P1", "01a2b3", None), motion, download_info("P2", "04c5d6.jpg", None)];
+
+        let renamed = apply(&client, &mut infos, 2).await;
+
+        let names: Vec<&str> = infos.iter().map(|info| info.filename.as_str()).collect();
+        assert_eq!(names, ["IMG_0001.HEIC", "IMG_0001.mov", "04c5d6.jpg"]);
+        assert_eq!((renamed.renamed, renamed.unnamed), (1, 1));
+        assert_eq!(client.count("/ckP1motion/"), 0);
+    }
+}
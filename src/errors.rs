@@ -0,0 +1,105 @@
+// Typed errors for failures callers need to tell apart. Everything else is
+// reported through `anyhow` with context.
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::fmt;
+
+use crate::sharedstreams;
+
+#[derive(Debug)]
+pub enum AlbumError {
+    /// The share link was revoked, expired or never existed. Retrying won't help.
+    Unavailable { status: StatusCode, trace: String },
+    /// iCloud had a temporary problem; the same request may succeed later.
+    Transient { status: StatusCode, trace: String },
+    /// The album lives on another sharedstreams host than `from` (status 330).
+    WrongHost { from: String, host: Option<String>, trace: String },
+}
+
+impl AlbumError {
+    /// Classifies a failed webstream response from `from` by its status,
+    /// headers and body. `trace` holds Apple's request ID headers so users
+    /// have something to quote.
+    pub fn from_webstream_response(
+        from: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+        trace: String,
+    ) -> Option<Self> {
+        if status.as_u16() == sharedstreams::WRONG_HOST_STATUS {
+            let host = sharedstreams::host_from_wrong_host_response(headers, body);
+            return Some(AlbumError::WrongHost { from: from.to_string(), host, trace });
+        }
+
+        let body = body.to_ascii_lowercase();
+        let mentions_gone = ["not found", "notfound", "revoked", "expired", "does not exist"]
+            .iter()
+            .any(|needle| body.contains(needle));
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::GONE => {
+                Some(AlbumError::Unavailable { status, trace })
+            }
+            _ if status.is_client_error() && mentions_gone => Some(AlbumError::Unavailable { status, trace }),
+            StatusCode::TOO_MANY_REQUESTS => Some(AlbumError::Transient { status, trace }),
+            _ if status.is_server_error() => Some(AlbumError::Transient { status, trace }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AlbumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlbumError::Unavailable { status, trace } => write!(
+                f,
+                "This shared album link has been revoked or no longer exists (HTTP {}){}. \
+                 Ask the album owner for a new link.",
+                status.as_u16(),
+                trace
+            ),
+            AlbumError::Transient { status, trace } => write!(
+                f,
+                "iCloud is having temporary problems (HTTP {}){}. Try again in a few minutes.",
+                status.as_u16(),
+                trace
+            ),
+            AlbumError::WrongHost { from, host: Some(host), trace } => write!(
+                f,
+                "This album is served by {} rather than {}{}. Re-run with --host-override {}",
+                host,
+                from,
+                trace,
+                host
+            ),
+            AlbumError::WrongHost { from, host: None, trace } => write!(
+                f,
+                "{} doesn't serve this album (HTTP 330){} and didn't say which host does. \
+                 Try --host-override with another pNN-sharedstreams.icloud.com host",
+                from,
+                trace
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AlbumError {}
+
+/// A download (or one range of it) answered with an error status.
+#[derive(Debug)]
+pub struct DownloadStatus {
+    /// What was requested: `Download`, or `Request for bytes 0-99`.
+    pub request: String,
+    pub status: StatusCode,
+    pub trace: String,
+}
+
+impl fmt::Display for DownloadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed with status: {}{}", self.request, self.status, self.trace)
+    }
+}
+
+impl std::error::Error for DownloadStatus {}
@@ -0,0 +1,258 @@
+// Lookup of files saved by a previous run, shared by the skip, upgrade and
+// repair logic.
+
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+
+use crate::apple_checksum::verify_apple_checksum;
+use crate::normalize;
+use crate::{AssetKind, DownloadInfo};
+
+/// What happens to files already in the output directory (`--overwrite-policy`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverwritePolicy {
+    /// Keep them; only download what's missing
+    #[default]
+    Never,
+    /// Download everything again over them
+    Always,
+    /// Replace a file whose size or checksum differs from the album's version
+    IfDifferent,
+    /// Replace a file when the album offers a larger version
+    IfLarger,
+}
+
+/// The files of each directory in the output directory, each read once, so
+/// checking a whole album doesn't scan a directory per file.
+#[derive(Default)]
+pub struct DirListing {
+    dirs: HashMap<PathBuf, DirFiles>,
+}
+
+#[derive(Default)]
+struct DirFiles {
+    sizes: HashMap<String, u64>,
+    /// Composed stem to whether the file is a video, and its size.
+    stems: HashMap<String, Vec<(bool, u64)>>,
+}
+
+impl DirFiles {
+    fn read(dir: &Path) -> Self {
+        let mut files = Self::default();
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let Ok(name) = entry.file_name().into_string() else { continue };
+            // Followed, so links into a --content-store count with their target's size
+            let Ok(meta) = fs::metadata(entry.path()) else { continue };
+            if !meta.is_file() {
+                continue;
+            }
+            if let Some((stem, ext)) = name.rsplit_once('.') {
+                let is_video = matches!(ext.to_ascii_lowercase().as_str(), "mov" | "mp4");
+                files.stems.entry(normalize::composed(stem)).or_default().push((is_video, meta.len()));
+            }
+            files.sizes.insert(name, meta.len());
+        }
+        files
+    }
+}
+
+impl DirListing {
+    /// Size of the file previously saved for `info`, found as `existing_file`
+    /// would find it.
+    pub fn file_size(&mut self, info: &DownloadInfo, output_dir: &str) -> Option<u64> {
+        let path = Path::new(output_dir).join(&info.filename);
+        let name = path.file_name()?.to_str()?;
+        let files = self.files(path.parent()?);
+        if let Some(size) = files.sizes.get(name) {
+            return Some(*size);
+        }
+        let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+        let wants_video = info.kind != AssetKind::Still;
+        files.stems.get(&normalize::composed(stem))?
+            .iter()
+            .find(|(is_video, _)| *is_video == wants_video)
+            .map(|(_, size)| *size)
+    }
+
+    /// Size of the file at `relative`, by its exact name.
+    pub fn exact_size(&mut self, output_dir: &str, relative: &str) -> Option<u64> {
+        let path = Path::new(output_dir).join(relative);
+        let name = path.file_name()?.to_str()?;
+        self.files(path.parent()?).sizes.get(name).copied()
+    }
+
+    fn files(&mut self, dir: &Path) -> &DirFiles {
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| DirFiles::read(dir))
+    }
+}
+
+/// The file previously saved for `info`. Extension correction may have
+/// renamed it, so a file with the same stem and a matching media type also
+/// counts, as does one whose name is in another Unicode normalization form.
+fn existing_file(info: &DownloadInfo, output_dir: &str) -> Option<(PathBuf, Metadata)> {
+    let path = Path::new(output_dir).join(&info.filename);
+    if let Ok(meta) = fs::metadata(&path) {
+        return Some((path, meta));
+    }
+
+    // The filename may include date folders, so look next to where it would be
+    let dir = path.parent()?;
+    let name = path.file_name()?.to_str()?;
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let wants_video = info.kind != AssetKind::Still;
+
+    fs::read_dir(dir).ok()?.flatten().find_map(|entry| {
+        let name = entry.file_name();
+        let name = name.to_str()?;
+        let (entry_stem, ext) = name.rsplit_once('.')?;
+        let is_video = matches!(ext.to_ascii_lowercase().as_str(), "mov" | "mp4");
+        if normalize::same_name(entry_stem, stem) && is_video == wants_video {
+            Some((entry.path(), entry.metadata().ok()?))
+        } else {
+            None
+        }
+    })
+}
+
+pub enum ExistingAction {
+    /// Nothing on disk yet
+    Download,
+    /// Already downloaded, leave it alone
+    Skip,
+    /// On disk, but smaller than what the album now offers
+    Upgrade { existing: u64 },
+    /// On disk, but its size or checksum doesn't match the album's version
+    Differs { existing: u64 },
+    /// On disk, but downloaded from an asset the album has since replaced
+    Update { recorded: String },
+}
+
+/// Decides what to do with a download given what's already on disk.
+///
+/// - `Never` keeps any existing file.
+/// - `IfLarger` re-downloads when the selected derivative is larger than the
+///   local copy; a file of unknown size is kept.
+/// - `IfDifferent` re-downloads when the local copy's size differs from the
+///   listed one, or, when the sizes match or the size isn't listed, when its
+///   content doesn't match the album's checksum. That means reading the whole
+///   file. A checksum of a kind that can't be verified counts as a match.
+///   Without `comparable` (files changed on save, as by --strip-metadata)
+///   nothing can be compared and it behaves like `Never`.
+/// - `Always` never skips.
+///
+/// With `recorded` (--if-newer), the checksums the manifest recorded for the
+/// files it saved, by file name: a file is also re-downloaded when the album
+/// now lists a different checksum for it, i.e. the photo was replaced or
+/// edited. A file the manifest doesn't know is left alone.
+pub async fn existing_action(
+    info: &DownloadInfo,
+    output_dir: &str,
+    policy: OverwritePolicy,
+    recorded: Option<&HashMap<String, String>>,
+    comparable: bool,
+) -> ExistingAction {
+    let Some((path, meta)) = existing_file(info, output_dir) else {
+        return ExistingAction::Download;
+    };
+
+    match policy {
+        OverwritePolicy::Never => {}
+        OverwritePolicy::Always => return ExistingAction::Download,
+        OverwritePolicy::IfLarger => {
+            if info.file_size.is_some_and(|expected| expected > meta.len()) {
+                return ExistingAction::Upgrade { existing: meta.len() };
+            }
+        }
+        OverwritePolicy::IfDifferent if comparable => {
+            if info.file_size.is_some_and(|expected| expected != meta.len())
+                || content_differs(path.clone(), info.checksum.clone()).await
+            {
+                return ExistingAction::Differs { existing: meta.len() };
+            }
+        }
+        OverwritePolicy::IfDifferent => {}
+    }
+
+    let recorded = recorded.and_then(|recorded| {
+        let name = path.strip_prefix(output_dir).ok()?.to_str()?;
+        recorded.get(name)
+    });
+    if let Some(recorded) = recorded.filter(|recorded| **recorded != info.checksum) {
+        return ExistingAction::Update { recorded: recorded.clone() };
+    }
+
+    ExistingAction::Skip
+}
+
+/// Whether the file at `path` fails the album's checksum. Hashed on a
+/// blocking thread; a file that can't be read counts as different.
+async fn content_differs(path: PathBuf, checksum: String) -> bool {
+    let verified = tokio::task::spawn_blocking(move || {
+        let content = fs::read(&path).ok()?;
+        Some(verify_apple_checksum(&content, &checksum))
+    })
+    .await;
+    matches!(verified, Ok(None) | Ok(Some(Some(false))) | Err(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::download_info;
+
+    fn recorded(name: &str, checksum: &str) -> HashMap<String, String> {
+        HashMap::from([(name.to_string(), checksum.to_string())])
+    }
+
+    #[tokio::test]
+    async fn if_newer_replaces_a_file_whose_checksum_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("IMG_0001.JPG"), b"old").unwrap();
+        let info = download_info("P1", "IMG_0001.JPG", None);
+
+        let changed = recorded("IMG_0001.JPG", "ckOld");
+        let action = existing_action(&info, output, OverwritePolicy::Never, Some(&changed), true).await;
+        assert!(matches!(action, ExistingAction::Update { recorded } if recorded == "ckOld"));
+
+        let same = recorded("IMG_0001.JPG", "ckP1");
+        let action = existing_action(&info, output, OverwritePolicy::Never, Some(&same), true).await;
+        assert!(matches!(action, ExistingAction::Skip));
+    }
+
+    #[tokio::test]
+    async fn a_file_saved_in_the_other_normalization_form_is_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("Cafe\u{301}.JPG"), b"old").unwrap();
+        let info = download_info("P1", "Caf\u{e9}.JPG", None);
+
+        let action = existing_action(&info, output, OverwritePolicy::Never, None, true).await;
+        assert!(matches!(action, ExistingAction::Skip));
+    }
+
+    #[tokio::test]
+    async fn if_newer_keeps_files_the_manifest_doesnt_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("IMG_0001.JPG"), b"old").unwrap();
+        let info = download_info("P1", "IMG_0001.JPG", None);
+
+        let other = recorded("IMG_0002.JPG", "ckOld");
+        let action = existing_action(&info, output, OverwritePolicy::Never, Some(&other), true).await;
+        assert!(matches!(action, ExistingAction::Skip));
+    }
+
+    #[tokio::test]
+    async fn without_if_newer_an_existing_file_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().to_str().unwrap();
+        let info = download_info("P1", "IMG_0001.JPG", None);
+        assert!(matches!(existing_action(&info, output, OverwritePolicy::Never, None, true).await, ExistingAction::Download));
+
+        fs::write(dir.path().join("IMG_0001.JPG"), b"old").unwrap();
+        assert!(matches!(existing_action(&info, output, OverwritePolicy::Never, None, true).await, ExistingAction::Skip));
+    }
+}
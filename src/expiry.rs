@@ -0,0 +1,126 @@
+// Signed download URLs from webasseturls only stay valid for a limited time.
+// For large albums the tail of the download can outlive them, which shows up
+// as a wall of 403s at the end of the run. These helpers spot that up front
+// and can swap in fresh URLs just before a download starts.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::HashMap;
+
+use crate::clock;
+use crate::http::HttpClient;
+use crate::stats;
+use crate::{fetch_asset_urls_batch, DerivativeSelection, DownloadInfo, Photo};
+
+/// Throughput assumed for a single connection when estimating how long the
+/// download phase will take.
+const ASSUMED_BYTES_PER_SEC_PER_CONNECTION: u64 = 1024 * 1024;
+
+/// Parses `url_expiry`, which is normally RFC 3339 but is accepted as epoch
+/// seconds or milliseconds too.
+pub fn parse_url_expiry(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
+    }
+
+    let number: i64 = value.trim().parse().ok()?;
+    if number > 100_000_000_000 {
+        Utc.timestamp_millis_opt(number).single()
+    } else {
+        Utc.timestamp_opt(number, 0).single()
+    }
+}
+
+pub struct ExpiryRisk {
+    pub time_until_expiry: Duration,
+    pub estimated_duration: Duration,
+}
+
+/// Estimates how long downloading `infos` will take. Items without a known
+/// size are assumed to be as large as the average known item.
+pub fn estimate_download_duration(infos: &[DownloadInfo], concurrent: usize) -> Duration {
+    let known: Vec<u64> = infos.iter().filter_map(|i| i.file_size).collect();
+    let average = if known.is_empty() {
+        0
+    } else {
+        known.iter().sum::<u64>() / known.len() as u64
+    };
+    let total_bytes = known.iter().sum::<u64>() + average * (infos.len() - known.len()) as u64;
+
+    let bytes_per_sec = ASSUMED_BYTES_PER_SEC_PER_CONNECTION * concurrent.max(1) as u64;
+    Duration::seconds((total_bytes / bytes_per_sec) as i64)
+}
+
+/// Returns the risk details if the soonest-expiring URL is likely to expire
+/// before the download finishes, within `margin`.
+pub fn check_url_expiry(
+    infos: &[DownloadInfo],
+    concurrent: usize,
+    margin: Duration,
+) -> Option<ExpiryRisk> {
+    let soonest = infos.iter().filter_map(|i| i.url_expiry).min()?;
+    let time_until_expiry = soonest - clock::server_now();
+    let estimated_duration = estimate_download_duration(infos, concurrent);
+
+    (time_until_expiry < estimated_duration + margin).then_some(ExpiryRisk {
+        time_until_expiry,
+        estimated_duration,
+    })
+}
+
+pub fn print_expiry_warning(risk: &ExpiryRisk, refreshing: bool) {
+    status!(
+        "⚠️  Download URLs expire in ~{} min, but downloading is estimated to take ~{} min",
+        risk.time_until_expiry.num_minutes().max(0),
+        risk.estimated_duration.num_minutes()
+    );
+    if refreshing {
+        status!("   URLs close to expiry will be re-fetched right before downloading");
+    } else {
+        status!("   Late downloads may fail with 403. Consider lowering --concurrent if the server is throttling,");
+        status!("   pass --refresh-expiring-urls, or re-run afterwards to refresh the URLs for anything that failed");
+    }
+}
+
+/// Re-fetches download URLs for individual photos whose signed URLs are about to expire.
+pub struct UrlRefresher<'a, C: HttpClient> {
+    client: &'a C,
+    hash: &'a str,
+    photos: HashMap<&'a str, &'a Photo>,
+    selection: &'a DerivativeSelection,
+    margin: Duration,
+}
+
+impl<'a, C: HttpClient> UrlRefresher<'a, C> {
+    pub fn new(
+        client: &'a C,
+        hash: &'a str,
+        photos: &'a [Photo],
+        selection: &'a DerivativeSelection,
+        margin: Duration,
+    ) -> Self {
+        let photos = photos.iter().map(|p| (p.photo_guid.as_str(), p)).collect();
+        Self { client, hash, photos, selection, margin }
+    }
+
+    pub fn needs_refresh(&self, info: &DownloadInfo) -> bool {
+        info.url_expiry
+            .is_some_and(|expiry| expiry - clock::server_now() < self.margin)
+    }
+
+    pub async fn refresh(&self, info: &DownloadInfo) -> Result<DownloadInfo> {
+        let photo = self.photos
+            .get(info.photo_guid.as_str())
+            .ok_or_else(|| anyhow!("Photo {} is not part of this album", info.photo_guid))?;
+
+        let fresh = fetch_asset_urls_batch(self.client, self.hash, std::slice::from_ref(*photo), self.selection)
+            .await?
+            .into_iter()
+            .find(|fresh| fresh.checksum == info.checksum)
+            .map(|fresh| DownloadInfo { filename: info.filename.clone(), ..fresh })
+            .ok_or_else(|| anyhow!("No download URL returned for photo {}", info.photo_guid))?;
+        stats::count_url_refresh();
+        Ok(fresh)
+    }
+}
+
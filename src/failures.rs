@@ -0,0 +1,181 @@
+// Running tally of download outcomes plus a failures file that is appended to
+// as each failure happens, so an interrupted run still leaves a usable record.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::atomic::atomic_write;
+
+pub const FAILURES_FILE_NAME: &str = "failures.txt";
+
+#[derive(Default)]
+pub struct DownloadCounters {
+    succeeded: AtomicUsize,
+    failed: AtomicUsize,
+    timed_out: AtomicUsize,
+    size_mismatches: AtomicUsize,
+    verified: AtomicUsize,
+    unverifiable: AtomicUsize,
+}
+
+impl DownloadCounters {
+    pub fn record_success(&self) {
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A failure that was the download running past --per-file-timeout.
+    pub fn record_timeout(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A download that was kept but didn't match the album's listed size.
+    pub fn record_size_mismatch(&self) {
+        self.size_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// With --verify: whether a saved file's checksum could be checked.
+    pub fn record_verification(&self, verified: bool) {
+        let counter = if verified { &self.verified } else { &self.unverifiable };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.succeeded.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn timed_out(&self) -> usize {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    pub fn size_mismatches(&self) -> usize {
+        self.size_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub fn verified(&self) -> usize {
+        self.verified.load(Ordering::Relaxed)
+    }
+
+    pub fn unverifiable(&self) -> usize {
+        self.unverifiable.load(Ordering::Relaxed)
+    }
+}
+
+/// Appends one line per failed download (`guid<TAB>filename<TAB>error`), and
+/// per download kept despite a size mismatch.
+/// The file (and its directory) is only created once the first failure is
+/// recorded.
+pub struct FailureLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl FailureLog {
+    /// Starts a fresh log, removing the failures file left by a previous run.
+    pub fn create(path: PathBuf) -> Result<Self> {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove old {}", path.display())),
+        }
+
+        Ok(Self {
+            path,
+            file: Mutex::new(None),
+        })
+    }
+
+    pub fn record(&self, photo_guid: &str, filename: &str, error: &anyhow::Error) {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(opened) => *file = Some(opened),
+                Err(e) => {
+                    eprintln!("⚠️  Could not write {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+
+        // Keep each failure on one line so the file stays easy to parse
+        let error = format!("{:#}", error).replace(['\n', '\t'], " ");
+        if let Some(file) = file.as_mut() {
+            let _ = writeln!(file, "{}\t{}\t{}", photo_guid, filename, error);
+            let _ = file.flush();
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// Photo GUIDs listed in a failures file, for --retry-failed. A missing
+/// file lists nothing.
+pub fn read_failed_guids(path: &Path) -> Result<HashSet<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .map(str::trim)
+        .filter(|guid| !guid.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// One line of a failures file.
+#[derive(Serialize)]
+pub struct FailureRecord {
+    pub guid: String,
+    pub filename: String,
+    pub error: String,
+}
+
+/// Every entry of a failures file. A missing file has none.
+pub fn read_failures(path: &Path) -> Result<Vec<FailureRecord>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let mut field = || fields.next().unwrap_or_default().to_string();
+            FailureRecord { guid: field(), filename: field(), error: field() }
+        })
+        .collect())
+}
+
+/// Replaces `path` with the failures recorded in `log` this run, or empties
+/// it if there were none.
+pub fn rewrite_from(path: &Path, log: &FailureLog) -> Result<()> {
+    let contents = match fs::read(&log.path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", log.path.display())),
+    };
+    atomic_write(path, &contents)
+}
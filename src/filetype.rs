@@ -0,0 +1,127 @@
+// Detects the real format of a downloaded asset so it can be saved with a
+// matching extension. Apple's signed URLs don't always carry a trustworthy
+// one, and a HEIC saved as `.jpg` trips up most viewers.
+//
+// Animated images need no special casing here: an animated GIF starts like
+// any GIF, and an APNG like any PNG (whose extension it shares). What matters
+// is that they're recognised as images, so a GIF the URL calls `.mp4` isn't
+// handed to the video re-encoder, which would turn it into a video.
+
+/// Enough of the start of a file to recognise its type.
+pub const SNIFF_BYTES: usize = 64;
+
+/// Returns the canonical extension for a downloaded file, preferring the
+/// file's magic bytes over the `Content-Type` header.
+pub fn detect_extension(content_type: Option<&str>, bytes: &[u8]) -> Option<&'static str> {
+    sniff_magic_bytes(bytes).or_else(|| content_type.and_then(extension_for_content_type))
+}
+
+fn sniff_magic_bytes(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("jpg");
+    }
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("png");
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some("gif");
+    }
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("webp");
+    }
+
+    // ISO base media files (HEIF, MP4, MOV) start with an `ftyp` box
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => Some("heic"),
+            b"mif1" | b"msf1" => Some("heif"),
+            b"avif" | b"avis" => Some("avif"),
+            b"qt  " => Some("mov"),
+            _ => Some("mp4"),
+        };
+    }
+
+    None
+}
+
+fn extension_for_content_type(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "image/jpeg" | "image/jpg" => Some("jpg"),
+        "image/png" | "image/apng" | "image/vnd.mozilla.apng" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/heic" | "image/heic-sequence" => Some("heic"),
+        "image/heif" | "image/heif-sequence" => Some("heif"),
+        "image/avif" => Some("avif"),
+        "video/quicktime" => Some("mov"),
+        "video/mp4" => Some("mp4"),
+        _ => None,
+    }
+}
+
+/// Whether a detected extension is an image format, still or animated.
+pub fn is_image(detected: &str) -> bool {
+    matches!(detected, "jpg" | "png" | "gif" | "webp" | "heic" | "heif" | "avif")
+}
+
+/// Gives `filename` the `detected` extension unless it already has an
+/// equivalent one (`.jpeg` for `jpg`, any case).
+pub fn correct_extension(filename: &str, detected: &str) -> String {
+    let (stem, current) = match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (filename, None),
+    };
+
+    match current {
+        Some(ext) if is_equivalent_extension(ext, detected) => filename.to_string(),
+        _ => format!("{}.{}", stem, detected),
+    }
+}
+
+fn is_equivalent_extension(ext: &str, detected: &str) -> bool {
+    let ext = ext.to_ascii_lowercase();
+    ext == detected
+        || matches!((ext.as_str(), detected), ("jpeg", "jpg") | ("heif", "heic") | ("heic", "heif"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an animated GIF: header, screen descriptor and the
+    /// NETSCAPE2.0 looping extension.
+    const ANIMATED_GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00";
+
+    /// A PNG signature followed by an IHDR and the acTL chunk of an APNG.
+    const APNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x01\x00\x00\x00\x01\x08\x06\x00\x00\x00\x1f\x15\xc4\x89\x00\x00\x00\x08acTL\x00\x00\x00\x02\x00\x00\x00\x00";
+
+    #[test]
+    fn animated_images_are_recognised_from_their_bytes() {
+        assert_eq!(detect_extension(Some("video/mp4"), ANIMATED_GIF), Some("gif"));
+        assert_eq!(detect_extension(None, APNG), Some("png"));
+    }
+
+    #[test]
+    fn animated_content_types_are_recognised_without_magic_bytes() {
+        assert_eq!(detect_extension(Some("image/gif"), b""), Some("gif"));
+        assert_eq!(detect_extension(Some("image/apng"), b""), Some("png"));
+        assert_eq!(detect_extension(Some("image/vnd.mozilla.apng; charset=binary"), b""), Some("png"));
+    }
+
+    #[test]
+    fn animated_images_are_never_taken_for_videos() {
+        assert!(is_image("gif"));
+        assert!(is_image("png"));
+        assert!(!is_image("mp4"));
+        assert!(!is_image("mov"));
+    }
+
+    #[test]
+    fn extensions_are_corrected_to_the_detected_format() {
+        assert_eq!(correct_extension("IMG_0001.mp4", "gif"), "IMG_0001.gif");
+        assert_eq!(correct_extension("IMG_0001.GIF", "gif"), "IMG_0001.GIF");
+        assert_eq!(correct_extension("IMG_0001.jpeg", "jpg"), "IMG_0001.jpeg");
+        assert_eq!(correct_extension("01a2b3c4", "png"), "01a2b3c4.png");
+    }
+}
@@ -0,0 +1,109 @@
+// `--output-index-html-per-run`: an `index.html` in the output directory that
+// shows every downloaded photo and video, updated at the end of each run.
+//
+// The page is built from the manifest rather than from this run's downloads,
+// so files from earlier runs stay on it. Its order is kept in
+// `.icloud-dl/gallery.json`: files already on the page keep their place
+// (also when a newer version replaced them), files new in the manifest are
+// added at the end by capture date, and files deleted from disk are dropped.
+// Both files are replaced atomically, so an interrupted run leaves the
+// previous page intact.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::atomic::atomic_write;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::workdir;
+
+pub const GALLERY_NAME: &str = "index.html";
+const ORDER_NAME: &str = "gallery.json";
+
+/// Rewrites the gallery with the manifest's current entries. Returns how
+/// many files are on the page and how many of them are new.
+pub fn update(output_dir: &str, manifest: &Manifest, title: &str) -> Result<(usize, usize)> {
+    let order_path = workdir::tool_dir(output_dir).join(ORDER_NAME);
+    let previous: Vec<String> = match fs::read(&order_path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+
+    let on_disk = |entry: &ManifestEntry| Path::new(output_dir).join(&entry.filename).is_file();
+    let mut entries: HashMap<String, ManifestEntry> = manifest
+        .entries()?
+        .into_iter()
+        // The motion half of a Live Photo isn't shown on its own
+        .filter(|entry| entry.kind != "live-photo-video" && on_disk(entry))
+        .map(|entry| (entry.filename.clone(), entry))
+        .collect();
+
+    let mut shown: Vec<ManifestEntry> = previous.iter().filter_map(|filename| entries.remove(filename)).collect();
+    let kept = shown.len();
+    let mut added: Vec<ManifestEntry> = entries.into_values().collect();
+    added.sort_by(|a, b| (&a.date_created, &a.filename).cmp(&(&b.date_created, &b.filename)));
+    shown.extend(added);
+
+    let html = render_page(title, &shown);
+    atomic_write(&Path::new(output_dir).join(GALLERY_NAME), html.as_bytes())?;
+    let order: Vec<&str> = shown.iter().map(|entry| entry.filename.as_str()).collect();
+    atomic_write(&order_path, &serde_json::to_vec(&order)?)
+        .with_context(|| format!("Failed to save the gallery order to {}", order_path.display()))?;
+
+    Ok((shown.len(), shown.len() - kept))
+}
+
+fn render_page(title: &str, entries: &[ManifestEntry]) -> String {
+    let title = render_caption(title, CaptionContext::Xml);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n<style>\n\
+         body {{ font-family: system-ui, sans-serif; margin: 1rem; background: #111; color: #eee; }}\n\
+         main {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(240px, 1fr)); gap: 1rem; }}\n\
+         figure {{ margin: 0; }}\n\
+         img, video {{ width: 100%; height: 240px; object-fit: cover; border-radius: 4px; background: #222; }}\n\
+         figcaption {{ font-size: 0.85rem; margin-top: 0.25rem; overflow-wrap: anywhere; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n<main>\n"
+    );
+    for entry in entries {
+        html.push_str(&render_entry(entry));
+    }
+    html.push_str("</main>\n</body>\n</html>\n");
+    html
+}
+
+fn render_entry(entry: &ManifestEntry) -> String {
+    let src = url_path(&entry.filename);
+    let caption = entry.caption.as_deref().map(|caption| render_caption(caption, CaptionContext::Xml));
+    let alt = caption.clone().unwrap_or_else(|| render_caption(&entry.filename, CaptionContext::Xml));
+    let media = if entry.kind == "video" {
+        format!("<video src=\"{}\" controls preload=\"metadata\"></video>", src)
+    } else {
+        format!("<a href=\"{src}\"><img src=\"{src}\" alt=\"{alt}\" loading=\"lazy\"></a>")
+    };
+    match caption {
+        Some(caption) => format!("<figure>{}<figcaption>{}</figcaption></figure>\n", media, caption),
+        None => format!("<figure>{}</figure>\n", media),
+    }
+}
+
+/// `filename` as a relative URL: each path segment percent-encoded, so
+/// spaces, `#` and `?` in names don't break the link.
+fn url_path(filename: &str) -> String {
+    filename
+        .split('/')
+        .map(|segment| {
+            segment
+                .bytes()
+                .map(|b| match b {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+                    _ => format!("%{:02X}", b),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,147 @@
+// --checksum-manifest: SHA-256 of every downloaded file, written in
+// `sha256sum` format so `sha256sum -c` can check the download later.
+//
+// Hashing is CPU-bound, so it runs on blocking threads fed through a bounded
+// queue. Download tasks hand over each written file and move on; they only
+// wait when the queue is full. If hashing falls behind, its progress is shown
+// once the downloads are done.
+
+use anyhow::{Context, Result};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::atomic::atomic_write;
+
+pub const CHECKSUMS_FILE_NAME: &str = "checksums.sha256";
+
+/// Files waiting to be hashed per hashing thread before downloads block.
+const QUEUE_PER_WORKER: usize = 4;
+
+struct HashJob {
+    path: PathBuf,
+    name: String,
+}
+
+pub struct HashPipeline {
+    sender: mpsc::Sender<HashJob>,
+    workers: Vec<JoinHandle<()>>,
+    checksums: Arc<Mutex<BTreeMap<String, String>>>,
+    checksums_path: PathBuf,
+    progress: ProgressBar,
+}
+
+impl HashPipeline {
+    /// Starts the hashing threads. Checksums already in `checksums_path` from
+    /// earlier runs are kept unless the file is hashed again.
+    pub fn start(checksums_path: PathBuf) -> Result<Self> {
+        let checksums = Arc::new(Mutex::new(read_checksums(&checksums_path)?));
+        let worker_count = std::thread::available_parallelism().map_or(2, |n| n.get()).min(4);
+        let (sender, receiver) = mpsc::channel::<HashJob>(worker_count * QUEUE_PER_WORKER);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let progress = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::hidden());
+
+        let workers = (0..worker_count)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let checksums = Arc::clone(&checksums);
+                let progress = progress.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let Some(job) = receiver.lock().await.recv().await else {
+                            break;
+                        };
+                        let path = job.path.clone();
+                        match tokio::task::spawn_blocking(move || hash_file(&path)).await {
+                            Ok(Ok(hash)) => {
+                                checksums.lock().unwrap().insert(job.name, hash);
+                            }
+                            Ok(Err(e)) => eprintln!("⚠️  Could not hash {}: {:#}", job.name, e),
+                            Err(e) => eprintln!("⚠️  Hashing {} panicked: {}", job.name, e),
+                        }
+                        progress.inc(1);
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self { sender, workers, checksums, checksums_path, progress })
+    }
+
+    /// Queues a written file for hashing; `name` is its path relative to the
+    /// output directory.
+    pub async fn submit(&self, path: PathBuf, name: String) {
+        self.progress.inc_length(1);
+        if self.sender.send(HashJob { path, name }).await.is_err() {
+            eprintln!("⚠️  Hashing stopped unexpectedly; checksums will be incomplete");
+        }
+    }
+
+    /// Waits for the queue to drain and writes the checksums file.
+    pub async fn finish(self) -> Result<PathBuf> {
+        let Self { sender, workers, checksums, checksums_path, progress } = self;
+        drop(sender);
+
+        let pending = progress.length().unwrap_or(0).saturating_sub(progress.position());
+        if pending > 0 {
+            status!("⏳ Hashing {} remaining files...", pending);
+            progress.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} files hashed")?
+                    .progress_chars("#>-"),
+            );
+            if crate::progress::bars_enabled() {
+                progress.set_draw_target(ProgressDrawTarget::stderr());
+            }
+        }
+        for worker in workers {
+            let _ = worker.await;
+        }
+        progress.finish_and_clear();
+
+        let contents: String = checksums
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, hash)| format!("{}  {}\n", hash, name))
+            .collect();
+        if let Some(parent) = checksums_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        atomic_write(&checksums_path, contents.as_bytes())?;
+        Ok(checksums_path)
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_checksums(path: &Path) -> Result<BTreeMap<String, String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, name)| (name.to_string(), hash.to_string()))
+        .collect())
+}
@@ -0,0 +1,118 @@
+// --head-check: a HEAD request for every resolved download URL before the
+// download starts, so expired or broken URLs and wrong sizes show up in
+// seconds rather than as 403s an hour into the run.
+//
+// --prefetch-sizes: the same HEAD requests, used to take each file's size
+// from its `Content-Length` rather than the album's `fileSize`, which is
+// sometimes missing or wrong. Progress, the size limits, --max-total-size and
+// the comparison with files already on disk then go by the real sizes.
+
+use futures::stream::{self, StreamExt};
+use std::fmt;
+
+use crate::headers::RequestKind;
+use crate::http::{HttpClient, HttpResponse};
+use crate::integrity::{self, SizeMismatch};
+use crate::DownloadInfo;
+
+pub enum HeadProblem {
+    Unreachable(anyhow::Error),
+    Status(reqwest::StatusCode),
+    Size(SizeMismatch),
+}
+
+impl fmt::Display for HeadProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeadProblem::Unreachable(e) => write!(f, "unreachable: {:#}", e),
+            HeadProblem::Status(status) => write!(f, "HTTP {}", status),
+            HeadProblem::Size(mismatch) => write!(f, "{}", mismatch),
+        }
+    }
+}
+
+/// Checks every URL, at most `concurrency` at a time, and returns the index
+/// into `infos` and the problem for each one that failed.
+pub async fn check_urls(
+    client: &impl HttpClient,
+    infos: &[DownloadInfo],
+    concurrency: usize,
+) -> Vec<(usize, HeadProblem)> {
+    let mut problems: Vec<(usize, HeadProblem)> = stream::iter(infos.iter().enumerate())
+        .map(|(i, info)| async move { check_url(client, info).await.map(|problem| (i, problem)) })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|problem| async move { problem })
+        .collect()
+        .await;
+
+    problems.sort_by_key(|(i, _)| *i);
+    problems
+}
+
+async fn check_url(client: &impl HttpClient, info: &DownloadInfo) -> Option<HeadProblem> {
+    let response = match client.head(&info.download_url, RequestKind::Download).await {
+        Ok(response) => response,
+        Err(e) => return Some(HeadProblem::Unreachable(e)),
+    };
+
+    if !response.status().is_success() {
+        return Some(HeadProblem::Status(response.status()));
+    }
+
+    let content_length = content_length(&response)?;
+    integrity::check_content_length(info.file_size, content_length).map(HeadProblem::Size)
+}
+
+fn content_length(response: &HttpResponse) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// What `prefetch_sizes` changed.
+#[derive(Default)]
+pub struct PrefetchedSizes {
+    /// Files the album listed no size for that now have one.
+    pub filled: usize,
+    /// Index into the infos and the mismatch, for files whose listed size was
+    /// off by more than the usual tolerance.
+    pub corrected: Vec<(usize, SizeMismatch)>,
+    /// Files whose HEAD request failed or had no `Content-Length`. They keep
+    /// the listed size, if any.
+    pub unknown: usize,
+}
+
+/// Sets each file's size to the `Content-Length` of a HEAD request for it,
+/// at most `concurrency` at a time.
+pub async fn prefetch_sizes(client: &impl HttpClient, infos: &mut [DownloadInfo], concurrency: usize) -> PrefetchedSizes {
+    let mut lengths: Vec<(usize, Option<u64>)> = stream::iter(infos.iter().enumerate())
+        .map(|(i, info)| async move {
+            let length = match client.head(&info.download_url, RequestKind::Download).await {
+                Ok(response) if response.status().is_success() => content_length(&response),
+                _ => None,
+            };
+            (i, length)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    lengths.sort_by_key(|(i, _)| *i);
+
+    let mut prefetched = PrefetchedSizes::default();
+    for (i, length) in lengths {
+        let Some(length) = length else {
+            prefetched.unknown += 1;
+            continue;
+        };
+        let info = &mut infos[i];
+        match integrity::check_content_length(info.file_size, length) {
+            Some(mismatch) => prefetched.corrected.push((i, mismatch)),
+            None if info.file_size.is_none() => prefetched.filled += 1,
+            None => {}
+        }
+        info.file_size = Some(length);
+    }
+    prefetched
+}
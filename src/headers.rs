@@ -0,0 +1,100 @@
+// The browser-like headers sent with every request, in one place. iCloud
+// checks some of them (Origin, Referer, Sec-Fetch-*), so when Apple changes
+// what it expects, `--header` can patch the set without a new release.
+
+/// Which kind of request a header set is for.
+#[derive(Clone, Copy, Debug)]
+pub enum RequestKind {
+    /// The webstream and webasseturls API calls.
+    Api,
+    /// Fetching an asset from the CDN.
+    Download,
+    /// Loading the web app's page, for the session handshake.
+    Page,
+}
+
+/// A `--header 'Name: Value'` flag. An empty value removes the header.
+#[derive(Clone, Debug)]
+pub struct HeaderOverride {
+    name: String,
+    value: String,
+}
+
+pub fn parse_header_override(value: &str) -> Result<HeaderOverride, String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected 'Name: Value', got '{}'", value))?;
+    let name = name.trim();
+    let value = value.trim();
+
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("'{}' is not a valid header name", name))?;
+    reqwest::header::HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for header '{}'", name))?;
+
+    Ok(HeaderOverride { name: name.to_string(), value: value.to_string() })
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestHeaders {
+    api: Vec<(String, String)>,
+    download: Vec<(String, String)>,
+    page: Vec<(String, String)>,
+}
+
+impl Default for RequestHeaders {
+    fn default() -> Self {
+        Self {
+            api: owned(&[
+                ("Accept", "*/*"),
+                ("Accept-Language", "en-US,en;q=0.9"),
+                ("Content-Type", "text/plain"),
+                ("Origin", "https://www.icloud.com"),
+                ("Referer", "https://www.icloud.com/"),
+            ]),
+            download: owned(&[
+                ("Accept", "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"),
+                ("Accept-Language", "en-US,en;q=0.9"),
+                ("Referer", "https://www.icloud.com/"),
+                ("Sec-Fetch-Dest", "image"),
+            ]),
+            page: owned(&[
+                ("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                ("Accept-Language", "en-US,en;q=0.9"),
+                ("Sec-Fetch-Dest", "document"),
+                ("Sec-Fetch-Mode", "navigate"),
+            ]),
+        }
+    }
+}
+
+impl RequestHeaders {
+    /// Applies `--header` flags to every header set: a known name is replaced
+    /// (or removed, for an empty value), anything else is added.
+    pub fn with_overrides(mut self, overrides: &[HeaderOverride]) -> Self {
+        for set in [&mut self.api, &mut self.download, &mut self.page] {
+            for header in overrides {
+                set.retain(|(name, _)| !name.eq_ignore_ascii_case(&header.name));
+                if !header.value.is_empty() {
+                    set.push((header.name.clone(), header.value.clone()));
+                }
+            }
+        }
+        self
+    }
+
+    pub fn for_kind(&self, kind: RequestKind) -> &[(String, String)] {
+        match kind {
+            RequestKind::Api => &self.api,
+            RequestKind::Download => &self.download,
+            RequestKind::Page => &self.page,
+        }
+    }
+}
+
+fn owned(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
@@ -0,0 +1,117 @@
+// `--snapshot`: an append-only history of what the album contained, one line
+// of JSON per run in `.icloud-dl/history.jsonl`:
+//
+//     {"taken_at":"2024-05-01T12:00:00Z","album":"B0a5...","photo_count":2,"photos":[...]}
+//
+// Each snapshot lists every photo in the album at that moment (before any
+// filters) with the checksums of its renditions, so the album's state on any
+// past run can be looked up even after photos are removed. With a
+// `--content-store` the checksums name the stored files, so an old state can
+// be rebuilt from the store.
+//
+// A run whose album is unchanged from the last snapshot adds nothing, so the
+// file grows only when the album does.
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::atomic::append_line;
+use crate::WebstreamResponse;
+
+pub const HISTORY_FILE_NAME: &str = "history.jsonl";
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// RFC 3339, UTC.
+    taken_at: String,
+    album: String,
+    #[serde(default)]
+    name: Option<String>,
+    photo_count: usize,
+    photos: Vec<SnapshotPhoto>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct SnapshotPhoto {
+    guid: String,
+    #[serde(default)]
+    date_created: Option<String>,
+    #[serde(default)]
+    caption: Option<String>,
+    /// Rendition name (`"1"`, `"PosterFrame"`, ...) to checksum.
+    checksums: BTreeMap<String, String>,
+}
+
+/// What `Snapshot::append` did.
+pub enum Appended {
+    New,
+    /// Same photos as the snapshot taken at this time.
+    Unchanged(String),
+}
+
+impl Snapshot {
+    /// Captures the album as fetched, before --range and other filters.
+    pub fn of(webstream: &WebstreamResponse, hash: &str) -> Self {
+        let photos: Vec<SnapshotPhoto> = webstream
+            .photos
+            .iter()
+            .map(|photo| SnapshotPhoto {
+                guid: photo.photo_guid.clone(),
+                date_created: photo.date_created.clone(),
+                caption: photo.caption.clone(),
+                checksums: photo
+                    .derivatives
+                    .iter()
+                    .map(|(name, derivative)| (name.clone(), derivative.checksum.clone()))
+                    .collect(),
+            })
+            .collect();
+        Self {
+            taken_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            album: hash.to_string(),
+            name: webstream.stream_name.clone(),
+            photo_count: photos.len(),
+            photos,
+        }
+    }
+
+    /// Appends the snapshot to `path` unless the last one there has the
+    /// same photos.
+    pub fn append(&self, path: &Path) -> Result<Appended> {
+        if let Some(last) = last_snapshot(path)? {
+            if last.photos == self.photos {
+                return Ok(Appended::Unchanged(last.taken_at));
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        append_line(path, &line)?;
+        Ok(Appended::New)
+    }
+}
+
+fn last_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    // A line cut short by a crash is as good as no snapshot
+    Ok(last.and_then(|line| serde_json::from_str(&line).ok()))
+}
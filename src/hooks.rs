@@ -0,0 +1,109 @@
+// --post-download-cmd: run a user command after each file is saved.
+//
+// The template is split into arguments once, shell-style, and tokens are
+// substituted per argument, so a caption containing quotes or `;` can't
+// change the command. The same values are passed as ICLOUD_DL_* environment
+// variables. Hooks run inside the download tasks, so at most --concurrent
+// of them run at a time.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::DownloadInfo;
+
+#[derive(Clone, Debug)]
+pub struct PostDownloadHook {
+    program: String,
+    args: Vec<String>,
+}
+
+pub fn parse_hook_template(template: &str) -> Result<PostDownloadHook, String> {
+    let mut words = split_words(template)?.into_iter();
+    let program = words.next().ok_or("the command is empty")?;
+    Ok(PostDownloadHook { program, args: words.collect() })
+}
+
+impl PostDownloadHook {
+    pub async fn run(&self, info: &DownloadInfo, path: &Path) -> Result<()> {
+        let path = path.to_string_lossy();
+        let caption = info
+            .caption
+            .as_deref()
+            .map(|c| render_caption(c, CaptionContext::Display))
+            .unwrap_or_default();
+        let size = info.file_size.map(|size| size.to_string()).unwrap_or_default();
+        let values = [
+            ("path", path.as_ref()),
+            ("guid", info.photo_guid.as_str()),
+            ("checksum", info.checksum.as_str()),
+            ("caption", caption.as_str()),
+            ("size", size.as_str()),
+            ("resolution", info.size_info.as_str()),
+        ];
+
+        let substitute = |word: &str| {
+            values
+                .iter()
+                .fold(word.to_string(), |word, (token, value)| word.replace(&format!("{{{}}}", token), value))
+        };
+
+        let mut command = Command::new(substitute(&self.program));
+        command.args(self.args.iter().map(|arg| substitute(arg)));
+        for (token, value) in values {
+            command.env(format!("ICLOUD_DL_{}", token.to_ascii_uppercase()), value);
+        }
+
+        let status = command
+            .status()
+            .await
+            .with_context(|| format!("Failed to run post-download command '{}'", self.program))?;
+        if !status.success() {
+            return Err(anyhow!("Post-download command exited with {}", status));
+        }
+        Ok(())
+    }
+}
+
+/// Splits on whitespace, honouring single and double quotes and backslash escapes.
+fn split_words(template: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                current.push(chars.next().ok_or("trailing backslash")?);
+                in_word = true;
+            }
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    if in_word {
+        words.push(current);
+    }
+    Ok(words)
+}
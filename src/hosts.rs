@@ -0,0 +1,118 @@
+// --compare-hosts: webasseturls lists several CDN hosts for each location,
+// and downloads normally use the first. This times the same probe file from
+// every host, prints a ranking, and with `pin` sends all downloads to the
+// fastest one.
+
+use anyhow::{anyhow, Result};
+use comfy_table::presets::UTF8_FULL_CONDENSED;
+use comfy_table::Table;
+use reqwest::Url;
+use std::time::{Duration, Instant};
+
+use crate::headers::RequestKind;
+use crate::http::HttpClient;
+use crate::size::format_size;
+use crate::DownloadInfo;
+
+/// Probe files larger than this would make the comparison slow.
+const MAX_PROBE_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum HostComparison {
+    /// Only print the ranking
+    Report,
+    /// Print the ranking and download everything from the fastest host
+    Pin,
+}
+
+struct Probe {
+    host: String,
+    result: Result<(Duration, Duration, u64)>,
+}
+
+impl Probe {
+    fn throughput(&self) -> f64 {
+        match &self.result {
+            Ok((_, total, bytes)) if !total.is_zero() => *bytes as f64 / total.as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Times one download from each host and returns the fastest working host.
+pub async fn compare_hosts(client: &impl HttpClient, infos: &[DownloadInfo]) -> Result<Option<String>> {
+    let Some(sample) = pick_sample(infos) else {
+        status!("⚠️  No download to probe hosts with");
+        return Ok(None);
+    };
+    if sample.hosts.len() < 2 {
+        status!("ℹ️  iCloud offered only one download host, nothing to compare");
+        return Ok(None);
+    }
+
+    status!("\n🏁 Comparing {} download hosts using {}...", sample.hosts.len(), sample.filename);
+    let mut probes = Vec::new();
+    for host in &sample.hosts {
+        let result = probe(client, &sample.download_url, host).await;
+        probes.push(Probe { host: host.clone(), result });
+    }
+    probes.sort_by(|a, b| b.throughput().total_cmp(&a.throughput()));
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_header(vec!["Host", "First byte", "Total", "Throughput"]);
+    for probe in &probes {
+        match &probe.result {
+            Ok((first_byte, total, _)) => table.add_row(vec![
+                probe.host.clone(),
+                format!("{} ms", first_byte.as_millis()),
+                format!("{} ms", total.as_millis()),
+                format!("{}/s", format_size(probe.throughput() as u64)),
+            ]),
+            Err(e) => table.add_row(vec![probe.host.clone(), format!("failed: {:#}", e), String::new(), String::new()]),
+        };
+    }
+    status!("{}", table);
+
+    Ok(probes.into_iter().find(|probe| probe.result.is_ok()).map(|probe| probe.host))
+}
+
+/// Points every download whose location offers `host` at it.
+pub fn pin_host(infos: &mut [DownloadInfo], host: &str) -> usize {
+    let mut pinned = 0;
+    for info in infos.iter_mut().filter(|info| info.hosts.iter().any(|h| h == host)) {
+        if let Ok(url) = with_host(&info.download_url, host) {
+            info.download_url = url;
+            pinned += 1;
+        }
+    }
+    pinned
+}
+
+/// The largest file under the probe limit, or the smallest one if all are bigger.
+fn pick_sample(infos: &[DownloadInfo]) -> Option<&DownloadInfo> {
+    infos
+        .iter()
+        .filter(|info| info.file_size.is_some_and(|size| size <= MAX_PROBE_BYTES))
+        .max_by_key(|info| info.file_size)
+        .or_else(|| infos.iter().min_by_key(|info| info.file_size.unwrap_or(u64::MAX)))
+}
+
+async fn probe(client: &impl HttpClient, url: &str, host: &str) -> Result<(Duration, Duration, u64)> {
+    let url = with_host(url, host)?;
+    let started = Instant::now();
+    let response = client.get(&url, RequestKind::Download).await?;
+    let first_byte = started.elapsed();
+    if !response.status().is_success() {
+        return Err(anyhow!("HTTP {}", response.status()));
+    }
+    let bytes = response.bytes().await?;
+    Ok((first_byte, started.elapsed(), bytes.len() as u64))
+}
+
+fn with_host(url: &str, host: &str) -> Result<String> {
+    let mut url = Url::parse(url)?;
+    url.set_host(Some(host))?;
+    Ok(url.into())
+}
@@ -0,0 +1,542 @@
+// Minimal HTTP abstraction over the handful of requests this tool makes.
+//
+// The fetch and download functions are generic over `HttpClient` so they can
+// be driven by something other than a live reqwest client (canned responses,
+// fixtures). Only the operations we actually use are exposed. Callers say
+// what kind of request they're making and the client adds the matching headers.
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, Bytes};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{BufReader, Read};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::headers::{RequestHeaders, RequestKind};
+use crate::ratelimit::Throttle;
+use crate::session::SessionTokens;
+use crate::{pinning, stats};
+
+pub trait HttpClient: Clone + Send + Sync {
+    /// Sends `body` as JSON in a POST request.
+    fn post_json<B: Serialize + Sync>(
+        &self,
+        url: &str,
+        kind: RequestKind,
+        body: &B,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send;
+
+    fn get(
+        &self,
+        url: &str,
+        kind: RequestKind,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send;
+
+    fn head(
+        &self,
+        url: &str,
+        kind: RequestKind,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send;
+
+    /// GETs the bytes in `range` (end exclusive) with a `Range` header. Servers
+    /// that don't support ranges answer 200 with the whole body.
+    fn get_range(
+        &self,
+        url: &str,
+        kind: RequestKind,
+        range: std::ops::Range<u64>,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send;
+}
+
+/// Status, headers and the not-yet-read body of a response.
+pub struct HttpResponse {
+    status: StatusCode,
+    /// Where the response came from, after any redirects.
+    url: String,
+    headers: HeaderMap,
+    body: ResponseBody,
+    /// Paces reading the body, with --max-rate-per-file.
+    throttle: Option<Arc<Throttle>>,
+}
+
+enum ResponseBody {
+    Live(reqwest::Response),
+    /// A body known up front, from a client that doesn't go to the network.
+    Canned(Bytes),
+}
+
+impl HttpResponse {
+    /// A response with a body already in hand, for clients that answer
+    /// requests themselves.
+    pub fn canned(status: StatusCode, url: &str, headers: HeaderMap, body: Bytes) -> Self {
+        Self { status, url: url.to_string(), headers, body: ResponseBody::Canned(body), throttle: None }
+    }
+
+
+    #[cfg(test)]
+    pub fn with_header(mut self, name: reqwest::header::HeaderName, value: reqwest::header::HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Apple's tracing headers (`X-Apple-Request-UUID`, edge node, ...) for
+    /// error messages, formatted as ` [name: value, ...]`, or empty if none.
+    pub fn apple_trace(&self) -> String {
+        let trace: Vec<String> = self.headers
+            .iter()
+            .filter(|(name, _)| {
+                let name = name.as_str();
+                name.starts_with("x-apple-") || name == "x-responding-instance"
+            })
+            .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+            .collect();
+
+        if trace.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", trace.join(", "))
+        }
+    }
+
+    pub async fn bytes(self) -> Result<Bytes> {
+        match self.body {
+            ResponseBody::Live(response) => response.bytes().await.context("Failed to read response body"),
+            ResponseBody::Canned(body) => Ok(body),
+        }
+    }
+
+    /// Like `bytes`, but calls `on_chunk` with the size of each chunk as it
+    /// arrives so callers can show per-file progress.
+    pub async fn bytes_with_progress(self, mut on_chunk: impl FnMut(usize)) -> Result<Bytes> {
+        match self.body {
+            ResponseBody::Live(mut response) => {
+                let mut body = Vec::new();
+                while let Some(chunk) = response.chunk().await.context("Failed to read response body")? {
+                    on_chunk(chunk.len());
+                    body.extend_from_slice(&chunk);
+                    if let Some(throttle) = &self.throttle {
+                        throttle.consume(chunk.len()).await;
+                    }
+                }
+                Ok(Bytes::from(body))
+            }
+            ResponseBody::Canned(body) => {
+                on_chunk(body.len());
+                Ok(body)
+            }
+        }
+    }
+
+    /// Writes the body to `writer` as it arrives, calling `on_chunk` like
+    /// `bytes_with_progress`. Returns the number of bytes written; a body
+    /// longer than `limit` is an error.
+    pub async fn write_into(
+        self,
+        writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+        limit: u64,
+        mut on_chunk: impl FnMut(usize),
+    ) -> Result<u64> {
+        use tokio::io::AsyncWriteExt;
+        let too_long = || anyhow!("Response body is longer than the {} bytes expected", limit);
+        match self.body {
+            ResponseBody::Live(mut response) => {
+                let mut written = 0u64;
+                while let Some(chunk) = response.chunk().await.context("Failed to read response body")? {
+                    written += chunk.len() as u64;
+                    if written > limit {
+                        return Err(too_long());
+                    }
+                    writer.write_all(&chunk).await.context("Failed to write response body")?;
+                    on_chunk(chunk.len());
+                    if let Some(throttle) = &self.throttle {
+                        throttle.consume(chunk.len()).await;
+                    }
+                }
+                Ok(written)
+            }
+            ResponseBody::Canned(body) => {
+                if body.len() as u64 > limit {
+                    return Err(too_long());
+                }
+                writer.write_all(&body).await.context("Failed to write response body")?;
+                on_chunk(body.len());
+                Ok(body.len() as u64)
+            }
+        }
+    }
+
+    pub async fn text(self) -> Result<String> {
+        let bytes = self.bytes().await?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Parses the body as JSON. An HTML body (maintenance or error page, often
+    /// from the wrong host) is reported as such instead of as a serde error
+    /// about an unexpected `<`.
+    pub async fn json<T: DeserializeOwned>(self) -> Result<T> {
+        let status = self.status;
+        let is_html_type = self.is_html_type();
+
+        let bytes = self.bytes().await?;
+        if is_html_type || bytes.trim_ascii_start().starts_with(b"<") {
+            return Err(html_instead_of_json(status));
+        }
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Like `json`, but parses the body while it arrives instead of reading
+    /// it all first, so a huge response (the webstream of an album with tens
+    /// of thousands of photos) is never held as text next to what it's
+    /// parsed into. Parsing runs on a blocking thread fed chunk by chunk.
+    pub async fn json_streamed<T: DeserializeOwned + Send + 'static>(self) -> Result<T> {
+        let status = self.status;
+        let is_html_type = self.is_html_type();
+        let mut response = match self.body {
+            ResponseBody::Live(response) => response,
+            // Already in memory, nothing to gain from streaming
+            ResponseBody::Canned(_) => return self.json().await,
+        };
+
+        let first = response.chunk().await.context("Failed to read response body")?.unwrap_or_default();
+        if is_html_type || first.trim_ascii_start().starts_with(b"<") {
+            return Err(html_instead_of_json(status));
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(STREAMED_JSON_CHUNKS);
+        let parsing = tokio::task::spawn_blocking(move || {
+            serde_json::from_reader::<_, T>(BufReader::new(ChunkReader { chunks: receiver, current: first }))
+        });
+
+        // Ends early if parsing fails and drops the receiver
+        let mut read_error = None;
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    if sender.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    read_error = Some(e);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+
+        let parsed = parsing.await.context("JSON parsing task panicked")?;
+        // A body cut short fails parsing too; the read error says why
+        if let Some(e) = read_error {
+            return Err(e).context("Failed to read response body");
+        }
+        Ok(parsed?)
+    }
+
+    fn is_html_type(&self) -> bool {
+        self.headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.to_ascii_lowercase().contains("text/html"))
+    }
+}
+
+/// Chunks of a body being parsed by `json_streamed` that may wait for the
+/// parser; bounds how much of the body is held at once.
+const STREAMED_JSON_CHUNKS: usize = 16;
+
+fn html_instead_of_json(status: StatusCode) -> anyhow::Error {
+    anyhow!(
+        "Server returned an HTML error page instead of JSON (HTTP {}). \
+         The iCloud service may be down or the host is wrong",
+        status
+    )
+}
+
+/// A body arriving in chunks, read as one stream. Blocks between chunks, so
+/// it's only read on a blocking thread.
+struct ChunkReader {
+    chunks: tokio::sync::mpsc::Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+/// Which responses get their headers dumped to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeaderDebug {
+    /// Only responses with a non-success status
+    Failed,
+    /// Every response
+    All,
+}
+
+/// Production client backed by `reqwest`.
+#[derive(Clone)]
+pub struct ReqwestClient {
+    inner: reqwest::Client,
+    headers: RequestHeaders,
+    debug_headers: Option<HeaderDebug>,
+    /// Session tokens to remember and replay; `None` with --no-session.
+    session: Option<SessionTokens>,
+    rate_limits: Option<FileRateLimits>,
+}
+
+/// --max-rate-per-file: one `Throttle` per download URL, shared by every
+/// response for it that's still being read, so the parts of a file are
+/// limited together.
+#[derive(Clone)]
+struct FileRateLimits {
+    bytes_per_sec: u64,
+    throttles: Arc<Mutex<HashMap<String, Weak<Throttle>>>>,
+}
+
+impl FileRateLimits {
+    fn for_url(&self, url: &str) -> Arc<Throttle> {
+        let mut throttles = self.throttles.lock().unwrap();
+        throttles.retain(|_, throttle| throttle.strong_count() > 0);
+        if let Some(throttle) = throttles.get(url).and_then(Weak::upgrade) {
+            return throttle;
+        }
+        let throttle = Arc::new(Throttle::new(self.bytes_per_sec));
+        throttles.insert(url.to_string(), Arc::downgrade(&throttle));
+        throttle
+    }
+}
+
+impl ReqwestClient {
+    pub fn new(inner: reqwest::Client) -> Self {
+        Self { inner, headers: RequestHeaders::default(), debug_headers: None, session: None, rate_limits: None }
+    }
+
+    pub fn with_request_headers(mut self, headers: RequestHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn with_debug_headers(mut self, debug_headers: Option<HeaderDebug>) -> Self {
+        self.debug_headers = debug_headers;
+        self
+    }
+
+    pub fn with_session(mut self, session: Option<SessionTokens>) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Limits each downloaded file to `bytes_per_sec`.
+    pub fn with_rate_limit_per_file(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.rate_limits = bytes_per_sec.map(|bytes_per_sec| FileRateLimits {
+            bytes_per_sec,
+            throttles: Arc::new(Mutex::new(HashMap::new())),
+        });
+        self
+    }
+
+    /// The throttle for reading a response body, for CDN downloads.
+    fn throttle(&self, url: &str, kind: RequestKind) -> Option<Arc<Throttle>> {
+        match kind {
+            RequestKind::Download => self.rate_limits.as_ref().map(|limits| limits.for_url(url)),
+            RequestKind::Api | RequestKind::Page => None,
+        }
+    }
+
+    /// Adds the headers for `kind`, then any session tokens that a `--header`
+    /// flag doesn't already set.
+    fn with_headers(&self, request: reqwest::RequestBuilder, kind: RequestKind) -> reqwest::RequestBuilder {
+        let configured = self.headers.for_kind(kind);
+        let request = configured.iter().fold(request, |request, (name, value)| request.header(name, value));
+        let tokens = self.session.as_ref().map(SessionTokens::headers).unwrap_or_default();
+        tokens
+            .into_iter()
+            .filter(|(name, _)| !configured.iter().any(|(configured, _)| configured.eq_ignore_ascii_case(name)))
+            .fold(request, |request, (name, value)| request.header(name, value))
+    }
+
+    async fn send(
+        request: reqwest::RequestBuilder,
+        debug_headers: Option<HeaderDebug>,
+        session: Option<SessionTokens>,
+        throttle: Option<Arc<Throttle>>,
+    ) -> Result<HttpResponse> {
+        let response = request.send().await.map_err(|e| match pinning::find_pin_mismatch(&e) {
+            Some(mismatch) => anyhow!("{}", mismatch),
+            None => e.into(),
+        })?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            stats::count_rate_limited();
+        }
+        if let Some(session) = &session {
+            session.capture(response.headers());
+        }
+
+        let log = match debug_headers {
+            Some(HeaderDebug::All) => true,
+            Some(HeaderDebug::Failed) => !response.status().is_success(),
+            None => false,
+        };
+        if log {
+            log_response_headers(&response);
+        }
+
+        Ok(HttpResponse {
+            status: response.status(),
+            url: response.url().to_string(),
+            headers: response.headers().clone(),
+            body: ResponseBody::Live(response),
+            throttle,
+        })
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn post_json<B: Serialize + Sync>(
+        &self,
+        url: &str,
+        kind: RequestKind,
+        body: &B,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        // Headers go first so an explicit Content-Type wins over the JSON default
+        let request = self.with_headers(self.inner.post(url), kind).json(body);
+        Self::send(request, self.debug_headers, self.session.clone(), None)
+    }
+
+    fn get(
+        &self,
+        url: &str,
+        kind: RequestKind,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        let request = self.with_headers(self.inner.get(url), kind);
+        Self::send(request, self.debug_headers, self.session.clone(), self.throttle(url, kind))
+    }
+
+    fn head(
+        &self,
+        url: &str,
+        kind: RequestKind,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        let request = self.with_headers(self.inner.head(url), kind);
+        Self::send(request, self.debug_headers, self.session.clone(), None)
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        kind: RequestKind,
+        range: std::ops::Range<u64>,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        let request = self
+            .with_headers(self.inner.get(url), kind)
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+        Self::send(request, self.debug_headers, self.session.clone(), self.throttle(url, kind))
+    }
+}
+
+fn log_response_headers(response: &reqwest::Response) {
+    let mut out = format!("🔎 {} {}\n", response.status(), response.url());
+    for (name, value) in response.headers() {
+        out.push_str(&format!("    {}: {}\n", name, String::from_utf8_lossy(value.as_bytes())));
+    }
+    eprint!("{}", out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serves `body` once on a local port and returns its URL.
+    async fn serve_once(body: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ck1/IMG_0001.JPG", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn a_download_stays_under_the_per_file_rate() {
+        let rate = 32 * 1024;
+        let url = serve_once(vec![7u8; 48 * 1024]).await;
+        let client = ReqwestClient::new(reqwest::Client::new()).with_rate_limit_per_file(Some(rate));
+
+        let started = Instant::now();
+        let response = client.get(&url, RequestKind::Download).await.unwrap();
+        let body = response.bytes_with_progress(|_| {}).await.unwrap();
+        let elapsed = started.elapsed().as_secs_f64();
+
+        assert_eq!(body.len(), 48 * 1024);
+        // Everything past the quarter second of burst comes at the rate
+        let burst = rate as f64 * crate::ratelimit::BURST_SECONDS;
+        let fastest = (body.len() as f64 - burst) / rate as f64;
+        assert!(elapsed >= fastest * 0.95, "48KB at 32KB/s took only {:.2}s", elapsed);
+    }
+
+    #[tokio::test]
+    async fn api_requests_are_not_limited() {
+        let url = serve_once(vec![7u8; 48 * 1024]).await;
+        let client = ReqwestClient::new(reqwest::Client::new()).with_rate_limit_per_file(Some(1024));
+
+        let started = Instant::now();
+        let response = client.get(&url, RequestKind::Api).await.unwrap();
+        response.bytes_with_progress(|_| {}).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn the_parts_of_a_file_share_its_limit() {
+        let client = ReqwestClient::new(reqwest::Client::new()).with_rate_limit_per_file(Some(1024));
+        let first = client.throttle("https://files.test/a", RequestKind::Download).unwrap();
+        let second = client.throttle("https://files.test/a", RequestKind::Download).unwrap();
+        let other = client.throttle("https://files.test/b", RequestKind::Download).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        // Once nothing is reading the file, a new download starts afresh
+        drop((first, second));
+        let again = client.throttle("https://files.test/a", RequestKind::Download).unwrap();
+        assert_eq!(Arc::strong_count(&again), 1);
+    }
+}
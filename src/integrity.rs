@@ -0,0 +1,100 @@
+// Cheap integrity check for downloads: compare what the server says it sent
+// (Content-Length) and what actually arrived against the file size the album
+// metadata promised. A mismatch usually means a truncated or substituted asset.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::dashboard::Transfer;
+use crate::size::format_size;
+use crate::stats;
+
+/// Wait before the first retry of a body cut short; doubles with each retry.
+const TRUNCATED_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The connection ended before the full `Content-Length` arrived. Unlike a
+/// size mismatch against the album metadata, this is always an error: the
+/// server itself says the body is incomplete.
+#[derive(Debug)]
+pub struct TruncatedBody {
+    pub received: u64,
+    pub expected: u64,
+}
+
+impl fmt::Display for TruncatedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection closed after {} of {} bytes",
+            self.received, self.expected
+        )
+    }
+}
+
+impl std::error::Error for TruncatedBody {}
+
+/// Gets ready for another try after `error` cut attempt number `attempt`
+/// short: the bytes thrown away come off the dashboard, so its progress
+/// starts over, and it waits a little longer each time so a struggling
+/// server isn't asked again straight away.
+pub async fn before_retry(error: &anyhow::Error, attempt: u32, transfer: Option<&Transfer<'_>>) {
+    if let (Some(truncated), Some(transfer)) = (error.downcast_ref::<TruncatedBody>(), transfer) {
+        transfer.rewind(truncated.received);
+    }
+    stats::count_retry();
+    tokio::time::sleep(TRUNCATED_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+}
+
+/// Relative difference allowed before sizes count as mismatched.
+const SIZE_TOLERANCE: f64 = 0.01;
+
+pub struct SizeMismatch {
+    expected: u64,
+    actual: u64,
+    source: &'static str,
+}
+
+impl fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "size mismatch: album lists {} but {} was {}",
+            format_size(self.expected),
+            self.source,
+            format_size(self.actual)
+        )
+    }
+}
+
+/// Checks a `Content-Length` header on its own, e.g. from a HEAD request.
+pub fn check_content_length(expected: Option<u64>, content_length: u64) -> Option<SizeMismatch> {
+    let expected = expected?;
+    differs(expected, content_length).then_some(SizeMismatch {
+        expected,
+        actual: content_length,
+        source: "Content-Length",
+    })
+}
+
+/// Checks the `Content-Length` header and the received body length against
+/// the expected size. Nothing is checked when the album didn't list a size.
+pub fn check_size(expected: Option<u64>, content_length: Option<u64>, received: u64) -> Option<SizeMismatch> {
+    let expected = expected?;
+
+    let checks = content_length
+        .map(|length| (length, "Content-Length"))
+        .into_iter()
+        .chain([(received, "the downloaded body")]);
+
+    for (actual, source) in checks {
+        if differs(expected, actual) {
+            return Some(SizeMismatch { expected, actual, source });
+        }
+    }
+    None
+}
+
+fn differs(expected: u64, actual: u64) -> bool {
+    let allowed = (expected as f64 * SIZE_TOLERANCE) as u64;
+    expected.abs_diff(actual) > allowed
+}
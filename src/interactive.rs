@@ -0,0 +1,153 @@
+// `--interactive`: a checklist of the album's photos, shown after the
+// metadata is fetched and before any download URL is requested, to pick
+// which photos to download. Nothing is selected to begin with.
+//
+// Unlike the --tui dashboard this needs raw mode to read single keys, so
+// Ctrl-C arrives as a key here and cancels the selection.
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::HashSet;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::dates::{self, DateTimezone};
+use crate::size::format_size;
+use crate::{select_derivative, Photo};
+
+const HELP: &str = "↑/↓ move  space toggle  a all/none  enter download  esc cancel";
+
+/// One line of the checklist.
+struct Row {
+    label: String,
+    size: Option<u64>,
+}
+
+/// Shows the checklist and returns the GUIDs of the chosen photos, or
+/// `None` if the selection was cancelled.
+pub fn choose(photos: &[Photo], timezone: DateTimezone) -> Result<Option<HashSet<String>>> {
+    let rows: Vec<Row> = photos.iter().map(|photo| row(photo, timezone)).collect();
+    let mut chosen = vec![false; photos.len()];
+    let mut list = ListState::default().with_selected(Some(0));
+
+    let _guard = RawScreen::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+        .context("Failed to set up the terminal")?;
+    let mut page = 10;
+
+    loop {
+        terminal
+            .draw(|frame| page = render(frame, &rows, &chosen, &mut list))
+            .context("Failed to draw the photo list")?;
+
+        let Event::Key(key) = event::read().context("Failed to read from the terminal")? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let current = list.selected().unwrap_or(0);
+        let last = rows.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(None),
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+            KeyCode::Enter => break,
+            KeyCode::Up | KeyCode::Char('k') => list.select(Some(current.saturating_sub(1))),
+            KeyCode::Down | KeyCode::Char('j') => list.select(Some((current + 1).min(last))),
+            KeyCode::PageUp => list.select(Some(current.saturating_sub(page))),
+            KeyCode::PageDown => list.select(Some((current + page).min(last))),
+            KeyCode::Home => list.select(Some(0)),
+            KeyCode::End => list.select(Some(last)),
+            KeyCode::Char(' ') => {
+                if let Some(flag) = chosen.get_mut(current) {
+                    *flag = !*flag;
+                }
+                list.select(Some((current + 1).min(last)));
+            }
+            KeyCode::Char('a') => {
+                let all = chosen.iter().all(|&flag| flag);
+                chosen.iter_mut().for_each(|flag| *flag = !all);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Some(
+        photos
+            .iter()
+            .zip(&chosen)
+            .filter(|(_, &flag)| flag)
+            .map(|(photo, _)| photo.photo_guid.clone())
+            .collect(),
+    ))
+}
+
+fn row(photo: &Photo, timezone: DateTimezone) -> Row {
+    let date = photo.date_created.as_deref().and_then(dates::parse_date_created).map_or_else(
+        || "????-??-?? ??:??".to_string(),
+        |date| match timezone {
+            DateTimezone::Utc => date.format("%Y-%m-%d %H:%M").to_string(),
+            DateTimezone::Local => date.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+        },
+    );
+    let size = select_derivative(photo).and_then(|(_, derivative)| derivative.file_size_bytes());
+    let caption = photo.caption.as_deref().map(|caption| render_caption(caption, CaptionContext::Display));
+    let label = format!(
+        "{}  {:<5}  {:>9}  {}",
+        date,
+        if photo.is_video() { "video" } else { "photo" },
+        size.map_or("?".to_string(), format_size),
+        caption.unwrap_or_default()
+    );
+    Row { label, size }
+}
+
+/// Draws the checklist; returns how many rows fit, for paging.
+fn render(frame: &mut Frame, rows: &[Row], chosen: &[bool], list: &mut ListState) -> usize {
+    let [list_area, help_area] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+
+    let count = chosen.iter().filter(|&&flag| flag).count();
+    let bytes: u64 = rows.iter().zip(chosen).filter(|(_, &flag)| flag).filter_map(|(row, _)| row.size).sum();
+    let title = format!(" Select photos: {} of {} chosen ({}) ", count, rows.len(), format_size(bytes));
+
+    let items: Vec<ListItem> = rows
+        .iter()
+        .zip(chosen)
+        .map(|(row, &flag)| ListItem::new(format!("[{}] {}", if flag { "x" } else { " " }, row.label)))
+        .collect();
+    let widget = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(widget, list_area, list);
+    frame.render_widget(Paragraph::new(Line::from(HELP)), help_area);
+
+    list_area.height.saturating_sub(2).max(1) as usize
+}
+
+/// Raw mode on the alternate screen, undone when dropped, including on
+/// early returns and errors.
+struct RawScreen;
+
+impl RawScreen {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode().context("Failed to switch the terminal to raw mode")?;
+        let guard = Self;
+        execute!(std::io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)
+            .context("Failed to switch to the alternate screen")?;
+        Ok(guard)
+    }
+}
+
+impl Drop for RawScreen {
+    fn drop(&mut self) {
+        let _ = execute!(std::io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
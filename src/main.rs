@@ -1,16 +1,127 @@
 use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use futures::future::join_all;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use futures::stream::{self, StreamExt};
 use regex::Regex;
-use reqwest::Client;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
-use tokio::fs::File;
+use std::io::{IsTerminal, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::AsyncWriteExt;
 
+#[macro_use]
+mod output;
+
+mod apple_checksum;
+mod archive;
+mod aria2;
+mod atomic;
+mod batches;
+mod benchmark;
+mod breaker;
+mod caption;
+mod clock;
+mod conflicts;
+mod curate;
+mod dashboard;
+mod dates;
+mod diskspace;
+mod disposition;
+mod errors;
+mod existing;
+mod expiry;
+mod failures;
+mod filetype;
+mod gallery;
+mod hashing;
+mod headcheck;
+mod headers;
+mod history;
+mod hooks;
+mod hosts;
+mod http;
+mod integrity;
+mod interactive;
+mod manifest;
+mod memory;
+mod metadata;
+mod normalize;
+mod outcomes;
+mod partition;
+mod parts;
+mod permissions;
+mod pinning;
+mod pipeline;
+mod probe;
+mod progress;
+mod progress_file;
+mod ratelimit;
+mod recovery;
+mod renditions;
+mod repair;
+mod safepath;
+mod selftest;
+mod session;
+mod sharedstreams;
+mod size;
+mod smartnames;
+mod stats;
+mod store;
+mod summary;
+#[cfg(test)]
+mod testing;
+mod transcode;
+mod workdir;
+mod worker;
+mod xmp;
+
+use archive::TarArchive;
+use aria2::Aria2Export;
+use breaker::CircuitBreaker;
+use caption::{render_caption, truncate_at_char_boundary, CaptionContext};
+use conflicts::{ConflictStrategy, NameConflicts};
+use disposition::NamingSource;
+use dashboard::Dashboard;
+use dates::{DateNaming, DateTimezone};
+use diskspace::{DiskSpaceGuard, LowSpaceAction};
+use errors::AlbumError;
+use failures::{DownloadCounters, FailureLog};
+use hashing::HashPipeline;
+use manifest::{Manifest, ManifestExport, ManifestFormat};
+use outcomes::{OutcomeTable, TableScope};
+use permissions::OutputPermissions;
+use existing::{DirListing, OverwritePolicy};
+use parts::PartsDownload;
+use pipeline::{DownloadOrder, DownloadSource};
+use progress::Progress;
+use progress_file::ProgressFile;
+use headers::{HeaderOverride, RequestHeaders, RequestKind};
+use http::{HeaderDebug, HttpClient, HttpResponse, ReqwestClient};
+use session::SessionTokens;
+use batches::BatchFolders;
+use smartnames::SmartNames;
+use stats::RunStats;
+use summary::RunSummary;
+use store::{ContentStore, LinkMode};
+use transcode::{ReencodePreset, Transcoder};
+
+/// Tries per file before a body cut short of its Content-Length is an error.
+const TRUNCATED_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Requests for a batch before its photos are skipped for answering with an
+/// empty webasseturls response.
+const EMPTY_BATCH_ATTEMPTS: u32 = 3;
+
+/// Photos per webasseturls request.
+const URL_BATCH_SIZE: usize = 25;
+
+// Most filesystems cap a single path component at 255 bytes
+const MAX_FILENAME_BYTES: usize = 255;
+
 // Custom deserialization functions for string-to-number conversion
 mod deserialize_helpers {
     use super::*;
@@ -31,13 +142,30 @@ mod deserialize_helpers {
     }
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = "icloud-photo-download")]
 #[command(about = "Download all photos from an Apple Photos web album")]
 struct Args {
-    /// Apple Photos web album URL (e.g., https://www.icloud.com/sharedalbum/#B2T5oqs3q2VPkhS)
-    #[arg(short, long)]
-    url: String,
+    /// Apple Photos web album URL (e.g., https://www.icloud.com/sharedalbum/#B2T5oqs3q2VPkhS).
+    /// Can be repeated to download several albums
+    #[arg(short, long, required_unless_present_any = ["url_file", "json_lines_input", "selftest_fixtures"])]
+    url: Vec<String>,
+
+    /// File with one album URL per line, or `-` to read from stdin.
+    /// Blank lines and lines starting with `#` are ignored
+    #[arg(long)]
+    url_file: Option<String>,
+
+    /// Run as a worker: read one JSON job per line from stdin ({"url": ..., "output": ..., ...})
+    /// and write one JSON result per line to stdout. Status output goes to stderr
+    #[arg(long, conflicts_with_all = ["url", "url_file"])]
+    json_lines_input: bool,
+
+    /// Name to use for the album instead of the one from iCloud (output subdirectory, directory in
+    /// the --tar archive, manifest and gallery title, status output). Give one per album, or a
+    /// single template where {name} is iCloud's name
+    #[arg(long, value_name = "NAME")]
+    album_name: Vec<String>,
 
     /// Output directory for downloaded photos
     #[arg(short, long, default_value = "./photos")]
@@ -46,8 +174,674 @@ struct Args {
     /// Maximum concurrent downloads
     #[arg(short, long, default_value = "5")]
     concurrent: usize,
+
+    /// Download each file of 32 MB or more as this many byte ranges in parallel, for large
+    /// videos on fast connections. Falls back to one stream when the server doesn't support ranges
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u16).range(1..=16))]
+    parallel_parts: u16,
+
+    /// Give up on a file when its download, retries included, takes longer than this many
+    /// seconds, so a few stuck files can't hold up the end of a large run. No limit by default
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    per_file_timeout: Option<u64>,
+
+    /// Download no single file faster than this many bytes per second, e.g. 2MB or 500KB/s,
+    /// however few downloads run at once. Parts of a --parallel-parts download share the limit
+    #[arg(long, value_name = "RATE", value_parser = ratelimit::parse_rate)]
+    max_rate_per_file: Option<u64>,
+
+    /// Keep downloading at full speed however many downloads fail, instead of backing off
+    /// when iCloud appears to be down or rate-limiting
+    #[arg(long)]
+    no_circuit_breaker: bool,
+
+    /// Number of recent downloads the circuit breaker looks at
+    #[arg(long, value_name = "N", default_value = "20")]
+    breaker_window: usize,
+
+    /// Share of failures among the recent downloads that pauses downloading, e.g. 0.5 or 50%
+    #[arg(long, value_name = "RATE", default_value = "0.5", value_parser = breaker::parse_threshold)]
+    breaker_threshold: f64,
+
+    /// How long to pause before probing with a single download, like 30s or 2m. Doubles after
+    /// every failed probe, up to 10 minutes
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration_secs)]
+    breaker_backoff: f64,
+
+    /// Before each download, check that the output disk has at least this much free space,
+    /// e.g. 5GB; see --on-low-space for what happens when it doesn't
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Soft cap on the memory held by downloads in flight, e.g. 512MB. Fewer files are
+    /// downloaded at once when the ones running would take more, going by their listed sizes
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    max_memory: Option<u64>,
+
+    /// With --min-free-space: `wait` for space to be freed, or `abort` the run so it can be
+    /// resumed with --repair later
+    #[arg(long, value_enum, default_value = "wait", requires = "min_free_space")]
+    on_low_space: LowSpaceAction,
+
+    /// What to do when two photos get the same file name, in this run or because the manifest
+    /// has the name for another photo. Resolved in album order, so the result is the same every run
+    #[arg(long, value_enum, default_value_t = ConflictStrategy::GuidSuffix)]
+    on_conflict: ConflictStrategy,
+
+    /// What to do with files already in the output directory
+    #[arg(long, value_enum, default_value_t = OverwritePolicy::Never)]
+    overwrite_policy: OverwritePolicy,
+
+    /// Deprecated: existing files are skipped by default now (--overwrite-policy never)
+    #[arg(long, hide = true, conflicts_with = "overwrite_policy")]
+    skip_existing: bool,
+
+    /// Only download photos that aren't listed in this manifest.json (or manifest.jsonl) from an
+    /// earlier download, e.g. one that lives on another machine
+    #[arg(long, value_name = "PATH")]
+    since_manifest: Option<PathBuf>,
+
+    /// Also export the manifest of downloaded files as .icloud-dl/manifest.csv, or into a SQLite
+    /// database shared by all albums (SQLite needs a build with `--features sqlite`)
+    #[arg(long, value_enum, default_value = "json")]
+    manifest_format: ManifestFormat,
+
+    /// The SQLite database for --manifest-format sqlite, e.g. one shared by several --output
+    /// directories. Defaults to <output>/.icloud-dl/manifest.sqlite
+    #[arg(long, value_name = "PATH")]
+    manifest_db: Option<PathBuf>,
+
+    /// Put a .nomedia file in the output directory so Android's media scanner leaves it out of
+    /// the gallery, e.g. for a staging folder synced to a phone
+    #[arg(long)]
+    write_nomedia: bool,
+
+    /// Keep an index.html in the output directory that shows everything downloaded so far,
+    /// updated at the end of each run with the new files
+    #[arg(long)]
+    output_index_html_per_run: bool,
+
+    /// Update the captions and capture dates in the manifest of an earlier download from the
+    /// album's current metadata, without downloading anything. With --xmp-sidecars the sidecars
+    /// of the files that changed are written again
+    #[arg(long, conflicts_with_all = ["dry_run", "tar", "repair"])]
+    album_metadata_only_refresh: bool,
+
+    /// Deprecated: use --overwrite-policy if-larger
+    #[arg(long, hide = true, conflicts_with = "overwrite_policy")]
+    replace_existing_smaller: bool,
+
+    /// Also re-download files whose photo was replaced or edited in the album since they were
+    /// downloaded, going by the checksum in the manifest. Files the manifest doesn't list are kept
+    #[arg(long)]
+    if_newer: bool,
+
+    /// Only re-download files from a previous run that are missing, empty or the wrong size
+    #[arg(long)]
+    repair: bool,
+
+    /// Show what would be downloaded and the estimated size, then exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Test each step of a download (host lookup, album metadata, download URLs, one small
+    /// download) for a single --url and print a checklist to paste into bug reports
+    #[arg(long, conflicts_with_all = ["url_file", "json_lines_input"])]
+    probe: bool,
+
+    /// Download a single --url without saving anything and report throughput, CPU time and peak
+    /// memory, for tuning settings and spotting regressions
+    #[arg(long, conflicts_with_all = ["url_file", "json_lines_input", "probe", "tar"])]
+    benchmark: bool,
+
+    /// With --benchmark, run once per --concurrent value in this list (e.g. 1,4,8,16)
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        requires = "benchmark",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    benchmark_concurrency: Vec<u16>,
+
+    /// With --benchmark, run once per --parallel-parts value in this list, combined with each
+    /// --benchmark-concurrency value
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        requires = "benchmark",
+        value_parser = clap::value_parser!(u16).range(1..=16)
+    )]
+    benchmark_parts: Vec<u16>,
+
+    /// Run the download pipeline offline against built-in fixtures (a photo, a video, a Live
+    /// Photo, photos without derivatives, an empty album) and print which stages pass
+    #[arg(long, conflicts_with_all = ["url", "url_file", "json_lines_input", "probe", "benchmark"])]
+    selftest_fixtures: bool,
+
+    /// Only download photos at these 1-based, inclusive positions in album order, e.g. `100..200`, `500..` or `..50`
+    #[arg(long, value_parser = parse_photo_range)]
+    range: Option<PhotoRange>,
+
+    /// Only download a curated subset: `best-per-day` (highest resolution of each day),
+    /// `first-per-day` (earliest of each day) or `largest-<N>` (the N highest-resolution photos).
+    /// Days follow --timezone; photos without a date are always kept by the per-day strategies
+    #[arg(long, value_name = "STRATEGY", value_parser = curate::parse_select)]
+    select: Option<curate::SelectStrategy>,
+
+    /// Pick the photos to download from a checklist (captions, dates, sizes) before any download
+    /// URL is fetched. Needs a terminal
+    #[arg(long, conflicts_with_all = ["json_lines_input", "probe", "benchmark"])]
+    interactive: bool,
+
+    /// Only download the photos listed in a failures file from an earlier run (usually
+    /// OUTPUT/.icloud-dl/failures.txt), with fresh download URLs. The file is rewritten with
+    /// whatever still fails
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["url_file", "range", "select", "cover_only", "repair"])]
+    retry_failed: Option<PathBuf>,
+
+    /// Also download photos the album metadata marks as hidden
+    #[arg(long)]
+    include_hidden: bool,
+
+    /// Also download photos the album metadata marks as recently deleted, to recover them
+    /// before they are purged
+    #[arg(long)]
+    include_deleted: bool,
+
+    /// Only download the album's cover photo, or its first photo if the album doesn't name one
+    #[arg(long, conflicts_with_all = ["range", "select"])]
+    cover_only: bool,
+
+    /// With --cover-only, fail instead of falling back to the first photo when the album
+    /// doesn't name a cover
+    #[arg(long, requires = "cover_only")]
+    no_cover_fallback: bool,
+
+    /// Send album API requests to this sharedstreams host instead of the one the album token names,
+    /// e.g. p42-sharedstreams.icloud.com, or a .icloud.com.cn host for albums from mainland China
+    #[arg(long, value_name = "HOST", value_parser = sharedstreams::parse_host)]
+    host_override: Option<String>,
+
+    /// Extra root certificate (PEM or DER) to trust, e.g. for a TLS-intercepting corporate proxy
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// DANGEROUS: disable TLS certificate verification entirely. Anyone on the network path can
+    /// then read and tamper with the traffic. Prefer --ca-cert with your proxy's certificate
+    #[arg(long)]
+    insecure: bool,
+
+    /// Only accept TLS connections whose certificate chain includes a certificate with this
+    /// SHA-256 fingerprint (repeatable), on top of normal CA validation. Guards against
+    /// interception by other trusted CAs; pins need updating when Apple rotates certificates
+    #[arg(long, value_name = "FINGERPRINT", value_parser = pinning::parse_fingerprint, conflicts_with = "insecure")]
+    pin_cert: Vec<pinning::Fingerprint>,
+
+    /// IP version to connect over: 4, 6 or auto. Forcing 4 helps when the IPv6 path to
+    /// iCloud is flaky
+    #[arg(long, value_enum, default_value = "auto")]
+    ip_version: IpVersion,
+
+    /// Print response headers to stderr for failed requests, or for every request with `all`.
+    /// The output can include signed URLs and tokens, so don't paste it publicly
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "failed")]
+    debug_headers: Option<HeaderDebug>,
+
+    /// Add or override a request header, e.g. --header 'Sec-Fetch-Mode: cors'. Repeatable.
+    /// An empty value ('Sec-Fetch-Dest:') removes a default header
+    #[arg(long, value_name = "NAME: VALUE", value_parser = headers::parse_header_override)]
+    header: Vec<HeaderOverride>,
+
+    /// Don't load www.icloud.com first for a session, and don't send cookies or session tokens
+    /// received from iCloud back with later requests
+    #[arg(long)]
+    no_session: bool,
+
+    /// Print a table of per-file outcomes at the end: failed and skipped files, or every file with `all`
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "problems")]
+    summary_table: Option<TableScope>,
+
+    /// Also write failed downloads, with freshly fetched URLs, to this aria2c input file
+    /// (`aria2c --input-file <PATH>`)
+    #[arg(long, value_name = "PATH")]
+    failures_aria2: Option<PathBuf>,
+
+    /// Write a SHA-256 of every downloaded file to .icloud-dl/checksums.sha256 (sha256sum format).
+    /// Hashing runs on its own threads so it doesn't slow the downloads down
+    #[arg(long)]
+    checksum_manifest: bool,
+
+    /// Write everything into a tar archive at this path instead of separate files, or stream it
+    /// to stdout with '-'. Status output then goes to stderr
+    #[arg(long, value_name = "PATH", conflicts_with_all = [
+        "json_lines_input", "tui", "overwrite_policy", "skip_existing", "replace_existing_smaller", "if_newer", "repair",
+        "checksum_manifest", "post_download_cmd", "content_store", "reencode_videos", "snapshot",
+        "preserve_dates", "xmp_sidecars", "photos_import",
+    ])]
+    tar: Option<String>,
+
+    /// Append the album's full photo list with checksums to .icloud-dl/history.jsonl, when it
+    /// changed since the last snapshot, to keep a history of the album over time
+    #[arg(long)]
+    snapshot: bool,
+
+    /// Re-encode downloaded videos with ffmpeg in the background: `h264`, `hevc` (smaller) or
+    /// `h264-720p`. Live Photo videos are left alone
+    #[arg(long, value_name = "PRESET", value_enum)]
+    reencode_videos: Option<ReencodePreset>,
+
+    /// With --reencode-videos, keep the original next to the re-encoded NAME.reencoded.EXT
+    /// instead of replacing it
+    #[arg(long, requires = "reencode_videos")]
+    keep_original: bool,
+
+    /// Videos re-encoded at once, independent of --concurrent
+    #[arg(long, value_name = "N", default_value = "1", requires = "reencode_videos")]
+    reencode_jobs: usize,
+
+    /// Keep each photo once in this directory, named by its iCloud checksum, and link album
+    /// files to it, so photos shared into several albums are downloaded and stored once
+    #[arg(long, value_name = "DIR", conflicts_with = "strip_metadata")]
+    content_store: Option<PathBuf>,
+
+    /// How album files point into the --content-store: `hardlink` (same filesystem only),
+    /// `symlink` or `copy`
+    #[arg(long, value_enum, default_value = "hardlink", requires = "content_store")]
+    link_mode: LinkMode,
+
+    /// Command to run after each file is saved, e.g. 'convert {path} -resize 512 thumbs/{guid}.jpg'.
+    /// Tokens: {path}, {guid}, {checksum}, {caption}, {size}, {resolution}; also passed as
+    /// ICLOUD_DL_* environment variables. Not run through a shell
+    #[arg(long, value_name = "TEMPLATE", value_parser = hooks::parse_hook_template)]
+    post_download_cmd: Option<hooks::PostDownloadHook>,
+
+    /// Count a download as failed when the --post-download-cmd exits non-zero (the file is kept)
+    #[arg(long, requires = "post_download_cmd")]
+    hook_required: bool,
+
+    /// When to exit with a non-zero status: `any-failure` (any file or album failed),
+    /// `total-failure` (failures, and nothing at all was downloaded) or `never`
+    #[arg(long, value_enum, default_value = "total-failure")]
+    exit_on: ExitPolicy,
+
+    /// Print a timing breakdown of each phase and of per-file download times at the end
+    #[arg(long)]
+    stats: bool,
+
+    /// Append machine-readable stats for each album to this file as a line of JSON: phase
+    /// times, retries, URL refreshes, HTTP 429s, bytes downloaded and average concurrency
+    #[arg(long, value_name = "PATH")]
+    stats_json: Option<PathBuf>,
+
+    /// Write a short JSON summary of the run to this file at the end of every run, also
+    /// when it fails: start and end time, albums, counts, bytes and the failed files
+    #[arg(long, value_name = "PATH", conflicts_with = "json_lines_input")]
+    summary_json: Option<PathBuf>,
+
+    /// Keep live progress (files done, bytes, rate, ETA) in this JSON file, rewritten every
+    /// second during downloads, for monitors polling long unattended runs
+    #[arg(long, value_name = "PATH")]
+    progress_file: Option<PathBuf>,
+
+    /// Show a full-screen live dashboard (speed, ETA, active downloads, recent failures)
+    /// instead of a progress bar. Falls back to the progress bar when stdout isn't a terminal
+    #[arg(long, conflicts_with = "json_lines_input")]
+    tui: bool,
+
+    /// Order to start downloads in; sizes are those listed in the album
+    #[arg(long, value_enum, default_value_t = DownloadOrder::Album)]
+    order: DownloadOrder,
+
+    /// Print a plain progress line every few seconds instead of drawing progress bars. This is
+    /// the default when stdout or stderr isn't a terminal (systemd, cron, pipes)
+    #[arg(long, conflicts_with = "tui")]
+    no_progress: bool,
+
+    /// Treat a download whose size doesn't match the album's listed size as failed instead of
+    /// keeping it with a warning
+    #[arg(long)]
+    strict: bool,
+
+    /// Check each download against the checksum iCloud lists for it and fail it on a mismatch.
+    /// Files whose checksum type isn't understood are counted as unverifiable
+    #[arg(long)]
+    verify: bool,
+
+    /// Send a HEAD request for every download URL before downloading, to find expired or broken
+    /// URLs and wrong sizes up front. Bad URLs are re-fetched with --refresh-expiring-urls,
+    /// and abort the run with --strict-head-check
+    #[arg(long)]
+    head_check: bool,
+
+    /// With --head-check, stop the run if any download URL fails the check (and can't be
+    /// re-fetched) instead of only reporting it
+    #[arg(long, requires = "head_check")]
+    strict_head_check: bool,
+
+    /// Send a HEAD request for every download URL before downloading and use its Content-Length
+    /// as the file's size, for albums whose listed sizes are missing or wrong
+    #[arg(long)]
+    prefetch_sizes: bool,
+
+    /// Time a probe download from each CDN host iCloud offers and print a ranking. With `pin`,
+    /// download everything from the fastest host
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "report")]
+    compare_hosts: Option<hosts::HostComparison>,
+
+    /// Don't ask for confirmation before downloading into a directory that already holds
+    /// many files this tool didn't download there
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Warn when download URLs expire within this many minutes of the estimated finish time
+    #[arg(long, default_value = "10")]
+    expiry_margin: i64,
+
+    /// Re-fetch a photo's download URL right before downloading it if it is about to expire
+    #[arg(long)]
+    refresh_expiring_urls: bool,
+
+    /// Keep the file extension from the download URL even if the content is a different format
+    #[arg(long)]
+    no_ext_correction: bool,
+
+    /// Download several sizes of each photo, e.g. `thumb,full`. Each is saved with the size as a
+    /// suffix (`IMG_1234_thumb.jpg`). Accepts `full`, `medium`, `thumb` or raw derivative keys like `342`
+    #[arg(long, value_delimiter = ',', value_parser = parse_derivative_name)]
+    derivatives: Option<Vec<String>>,
+
+    /// Print every rendition of each photo (key, dimensions, file size) from the album metadata,
+    /// as a table or with `json` on stdout, then exit without downloading
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "table",
+          conflicts_with_all = ["dry_run", "tar", "album_metadata_only_refresh", "json_lines_input"])]
+    list_derivatives: Option<renditions::ListFormat>,
+
+    /// Download only the still image of Live Photos and skip their motion video. By default the
+    /// motion video is saved too, as a .mov next to the still (earlier versions saved only the
+    /// still). The video belongs to its photo, so --exclude-videos-over and --reencode-videos skip
+    /// it; --max-file-size and --min-file-size check it on its own
+    #[arg(long)]
+    flatten_live_photos: bool,
+
+    /// Skip files larger than this, e.g. 50MB
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Skip files smaller than this, e.g. 200KB
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    min_file_size: Option<u64>,
+
+    /// Stop once the files to download add up to this, e.g. 10GB, going by the sizes the
+    /// album lists. Files are taken in --order until the next one would go past it
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    max_total_size: Option<u64>,
+
+    /// With --max-file-size/--min-file-size, also skip files whose size the album doesn't list
+    #[arg(long)]
+    strict_size: bool,
+
+    /// Skip videos longer than this (90s, 5m, 1h). Needs duration metadata, which iCloud
+    /// doesn't always include; the run stops with an error if the album has none
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration_secs)]
+    exclude_videos_over: Option<f64>,
+
+    /// Name files of captioned photos after their caption ("Beach.jpg", or "Beach_1.jpg",
+    /// "Beach_2.jpg" when several share it); photos without a caption keep their original name
+    #[arg(long)]
+    smart_names: bool,
+
+    /// Where file names come from: the original name iCloud lists (`listed`), or with
+    /// `content-disposition` the name in each download's Content-Disposition header, read with a
+    /// HEAD request before downloading. Files without the header keep the listed name
+    #[arg(long, value_enum, value_name = "SOURCE", default_value_t = NamingSource::Listed)]
+    naming: NamingSource,
+
+    /// Prefix each filename with the photo's capture date (see --date-format)
+    #[arg(long)]
+    date_prefix: bool,
+
+    /// With --date-prefix, number photos whose prefixes are identical (bursts) in album order,
+    /// e.g. 2023-06-01_120000_01_IMG_0001.JPG; use a --date-format with seconds for bursts
+    #[arg(long, requires = "date_prefix")]
+    burst_index: bool,
+
+    /// Put each file into a folder named after the photo's capture date (see --date-format).
+    /// A '/' in the format makes nested folders, e.g. '%Y/%m'
+    #[arg(long)]
+    folder_by_date: bool,
+
+    /// Set each file's modification time to the photo's capture date
+    #[arg(long)]
+    preserve_dates: bool,
+
+    /// Write a NAME.xmp sidecar next to each photo and video with its caption and capture
+    /// date, for Apple Photos, Lightroom and the like to pick up on import
+    #[arg(long)]
+    xmp_sidecars: bool,
+
+    /// Prepare the download for Apple Photos' File > Import: original names, Live Photo stills
+    /// and videos side by side under one name, capture dates as file times and XMP sidecars
+    /// with captions. Same as --preserve-dates --xmp-sidecars, ruling out options that rename,
+    /// move or change files
+    #[arg(long, conflicts_with_all = [
+        "smart_names", "date_prefix", "folder_by_date", "group_by_batch", "derivatives",
+        "flatten_live_photos", "strip_metadata", "reencode_videos", "no_ext_correction",
+    ])]
+    photos_import: bool,
+
+    /// Put each file into a folder named after the upload batch (batchGuid) it was added to
+    /// the album in, so photos posted together stay together
+    #[arg(long, conflicts_with = "folder_by_date")]
+    group_by_batch: bool,
+
+    /// strftime pattern for capture dates in filenames and folder names
+    #[arg(long, default_value = "%Y-%m-%d", value_parser = dates::parse_date_format)]
+    date_format: String,
+
+    /// Time zone capture dates are rendered in
+    #[arg(long, value_enum, default_value = "local")]
+    timezone: DateTimezone,
+
+    /// Folder for photos without a capture date when using --folder-by-date
+    #[arg(long, default_value = "undated")]
+    undated_folder: String,
+
+    /// Permissions for downloaded files, in octal (e.g. 640). Defaults to the process umask. Unix only
+    #[arg(long, value_parser = permissions::parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Permissions for directories created for the download, in octal (e.g. 750). Defaults to the process umask. Unix only
+    #[arg(long, value_parser = permissions::parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Remove EXIF/XMP/IPTC metadata (GPS, device info, timestamps) from downloaded JPEG, HEIC, PNG and
+    /// WebP images; videos are saved unchanged
+    #[arg(long)]
+    strip_metadata: bool,
+}
+
+/// IP version selected with `--ip-version`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum IpVersion {
+    #[value(name = "4")]
+    V4,
+    #[value(name = "6")]
+    V6,
+    Auto,
+}
+
+/// What makes the process exit with a non-zero status (--exit-on).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ExitPolicy {
+    /// Any failed file or album
+    AnyFailure,
+    /// Failures, when not a single file was downloaded
+    TotalFailure,
+    /// Nothing; failures are only reported
+    Never,
+}
+
+/// A 1-based, inclusive slice of the album selected with `--range`.
+#[derive(Clone, Copy, Debug)]
+struct PhotoRange {
+    start: Option<usize>,
+    end: Option<usize>,
+}
+
+fn parse_photo_range(value: &str) -> Result<PhotoRange, String> {
+    let (start, end) = value
+        .split_once("..")
+        .ok_or_else(|| format!("expected START..END, got '{}'", value))?;
+
+    let parse_bound = |bound: &str| -> Result<Option<usize>, String> {
+        let bound = bound.trim();
+        if bound.is_empty() {
+            return Ok(None);
+        }
+        match bound.parse::<usize>() {
+            Ok(0) => Err("positions start at 1".to_string()),
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("'{}' is not a valid position", bound)),
+        }
+    };
+
+    let range = PhotoRange {
+        start: parse_bound(start)?,
+        end: parse_bound(end)?,
+    };
+    if let (Some(start), Some(end)) = (range.start, range.end) {
+        if start > end {
+            return Err(format!("start ({}) is after end ({})", start, end));
+        }
+    }
+    Ok(range)
+}
+
+/// Parses a duration like `90`, `90s`, `5m` or `1h` into seconds.
+fn parse_duration_secs(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1.0),
+        Some((i, 'm')) => (&value[..i], 60.0),
+        Some((i, 'h')) => (&value[..i], 3600.0),
+        _ => (value, 1.0),
+    };
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n >= 0.0)
+        .map(|n| n * multiplier)
+        .ok_or_else(|| format!("'{}' is not a duration like 90s, 5m or 1h", value))
+}
+
+impl PhotoRange {
+    /// Converts to 0-based indices into an album of `len` photos, clamping
+    /// the end to the album size.
+    fn bounds(&self, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = self.start.unwrap_or(1);
+        if start > len {
+            return Err(anyhow!(
+                "--range starts at {} but the album only has {} photos",
+                start,
+                len
+            ));
+        }
+
+        let end = match self.end {
+            Some(end) if end > len => {
+                status!("⚠️  --range end {} is past the end of the album, stopping at {}", end, len);
+                len
+            }
+            Some(end) => end,
+            None => len,
+        };
+
+        Ok(start - 1..end)
+    }
+}
+
+/// Where the download phase reports what happened to each file.
+struct DownloadReporting<'a> {
+    failure_log: &'a FailureLog,
+    manifest: Option<&'a Manifest>,
+    hashes: Option<&'a HashPipeline>,
+    transcoder: Option<&'a Transcoder>,
+    outcome_table: Option<&'a OutcomeTable>,
+    aria2: Option<&'a Aria2Export>,
+    progress: Option<&'a ProgressFile>,
+    stats: &'a RunStats,
 }
 
+/// What `download_single_photo` wrote.
+struct SavedFile {
+    filename: String,
+    /// Bytes written to disk.
+    size: u64,
+    /// Set when the file was kept despite not matching the album's listed size.
+    size_mismatch: Option<integrity::SizeMismatch>,
+    /// With --verify: whether iCloud's checksum could be checked (a mismatch
+    /// is an error instead). `None` without --verify.
+    verified: Option<bool>,
+    /// The format found from the file's contents or `Content-Type`, if any.
+    format: Option<&'static str>,
+}
+
+/// Per-file behaviour of the download phase.
+struct DownloadOptions {
+    max_concurrent: usize,
+    parallel_parts: usize,
+    /// Longest a single file may take (--per-file-timeout).
+    per_file_timeout: Option<Duration>,
+    correct_extensions: bool,
+    strip_metadata: bool,
+    dashboard: bool,
+    strict_sizes: bool,
+    verify: bool,
+    permissions: OutputPermissions,
+    breaker: Option<CircuitBreaker>,
+    disk_space: Option<DiskSpaceGuard>,
+    memory: Option<memory::MemoryBudget>,
+    post_download: Option<hooks::PostDownloadHook>,
+    hook_required: bool,
+    /// Set with --tar; files are written into the archive instead of the output directory.
+    archive: Option<TarArchive>,
+    /// Set with --benchmark; downloaded files are dropped instead of written.
+    discard: bool,
+    content_store: Option<ContentStore>,
+    preserve_dates: bool,
+    xmp_sidecars: bool,
+}
+
+impl DownloadOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            max_concurrent: args.concurrent,
+            parallel_parts: args.parallel_parts as usize,
+            per_file_timeout: args.per_file_timeout.map(Duration::from_secs),
+            correct_extensions: !args.no_ext_correction,
+            strip_metadata: args.strip_metadata,
+            dashboard: args.tui && std::io::stdout().is_terminal(),
+            strict_sizes: args.strict,
+            verify: args.verify,
+            permissions: OutputPermissions::from_args(args),
+            breaker: CircuitBreaker::from_args(args),
+            disk_space: DiskSpaceGuard::from_args(args),
+            memory: args.max_memory.map(memory::MemoryBudget::new),
+            post_download: args.post_download_cmd.clone(),
+            hook_required: args.hook_required,
+            archive: None,
+            discard: false,
+            content_store: args.content_store.clone().map(|dir| ContentStore::new(dir, args.link_mode)),
+            preserve_dates: args.preserve_dates,
+            xmp_sidecars: args.xmp_sidecars,
+        }
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct WebstreamResponse {
     #[serde(rename = "streamCtag")]
@@ -59,7 +853,8 @@ struct WebstreamResponse {
     extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, Clone)]
 struct Photo {
     #[serde(rename = "photoGuid")]
     photo_guid: String,
@@ -77,7 +872,8 @@ struct Photo {
     extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, Clone)]
 struct Derivative {
     #[serde(rename = "fileSize")]
     file_size: Option<String>,
@@ -90,6 +886,53 @@ struct Derivative {
     extra: HashMap<String, serde_json::Value>,
 }
 
+/// Fields that may carry a video's length. The shared-album webstream has no
+/// documented duration field, so this only works when Apple sends one.
+const DURATION_KEYS: [&str; 3] = ["duration", "videoDuration", "durationSeconds"];
+
+/// Fields that may carry an asset's original filename. Not part of the
+/// documented responses either; the name in the URL path is used without one.
+const ORIGINAL_FILENAME_KEYS: [&str; 4] = ["originalFilename", "original_filename", "fileName", "filename"];
+
+impl Photo {
+    fn is_video(&self) -> bool {
+        self.extra
+            .get("mediaAssetType")
+            .and_then(|v| v.as_str())
+            .is_some_and(|t| t.eq_ignore_ascii_case("video"))
+    }
+
+    /// Video length in seconds, from the photo or any of its derivatives.
+    fn duration_secs(&self) -> Option<f64> {
+        std::iter::once(&self.extra)
+            .chain(self.derivatives.values().map(|d| &d.extra))
+            .flat_map(|extra| DURATION_KEYS.iter().filter_map(|key| extra.get(*key)))
+            .find_map(|value| match value {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            })
+    }
+}
+
+impl Derivative {
+    fn is_video(&self, key: &str) -> bool {
+        let tagged_video = self.extra
+            .get("mediaAssetType")
+            .and_then(|v| v.as_str())
+            .is_some_and(|t| t.eq_ignore_ascii_case("video"));
+        // Video renditions are keyed by resolution, e.g. `720p`
+        let video_key = key
+            .strip_suffix('p')
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        tagged_video || video_key
+    }
+
+    fn file_size_bytes(&self) -> Option<u64> {
+        self.file_size.as_deref().and_then(|s| s.trim().parse().ok())
+    }
+}
+
 #[derive(Serialize)]
 struct WebstreamRequest {
     #[serde(rename = "streamCtag")]
@@ -102,14 +945,26 @@ struct AssetUrlsRequest {
     photo_guids: Vec<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct AssetUrlsResponse {
+    #[serde(default)]
     locations: HashMap<String, Location>,
+    #[serde(default)]
     items: HashMap<String, AssetUrl>,
     #[serde(flatten)]
     extra: HashMap<String, serde_json::Value>,
 }
 
+impl AssetUrlsResponse {
+    /// No URLs at all, as opposed to some photos missing from the response.
+    /// Seen with expired batches and server hiccups; worth asking again.
+    fn is_empty(&self) -> bool {
+        self.items.is_empty() || self.locations.is_empty()
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct Location {
     scheme: String,
@@ -118,6 +973,7 @@ struct Location {
     extra: HashMap<String, serde_json::Value>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct AssetUrl {
     #[serde(rename = "url_expiry")]
@@ -130,330 +986,2752 @@ struct AssetUrl {
     extra: HashMap<String, serde_json::Value>,
 }
 
+#[allow(dead_code)]
 struct DownloadInfo {
     photo_guid: String,
     checksum: String,
     download_url: String,
     filename: String,
+    /// The stem all of the photo's files share; what follows it in
+    /// `filename` tells them apart. Set before any renaming.
+    photo_stem: String,
     size_info: String,
+    caption: Option<String>,
+    date_created: Option<DateTime<Utc>>,
+    /// All CDN hosts that serve this asset, for --compare-hosts.
+    hosts: Vec<String>,
+    file_size: Option<u64>,
+    url_expiry: Option<DateTime<Utc>>,
+    kind: AssetKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AssetKind {
+    Still,
+    Video,
+    /// The motion video that belongs to a Live Photo still
+    LiveMotion,
+}
+
+impl AssetKind {
+    fn default_extension(self) -> &'static str {
+        match self {
+            AssetKind::Still => "jpg",
+            AssetKind::Video | AssetKind::LiveMotion => "mov",
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    // On every way out, including errors before any album is downloaded
+    let result = run(args).await;
+    stats::print_result_line();
+    result
+}
 
-    println!("🍎 iCloud Photo Album Downloader");
-    println!("================================");
+async fn run(mut args: Args) -> Result<()> {
+    progress::configure(args.no_progress);
+    if let Some(host) = &args.host_override {
+        sharedstreams::set_override(host.clone());
+    }
 
-    // Extract hash from URL
-    let hash = extract_hash_from_url(&args.url)
-        .context("Failed to extract hash from URL")?;
-    
-    println!("📱 Album hash: {}", hash);
+    if args.json_lines_input
+        || args.tar.as_deref() == Some("-")
+        || args.list_derivatives == Some(renditions::ListFormat::Json)
+    {
+        output::redirect_to_stderr();
+    }
 
-    // Create output directory
-    fs::create_dir_all(&args.output)
-        .context("Failed to create output directory")?;
+    status!("🍎 iCloud Photo Album Downloader");
+    status!("================================");
 
-    let client = Client::new();
+    if args.debug_headers.is_some() {
+        eprintln!("⚠️  --debug-headers output may contain signed URLs and tokens; redact it before sharing");
+    }
+    OutputPermissions::from_args(&args).warn_if_unsupported();
+    if args.interactive && !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        return Err(anyhow!("--interactive needs a terminal to show the photo list on"));
+    }
+    if args.replace_existing_smaller {
+        eprintln!("⚠️  --replace-existing-smaller is deprecated; use --overwrite-policy if-larger");
+        args.overwrite_policy = OverwritePolicy::IfLarger;
+    } else if args.skip_existing {
+        eprintln!("⚠️  --skip-existing is deprecated; existing files are now skipped by default");
+    }
 
-    // Step 1: Get webstream data
-    println!("\n🔍 Fetching album metadata...");
-    let webstream_data = fetch_webstream(&client, &hash).await
-        .context("Failed to fetch album metadata")?;
+    if args.photos_import {
+        args.preserve_dates = true;
+        args.xmp_sidecars = true;
+    }
 
-    let album_name = webstream_data.stream_name
-        .as_deref()
-        .unwrap_or("Unknown Album");
-    let photo_count = webstream_data.photos.len();
+    if args.selftest_fixtures {
+        return selftest::run().await;
+    }
 
-    println!("📸 Album: '{}'", album_name);
-    println!("📊 Found {} photos", photo_count);
+    let client = ReqwestClient::new(build_reqwest_client(&args)?)
+        .with_request_headers(RequestHeaders::default().with_overrides(&args.header))
+        .with_debug_headers(args.debug_headers)
+        .with_session((!args.no_session).then(SessionTokens::default))
+        .with_rate_limit_per_file(args.max_rate_per_file);
+    if !args.no_session {
+        if let Err(e) = session::handshake(&client).await {
+            eprintln!("⚠️  Could not start a session with iCloud, continuing without one: {:#}", e);
+        }
+    }
 
-    if photo_count == 0 {
-        println!("✅ No photos to download");
-        return Ok(());
+    if args.json_lines_input {
+        return worker::run(&client, &args).await;
     }
 
-    // Step 2: Get download URLs in batches
-    println!("\n🔗 Fetching download URLs...");
-    let download_infos = fetch_download_urls(&client, &hash, &webstream_data.photos).await
-        .context("Failed to fetch download URLs")?;
+    if args.probe {
+        return probe::run(&client, &args).await;
+    }
 
-    println!("🎯 Prepared {} downloads", download_infos.len());
+    if args.benchmark {
+        return benchmark::run(&client, &args).await;
+    }
 
-    // Step 3: Download photos
-    println!("\n⬇️  Downloading photos...");
-    download_photos(&client, download_infos, &args.output, args.concurrent).await
-        .context("Failed to download photos")?;
+    if let Some(path) = &args.failures_aria2 {
+        Aria2Export::remove_stale(path)?;
+    }
+    if let Some(path) = &args.stats_json {
+        stats::remove_stale_json(path)?;
+    }
 
-    println!("\n✅ Download complete! Photos saved to: {}", args.output);
-    Ok(())
-}
+    let mut urls = args.url.clone();
+    if let Some(url_file) = &args.url_file {
+        urls.extend(read_url_list(url_file)?);
+    }
 
-fn extract_hash_from_url(url: &str) -> Result<String> {
-    let re = Regex::new(r"icloud\.com/sharedalbum/#([A-Za-z0-9]+)")
-        .context("Failed to compile regex")?;
-    
-    let captures = re.captures(url)
-        .ok_or_else(|| anyhow!("Invalid iCloud shared album URL format"))?;
-    
-    let hash = captures.get(1)
-        .ok_or_else(|| anyhow!("No hash found in URL"))?
-        .as_str()
-        .to_string();
-    
-    Ok(hash)
-}
+    if args.album_name.len() > 1 && args.album_name.len() != urls.len() {
+        return Err(anyhow!(
+            "Got {} --album-name values for {} albums; give one per album or a single template",
+            args.album_name.len(),
+            urls.len()
+        ));
+    }
+    if args.album_name.len() == 1 && urls.len() > 1 && !args.album_name[0].contains("{name}") {
+        return Err(anyhow!(
+            "A single --album-name for several albums must contain {{name}}, or every album would get the same name"
+        ));
+    }
 
-async fn fetch_webstream(client: &Client, hash: &str) -> Result<WebstreamResponse> {
-    let url = format!("https://p153-sharedstreams.icloud.com/{}/sharedstreams/webstream", hash);
-    
-    let request_body = WebstreamRequest {
-        stream_ctag: None,
-    };
+    // Validate (and resolve) every URL up front so one typo doesn't abort the whole batch
+    let summary = RunSummary::default();
+    let mut hashes = Vec::new();
+    let mut failed_albums = 0;
+    for (i, url) in urls.iter().enumerate() {
+        let name_override = args.album_name.get(i).or(args.album_name.first()).cloned();
+        match resolve_album_hash(&client, url).await {
+            Ok(hash) => hashes.push((hash, name_override)),
+            Err(e) => {
+                eprintln!("❌ Skipping '{}': {}", url, e);
+                summary.record_outcome(url, &Err(e));
+                failed_albums += 1;
+            }
+        }
+    }
 
-    let response = client
-        .post(&url)
-        .header("Accept", "*/*")
-        .header("Accept-Language", "en-US,en;q=0.9")
-        .header("Content-Type", "text/plain")
-        .header("Origin", "https://www.icloud.com")
-        .header("Referer", "https://www.icloud.com/")
-        .json(&request_body)
-        .send()
-        .await
-        .context("Failed to send webstream request")?;
+    if hashes.is_empty() {
+        let outcome = Err(anyhow!("No valid album URLs provided"));
+        write_summary(&args, &summary, &outcome);
+        return outcome;
+    }
+    if args.retry_failed.is_some() && hashes.len() > 1 {
+        return Err(anyhow!("--retry-failed works on one album at a time"));
+    }
 
-    if !response.status().is_success() {
-        return Err(anyhow!("Webstream request failed with status: {}", response.status()));
+    let multiple_albums = urls.len() > 1;
+    let archive = args.tar.as_deref().map(TarArchive::create).transpose()?;
+
+    let outcome = async {
+        for (hash, name_override) in &hashes {
+            let result = download_album(
+                &client,
+                &args,
+                hash,
+                name_override.as_deref(),
+                multiple_albums,
+                archive.as_ref(),
+                args.summary_json.is_some().then_some(&summary),
+            )
+            .await;
+            summary.record_outcome(hash, &result);
+            if !multiple_albums {
+                return result;
+            }
+            if let Err(e) = result {
+                eprintln!("❌ Album {} failed: {:#}", hash, e);
+                failed_albums += 1;
+            }
+        }
+
+        if failed_albums > 0 {
+            return Err(anyhow!("{} of {} albums failed", failed_albums, urls.len()));
+        }
+        Ok(())
     }
+    .await;
 
-    let webstream_data: WebstreamResponse = response
-        .json()
-        .await
-        .context("Failed to parse webstream response")?;
+    // Even after a failure, end the archive properly so what's there can be extracted
+    if let Some(archive) = &archive {
+        archive.finish().await?;
+    }
 
-    Ok(webstream_data)
+    write_summary(&args, &summary, &outcome);
+    match args.exit_on {
+        ExitPolicy::AnyFailure => outcome,
+        ExitPolicy::TotalFailure => match outcome {
+            Err(e) if stats::run_succeeded() > 0 => {
+                eprintln!("⚠️  {:#}", e);
+                Ok(())
+            }
+            outcome => outcome,
+        },
+        ExitPolicy::Never => {
+            if let Err(e) = outcome {
+                eprintln!("⚠️  {:#}", e);
+            }
+            Ok(())
+        }
+    }
 }
 
-async fn fetch_download_urls(
-    client: &Client,
-    hash: &str,
-    photos: &[Photo],
-) -> Result<Vec<DownloadInfo>> {
-    let url = format!("https://p153-sharedstreams.icloud.com/{}/sharedstreams/webasseturls", hash);
-    
-    // Collect photo GUIDs in batches of 25
-    let mut download_infos = Vec::new();
-    let batch_size = 25;
-    
-    let progress_bar = ProgressBar::new(photos.len() as u64);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} batches")?
-            .progress_chars("#>-"),
-    );
+/// Writes the --summary-json file, if asked for. Failing to doesn't fail the run.
+fn write_summary(args: &Args, summary: &RunSummary, outcome: &Result<()>) {
+    if let Some(path) = &args.summary_json {
+        if let Err(e) = summary.write(path, outcome) {
+            eprintln!("⚠️  Could not write the run summary: {:#}", e);
+        }
+    }
+}
 
-    for batch in photos.chunks(batch_size) {
-        let photo_guids: Vec<String> = batch.iter()
-            .map(|p| p.photo_guid.clone())
-            .collect();
+fn build_reqwest_client(args: &Args) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
 
-        let request_body = AssetUrlsRequest { photo_guids };
-
-        let response = client
-            .post(&url)
-            .header("Accept", "*/*")
-            .header("Accept-Language", "en-US,en;q=0.9")
-            .header("Content-Type", "text/plain")
-            .header("Origin", "https://www.icloud.com")
-            .header("Referer", "https://www.icloud.com/")
-            .json(&request_body)
-            .send()
-            .await
-            .context("Failed to send asset URLs request")?;
+    if let Some(path) = &args.ca_cert {
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read CA certificate: {}", path.display()))?;
+        let certificate = reqwest::Certificate::from_pem(&data)
+            .or_else(|_| reqwest::Certificate::from_der(&data))
+            .with_context(|| format!("{} is not a PEM or DER certificate", path.display()))?;
+        builder = builder.add_root_certificate(certificate);
+    }
 
-        if !response.status().is_success() {
-            return Err(anyhow!("Asset URLs request failed with status: {}", response.status()));
-        }
+    if !args.pin_cert.is_empty() {
+        let config = pinning::tls_config(&args.pin_cert, args.ca_cert.as_deref())?;
+        builder = builder.use_preconfigured_tls(config);
+    }
 
-        let assets_response: AssetUrlsResponse = response
-            .json()
-            .await
-            .context("Failed to parse asset URLs response")?;
+    if args.insecure {
+        eprintln!("⚠️  --insecure: TLS certificates are NOT being verified. Traffic can be read and modified by anyone in between.");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
 
-        // Process this batch
-        for photo in batch {
-            if let Some(download_info) = process_photo_for_download(photo, &assets_response)? {
-                download_infos.push(download_info);
-            }
-        }
+    // Binding to the unspecified address of one family makes the connector skip
+    // resolved addresses of the other family
+    match args.ip_version {
+        IpVersion::V4 => builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpVersion::V6 => builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        IpVersion::Auto => {}
+    }
 
-        progress_bar.inc(batch.len() as u64);
+    if !args.no_session {
+        builder = builder.cookie_store(true);
     }
 
-    progress_bar.finish_with_message("URL fetching complete");
-    Ok(download_infos)
+    builder.build().context("Failed to build HTTP client")
 }
 
-fn process_photo_for_download(
-    photo: &Photo,
-    assets_response: &AssetUrlsResponse,
-) -> Result<Option<DownloadInfo>> {
-    // Find the highest resolution derivative
-    let best_derivative = photo.derivatives
-        .iter()
-        .max_by_key(|(size, _)| size.parse::<u32>().unwrap_or(0));
-
-    let (_size_key, derivative) = match best_derivative {
-        Some((key, deriv)) => (key, deriv),
-        None => return Ok(None), // No derivatives found
+/// Reads newline-delimited album URLs from a file, or from stdin when `path` is `-`.
+fn read_url_list(path: &str) -> Result<Vec<String>> {
+    let contents = if path == "-" {
+        let mut buf = String::new();
+        std::io::stdin()
+            .read_to_string(&mut buf)
+            .context("Failed to read album URLs from stdin")?;
+        buf
+    } else {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read URL file: {}", path))?
     };
 
-    // Get the download URL for this checksum
-    let asset_url = match assets_response.items.get(&derivative.checksum) {
-        Some(url) => url,
-        None => return Ok(None), // No URL found for this checksum
-    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
 
-    // Construct the full download URL
-    let location = assets_response.locations
-        .get(&asset_url.url_location)
-        .ok_or_else(|| anyhow!("Location not found for: {}", asset_url.url_location))?;
+async fn download_album(
+    client: &impl HttpClient,
+    args: &Args,
+    hash: &str,
+    name_override: Option<&str>,
+    use_album_subdirectory: bool,
+    archive: Option<&TarArchive>,
+    summary: Option<&RunSummary>,
+) -> Result<()> {
+    status!("\n📱 Album hash: {}", hash);
+    let mut stats = RunStats::default();
 
-    let download_url = format!("{}://{}{}", 
-        location.scheme,
-        location.hosts.first()
-            .ok_or_else(|| anyhow!("No hosts found for location"))?,
-        asset_url.url_path
-    );
+    // Step 1: Get webstream data
+    status!("\n🔍 Fetching album metadata...");
+    let phase_start = Instant::now();
+    let (mut webstream_data, host_resolution) = fetch_webstream_timed(client, hash).await
+        .context("Failed to fetch album metadata")?;
+    stats.record_phase("Host resolution", host_resolution);
+    stats.record_phase("Metadata fetch", phase_start.elapsed().saturating_sub(host_resolution));
+
+    // --album-name wins over the server's name everywhere; `{name}` in it
+    // stands for the server's name (or the album hash if there is none)
+    let album_name = match name_override {
+        Some(template) => Some(template.replace(
+            "{name}",
+            webstream_data.stream_name.as_deref().unwrap_or(hash),
+        )),
+        None => webstream_data.stream_name.clone(),
+    };
+    let photo_count = webstream_data.photos.len();
+    let snapshot = args.snapshot.then(|| history::Snapshot::of(&webstream_data, hash));
 
-    // Extract filename from URL path
-    let filename = Path::new(&asset_url.url_path)
-        .file_name()
-        .and_then(|name| name.to_str())
-        .map(|name| {
-            // Remove query parameters
-            name.split('?').next().unwrap_or(name).to_string()
-        })
-        .unwrap_or_else(|| format!("{}.jpg", photo.photo_guid));
+    status!("📸 Album: '{}'", album_name.as_deref().map_or("Unknown Album".to_string(), |name| render_caption(name, CaptionContext::Display)));
+    status!("📊 Found {} photos", photo_count);
 
-    let size_info = format!("{}x{}", 
-        derivative.width.map_or("?".to_string(), |w| w.to_string()),
-        derivative.height.map_or("?".to_string(), |h| h.to_string())
-    );
+    if photo_count == 0 {
+        status!("✅ The album is empty, nothing to download");
+        return Ok(());
+    }
 
-    Ok(Some(DownloadInfo {
-        photo_guid: photo.photo_guid.clone(),
-        checksum: derivative.checksum.clone(),
-        download_url,
-        filename,
-        size_info,
-    }))
-}
+    let mut funnel = curate::Funnel::new(photo_count);
+    // Positions are the album's own, hidden and deleted photos included, so
+    // every shard of a split download sees the same numbering
+    if let Some(range) = &args.range {
+        let bounds = range.bounds(photo_count)?;
+        status!("✂️  Selected {} photos with --range", bounds.len());
+        webstream_data.photos.truncate(bounds.end);
+        webstream_data.photos.drain(..bounds.start);
+        funnel.record("--range", photo_count - webstream_data.photos.len());
+    }
 
-async fn download_photos(
-    client: &Client,
-    download_infos: Vec<DownloadInfo>,
-    output_dir: &str,
-    max_concurrent: usize,
-) -> Result<()> {
-    let multi_progress = MultiProgress::new();
-    let main_progress = multi_progress.add(ProgressBar::new(download_infos.len() as u64));
-    main_progress.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} photos downloaded")?
-            .progress_chars("#>-"),
-    );
+    let exclusions = curate::exclude_hidden_and_deleted(&mut webstream_data.photos, args.include_hidden, args.include_deleted);
+    if exclusions.hidden > 0 {
+        status!("🙈 Skipping {} hidden photos (use --include-hidden to download them)", exclusions.hidden);
+    }
+    if exclusions.deleted > 0 {
+        status!("🗑️  Skipping {} recently deleted photos (use --include-deleted to download them)", exclusions.deleted);
+    }
+    if (args.include_hidden || args.include_deleted) && !exclusions.markers_seen {
+        eprintln!("⚠️  This album's metadata doesn't mark any photos as hidden or deleted, so --include-hidden and --include-deleted have no effect");
+    }
+    funnel.record("hidden", exclusions.hidden);
+    funnel.record("recently deleted", exclusions.deleted);
+    if webstream_data.photos.is_empty() {
+        funnel.report_nothing_left();
+        return Ok(());
+    }
 
-    // Use semaphore to limit concurrent downloads
-    let semaphore = tokio::sync::Semaphore::new(max_concurrent);
-    
-    let download_tasks: Vec<_> = download_infos
-        .into_iter()
-        .map(|info| {
-            let client = client.clone();
-            let output_dir = output_dir.to_string();
-            let semaphore = &semaphore;
-            let main_progress = main_progress.clone();
-
-            async move {
-                let _permit = semaphore.acquire().await.unwrap();
-                
-                let result = download_single_photo(&client, &info, &output_dir).await;
-                main_progress.inc(1);
-                
-                match result {
-                    Ok(_) => Ok(info.filename),
-                    Err(e) => {
-                        eprintln!("❌ Failed to download {}: {}", info.filename, e);
-                        Err(e)
-                    }
-                }
+    let retried = match &args.retry_failed {
+        Some(path) => {
+            let failed = failures::read_failed_guids(path)?;
+            let before = webstream_data.photos.len();
+            webstream_data.photos.retain(|photo| failed.contains(&photo.photo_guid));
+            funnel.record("not in --retry-failed", before - webstream_data.photos.len());
+            status!("🔁 Retrying {} previously failed photos from {}", webstream_data.photos.len(), path.display());
+            let gone = failed.len() - webstream_data.photos.len();
+            if gone > 0 {
+                status!("   ({} of them are no longer in the album)", gone);
             }
-        })
-        .collect();
+            if webstream_data.photos.is_empty() {
+                status!("✅ Nothing to retry");
+                return Ok(());
+            }
+            Some(webstream_data.photos.len())
+        }
+        None => None,
+    };
 
-    let results = join_all(download_tasks).await;
-    main_progress.finish_with_message("All downloads complete");
+    if args.cover_only {
+        let before = webstream_data.photos.len();
+        match curate::keep_cover(&mut webstream_data.photos, &webstream_data.extra, !args.no_cover_fallback)? {
+            curate::CoverSource::Designated => status!("🖼️  Selected the album's cover photo"),
+            curate::CoverSource::FirstPhoto => {
+                status!("🖼️  The album doesn't name a cover photo; selected its first photo")
+            }
+        }
+        funnel.record("--cover-only", before - webstream_data.photos.len());
+    }
 
-    // Count successes and failures
-    let mut success_count = 0;
-    let mut failure_count = 0;
+    if let Some(strategy) = args.select {
+        let before = webstream_data.photos.len();
+        let undated = curate::select_photos(&mut webstream_data.photos, strategy, args.timezone);
+        status!("🎯 Selected {} of {} photos with --select {}", webstream_data.photos.len(), before, strategy);
+        if undated > 0 && !matches!(strategy, curate::SelectStrategy::Largest(_)) {
+            status!("   ({} without a capture date were all kept)", undated);
+        }
+        funnel.record("--select", before - webstream_data.photos.len());
+    }
+    if webstream_data.photos.is_empty() {
+        funnel.report_nothing_left();
+        return Ok(());
+    }
 
-    for result in results {
-        match result {
-            Ok(_) => success_count += 1,
-            Err(_) => failure_count += 1,
+    if args.interactive {
+        let Some(chosen) = interactive::choose(&webstream_data.photos, args.timezone)? else {
+            status!("✅ Selection cancelled, nothing downloaded");
+            return Ok(());
+        };
+        let before = webstream_data.photos.len();
+        webstream_data.photos.retain(|photo| chosen.contains(&photo.photo_guid));
+        status!("☑️  Selected {} of {} photos", webstream_data.photos.len(), before);
+        if webstream_data.photos.is_empty() {
+            status!("✅ No photos selected");
+            return Ok(());
         }
     }
+    let photos = &webstream_data.photos;
 
-    println!("📊 Results: {} succeeded, {} failed", success_count, failure_count);
+    let selection = match &args.derivatives {
+        Some(names) => DerivativeSelection::Named(names.clone()),
+        None => DerivativeSelection::Best,
+    };
 
-    if failure_count > 0 {
-        return Err(anyhow!("{} downloads failed", failure_count));
+    if let Some(format) = args.list_derivatives {
+        return renditions::print(photos, &selection, !args.flatten_live_photos, format);
     }
 
-    Ok(())
-}
+    if !args.album_metadata_only_refresh {
+        let estimate = estimate_download_size(photos, &selection, !args.flatten_live_photos);
+        print_size_estimate(&estimate);
+    }
 
-async fn download_single_photo(
-    client: &Client,
-    info: &DownloadInfo,
-    output_dir: &str,
-) -> Result<()> {
-    let response = client
-        .get(&info.download_url)
-        .header("Accept", "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8")
-        .header("Accept-Language", "en-US,en;q=0.9")
-        .header("Referer", "https://www.icloud.com/")
-        .header("Sec-Fetch-Dest", "image")
-        .send()
-        .await
-        .context("Failed to start download")?;
+    if args.dry_run {
+        status!("\n🧪 Dry run: nothing was downloaded");
+        return Ok(());
+    }
 
-    if !response.status().is_success() {
-        return Err(anyhow!("Download failed with status: {}", response.status()));
+    let album_directory = use_album_subdirectory
+        .then(|| album_directory_name(album_name.as_deref(), hash));
+    let output_dir = match &album_directory {
+        Some(name) => Path::new(&args.output).join(name),
+        None => PathBuf::from(&args.output),
+    };
+    let output_dir = output_dir.to_string_lossy().into_owned();
+
+    if args.album_metadata_only_refresh {
+        let export = ManifestExport::new(args, hash, album_name.as_deref());
+        let manifest = Manifest::open(workdir::tool_dir(&output_dir), export)?;
+        let (changed, missing) = manifest.refresh_metadata(&webstream_data.photos)?;
+        status!("📝 Updated the metadata of {} files in the manifest", changed.len());
+        if missing > 0 {
+            status!("   {} files belong to photos that are no longer in the album", missing);
+        }
+        if args.xmp_sidecars {
+            let permissions = OutputPermissions::from_args(args);
+            xmp::refresh_sidecars(&changed, Path::new(&output_dir), &permissions).await.report();
+        }
+        return Ok(());
     }
 
-    let content = response
-        .bytes()
-        .await
-        .context("Failed to read response bytes")?;
+    if archive.is_none() {
+        workdir::confirm_output_dir(&output_dir, args.yes, !args.json_lines_input)?;
+    }
 
-    let file_path = Path::new(output_dir).join(&info.filename);
-    let mut file = File::create(&file_path)
-        .await
-        .context("Failed to create output file")?;
+    // With --tar the files go into the archive, and only a failures file
+    // (if anything fails) lands in the output directory. An album named with
+    // --album-name gets its directory in the archive even when it's the only one
+    let archive_directory = album_directory
+        .clone()
+        .or_else(|| name_override.map(|_| album_directory_name(album_name.as_deref(), hash)));
+    let archive = archive.map(|archive| archive.with_prefix(archive_directory.as_deref().unwrap_or_default()));
+    if archive.is_none() {
+        safepath::check_output_dir(Path::new(&output_dir))?;
+        let permissions = OutputPermissions::from_args(args);
+        permissions
+            .create_dir_all(Path::new(&output_dir))
+            .await
+            .context("Failed to create output directory")?;
+        if args.write_nomedia && workdir::write_nomedia(&output_dir, &permissions).await? {
+            status!("🙈 Added {} so Android galleries skip {}", workdir::NOMEDIA_FILE_NAME, output_dir);
+        }
+    } else if args.write_nomedia {
+        status!("   (--write-nomedia is skipped with --tar: there's no directory to put it in)");
+    }
 
-    file.write_all(&content)
-        .await
-        .context("Failed to write file")?;
+    if let Some(snapshot) = &snapshot {
+        match snapshot.append(&workdir::tool_dir(&output_dir).join(history::HISTORY_FILE_NAME)) {
+            Ok(history::Appended::New) => status!("🗂️  Recorded a snapshot of the album's {} photos", photo_count),
+            Ok(history::Appended::Unchanged(since)) => {
+                status!("🗂️  The album is unchanged since the snapshot of {}", since)
+            }
+            Err(e) => eprintln!("⚠️  Could not record the album snapshot: {:#}", e),
+        }
+    }
 
-    file.sync_all()
-        .await
-        .context("Failed to sync file")?;
+    let failure_log = FailureLog::create(workdir::tool_dir(&output_dir).join(failures::FAILURES_FILE_NAME))?;
+    let manifest = match archive {
+        Some(_) => None,
+        None => {
+            let export = ManifestExport::new(args, hash, album_name.as_deref());
+            Some(Manifest::open(workdir::tool_dir(&output_dir), export)?)
+        }
+    };
+    let outcome_table = args.summary_table.map(OutcomeTable::new);
 
-    Ok(())
+    // Step 2: Get download URLs in batches
+    status!("\n🔗 Fetching download URLs...");
+    let phase_start = Instant::now();
+    let mut recovered = recovery::recover_missing_derivatives(
+        client,
+        hash,
+        &mut webstream_data.photos,
+        &failure_log,
+    ).await;
+    let photos = &webstream_data.photos;
+
+    let mut screening = pipeline::Screening::new(args, photos, outcome_table.as_ref())?;
+    let mut existing_files = pipeline::ExistingFiles::new(args, &output_dir, archive.is_some(), manifest.as_ref(), outcome_table.as_ref());
+    let date_naming = DateNaming {
+        format: args.date_format.clone(),
+        timezone: args.timezone,
+        prefix: args.date_prefix,
+        burst_index: args.burst_index,
+        folders: args.folder_by_date,
+        undated_folder: args.undated_folder.clone(),
+    };
+    let smart_names = args.smart_names.then(|| SmartNames::new(photos));
+    let batch_folders = args.group_by_batch.then(|| BatchFolders::new(photos));
+    let mut name_conflicts = NameConflicts::new(args.on_conflict, args.xmp_sidecars, manifest.as_ref(), outcome_table.as_ref())?;
+    if let Some(batch_folders) = &batch_folders {
+        batch_folders.report(photos.len());
+    }
+    let expiry_margin = chrono::Duration::minutes(args.expiry_margin);
+    let refresher = args.refresh_expiring_urls.then(|| {
+        expiry::UrlRefresher::new(client, hash, photos, &selection, expiry_margin)
+    });
+
+    // Large albums download while later URLs are still being fetched,
+    // unless something needs the whole list first
+    let streaming = pipeline::can_stream(args);
+    let mut download_infos = Vec::new();
+    let mut listing = DirListing::default();
+    if !streaming {
+        let damaged_photos;
+        let mut url_photos = photos.as_slice();
+        if let (true, Some(manifest)) = (args.repair, &manifest) {
+            let intact = repair::intact_photos(
+                photos,
+                &selection,
+                !args.flatten_live_photos,
+                &manifest.entries()?,
+                &mut listing,
+                &output_dir,
+                !args.strip_metadata,
+            );
+            if intact.len() == photos.len() {
+                status!("✅ All {} photos are present and complete, nothing to repair", photos.len());
+                return Ok(());
+            }
+            status!("🔧 {} of {} photos are intact according to the manifest", intact.len(), photos.len());
+            damaged_photos = photos.iter().filter(|photo| !intact.contains(&photo.photo_guid)).cloned().collect::<Vec<_>>();
+            url_photos = &damaged_photos;
+        }
+        download_infos = fetch_download_urls(client, hash, url_photos, &selection).await
+            .context("Failed to fetch download URLs")?;
+        download_infos.append(&mut recovered);
+        stats.record_phase("URL fetch", phase_start.elapsed());
+
+        if args.naming == NamingSource::ContentDisposition {
+            let phase_start = Instant::now();
+            status!("\n📛 Reading file names from the downloads' Content-Disposition...");
+            disposition::apply(client, &mut download_infos, args.concurrent).await.report();
+            stats.record_phase("Content-Disposition names", phase_start.elapsed());
+        }
+
+        if args.prefetch_sizes {
+            let phase_start = Instant::now();
+            prefetch_sizes(client, &mut download_infos, args.concurrent).await;
+            stats.record_phase("Size prefetch", phase_start.elapsed());
+        }
+
+        download_infos.retain(|info| screening.admit(info));
+        screening.report();
+        if args.since_manifest.is_some() && download_infos.is_empty() {
+            status!("✅ Nothing new since that manifest");
+            return Ok(());
+        }
+        if screening.left_nothing() {
+            status!("🚫 None of the files of the {} photos passed the filters above, nothing to download", photos.len());
+            return Ok(());
+        }
+
+        pipeline::name_downloads(&date_naming, smart_names.as_ref(), batch_folders.as_ref(), &mut download_infos, photos);
+        download_infos = name_conflicts.resolve(download_infos)?;
+        name_conflicts.report();
+
+        if matches!(selection, DerivativeSelection::Named(_)) {
+            status!("🎯 Prepared {} downloads for {} photos", download_infos.len(), photos.len());
+        } else {
+            status!("🎯 Prepared {} downloads", download_infos.len());
+        }
+
+        if args.repair {
+            let total = download_infos.len();
+            let damaged = repair::find_damaged(download_infos, &mut listing, &output_dir, !args.strip_metadata);
+            if damaged.is_empty() {
+                status!("✅ All {} files are present and complete, nothing to repair", total);
+                return Ok(());
+            }
+
+            status!("\n🔧 {} of {} files need repair:", damaged.len(), total);
+            for (info, reason) in &damaged {
+                status!("   {} - {}", info.filename, reason);
+            }
+            download_infos = damaged.into_iter().map(|(info, _)| info).collect();
+        } else if let Some(existing_files) = &mut existing_files {
+            existing_files.overwrite(name_conflicts.take_overwrites());
+            download_infos = existing_files.filter(download_infos).await;
+            existing_files.report();
+            if download_infos.is_empty() {
+                status!("✅ Everything is already downloaded");
+                return Ok(());
+            }
+        }
+
+        args.order.apply(&mut download_infos);
+        if let Some(cap) = args.max_total_size {
+            pipeline::apply_total_size_cap(&mut download_infos, cap, outcome_table.as_ref());
+            if download_infos.is_empty() {
+                status!("✅ Nothing fits in --max-total-size {}", size::format_size(cap));
+                return Ok(());
+            }
+        }
+
+        if let Some(risk) = expiry::check_url_expiry(&download_infos, args.concurrent, expiry_margin) {
+            expiry::print_expiry_warning(&risk, args.refresh_expiring_urls);
+        }
+
+        if let Some(comparison) = args.compare_hosts {
+            let phase_start = Instant::now();
+            if let Some(fastest) = hosts::compare_hosts(client, &download_infos).await? {
+                if comparison == hosts::HostComparison::Pin {
+                    let pinned = hosts::pin_host(&mut download_infos, &fastest);
+                    status!("📌 Downloading {} files from {}", pinned, fastest);
+                } else {
+                    status!("🏆 Fastest host: {} (use --compare-hosts pin to download from it)", fastest);
+                }
+            }
+            stats.record_phase("Host comparison", phase_start.elapsed());
+        }
+
+        if args.head_check {
+            let phase_start = Instant::now();
+            run_head_check(client, &mut download_infos, args, refresher.as_ref()).await?;
+            stats.record_phase("HEAD check", phase_start.elapsed());
+        }
+    }
+
+    // Step 3: Download photos
+    if streaming {
+        status!("\n⬇️  Downloading photos as their URLs arrive...");
+    } else {
+        status!("\n⬇️  Downloading photos...");
+    }
+    let options = DownloadOptions { archive, ..DownloadOptions::from_args(args) };
+    let phase_start = Instant::now();
+    let aria2 = args.failures_aria2.clone().map(Aria2Export::new);
+    let progress = args.progress_file.clone().map(|path| {
+        let bytes_total = download_infos.iter().map(|info| info.file_size).sum::<Option<u64>>();
+        ProgressFile::new(path, hash, download_infos.len(), bytes_total)
+    });
+    let hashes = if args.checksum_manifest {
+        Some(HashPipeline::start(workdir::tool_dir(&output_dir).join(hashing::CHECKSUMS_FILE_NAME))?)
+    } else {
+        None
+    };
+    let transcoder = Transcoder::from_args(args).await?;
+    let reporting = DownloadReporting {
+        failure_log: &failure_log,
+        manifest: manifest.as_ref(),
+        hashes: hashes.as_ref(),
+        transcoder: transcoder.as_ref(),
+        outcome_table: outcome_table.as_ref(),
+        aria2: aria2.as_ref(),
+        progress: progress.as_ref(),
+        stats: &stats,
+    };
+    let (fetched, result) = if streaming {
+        let (sender, receiver) = tokio::sync::mpsc::channel(pipeline::QUEUE_CAPACITY);
+        let directories = options.archive.is_none().then_some((output_dir.as_str(), &options.permissions));
+        let prepare = |mut infos: Vec<DownloadInfo>| {
+            infos.retain(|info| screening.admit(info));
+            pipeline::name_downloads(&date_naming, smart_names.as_ref(), batch_folders.as_ref(), &mut infos, photos);
+            name_conflicts.resolve(infos)
+        };
+        let fetching = async {
+            let mut queue = pipeline::Queue::new(sender, directories, existing_files);
+            let fetched = pipeline::fetch_into(&mut queue, client, hash, photos, &selection, recovered, prepare).await;
+            (fetched, queue.close())
+        };
+        let downloading = download_photos(
+            client,
+            DownloadSource::Streamed(receiver),
+            &output_dir,
+            &options,
+            refresher.as_ref(),
+            &reporting,
+        );
+        let ((fetched, (queued, existing_files)), result) = tokio::join!(fetching, downloading);
+
+        screening.report();
+        if screening.left_nothing() {
+            status!("🚫 None of the files of the {} photos passed the filters above", photos.len());
+        }
+        name_conflicts.report();
+        if let Some(existing_files) = &existing_files {
+            existing_files.report();
+        }
+        status!("🎯 Queued {} downloads", queued);
+        (fetched.context("Failed to fetch download URLs"), result)
+    } else {
+        let source = DownloadSource::Listed(download_infos);
+        (Ok(()), download_photos(client, source, &output_dir, &options, refresher.as_ref(), &reporting).await)
+    };
+    stats.record_phase("Download", phase_start.elapsed());
+
+    if let Some(aria2) = &aria2 {
+        match aria2.write(client, hash, photos, &selection, &output_dir).await {
+            Ok(0) => {}
+            Ok(written) => status!("📝 {} failed downloads written to {} for aria2c", written, aria2.path().display()),
+            Err(e) => eprintln!("⚠️  Could not write the aria2 input file: {:#}", e),
+        }
+    }
+
+    if let Some(Err(e)) = manifest.as_ref().map(Manifest::compact) {
+        eprintln!("⚠️  Could not update the manifest: {:#}", e);
+    }
+    if let (true, Some(manifest)) = (args.output_index_html_per_run, &manifest) {
+        let title = album_name.as_deref().unwrap_or("iCloud Photos");
+        match gallery::update(&output_dir, manifest, title) {
+            Ok((shown, new)) => status!("🖼️  Gallery {} shows {} files ({} new)", gallery::GALLERY_NAME, shown, new),
+            Err(e) => eprintln!("⚠️  Could not update the gallery: {:#}", e),
+        }
+    }
+
+    if let Some(transcoder) = transcoder {
+        let phase_start = Instant::now();
+        let (reencoded, failed) = transcoder.finish().await;
+        if reencoded + failed > 0 {
+            status!("🎞️  Re-encoded {} videos{}", reencoded, if failed > 0 { format!(", {} failed", failed) } else { String::new() });
+        }
+        stats.record_phase("Re-encoding (after downloads)", phase_start.elapsed());
+    }
+
+    if let Some(hashes) = hashes {
+        let phase_start = Instant::now();
+        match hashes.finish().await {
+            Ok(path) => status!("🔐 Checksums written to {}", path.display()),
+            Err(e) => eprintln!("⚠️  Could not write checksums: {:#}", e),
+        }
+        stats.record_phase("Hashing (after downloads)", phase_start.elapsed());
+    }
+
+    if let (Some(path), Some(retried)) = (&args.retry_failed, retried) {
+        let still_failing = failures::read_failed_guids(failure_log.path())?.len();
+        status!("🔁 {} of {} previously failed photos downloaded this time", retried.saturating_sub(still_failing), retried);
+        if !same_file(path, failure_log.path()) {
+            if let Err(e) = failures::rewrite_from(path, &failure_log) {
+                eprintln!("⚠️  Could not update {}: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    if let Some(table) = &outcome_table {
+        table.print();
+    }
+
+    if args.stats {
+        stats.print();
+    }
+    if let Some(path) = &args.stats_json {
+        if let Err(e) = stats.write_json(path, hash) {
+            eprintln!("⚠️  Could not write stats: {:#}", e);
+        }
+    }
+    if let Some(summary) = summary {
+        summary.record_album(hash, album_name.as_deref(), stats.results(), failure_log.path());
+    }
+    fetched?;
+    result.context("Failed to download photos")?;
+
+    status!("\n✅ Download complete! Photos saved to: {}", output_dir);
+    Ok(())
+}
+
+/// Notes a file left out of the download in the summary table and the run totals.
+fn record_skip(outcome_table: Option<&OutcomeTable>, info: &DownloadInfo, reason: &str) {
+    if let Some(table) = outcome_table {
+        table.record_skipped(info, reason);
+    }
+    stats::count_skipped();
+}
+
+/// The --head-check pass: reports bad URLs, swaps in fresh ones when a
+/// refresher is available, and aborts under --strict-head-check.
+async fn run_head_check<C: HttpClient>(
+    client: &C,
+    download_infos: &mut [DownloadInfo],
+    args: &Args,
+    refresher: Option<&expiry::UrlRefresher<'_, C>>,
+) -> Result<()> {
+    status!("\n🩺 Checking {} download URLs...", download_infos.len());
+    let problems = headcheck::check_urls(client, download_infos, args.concurrent).await;
+    if problems.is_empty() {
+        status!("✅ All download URLs look good");
+        return Ok(());
+    }
+
+    status!("⚠️  {} download URLs have problems:", problems.len());
+    for (i, problem) in &problems {
+        status!("   {} - {}", download_infos[*i].filename, problem);
+    }
+
+    if let Some(refresher) = refresher {
+        let mut refreshed = 0;
+        for (i, _) in &problems {
+            match refresher.refresh(&download_infos[*i]).await {
+                Ok(fresh) => {
+                    download_infos[*i] = fresh;
+                    refreshed += 1;
+                }
+                Err(e) => eprintln!("⚠️  Could not refresh URL for {}: {}", download_infos[*i].filename, e),
+            }
+        }
+        status!("🔄 Re-fetched {} of {} URLs", refreshed, problems.len());
+    } else if args.strict_head_check {
+        return Err(anyhow!("{} download URLs failed the HEAD check (--strict-head-check)", problems.len()));
+    }
+
+    Ok(())
+}
+
+/// Most size corrections listed one by one.
+const MAX_LISTED_CORRECTIONS: usize = 10;
+
+async fn prefetch_sizes(client: &impl HttpClient, download_infos: &mut [DownloadInfo], concurrency: usize) {
+    status!("\n📐 Fetching the size of {} files...", download_infos.len());
+    let prefetched = headcheck::prefetch_sizes(client, download_infos, concurrency).await;
+
+    if prefetched.filled > 0 {
+        status!("   Found the size of {} files the album doesn't list one for", prefetched.filled);
+    }
+    if !prefetched.corrected.is_empty() {
+        status!("   ⚠️  {} files have a different size than the album lists; using the server's:", prefetched.corrected.len());
+        for (i, mismatch) in prefetched.corrected.iter().take(MAX_LISTED_CORRECTIONS) {
+            status!("   {} - {}", download_infos[*i].filename, mismatch);
+        }
+        if prefetched.corrected.len() > MAX_LISTED_CORRECTIONS {
+            status!("   ... and {} more", prefetched.corrected.len() - MAX_LISTED_CORRECTIONS);
+        }
+    }
+    if prefetched.unknown > 0 {
+        status!("   ⚠️  Could not get the size of {} files from the server", prefetched.unknown);
+    }
+
+    let unknown = download_infos.iter().filter(|info| info.file_size.is_none()).count();
+    let total: u64 = download_infos.iter().filter_map(|info| info.file_size).sum();
+    match unknown {
+        0 => status!("💾 Download size: {}", size::format_size(total)),
+        _ => status!("💾 Download size: {} ({} files of unknown size not included)", size::format_size(total), unknown),
+    }
+}
+
+/// Whether two paths name the same file, which may not exist (yet).
+fn same_file(a: &Path, b: &Path) -> bool {
+    let resolve = |path: &Path| {
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        std::fs::canonicalize(parent).ok().map(|parent| parent.join(path.file_name().unwrap_or_default()))
+    };
+    matches!((resolve(a), resolve(b)), (Some(a), Some(b)) if a == b)
+}
+
+/// Name of the per-album subdirectory used when downloading several albums.
+fn album_directory_name(stream_name: Option<&str>, hash: &str) -> String {
+    let name = stream_name
+        .map(|name| render_caption(name, CaptionContext::Filename { max_bytes: MAX_FILENAME_BYTES }))
+        .unwrap_or_default();
+
+    if name.is_empty() {
+        hash.to_string()
+    } else {
+        name
+    }
+}
+
+/// Link formats that identify a shared album, tried in order. Every one of
+/// them carries the same album token and is served by the same
+/// sharedstreams API, so only the token is kept.
+const ALBUM_URL_PATTERNS: &[&str] = &[
+    // Current links, optionally with a locale segment: icloud.com/sharedalbum/en-gb/#B2T5oqs3q2VPkhS
+    // (or icloud.com.cn in mainland China, whose albums need a --host-override)
+    r"(?i)icloud\.com(?:\.cn)?/sharedalbum/(?:[a-z]{2}(?:-[a-z]{2})?/)?#([A-Za-z0-9]+)",
+    // Shared Photo Streams from iOS 6, before they were renamed to shared albums: icloud.com/photostream/#A2GqDbcx0fMNZ
+    r"(?i)icloud\.com(?:\.cn)?/photostream/(?:[a-z]{2}(?:-[a-z]{2})?/)?#([A-Za-z0-9]+)",
+    // The API endpoint itself, as copied from browser dev tools: p153-sharedstreams.icloud.com/B2T5oqs3q2VPkhS/sharedstreams/webstream
+    r"(?i)sharedstreams\.icloud\.com/([A-Za-z0-9]+)/sharedstreams",
+    // Just the token. Tokens start with A or B, which says how the rest encodes the server partition
+    r"^\s*([AB][A-Za-z0-9]{12,19})\s*$",
+];
+
+/// share.icloud.com short links redirect to a full album link.
+const SHORT_LINK_PATTERN: &str = r"(?i)^\s*(?:https?://)?share\.icloud\.com/";
+
+fn extract_hash_from_url(url: &str) -> Result<String> {
+    for pattern in ALBUM_URL_PATTERNS {
+        let re = Regex::new(pattern).context("Failed to compile regex")?;
+        if let Some(hash) = re.captures(url).and_then(|captures| captures.get(1)) {
+            return Ok(hash.as_str().to_string());
+        }
+    }
+
+    // MobileMe galleries predate iCloud shared albums and went away with MobileMe
+    if Regex::new(r"(?i)(?:^|[/.])me\.com/").context("Failed to compile regex")?.is_match(url) {
+        return Err(anyhow!(
+            "This is a MobileMe link; MobileMe galleries were shut down in 2012 and can't be downloaded"
+        ));
+    }
+
+    Err(anyhow!("Invalid iCloud shared album URL format"))
+}
+
+/// Like `extract_hash_from_url`, but first follows share.icloud.com short
+/// links to the album link they stand for.
+async fn resolve_album_hash(client: &impl HttpClient, url: &str) -> Result<String> {
+    if !Regex::new(SHORT_LINK_PATTERN).context("Failed to compile regex")?.is_match(url) {
+        return extract_hash_from_url(url);
+    }
+
+    let url = url.trim();
+    let full_url = if url.contains("://") { url.to_string() } else { format!("https://{}", url) };
+    let response = client
+        .get(&full_url, RequestKind::Api)
+        .await
+        .context("Failed to resolve short link")?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Short link returned status: {}{}", response.status(), response.apple_trace()));
+    }
+    if let Ok(hash) = extract_hash_from_url(response.url()) {
+        return Ok(hash);
+    }
+
+    // Some short links land on a page that forwards with JavaScript instead of a redirect
+    let page = response.text().await.context("Failed to read short link page")?;
+    extract_hash_from_url(&page).map_err(|_| anyhow!("The short link doesn't lead to a shared album"))
+}
+
+async fn fetch_webstream(client: &impl HttpClient, hash: &str) -> Result<WebstreamResponse> {
+    fetch_webstream_timed(client, hash).await.map(|(webstream, _)| webstream)
+}
+
+/// `fetch_webstream`, also returning how long it took to find the album's
+/// host: the time spent on a request the wrong partition answered, or zero.
+async fn fetch_webstream_timed(client: &impl HttpClient, hash: &str) -> Result<(WebstreamResponse, Duration)> {
+    let started = Instant::now();
+    let result = request_webstream(client, hash).await;
+    // A 330 names the partition that holds the album; go there instead
+    let redirect = match &result {
+        Err(e) => match e.downcast_ref::<AlbumError>() {
+            Some(AlbumError::WrongHost { host: Some(host), .. }) => Some(host.clone()),
+            _ => None,
+        },
+        Ok(_) => None,
+    };
+    match redirect {
+        Some(host) if sharedstreams::follow_redirect(hash, &host) => {
+            let host_resolution = started.elapsed();
+            status!("↪️  The album is served by {}, continuing there", host);
+            Ok((request_webstream(client, hash).await?, host_resolution))
+        }
+        _ => result.map(|webstream| (webstream, Duration::ZERO)),
+    }
+}
+
+async fn request_webstream(client: &impl HttpClient, hash: &str) -> Result<WebstreamResponse> {
+    let host = sharedstreams::host_for(hash);
+    let url = sharedstreams::api_url(hash, "webstream");
+    
+    let request_body = WebstreamRequest {
+        stream_ctag: None,
+    };
+
+    let response = client
+        .post_json(&url, RequestKind::Api, &request_body)
+        .await
+        .context("Failed to send webstream request")?;
+    clock::observe_server_date(response.headers());
+
+    let status = response.status();
+    if !status.is_success() {
+        let trace = response.apple_trace();
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        if let Some(err) = AlbumError::from_webstream_response(&host, status, &headers, &body, trace.clone()) {
+            return Err(err.into());
+        }
+        return Err(anyhow!("Webstream request failed with status: {}{}", status, trace));
+    }
+
+    // Parsed as it arrives; for big albums the body runs to many megabytes
+    let webstream_data: WebstreamResponse = response
+        .json_streamed()
+        .await
+        .context("Failed to parse webstream response")?;
+
+    Ok(webstream_data)
+}
+
+async fn fetch_download_urls(
+    client: &impl HttpClient,
+    hash: &str,
+    photos: &[Photo],
+    selection: &DerivativeSelection,
+) -> Result<Vec<DownloadInfo>> {
+    // Collect photo GUIDs in batches
+    let mut download_infos = Vec::new();
+    
+    let progress = Progress::new(photos.len() as u64, "batches")?;
+
+    for batch in photos.chunks(URL_BATCH_SIZE) {
+        download_infos.extend(fetch_asset_urls_batch(client, hash, batch, selection).await?);
+        progress.inc(batch.len() as u64);
+    }
+
+    progress.finish();
+    Ok(download_infos)
+}
+
+/// Requests signed download URLs for a single batch of photos.
+async fn fetch_asset_urls_batch(
+    client: &impl HttpClient,
+    hash: &str,
+    batch: &[Photo],
+    selection: &DerivativeSelection,
+) -> Result<Vec<DownloadInfo>> {
+    let photo_guids: Vec<String> = batch.iter()
+        .map(|p| p.photo_guid.clone())
+        .collect();
+
+    let mut attempt = 1;
+    let assets_response = loop {
+        let response = request_asset_urls(client, hash, photo_guids.clone()).await?;
+        if !response.is_empty() {
+            break response;
+        }
+        if attempt == EMPTY_BATCH_ATTEMPTS {
+            // One bad batch shouldn't cost the rest of the album
+            eprintln!(
+                "⚠️  webasseturls returned no download URLs for a batch of {} photos after {} attempts; skipping them (run again to retry)",
+                batch.len(),
+                attempt
+            );
+            batch.iter().for_each(|_| stats::count_skipped());
+            return Ok(Vec::new());
+        }
+        eprintln!("⚠️  Empty download URL response for a batch of {} photos, retrying...", batch.len());
+        stats::count_retry();
+        tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+        attempt += 1;
+    };
+
+    // From here a missing checksum only affects that one photo
+    let mut download_infos = Vec::new();
+    for photo in batch {
+        download_infos.extend(process_photo_for_download(photo, &assets_response, selection)?);
+    }
+
+    Ok(download_infos)
+}
+
+async fn request_asset_urls(
+    client: &impl HttpClient,
+    hash: &str,
+    photo_guids: Vec<String>,
+) -> Result<AssetUrlsResponse> {
+    let url = sharedstreams::api_url(hash, "webasseturls");
+
+    let request_body = AssetUrlsRequest { photo_guids };
+
+    let response = client
+        .post_json(&url, RequestKind::Api, &request_body)
+        .await
+        .context("Failed to send asset URLs request")?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Asset URLs request failed with status: {}{}", response.status(), response.apple_trace()));
+    }
+
+    response
+        .json()
+        .await
+        .context("Failed to parse asset URLs response")
+}
+
+/// Sort key for derivative size keys: numeric keys like `2049` are pixel
+/// sizes, video keys like `720p` are vertical resolutions, anything else
+/// (e.g. `PosterFrame`) ranks lowest.
+fn derivative_rank(key: &str) -> u32 {
+    key.strip_suffix('p')
+        .unwrap_or(key)
+        .parse::<u32>()
+        .unwrap_or(0)
+}
+
+/// A photo's image (or video) renditions from smallest to largest. iCloud
+/// sometimes lists one asset under several keys; those share a checksum and
+/// count once here, under their highest-ranked key. Ties in rank are broken
+/// by key, so the choice doesn't depend on the map's order.
+fn distinct_renditions(photo: &Photo, video: bool) -> Vec<(&String, &Derivative)> {
+    let mut renditions: Vec<(&String, &Derivative)> = photo.derivatives
+        .iter()
+        .filter(|(key, deriv)| deriv.is_video(key) == video)
+        .collect();
+    renditions.sort_by(|(a, _), (b, _)| (derivative_rank(a), a).cmp(&(derivative_rank(b), b)));
+    let mut distinct: Vec<(&String, &Derivative)> = Vec::with_capacity(renditions.len());
+    for (key, derivative) in renditions.into_iter().rev() {
+        if distinct.iter().all(|(_, d)| d.checksum != derivative.checksum) {
+            distinct.push((key, derivative));
+        }
+    }
+    distinct.reverse();
+    distinct
+}
+
+/// Picks the derivative that will be downloaded for a photo.
+fn select_derivative(photo: &Photo) -> Option<(&String, &Derivative)> {
+    // Highest resolution wins. For videos that means the best video rendition,
+    // for stills (including Live Photos) the best image.
+    distinct_renditions(photo, photo.is_video()).pop()
+}
+
+/// The motion half of a Live Photo: a still whose derivatives also include a video.
+fn live_photo_motion(photo: &Photo) -> Option<(&String, &Derivative)> {
+    if photo.is_video() {
+        return None;
+    }
+    let stills = distinct_renditions(photo, false);
+    distinct_renditions(photo, true)
+        .pop()
+        // The same asset listed under a video key isn't a second file
+        .filter(|(_, motion)| stills.iter().all(|(_, still)| still.checksum != motion.checksum))
+}
+
+/// Which derivatives of each photo get downloaded.
+#[derive(Clone, Debug)]
+enum DerivativeSelection {
+    /// Just the highest-resolution one
+    Best,
+    /// Each named derivative, saved with the name as a suffix (`IMG_1234_thumb.jpg`).
+    /// Names are `full`, `medium`, `thumb` or a raw derivative key like `342`.
+    Named(Vec<String>),
+}
+
+/// The names --derivatives knows besides raw keys.
+const DERIVATIVE_NAMES: [&str; 3] = ["full", "medium", "thumb"];
+
+/// Accepts a --derivatives name or a raw key, which is a number (`342`) or,
+/// for videos, a number of lines (`720p`).
+fn parse_derivative_name(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let digits = value.strip_suffix('p').unwrap_or(value);
+    let raw_key = !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit());
+    if DERIVATIVE_NAMES.contains(&value) || raw_key {
+        return Ok(value.to_string());
+    }
+    Err(format!(
+        "'{}' is not a derivative; use {} or a derivative key like 342 or 720p (see --list-derivatives)",
+        value,
+        DERIVATIVE_NAMES.join(", ")
+    ))
+}
+
+/// The derivatives to download for `photo`, each with its filename suffix.
+fn selected_derivatives<'a>(
+    photo: &'a Photo,
+    selection: &'a DerivativeSelection,
+) -> Vec<(Option<&'a str>, &'a Derivative)> {
+    match selection {
+        DerivativeSelection::Best => select_derivative(photo)
+            .map(|(_, derivative)| vec![(None, derivative)])
+            .unwrap_or_default(),
+        DerivativeSelection::Named(names) => {
+            let mut selected: Vec<(Option<&str>, &Derivative)> = Vec::new();
+            for name in names {
+                let Some(derivative) = named_derivative(photo, name) else {
+                    continue;
+                };
+                // Two names can resolve to the same rendition on small photos
+                if selected.iter().all(|(_, d)| d.checksum != derivative.checksum) {
+                    selected.push((Some(name.as_str()), derivative));
+                }
+            }
+            selected
+        }
+    }
+}
+
+fn named_derivative<'a>(photo: &'a Photo, name: &str) -> Option<&'a Derivative> {
+    let candidates = distinct_renditions(photo, photo.is_video());
+    match name {
+        "full" => candidates.last().map(|(_, d)| *d),
+        "medium" => candidates.get(candidates.len() / 2).map(|(_, d)| *d),
+        "thumb" => candidates.first().map(|(_, d)| *d),
+        key => photo.derivatives.get(key),
+    }
+}
+
+#[derive(Default)]
+struct SizeEstimate {
+    known_bytes: u64,
+    photos: usize,
+    videos: usize,
+    live_motion: usize,
+    unknown_size: usize,
+}
+
+fn estimate_download_size(
+    photos: &[Photo],
+    selection: &DerivativeSelection,
+    include_live_motion: bool,
+) -> SizeEstimate {
+    let mut estimate = SizeEstimate::default();
+
+    for photo in photos {
+        let mut derivatives: Vec<&Derivative> = selected_derivatives(photo, selection)
+            .into_iter()
+            .map(|(_, derivative)| derivative)
+            .collect();
+        if derivatives.is_empty() {
+            continue;
+        }
+
+        if photo.is_video() {
+            estimate.videos += 1;
+        } else {
+            estimate.photos += 1;
+        }
+
+        if include_live_motion {
+            if let Some((_, motion)) = live_photo_motion(photo) {
+                estimate.live_motion += 1;
+                derivatives.push(motion);
+            }
+        }
+
+        for derivative in derivatives {
+            match derivative.file_size_bytes() {
+                Some(bytes) => estimate.known_bytes += bytes,
+                None => estimate.unknown_size += 1,
+            }
+        }
+    }
+
+    estimate
+}
+
+fn print_size_estimate(estimate: &SizeEstimate) {
+    status!("💾 Estimated download size: {} ({} photos, {} videos)",
+        size::format_size(estimate.known_bytes),
+        estimate.photos,
+        estimate.videos
+    );
+    if estimate.live_motion > 0 {
+        status!("   🎞️  Includes the motion video of {} Live Photos", estimate.live_motion);
+    }
+    if estimate.unknown_size > 0 {
+        status!("   ⚠️  {} items have an unknown size and are not included in the total", estimate.unknown_size);
+    }
+}
+
+fn process_photo_for_download(
+    photo: &Photo,
+    assets_response: &AssetUrlsResponse,
+    selection: &DerivativeSelection,
+) -> Result<Vec<DownloadInfo>> {
+    let mut download_infos = Vec::new();
+
+    let (_size_key, derivative) = match select_derivative(photo) {
+        Some((key, deriv)) => (key, deriv),
+        None => return Ok(download_infos), // No derivatives found
+    };
+
+    let kind = if photo.is_video() { AssetKind::Video } else { AssetKind::Still };
+    let Some(main) = build_download_info(photo, derivative, kind, assets_response)? else {
+        return Ok(download_infos);
+    };
+
+    // Every file for this photo is named after the full-size one so they stay together
+    let (stem, ext) = match main.filename.rsplit_once('.') {
+        Some((stem, ext)) => (stem.to_string(), format!(".{}", ext)),
+        None => (main.filename.clone(), String::new()),
+    };
+
+    match selection {
+        DerivativeSelection::Best => download_infos.push(main),
+        DerivativeSelection::Named(_) => {
+            for (suffix, derivative) in selected_derivatives(photo, selection) {
+                if let Some(mut info) = build_download_info(photo, derivative, kind, assets_response)? {
+                    if let Some(suffix) = suffix {
+                        info.filename = limit_filename_length(&format!("{}_{}{}", stem, suffix, ext));
+                    }
+                    info.photo_stem = stem.clone();
+                    download_infos.push(info);
+                }
+            }
+        }
+    }
+
+    if let Some((_, motion_derivative)) = live_photo_motion(photo) {
+        if let Some(mut motion) = build_download_info(photo, motion_derivative, AssetKind::LiveMotion, assets_response)? {
+            motion.filename = format!("{}.mov", stem);
+            motion.photo_stem = stem.clone();
+            download_infos.push(motion);
+        }
+    }
+
+    Ok(download_infos)
+}
+
+fn build_download_info(
+    photo: &Photo,
+    derivative: &Derivative,
+    kind: AssetKind,
+    assets_response: &AssetUrlsResponse,
+) -> Result<Option<DownloadInfo>> {
+    // Get the download URL for this checksum
+    let asset_url = match assets_response.items.get(&derivative.checksum) {
+        Some(url) => url,
+        None => return Ok(None), // No URL found for this checksum
+    };
+
+    let download_url = asset_download_url(assets_response, asset_url)?;
+    let filename = original_filename(asset_url, derivative)
+        .or_else(|| filename_from_url_path(&asset_url.url_path))
+        .unwrap_or_else(|| format!("{}.{}", photo.photo_guid, kind.default_extension()));
+
+    let size_info = format!("{}x{}", 
+        derivative.width.map_or("?".to_string(), |w| w.to_string()),
+        derivative.height.map_or("?".to_string(), |h| h.to_string())
+    );
+
+    Ok(Some(DownloadInfo {
+        photo_guid: photo.photo_guid.clone(),
+        checksum: derivative.checksum.clone(),
+        download_url,
+        photo_stem: file_stem(&filename).to_string(),
+        filename,
+        size_info,
+        caption: photo.caption.clone(),
+        date_created: photo.date_created.as_deref().and_then(dates::parse_date_created),
+        hosts: asset_hosts(assets_response, asset_url),
+        file_size: derivative.file_size_bytes(),
+        url_expiry: asset_url.url_expiry.as_deref().and_then(expiry::parse_url_expiry),
+        kind,
+    }))
+}
+
+/// Every CDN host the asset's location offers; downloads use the first.
+fn asset_hosts(assets_response: &AssetUrlsResponse, asset_url: &AssetUrl) -> Vec<String> {
+    assets_response.locations
+        .get(&asset_url.url_location)
+        .map(|location| location.hosts.clone())
+        .unwrap_or_default()
+}
+
+/// Builds the full CDN URL for an asset from its location and path.
+fn asset_download_url(assets_response: &AssetUrlsResponse, asset_url: &AssetUrl) -> Result<String> {
+    let location = assets_response.locations
+        .get(&asset_url.url_location)
+        .ok_or_else(|| anyhow!("Location not found for: {}", asset_url.url_location))?;
+
+    Ok(format!("{}://{}{}", 
+        location.scheme,
+        location.hosts.first()
+            .ok_or_else(|| anyhow!("No hosts found for location"))?,
+        asset_url.url_path
+    ))
+}
+
+/// The asset's original filename (`IMG_0001.HEIC`), when the webasseturls
+/// item or the derivative carries one.
+fn original_filename(asset_url: &AssetUrl, derivative: &Derivative) -> Option<String> {
+    let name = ORIGINAL_FILENAME_KEYS
+        .iter()
+        .find_map(|key| asset_url.extra.get(*key).or_else(|| derivative.extra.get(*key)))?
+        .as_str()?;
+    safe_filename(name)
+}
+
+/// Extracts the filename from a URL path: the last segment, without query
+/// parameters and with percent-escapes (`%20`) decoded.
+fn filename_from_url_path(url_path: &str) -> Option<String> {
+    let path = url_path.split(['?', '#']).next().unwrap_or(url_path);
+    let segment = path.rsplit('/').next()?;
+    safe_filename(&percent_decode(segment))
+}
+
+/// `name` as a plain file name for the output directory. Only what follows
+/// the last `/` or `\` is kept, so a name can't point into another
+/// directory (`../x`), and hidden, empty or control-character names are
+/// rejected.
+fn safe_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    let usable = !name.is_empty() && !name.starts_with('.') && !name.chars().any(char::is_control);
+    usable.then(|| limit_filename_length(name))
+}
+
+/// Decodes `%XX` escapes; anything malformed is kept as it is.
+fn percent_decode(value: &str) -> String {
+    String::from_utf8_lossy(&percent_decode_bytes(value)).into_owned()
+}
+
+/// The bytes `%XX` escapes stand for, for text that isn't UTF-8.
+fn percent_decode_bytes(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// `IMG_0001.HEIC` -> `IMG_0001`.
+fn file_stem(name: &str) -> &str {
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+/// Shortens `name` to fit in `MAX_FILENAME_BYTES`, keeping the extension and
+/// never cutting a multi-byte character in half.
+fn limit_filename_length(name: &str) -> String {
+    if name.len() <= MAX_FILENAME_BYTES {
+        return name.to_string();
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 && name.len() - idx <= 16 => name.split_at(idx),
+        _ => (name, ""),
+    };
+    let stem = truncate_at_char_boundary(stem, MAX_FILENAME_BYTES - ext.len());
+    format!("{}{}", stem, ext)
+}
+
+/// Creates every subdirectory (date folders and the like) the downloads
+/// will write into, once, before any of them start. On network filesystems
+/// a `create_dir_all` per file adds up to minutes for a large album.
+async fn create_subdirectories<'a>(
+    infos: impl IntoIterator<Item = &'a DownloadInfo>,
+    output_dir: &str,
+    permissions: &OutputPermissions,
+) -> Result<()> {
+    let dirs: BTreeSet<&Path> = infos
+        .into_iter()
+        .filter_map(|info| Path::new(&info.filename).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+
+    // Sorted, a directory is followed by its own subdirectories; only the
+    // deepest ones need creating, as that creates their parents too
+    let mut dirs = dirs.into_iter().peekable();
+    while let Some(dir) = dirs.next() {
+        if dirs.peek().is_some_and(|next| next.starts_with(dir)) {
+            continue;
+        }
+        permissions
+            .create_dir_all(&Path::new(output_dir).join(dir))
+            .await
+            .context("Failed to create output subdirectory")?;
+    }
+    Ok(())
+}
+
+async fn download_photos<C: HttpClient>(
+    client: &C,
+    source: DownloadSource,
+    output_dir: &str,
+    options: &DownloadOptions,
+    refresher: Option<&expiry::UrlRefresher<'_, C>>,
+    reporting: &DownloadReporting<'_>,
+) -> Result<()> {
+    let failure_log = reporting.failure_log;
+    // A streamed download's total grows as its URLs arrive
+    let (total, expected_bytes) = match &source {
+        DownloadSource::Listed(infos) => {
+            (infos.len() as u64, infos.iter().map(|info| info.file_size).sum::<Option<u64>>())
+        }
+        DownloadSource::Streamed(_) => (0, None),
+    };
+
+    if let (DownloadSource::Listed(infos), None) = (&source, &options.archive) {
+        create_subdirectories(infos, output_dir, &options.permissions).await?;
+    }
+
+    let dashboard = options.dashboard.then(|| Dashboard::new(total as usize, expected_bytes));
+    let screen = dashboard.clone().map(dashboard::Screen::show).transpose()?;
+
+    let main_progress = match dashboard {
+        Some(_) => Progress::hidden(total),
+        None => Progress::new(total, "photos downloaded")?,
+    };
+
+    let download_infos = match source {
+        DownloadSource::Listed(infos) => stream::iter(infos).left_stream(),
+        DownloadSource::Streamed(receiver) => {
+            stream::unfold(receiver, |mut receiver| async move { receiver.recv().await.map(|info| (info, receiver)) })
+                .inspect(|_| main_progress.inc_length(1))
+                .right_stream()
+        }
+    };
+
+    let counters = DownloadCounters::default();
+
+    let downloads = download_infos.for_each_concurrent(options.max_concurrent, |info| {
+        let counters = &counters;
+        let main_progress = &main_progress;
+        let dashboard = dashboard.as_deref();
+
+        async move {
+            // Swap in a fresh URL if this one would expire before we get to it
+            let info = match refresher {
+                Some(refresher) if refresher.needs_refresh(&info) => {
+                    match refresher.refresh(&info).await {
+                        Ok(fresh) => fresh,
+                        Err(e) => {
+                            // Stderr would scribble over the dashboard, which shows the download failure anyway
+                            if dashboard.is_none() {
+                                eprintln!("⚠️  Could not refresh URL for {}: {}", info.filename, e);
+                            }
+                            info
+                        }
+                    }
+                }
+                _ => info,
+            };
+
+            if let Some(disk_space) = &options.disk_space {
+                let report = |message: String| match dashboard {
+                    Some(dashboard) => dashboard.record_warning(message),
+                    None => eprintln!("{}", message),
+                };
+                // With --on-low-space abort this stops the whole download phase below
+                if disk_space.admit(Path::new(output_dir), report).await.is_err() {
+                    return;
+                }
+            }
+
+            // Held until the file is written
+            let _memory = match &options.memory {
+                Some(memory) => {
+                    let report = |message: String| match dashboard {
+                        Some(dashboard) => dashboard.record_warning(message),
+                        None => status!("{}", message),
+                    };
+                    Some(memory.admit(&info, report).await)
+                }
+                None => None,
+            };
+
+            let admission = match &options.breaker {
+                Some(breaker) => Some(breaker.admit().await),
+                None => None,
+            };
+
+            let started = Instant::now();
+            let download = download_single_photo(client, &info, output_dir, options, dashboard);
+            // Dropping the download on timeout frees its slot for the next file
+            let (result, timed_out) = match options.per_file_timeout {
+                Some(limit) => match tokio::time::timeout(limit, download).await {
+                    Ok(result) => (result, false),
+                    Err(_) => (Err(anyhow!("gave up after {}s (--per-file-timeout)", limit.as_secs())), true),
+                },
+                None => (download.await, false),
+            };
+            reporting.stats.record_download(started.elapsed());
+
+            if let (Some(breaker), Some(admission)) = (&options.breaker, admission) {
+                if let Some(message) = breaker.record(admission, result.as_ref().err()) {
+                    match dashboard {
+                        Some(dashboard) => dashboard.record_warning(message),
+                        None => eprintln!("{}", message),
+                    }
+                }
+            }
+
+            if let Ok(saved) = &result {
+                if let Some(verified) = saved.verified {
+                    counters.record_verification(verified);
+                }
+                if let Some(manifest) = reporting.manifest {
+                    manifest.record(&info, saved);
+                }
+                if let Some(hashes) = reporting.hashes {
+                    hashes.submit(Path::new(output_dir).join(&saved.filename), saved.filename.clone()).await;
+                }
+                if let Some(transcoder) = reporting.transcoder {
+                    // Also checks the contents, for an (animated) image named like a video
+                    let is_image = saved.format.is_some_and(filetype::is_image);
+                    if info.kind != AssetKind::LiveMotion && !is_image && Transcoder::is_video(&saved.filename) {
+                        transcoder.submit(Path::new(output_dir).join(&saved.filename), saved.filename.clone());
+                    }
+                }
+            }
+
+            match result {
+                Ok(SavedFile { filename: saved_as, size_mismatch: Some(mismatch), .. }) => {
+                    let message = format!("{} (file kept)", mismatch);
+                    match dashboard {
+                        Some(dashboard) => {
+                            dashboard.record_warning(format!("{}: {}", saved_as, message));
+                            dashboard.record_success();
+                        }
+                        None => eprintln!("⚠️  {}: {}", saved_as, message),
+                    }
+                    failure_log.record(&info.photo_guid, &saved_as, &anyhow!(message.clone()));
+                    if let Some(table) = reporting.outcome_table {
+                        table.record_size_mismatch(&info, &saved_as, &message);
+                    }
+                    counters.record_success();
+                    counters.record_size_mismatch();
+                }
+                Ok(SavedFile { filename: saved_as, size_mismatch: None, .. }) => {
+                    if let Some(table) = reporting.outcome_table {
+                        table.record_downloaded(&info, &saved_as);
+                    }
+                    if let Some(dashboard) = dashboard {
+                        dashboard.record_success();
+                    }
+                    counters.record_success();
+                }
+                Err(e) => {
+                    let message = match info.caption.as_deref().map(|c| render_caption(c, CaptionContext::Display)) {
+                        Some(caption) if !caption.is_empty() => {
+                            format!("Failed to download {} (\"{}\"): {}", info.filename, caption, e)
+                        }
+                        _ => format!("Failed to download {}: {}", info.filename, e),
+                    };
+                    match dashboard {
+                        Some(dashboard) => dashboard.record_failure(message),
+                        None => eprintln!("❌ {}", message),
+                    }
+                    failure_log.record(&info.photo_guid, &info.filename, &e);
+                    if let Some(table) = reporting.outcome_table {
+                        table.record_failed(&info, &e);
+                    }
+                    if let Some(aria2) = reporting.aria2 {
+                        aria2.record(&info);
+                    }
+                    counters.record_failure();
+                    if timed_out {
+                        counters.record_timeout();
+                    }
+                }
+            }
+            main_progress.inc(1);
+        }
+    });
+
+    let downloads = async {
+        match reporting.progress {
+            Some(progress) => tokio::select! {
+                _ = downloads => {}
+                _ = progress.run(&counters) => {}
+            },
+            None => downloads.await,
+        }
+    };
+
+    let out_of_space = async {
+        match &options.disk_space {
+            Some(disk_space) => disk_space.stopped().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let breaker_gave_up = async {
+        match &options.breaker {
+            Some(breaker) => breaker.gave_up().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // On Ctrl-C, a full disk or iCloud staying down, stop scheduling and
+    // still report what got done
+    let (interrupted, out_of_space, gave_up) = tokio::select! {
+        _ = downloads => (false, false, false),
+        _ = tokio::signal::ctrl_c() => (true, false, false),
+        _ = out_of_space => (true, true, false),
+        _ = breaker_gave_up => (true, false, true),
+    };
+
+    if let Some(progress) = reporting.progress {
+        progress.finish(&counters, interrupted);
+    }
+
+    if let Some(screen) = screen {
+        screen.close().await;
+    }
+
+    if out_of_space {
+        main_progress.abandon();
+        status!("\n💾 Stopped: the output disk is below --min-free-space");
+    } else if gave_up {
+        main_progress.abandon();
+        status!("\n🛑 Stopped: iCloud stayed unreachable through {} probe downloads", breaker::MAX_FAILED_PROBES);
+    } else if interrupted {
+        main_progress.abandon();
+        status!("\n⚠️  Interrupted");
+    } else {
+        main_progress.finish();
+    }
+
+    let success_count = counters.succeeded();
+    let failure_count = counters.failed();
+
+    status!("📊 Results: {} succeeded, {} failed", success_count, failure_count);
+    reporting.stats.record_results(success_count, failure_count);
+
+    if options.verify {
+        status!(
+            "🔏 {} files matched iCloud's checksum, {} used a checksum type that can't be verified",
+            counters.verified(),
+            counters.unverifiable()
+        );
+    }
+
+    if let Some(store) = &options.content_store {
+        let (reused, saved_bytes) = store.take_savings();
+        if reused > 0 {
+            status!("♻️  {} files ({}) linked from the content store instead of downloaded", reused, size::format_size(saved_bytes));
+        }
+    }
+
+    if let Some(memory) = &options.memory {
+        let delayed = memory.delayed();
+        if delayed > 0 {
+            status!("🧠 {} downloads waited for memory to start (--max-memory)", delayed);
+        }
+    }
+
+    let timeout_count = counters.timed_out();
+    if timeout_count > 0 {
+        status!("⏱️  {} of the failed downloads were abandoned after --per-file-timeout", timeout_count);
+    }
+
+    let mismatch_count = counters.size_mismatches();
+    if mismatch_count > 0 {
+        status!("⚠️  {} downloads didn't match the size listed in the album (use --strict to fail them)", mismatch_count);
+    }
+
+    if failure_count > 0 || mismatch_count > 0 {
+        status!("📝 Failed downloads listed in {}", failure_log.path().display());
+    }
+
+    if out_of_space {
+        return Err(anyhow!(
+            "Stopped after {} of {} downloads because the output disk is nearly full; free up space and re-run with --repair to fetch the rest",
+            success_count + failure_count,
+            main_progress.length()
+        ));
+    }
+    if gave_up {
+        return Err(anyhow!(
+            "Stopped after {} of {} downloads because iCloud kept failing; try again later and re-run with --repair to fetch the rest",
+            success_count + failure_count,
+            main_progress.length()
+        ));
+    }
+    if interrupted {
+        return Err(anyhow!("Interrupted after {} of {} downloads", success_count + failure_count, main_progress.length()));
+    }
+
+    if failure_count > 0 {
+        return Err(anyhow!("{} downloads failed", failure_count));
+    }
+
+    Ok(())
+}
+
+struct FetchedBody {
+    content_type: Option<String>,
+    content_length: Option<u64>,
+    content: Bytes,
+}
+
+/// A downloaded file on its way to being saved: in memory, or already on
+/// disk when it was downloaded in parts.
+enum Content {
+    Memory(Bytes),
+    Spooled(parts::SpooledFile),
+}
+
+impl Content {
+    fn len(&self) -> u64 {
+        match self {
+            Content::Memory(content) => content.len() as u64,
+            Content::Spooled(file) => file.len,
+        }
+    }
+
+    /// The start of the file, enough for `filetype::detect_extension`.
+    fn head(&self) -> &[u8] {
+        match self {
+            Content::Memory(content) => content,
+            Content::Spooled(file) => &file.head,
+        }
+    }
+
+    /// The whole file in memory, for what can't work from a file on disk.
+    async fn into_bytes(self) -> Result<Bytes> {
+        match self {
+            Content::Memory(content) => Ok(content),
+            Content::Spooled(file) => file.into_bytes().await,
+        }
+    }
+}
+
+/// Where a download in parts is written before it's saved: next to its
+/// destination, so it can be renamed into place, or in the temporary
+/// directory when it ends up elsewhere.
+fn spool_path(options: &DownloadOptions, output_dir: &str, info: &DownloadInfo) -> parts::Spool {
+    if options.archive.is_some() || options.content_store.is_some() || options.discard {
+        let name: String = info.checksum.chars().filter(char::is_ascii_alphanumeric).collect();
+        let root = std::env::temp_dir();
+        let path = root.join(format!(".icloud-dl-{}-{}.parts", std::process::id(), name));
+        return parts::Spool { root, path };
+    }
+    let path = atomic::temporary_path(&Path::new(output_dir).join(&info.filename));
+    parts::Spool { root: PathBuf::from(output_dir), path }
+}
+
+/// GETs an asset and reads the whole body, failing with `TruncatedBody` if
+/// fewer bytes arrive than the response declared.
+async fn fetch_body(
+    client: &impl HttpClient,
+    info: &DownloadInfo,
+    transfer: Option<&dashboard::Transfer<'_>>,
+) -> Result<FetchedBody> {
+    let response = client
+        .get(&info.download_url, RequestKind::Download)
+        .await
+        .context("Failed to start download")?;
+    read_body(response, transfer).await
+}
+
+/// Reads a download response in full; see `fetch_body`.
+async fn read_body(response: HttpResponse, transfer: Option<&dashboard::Transfer<'_>>) -> Result<FetchedBody> {
+    if !response.status().is_success() {
+        let (status, trace) = (response.status(), response.apple_trace());
+        return Err(errors::DownloadStatus { request: "Download".to_string(), status, trace }.into());
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let content_length: Option<u64> = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    if let (Some(transfer), Some(length)) = (transfer, content_length) {
+        transfer.set_total(length);
+    }
+
+    let mut received = 0u64;
+    let content = response
+        .bytes_with_progress(|n| {
+            received += n as u64;
+            stats::count_bytes_downloaded(n as u64);
+            if let Some(transfer) = transfer {
+                transfer.advance(n);
+            }
+        })
+        .await;
+
+    // hyper usually reports a short body as a read error rather than
+    // returning fewer bytes; either way it's a truncation worth retrying
+    let content = match (content, content_length) {
+        (Ok(content), Some(expected)) if (content.len() as u64) < expected => {
+            return Err(integrity::TruncatedBody { received: content.len() as u64, expected }.into());
+        }
+        (Ok(content), _) => content,
+        (Err(_), Some(expected)) if received < expected => {
+            return Err(integrity::TruncatedBody { received, expected }.into());
+        }
+        (Err(e), _) => return Err(e.context("Failed to read response bytes")),
+    };
+
+    Ok(FetchedBody { content_type, content_length, content })
+}
+
+async fn download_single_photo(
+    client: &impl HttpClient,
+    info: &DownloadInfo,
+    output_dir: &str,
+    options: &DownloadOptions,
+    dashboard: Option<&Dashboard>,
+) -> Result<SavedFile> {
+    // With a content store, a photo already in it is linked instead of downloaded
+    let _store_lock = match &options.content_store {
+        Some(store) => Some(store.lock(&info.checksum).await),
+        None => None,
+    };
+    if let Some(store) = &options.content_store {
+        if let Some(asset) = store.find(&info.checksum).await? {
+            let format = filetype::detect_extension(None, &asset.head);
+            let filename = match format {
+                Some(ext) if options.correct_extensions => filetype::correct_extension(&info.filename, ext),
+                _ => info.filename.clone(),
+            };
+            let file_path = Path::new(output_dir).join(&filename);
+            if !store.is_store_link(&file_path) {
+                safepath::check_destination(Path::new(output_dir), &file_path).await?;
+            }
+            store.reuse(&asset, &file_path).await?;
+            apply_capture_metadata(options, info, &file_path, &filename).await;
+            run_post_download_hook(options, info, &file_path, &filename).await?;
+            return Ok(SavedFile { filename, size: asset.size, size_mismatch: None, verified: None, format });
+        }
+    }
+
+    let transfer = dashboard.map(|dashboard| dashboard.start(&info.filename, info.file_size));
+
+    // A body cut short of its Content-Length is fetched again rather than saved
+    let mut attempt = 1;
+    let fetched = loop {
+        let fetched = if options.parallel_parts > 1 {
+            let spool = spool_path(options, output_dir, info);
+            parts::fetch_in_parts(client, info, options.parallel_parts, &spool, transfer.as_ref()).await
+        } else {
+            fetch_body(client, info, transfer.as_ref()).await.map(PartsDownload::Whole)
+        };
+        match fetched {
+            Err(e) if attempt < TRUNCATED_DOWNLOAD_ATTEMPTS && e.is::<integrity::TruncatedBody>() => {
+                integrity::before_retry(&e, attempt, transfer.as_ref()).await;
+                attempt += 1;
+            }
+            result => break result?,
+        }
+    };
+    let (content_type, content_length, content) = match fetched {
+        PartsDownload::Whole(body) => (body.content_type, body.content_length, Content::Memory(body.content)),
+        PartsDownload::Spooled(file) => (file.content_type.clone(), Some(file.len), Content::Spooled(file)),
+    };
+
+    // Checked on the raw body, before metadata stripping changes its size
+    let size_mismatch = integrity::check_size(info.file_size, content_length, content.len());
+    if let Some(mismatch) = &size_mismatch {
+        if options.strict_sizes {
+            return Err(anyhow!("{}", mismatch));
+        }
+    }
+
+    let verified = if options.verify {
+        let checksum = info.checksum.clone();
+        let verdict = match &content {
+            Content::Memory(body) => {
+                let body = body.clone();
+                tokio::task::spawn_blocking(move || apple_checksum::verify_apple_checksum(&body, &checksum))
+                    .await
+                    .context("Checksum verification task panicked")?
+            }
+            Content::Spooled(file) => {
+                let path = file.path().to_path_buf();
+                tokio::task::spawn_blocking(move || apple_checksum::verify_file(&path, &checksum))
+                    .await
+                    .context("Checksum verification task panicked")?
+                    .context("Failed to read the download back for verification")?
+            }
+        };
+        if verdict == Some(false) {
+            return Err(anyhow!("Checksum mismatch: the download doesn't match iCloud's checksum {}", info.checksum));
+        }
+        Some(verdict.is_some())
+    } else {
+        None
+    };
+
+    let detected_ext = filetype::detect_extension(content_type.as_deref(), content.head());
+    let filename = match detected_ext {
+        Some(ext) if options.correct_extensions => filetype::correct_extension(&info.filename, ext),
+        _ => info.filename.clone(),
+    };
+
+    let content = if options.strip_metadata {
+        let ext = detected_ext
+            .or_else(|| filename.rsplit_once('.').map(|(_, ext)| ext))
+            .unwrap_or_default()
+            .to_string();
+        let content = content.into_bytes().await?;
+        let stripped = tokio::task::spawn_blocking(move || metadata::strip_metadata(content, &ext))
+            .await
+            .context("Metadata stripping task panicked")?
+            .context("Failed to strip metadata")?;
+        Content::Memory(stripped)
+    } else {
+        content
+    };
+    let size = content.len();
+
+    if options.discard {
+        return Ok(SavedFile { filename, size, size_mismatch, verified, format: detected_ext });
+    }
+
+    if let Some(archive) = &options.archive {
+        archive.append(&filename, content.into_bytes().await?, info.date_created).await?;
+        return Ok(SavedFile { filename, size, size_mismatch, verified, format: detected_ext });
+    }
+
+    // Its directory was made by `create_subdirectories` before the downloads started
+    let file_path = Path::new(output_dir).join(&filename);
+    match (&options.content_store, content) {
+        (Some(store), content) => {
+            if !store.is_store_link(&file_path) {
+                safepath::check_destination(Path::new(output_dir), &file_path).await?;
+            }
+            store.save(&info.checksum, &content.into_bytes().await?, &file_path, &options.permissions).await?;
+        }
+        (None, Content::Spooled(file)) => {
+            safepath::check_destination(Path::new(output_dir), &file_path).await?;
+            file.persist(&file_path).await?;
+            options.permissions.apply_to_file(&file_path).await?;
+        }
+        (None, Content::Memory(content)) => {
+            safepath::check_destination(Path::new(output_dir), &file_path).await?;
+            // Written next to its destination and renamed into place, so a
+            // download abandoned halfway never leaves a partial file there
+            let pending = atomic::PendingFile::new(atomic::temporary_path(&file_path));
+            let mut file = safepath::create_new_file(Path::new(output_dir), pending.path())
+                .await
+                .context("Failed to create output file")?;
+
+            file.write_all(&content)
+                .await
+                .context("Failed to write file")?;
+
+            file.sync_all()
+                .await
+                .context("Failed to sync file")?;
+            drop(file);
+            pending.persist(&file_path).await?;
+            options.permissions.apply_to_file(&file_path).await?;
+        }
+    }
+
+    apply_capture_metadata(options, info, &file_path, &filename).await;
+    run_post_download_hook(options, info, &file_path, &filename).await?;
+
+    Ok(SavedFile { filename, size, size_mismatch, verified, format: detected_ext })
+}
+
+/// --preserve-dates and --xmp-sidecars for a saved file. Failing either
+/// only warns; the file itself is fine.
+async fn apply_capture_metadata(options: &DownloadOptions, info: &DownloadInfo, file_path: &Path, filename: &str) {
+    if let (true, Some(date)) = (options.preserve_dates, info.date_created) {
+        if let Err(e) = set_modified(file_path, date).await {
+            eprintln!("⚠️  {}: could not set its date: {:#}", filename, e);
+        }
+    }
+    if options.xmp_sidecars {
+        if let Err(e) = xmp::write_sidecar(info, file_path, &options.permissions).await {
+            eprintln!("⚠️  {}: could not write its XMP sidecar: {:#}", filename, e);
+        }
+    }
+}
+
+async fn set_modified(path: &Path, date: DateTime<Utc>) -> Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        fs::OpenOptions::new().write(true).open(&path)?.set_modified(SystemTime::from(date))
+    })
+    .await
+    .context("Setting the file time panicked")?
+    .context("Failed to set the modification time")
+}
+
+/// Runs --post-download-cmd for a saved file. A failing hook only fails the
+/// download with --hook-required.
+async fn run_post_download_hook(options: &DownloadOptions, info: &DownloadInfo, file_path: &Path, filename: &str) -> Result<()> {
+    if let Some(hook) = &options.post_download {
+        if let Err(e) = hook.run(info, file_path).await {
+            if options.hook_required {
+                return Err(e);
+            }
+            eprintln!("⚠️  {}: {:#}", filename, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{FakeClient, FakePhoto};
+
+    const HASH: &str = "B0TestAlbumHash";
+
+    fn args(output: &Path, extra: &[&str]) -> Args {
+        let url = format!("https://www.icloud.com/sharedalbum/#{}", HASH);
+        let output = output.to_string_lossy().into_owned();
+        let base = ["icloud-photo-download", "--url", &url, "--output", &output, "--yes"];
+        Args::try_parse_from(base.iter().chain(extra)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn downloads_an_album_through_webstream_and_webasseturls() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(testing::album(vec![
+            FakePhoto::new("P1", "IMG_0001.JPG", b"first photo"),
+            FakePhoto::new("P2", "IMG_0002.JPG", b"second photo"),
+        ]));
+
+        download_album(&client, &args(dir.path(), &[]), HASH, None, false, None, None).await.unwrap();
+
+        assert_eq!(fs::read(dir.path().join("IMG_0001.JPG")).unwrap(), b"first photo");
+        assert_eq!(fs::read(dir.path().join("IMG_0002.JPG")).unwrap(), b"second photo");
+        assert_eq!(client.count("/webstream"), 1);
+        assert_eq!(client.count("/webasseturls"), 1);
+        let downloads = client.requests().into_iter().filter(|r| r.url.starts_with("https://files.test/"));
+        assert!(downloads.map(|r| r.method).eq(["GET", "GET"]));
+    }
+
+    #[tokio::test]
+    async fn the_time_to_find_the_albums_host_is_its_own_phase() {
+        let album = testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]);
+        let client = FakeClient::new(move |request| {
+            if request.url.contains("p42-sharedstreams.icloud.com") {
+                return album(request);
+            }
+            std::thread::sleep(Duration::from_millis(30));
+            testing::status(&request.url, 330).with_header(
+                reqwest::header::HeaderName::from_static("x-apple-mme-host"),
+                reqwest::header::HeaderValue::from_static("p42-sharedstreams.icloud.com"),
+            )
+        });
+
+        let (webstream, host_resolution) = fetch_webstream_timed(&client, "B0RedirectedAlbum").await.unwrap();
+
+        assert_eq!(webstream.photos.len(), 1);
+        assert!(host_resolution >= Duration::from_millis(30), "{:?}", host_resolution);
+        assert_eq!(client.count("/webstream"), 2);
+
+        let direct = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]));
+        let (_, host_resolution) = fetch_webstream_timed(&direct, "B0DirectAlbum").await.unwrap();
+        assert_eq!(host_resolution, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn fetches_urls_only_for_the_photos_in_the_webstream() {
+        let client = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]));
+
+        let webstream = fetch_webstream(&client, HASH).await.unwrap();
+        let infos = fetch_download_urls(&client, HASH, &webstream.photos, &DerivativeSelection::Best).await.unwrap();
+
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].filename, "IMG_0001.JPG");
+        assert_eq!(infos[0].download_url, "https://files.test/ckP1/IMG_0001.JPG");
+        let asked = client.requests().into_iter().find(|r| r.url.ends_with("/webasseturls")).unwrap();
+        assert_eq!(asked.body.unwrap()["photoGuids"], serde_json::json!(["P1"]));
+    }
+
+    /// The webasseturls answer for one photo `P1` whose URL ends in an opaque
+    /// token, with `extra` merged into its item.
+    fn asset_urls_with(extra: serde_json::Value) -> AssetUrlsResponse {
+        let mut response = testing::asset_urls(&[("ckP1", "01a2b3c4d5e6f7")]);
+        let item = response["items"]["ckP1"].as_object_mut().unwrap();
+        item.extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(response).unwrap()
+    }
+
+    fn filenames(infos: &[DownloadInfo]) -> Vec<&str> {
+        infos.iter().map(|info| info.filename.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn the_original_filename_is_preferred_over_the_url_token() {
+        let client = FakeClient::new(|request| {
+            if request.url.ends_with("/webstream") {
+                let photos = serde_json::json!({ "streamName": "Fake", "photos": [testing::photo("P1", 5)] });
+                return testing::json(&request.url, photos);
+            }
+            let mut urls = testing::asset_urls(&[("ckP1", "01a2b3c4d5e6f7")]);
+            urls["items"]["ckP1"]["originalFilename"] = serde_json::json!("IMG_1234.HEIC");
+            testing::json(&request.url, urls)
+        });
+
+        let webstream = fetch_webstream(&client, HASH).await.unwrap();
+        let infos = fetch_download_urls(&client, HASH, &webstream.photos, &DerivativeSelection::Best).await.unwrap();
+
+        assert_eq!(filenames(&infos), ["IMG_1234.HEIC"]);
+        assert_eq!(infos[0].photo_stem, "IMG_1234");
+        assert_eq!(infos[0].download_url, "https://files.test/ckP1/01a2b3c4d5e6f7");
+    }
+
+    #[test]
+    fn the_url_token_names_the_file_without_an_original_filename() {
+        let photo: Photo = serde_json::from_value(testing::photo("P1", 5)).unwrap();
+        let infos = process_photo_for_download(&photo, &asset_urls_with(serde_json::json!({})), &DerivativeSelection::Best)
+            .unwrap();
+        assert_eq!(filenames(&infos), ["01a2b3c4d5e6f7"]);
+    }
+
+    #[test]
+    fn the_original_filename_can_come_from_the_derivative() {
+        let mut photo = testing::photo("P1", 5);
+        photo["derivatives"]["1"]["fileName"] = serde_json::json!("IMG_0042.MOV");
+        let photo: Photo = serde_json::from_value(photo).unwrap();
+        let infos = process_photo_for_download(&photo, &asset_urls_with(serde_json::json!({})), &DerivativeSelection::Best)
+            .unwrap();
+        assert_eq!(filenames(&infos), ["IMG_0042.MOV"]);
+    }
+
+    #[test]
+    fn original_filenames_cant_leave_the_output_directory() {
+        let photo: Photo = serde_json::from_value(testing::photo("P1", 5)).unwrap();
+        let name = |original: &str| {
+            let urls = asset_urls_with(serde_json::json!({ "originalFilename": original }));
+            process_photo_for_download(&photo, &urls, &DerivativeSelection::Best).unwrap()[0].filename.clone()
+        };
+
+        assert_eq!(name("../../etc/IMG_0001.JPG"), "IMG_0001.JPG");
+        assert_eq!(name("C:\\Users\\me\\IMG_0002.JPG"), "IMG_0002.JPG");
+        // Unusable names fall back to the URL
+        assert_eq!(name(".."), "01a2b3c4d5e6f7");
+        assert_eq!(name(""), "01a2b3c4d5e6f7");
+        assert_eq!(name("IMG\u{7}.JPG"), "01a2b3c4d5e6f7");
+    }
+
+    #[tokio::test]
+    async fn a_batch_that_stays_empty_is_skipped_and_the_rest_still_fetched() {
+        let photos: Vec<FakePhoto> = (0..30).map(|i| FakePhoto::new(&format!("P{:02}", i), "IMG.JPG", b"photo")).collect();
+        let album = testing::album(photos);
+        // The first batch of 25 never gets any URLs
+        let client = FakeClient::new(move |request| {
+            let first_batch = request.body.as_ref().is_some_and(|body| body["photoGuids"][0] == "P00");
+            if request.url.ends_with("/webasseturls") && first_batch {
+                return testing::json(&request.url, serde_json::json!({ "locations": {}, "items": {} }));
+            }
+            album(request)
+        });
+
+        let webstream = fetch_webstream(&client, HASH).await.unwrap();
+        let infos = fetch_download_urls(&client, HASH, &webstream.photos, &DerivativeSelection::Best).await.unwrap();
+
+        let guids: Vec<&str> = infos.iter().map(|info| info.photo_guid.as_str()).collect();
+        assert_eq!(guids, ["P25", "P26", "P27", "P28", "P29"]);
+        assert_eq!(client.count("/webasseturls"), EMPTY_BATCH_ATTEMPTS as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn repair_fetches_urls_only_for_damaged_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(testing::album(vec![
+            FakePhoto::new("P1", "IMG_0001.JPG", b"first photo"),
+            FakePhoto::new("P2", "IMG_0002.JPG", b"second photo"),
+        ]));
+        download_album(&client, &args(dir.path(), &[]), HASH, None, false, None, None).await.unwrap();
+        fs::write(dir.path().join("IMG_0002.JPG"), b"cut").unwrap();
+
+        let repaired = FakeClient::new(testing::album(vec![
+            FakePhoto::new("P1", "IMG_0001.JPG", b"first photo"),
+            FakePhoto::new("P2", "IMG_0002.JPG", b"second photo"),
+        ]));
+        download_album(&repaired, &args(dir.path(), &["--repair"]), HASH, None, false, None, None).await.unwrap();
+
+        assert_eq!(fs::read(dir.path().join("IMG_0002.JPG")).unwrap(), b"second photo");
+        let asked: Vec<_> = repaired.requests().into_iter().filter(|r| r.url.ends_with("/webasseturls")).collect();
+        assert_eq!(asked.len(), 1);
+        assert_eq!(asked[0].body.as_ref().unwrap()["photoGuids"], serde_json::json!(["P2"]));
+        assert_eq!(repaired.count("https://files.test/"), 1);
+    }
+
+    #[tokio::test]
+    async fn a_gif_served_under_a_video_name_is_saved_as_a_gif() {
+        let dir = tempfile::tempdir().unwrap();
+        let gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00;";
+        let album = testing::album(vec![FakePhoto::new("P1", "IMG_0001.mp4", gif)]);
+        let client = FakeClient::new(move |request| {
+            let response = album(request);
+            match request.url.starts_with("https://files.test/") {
+                true => response.with_header(reqwest::header::CONTENT_TYPE, "image/gif".parse().unwrap()),
+                false => response,
+            }
+        });
+
+        download_album(&client, &args(dir.path(), &[]), HASH, None, false, None, None).await.unwrap();
+
+        assert_eq!(fs::read(dir.path().join("IMG_0001.gif")).unwrap(), gif);
+        assert!(!dir.path().join("IMG_0001.mp4").exists());
+    }
+
+    /// A photo whose largest asset is listed twice, under `2048` and `2049`,
+    /// next to a thumbnail and a Live Photo video under the same checksum.
+    fn photo_listed_twice() -> serde_json::Value {
+        let derivative = |checksum: &str, size: usize| {
+            serde_json::json!({ "fileSize": size.to_string(), "checksum": checksum, "width": "10", "height": "10" })
+        };
+        serde_json::json!({
+            "photoGuid": "P1",
+            "dateCreated": "2024-05-01T12:00:00Z",
+            "width": "10",
+            "height": "10",
+            "derivatives": {
+                "342": derivative("ckP1thumb", 3),
+                "2048": derivative("ckP1", 11),
+                "2049": derivative("ckP1", 11),
+                "720p": derivative("ckP1", 11),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn derivatives_sharing_a_checksum_are_downloaded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(|request| {
+            if request.url.ends_with("/webstream") {
+                return testing::json(&request.url, serde_json::json!({ "photos": [photo_listed_twice()] }));
+            }
+            if request.url.ends_with("/webasseturls") {
+                let urls = testing::asset_urls(&[("ckP1", "IMG_0001.JPG"), ("ckP1thumb", "IMG_0001.JPG")]);
+                return testing::json(&request.url, urls);
+            }
+            match request.url.starts_with("https://files.test/ckP1/") {
+                true => testing::file(&request.url, b"full photo!", 11),
+                false => testing::file(&request.url, b"thm", 3),
+            }
+        });
+
+        download_album(&client, &args(dir.path(), &[]), HASH, None, false, None, None).await.unwrap();
+
+        assert_eq!(fs::read(dir.path().join("IMG_0001.JPG")).unwrap(), b"full photo!");
+        assert_eq!(client.count("https://files.test/"), 1);
+        assert!(!listing(dir.path()).iter().any(|name| name.ends_with(".mov")));
+    }
+
+    #[test]
+    fn derivatives_sharing_a_checksum_count_as_one_rendition() {
+        let photo: Photo = serde_json::from_value(photo_listed_twice()).unwrap();
+
+        let (key, best) = select_derivative(&photo).unwrap();
+        assert_eq!((key.as_str(), best.checksum.as_str()), ("2049", "ckP1"));
+        // The video key is the still again, not a Live Photo video
+        assert!(live_photo_motion(&photo).is_none());
+
+        let named = DerivativeSelection::Named(vec!["thumb".into(), "medium".into(), "full".into()]);
+        let selected: Vec<(Option<&str>, &str)> = selected_derivatives(&photo, &named)
+            .into_iter()
+            .map(|(suffix, derivative)| (suffix, derivative.checksum.as_str()))
+            .collect();
+        assert_eq!(selected, [(Some("thumb"), "ckP1thumb"), (Some("medium"), "ckP1")]);
+
+        let estimate = estimate_download_size(std::slice::from_ref(&photo), &DerivativeSelection::Best, true);
+        assert_eq!((estimate.known_bytes, estimate.live_motion), (11, 0));
+    }
+
+    #[tokio::test]
+    async fn album_name_names_the_directory_in_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("album.tar");
+        let tar = tar_path.to_str().unwrap();
+        let client = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]));
+        let archive = TarArchive::create(tar).unwrap();
+
+        let args = args(&dir.path().join("out"), &["--tar", tar]);
+        download_album(&client, &args, HASH, Some("Family / {name}"), false, Some(&archive), None).await.unwrap();
+        archive.finish().await.unwrap();
+
+        let mut entries = tar::Archive::new(fs::File::open(&tar_path).unwrap());
+        let names: Vec<String> = entries
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["Family _ Fake/IMG_0001.JPG"]);
+    }
+
+    #[tokio::test]
+    async fn album_name_titles_the_gallery() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]));
+
+        let args = args(dir.path(), &["--output-index-html-per-run"]);
+        download_album(&client, &args, HASH, Some("Trip & {name}"), false, None, None).await.unwrap();
+
+        let page = fs::read_to_string(dir.path().join(gallery::GALLERY_NAME)).unwrap();
+        assert!(page.contains("<title>Trip &amp; Fake</title>"), "{}", page);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn album_name_is_recorded_in_the_sqlite_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", b"photo")]));
+
+        let args = args(dir.path(), &["--manifest-format", "sqlite"]);
+        download_album(&client, &args, HASH, Some("Family"), false, None, None).await.unwrap();
+
+        let db = rusqlite::Connection::open(workdir::tool_dir(dir.path().to_str().unwrap()).join("manifest.sqlite")).unwrap();
+        let name: String = db.query_row("SELECT album_name FROM photos", [], |row| row.get(0)).unwrap();
+        assert_eq!(name, "Family");
+    }
+
+    #[tokio::test]
+    async fn strict_alone_does_not_stop_on_a_failed_head_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = FakeClient::new(|request| testing::status(&request.url, 404));
+        let mut infos = vec![testing::download_info("P1", "IMG_0001.JPG", Some(5))];
+
+        let strict = args(dir.path(), &["--head-check", "--strict"]);
+        run_head_check(&client, &mut infos, &strict, None).await.unwrap();
+
+        let strict_head_check = args(dir.path(), &["--head-check", "--strict-head-check"]);
+        let error = run_head_check(&client, &mut infos, &strict_head_check, None).await.unwrap_err();
+        assert!(error.to_string().contains("1 download URLs failed the HEAD check"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn range_counts_hidden_photos_in_album_positions() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = testing::album(
+            ["P1", "P2", "P3"].iter().map(|guid| FakePhoto::new(guid, &format!("{}.JPG", guid), b"photo")).collect(),
+        );
+        // P1 and P2 are hidden, leaving one visible photo
+        let client = FakeClient::new(move |request| {
+            if !request.url.ends_with("/webstream") {
+                return album(request);
+            }
+            let photos: Vec<serde_json::Value> = ["P1", "P2", "P3"]
+                .iter()
+                .map(|guid| {
+                    let mut photo = testing::photo(guid, 5);
+                    photo["isHidden"] = serde_json::json!(*guid != "P3");
+                    photo
+                })
+                .collect();
+            testing::json(&request.url, serde_json::json!({ "streamName": "Fake", "photos": photos }))
+        });
+
+        download_album(&client, &args(dir.path(), &["--range", "3.."]), HASH, None, false, None, None).await.unwrap();
+        assert_eq!(listing(dir.path()), [".icloud-dl", "P3.JPG"]);
+
+        // A range of only hidden photos leaves nothing, without failing
+        let empty = tempfile::tempdir().unwrap();
+        download_album(&client, &args(empty.path(), &["--range", "2..2"]), HASH, None, false, None, None).await.unwrap();
+        assert!(!empty.path().join("P2.JPG").exists());
+    }
+
+    /// An album of photos `P1` and `P2`, both captioned `caption`.
+    fn captioned(caption: Option<&'static str>) -> FakeClient {
+        let album = testing::album(vec![
+            FakePhoto::new("P1", "IMG_0001.JPG", b"first photo"),
+            FakePhoto::new("P2", "IMG_0002.JPG", b"second photo"),
+        ]);
+        FakeClient::new(move |request| {
+            if !request.url.ends_with("/webstream") {
+                return album(request);
+            }
+            let photos: Vec<serde_json::Value> = ["P1", "P2"]
+                .iter()
+                .map(|guid| {
+                    let mut photo = testing::photo(guid, 11);
+                    photo["caption"] = serde_json::json!(caption);
+                    photo
+                })
+                .collect();
+            testing::json(&request.url, serde_json::json!({ "streamName": "Fake", "photos": photos }))
+        })
+    }
+
+    #[tokio::test]
+    async fn metadata_refresh_rewrites_the_sidecars_of_changed_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        download_album(&captioned(Some("Old")), &args(dir.path(), &["--xmp-sidecars"]), HASH, None, false, None, None)
+            .await
+            .unwrap();
+        assert!(fs::read_to_string(dir.path().join("IMG_0001.xmp")).unwrap().contains(">Old<"));
+        fs::remove_file(dir.path().join("IMG_0002.JPG")).unwrap();
+
+        let refresh = args(dir.path(), &["--album-metadata-only-refresh", "--xmp-sidecars"]);
+        download_album(&captioned(Some("New & improved")), &refresh, HASH, None, false, None, None).await.unwrap();
+
+        let sidecar = fs::read_to_string(dir.path().join("IMG_0001.xmp")).unwrap();
+        assert!(sidecar.contains(">New &amp; improved<"), "{}", sidecar);
+        // The file is gone, so its sidecar is left as it was
+        assert!(fs::read_to_string(dir.path().join("IMG_0002.xmp")).unwrap().contains(">Old<"));
+        assert_eq!(fs::read(dir.path().join("IMG_0001.JPG")).unwrap(), b"first photo");
+    }
+
+    #[tokio::test]
+    async fn metadata_refresh_without_sidecars_leaves_them_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        download_album(&captioned(Some("Old")), &args(dir.path(), &["--xmp-sidecars"]), HASH, None, false, None, None)
+            .await
+            .unwrap();
+
+        let refresh = args(dir.path(), &["--album-metadata-only-refresh"]);
+        download_album(&captioned(Some("New")), &refresh, HASH, None, false, None, None).await.unwrap();
+
+        assert!(fs::read_to_string(dir.path().join("IMG_0001.xmp")).unwrap().contains(">Old<"));
+    }
+
+    /// An album of one photo whose download is cut short the first `cut` times.
+    fn cut_short(content: &'static [u8], cut: usize) -> FakeClient {
+        let album = testing::album(vec![FakePhoto::new("P1", "IMG_0001.JPG", content)]);
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        FakeClient::new(move |request| {
+            let download = request.url.starts_with("https://files.test/");
+            if download && attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < cut {
+                return testing::file(&request.url, &content[..content.len() / 2], content.len() as u64);
+            }
+            album(request)
+        })
+    }
+
+    #[tokio::test]
+    async fn a_body_cut_short_is_fetched_again_after_a_pause() {
+        let content: &[u8] = b"a photo that arrives in full the second time";
+        let client = cut_short(content, 1);
+        let dir = tempfile::tempdir().unwrap();
+        let info = testing::download_info("P1", "IMG_0001.JPG", Some(content.len() as u64));
+        let options = DownloadOptions::from_args(&args(dir.path(), &[]));
+        let dashboard = Dashboard::new(1, None);
+
+        let started = Instant::now();
+        let output = dir.path().to_str().unwrap();
+        download_single_photo(&client, &info, output, &options, Some(&dashboard)).await.unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(client.count("files.test"), 2);
+        assert_eq!(fs::read(dir.path().join("IMG_0001.JPG")).unwrap(), content);
+        // Progress started over instead of counting the first half twice
+        assert_eq!(dashboard.received_bytes(), content.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn a_body_that_keeps_getting_cut_short_fails_without_a_file() {
+        let content: &[u8] = b"a photo that never arrives in full";
+        let client = cut_short(content, usize::MAX);
+        let dir = tempfile::tempdir().unwrap();
+        let info = testing::download_info("P1", "IMG_0001.JPG", Some(content.len() as u64));
+        let options = DownloadOptions::from_args(&args(dir.path(), &[]));
+
+        let output = dir.path().to_str().unwrap();
+        let Err(error) = download_single_photo(&client, &info, output, &options, None).await else {
+            panic!("expected the download to fail");
+        };
+
+        assert!(error.is::<integrity::TruncatedBody>(), "{:#}", error);
+        assert_eq!(client.count("files.test"), TRUNCATED_DOWNLOAD_ATTEMPTS as usize);
+        assert!(listing(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn current_album_links_are_parsed() {
+        for url in [
+            "https://www.icloud.com/sharedalbum/#B2T5oqs3q2VPkhS",
+            "http://icloud.com/sharedalbum/#B2T5oqs3q2VPkhS",
+            "https://www.icloud.com/sharedalbum/en-gb/#B2T5oqs3q2VPkhS",
+            "https://www.icloud.com/sharedalbum/fr/#B2T5oqs3q2VPkhS",
+            "https://www.icloud.com.cn/sharedalbum/zh-cn/#B2T5oqs3q2VPkhS",
+            "HTTPS://WWW.ICLOUD.COM/SHAREDALBUM/#B2T5oqs3q2VPkhS",
+        ] {
+            assert_eq!(extract_hash_from_url(url).unwrap(), "B2T5oqs3q2VPkhS", "{}", url);
+        }
+    }
+
+    #[test]
+    fn photo_stream_links_are_parsed() {
+        assert_eq!(extract_hash_from_url("https://www.icloud.com/photostream/#A2GqDbcx0fMNZ").unwrap(), "A2GqDbcx0fMNZ");
+        assert_eq!(extract_hash_from_url("icloud.com/photostream/de/#A2GqDbcx0fMNZ").unwrap(), "A2GqDbcx0fMNZ");
+    }
+
+    #[test]
+    fn api_endpoints_are_parsed() {
+        let url = "https://p153-sharedstreams.icloud.com/B2T5oqs3q2VPkhS/sharedstreams/webstream";
+        assert_eq!(extract_hash_from_url(url).unwrap(), "B2T5oqs3q2VPkhS");
+        let url = "p06-sharedstreams.icloud.com/A6GJqs8CbDvnQt/sharedstreams/webasseturls";
+        assert_eq!(extract_hash_from_url(url).unwrap(), "A6GJqs8CbDvnQt");
+    }
+
+    #[test]
+    fn bare_tokens_are_parsed() {
+        assert_eq!(extract_hash_from_url("B2T5oqs3q2VPkhS").unwrap(), "B2T5oqs3q2VPkhS");
+        assert_eq!(extract_hash_from_url("A2GqDbcx0fMNZ").unwrap(), "A2GqDbcx0fMNZ");
+    }
+
+    #[test]
+    fn bare_tokens_must_look_like_album_tokens() {
+        // Surrounding whitespace, as pasted, is fine
+        assert_eq!(extract_hash_from_url("  B2T5oqs3q2VPkhS\n").unwrap(), "B2T5oqs3q2VPkhS");
+        // 13 to 20 characters in all
+        assert_eq!(extract_hash_from_url("B2T5oqs3q2VPk").unwrap(), "B2T5oqs3q2VPk");
+        assert_eq!(extract_hash_from_url("B2T5oqs3q2VPkhS12345").unwrap(), "B2T5oqs3q2VPkhS12345");
+        assert!(extract_hash_from_url("B2T5oqs3q2VP").is_err());
+        assert!(extract_hash_from_url("B2T5oqs3q2VPkhS123456").is_err());
+        // Only A and B tokens exist
+        assert!(extract_hash_from_url("C2T5oqs3q2VPkhS").is_err());
+        assert!(extract_hash_from_url("b2T5oqs3q2VPkhS").is_err());
+        assert!(extract_hash_from_url("B2T5oqs3q-VPkhS").is_err());
+        assert!(extract_hash_from_url("B2T5oqs3q2VPkhS B2T5oqs3q2VPkhS").is_err());
+    }
+
+    fn page(url: &str, body: &str) -> HttpResponse {
+        HttpResponse::canned(reqwest::StatusCode::OK, url, reqwest::header::HeaderMap::new(), Bytes::from(body.to_string()))
+    }
+
+    #[tokio::test]
+    async fn short_links_are_followed_to_the_album() {
+        // Redirected: the final URL is the album link
+        let client = FakeClient::new(|_| page("https://www.icloud.com/sharedalbum/#B2T5oqs3q2VPkhS", "<html></html>"));
+        assert_eq!(resolve_album_hash(&client, "https://share.icloud.com/photos/0abcDEF").await.unwrap(), "B2T5oqs3q2VPkhS");
+        assert_eq!(client.requests()[0].url, "https://share.icloud.com/photos/0abcDEF");
+
+        // Forwarded by the page's JavaScript, and given without a scheme
+        let client = FakeClient::new(|request| {
+            page(&request.url, r#"<script>location.href = "https://www.icloud.com/sharedalbum/#B2T5oqs3q2VPkhS"</script>"#)
+        });
+        assert_eq!(resolve_album_hash(&client, " share.icloud.com/photos/0abcDEF ").await.unwrap(), "B2T5oqs3q2VPkhS");
+        assert_eq!(client.requests()[0].url, "https://share.icloud.com/photos/0abcDEF");
+    }
+
+    #[tokio::test]
+    async fn short_links_that_lead_nowhere_are_errors() {
+        let client = FakeClient::new(|request| page(&request.url, "<html>Not found</html>"));
+        let error = resolve_album_hash(&client, "https://share.icloud.com/photos/0abcDEF").await.unwrap_err();
+        assert!(error.to_string().contains("doesn't lead to a shared album"), "{}", error);
+
+        let client = FakeClient::new(|request| testing::status(&request.url, 404));
+        assert!(resolve_album_hash(&client, "https://share.icloud.com/photos/0abcDEF").await.is_err());
+
+        // Anything else is parsed without a request
+        let client = FakeClient::new(|request| testing::status(&request.url, 500));
+        assert_eq!(resolve_album_hash(&client, "B2T5oqs3q2VPkhS").await.unwrap(), "B2T5oqs3q2VPkhS");
+        assert!(client.requests().is_empty());
+    }
+
+    #[test]
+    fn derivative_names_and_raw_keys_are_accepted() {
+        let parsed = Args::try_parse_from(["icloud-photo-download", "--url", "x", "--derivatives", "thumb, full,342,720p"]);
+        assert_eq!(parsed.unwrap().derivatives.unwrap(), ["thumb", "full", "342", "720p"]);
+    }
+
+    #[test]
+    fn unknown_derivative_names_are_refused_with_the_valid_ones() {
+        for bad in ["large", "Full", "p", "", "34a"] {
+            let error = parse_derivative_name(bad).unwrap_err();
+            assert!(error.contains("full, medium, thumb"), "{}", error);
+        }
+        let parsed = Args::try_parse_from(["icloud-photo-download", "--url", "x", "--derivatives", "thumb,large"]);
+        let Err(error) = parsed else { panic!("--derivatives large was accepted") };
+        assert!(error.to_string().contains("'large' is not a derivative"), "{}", error);
+    }
+
+    #[test]
+    fn mobileme_and_unknown_links_are_refused() {
+        let error = extract_hash_from_url("https://gallery.me.com/someone#100001").unwrap_err().to_string();
+        assert!(error.contains("MobileMe"), "{}", error);
+        assert!(extract_hash_from_url("https://www.icloud.com/photos/#B2T5oqs3q2VPkhS").is_err());
+        assert!(extract_hash_from_url("https://www.icloud.com/sharedalbum/").is_err());
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("IMG%200001.JPG"), "IMG 0001.JPG");
+        assert_eq!(percent_decode("Caf%C3%A9.jpg"), "Caf\u{e9}.jpg");
+        assert_eq!(percent_decode("100%25"), "100%");
+        // Malformed escapes are kept as they are
+        assert_eq!(percent_decode("50%.jpg"), "50%.jpg");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+    }
+
+    #[test]
+    fn url_paths_give_clean_filenames() {
+        assert_eq!(filename_from_url_path("/ab/IMG%200001.JPG?o=AtZ&v=1").as_deref(), Some("IMG 0001.JPG"));
+        assert_eq!(filename_from_url_path("/ab/My%20Trip%20%281%29.mov#t=0").as_deref(), Some("My Trip (1).mov"));
+    }
+
+    #[test]
+    fn url_paths_cant_leave_the_output_directory() {
+        assert_eq!(filename_from_url_path("/ab/..%2F..%2Fetc%2Fpasswd").as_deref(), Some("passwd"));
+        assert_eq!(filename_from_url_path("/ab/..%5C..%5Cevil.jpg").as_deref(), Some("evil.jpg"));
+        assert_eq!(filename_from_url_path("/ab/..%2F"), None);
+        assert_eq!(filename_from_url_path("/ab/%2E%2E"), None);
+        assert_eq!(filename_from_url_path("/ab/.hidden.jpg"), None);
+        assert_eq!(filename_from_url_path("/ab/bad%0Aname.jpg"), None);
+        assert_eq!(filename_from_url_path("/ab/"), None);
+    }
+
+    #[test]
+    fn safe_filenames_keep_only_the_last_component() {
+        assert_eq!(safe_filename("../../x.jpg").as_deref(), Some("x.jpg"));
+        assert_eq!(safe_filename("C:\\Users\\x.jpg").as_deref(), Some("x.jpg"));
+        assert_eq!(safe_filename("  IMG 1.jpg  ").as_deref(), Some("IMG 1.jpg"));
+        assert_eq!(safe_filename(".."), None);
+        assert_eq!(safe_filename("dir/"), None);
+    }
+
+    /// Names of the files in `dir`, hidden ones included.
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_download_never_leaves_a_partial_file() {
+        let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let client = FakeClient::new(testing::album(vec![FakePhoto::new("P1", "IMG_0001.MOV", &content)]));
+        let info = testing::download_info("P1", "IMG_0001.MOV", Some(content.len() as u64));
+
+        // Give up at ever later points, as --per-file-timeout would
+        let mut abandoned = 0;
+        for step in 0..40u64 {
+            let dir = tempfile::tempdir().unwrap();
+            let output = dir.path().to_str().unwrap();
+            let options = DownloadOptions::from_args(&args(dir.path(), &[]));
+            let download = download_single_photo(&client, &info, output, &options, None);
+            let finished = tokio::time::timeout(Duration::from_micros(step * 50), download).await.is_ok();
+            abandoned += usize::from(!finished);
+
+            let files = listing(dir.path());
+            match fs::read(dir.path().join("IMG_0001.MOV")) {
+                Ok(saved) => assert_eq!(saved, content, "a partial file was left at the final path"),
+                Err(_) => assert!(!finished),
+            }
+            assert!(files.iter().all(|name| !name.ends_with(".tmp")), "left behind: {:?}", files);
+        }
+        assert!(abandoned > 0);
+    }
 }
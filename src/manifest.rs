@@ -0,0 +1,526 @@
+// Record of every file this tool has saved to the output directory.
+//
+// Each completed download is appended to `manifest.jsonl` right away, so a
+// crash or kill mid-run loses nothing. Appends go through `append_line`,
+// which closes off a line cut short by a crash, and compaction skips such a
+// line rather than failing on it. At the end of a run (and at the start
+// of the next one, if a crash left lines behind) the log is folded into
+// `manifest.json`, keeping the latest entry per file.
+//
+// With --manifest-format csv the compacted manifest is also exported as
+// `manifest.csv` next to it, whenever it's compacted, even by a run that
+// downloaded nothing new. With --manifest-format sqlite it goes into one
+// database shared by every album downloaded to the same --output
+// (`<output>/.icloud-dl/manifest.sqlite`, or --manifest-db), one row per
+// album, photo and checksum, so a single query covers all of them. The JSON
+// files stay the tool's own record either way.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::atomic::{append_line, atomic_write};
+use crate::{dates, workdir};
+use crate::{Args, AssetKind, DownloadInfo, Photo, SavedFile};
+
+pub const MANIFEST_LOG_NAME: &str = "manifest.jsonl";
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const MANIFEST_CSV_NAME: &str = "manifest.csv";
+pub const MANIFEST_SQLITE_NAME: &str = "manifest.sqlite";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestFormat {
+    /// Just manifest.json
+    Json,
+    /// Also export manifest.csv, for spreadsheets
+    Csv,
+    /// Also export manifest.sqlite (needs the `sqlite` build feature)
+    Sqlite,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub filename: String,
+    pub photo_guid: String,
+    pub checksum: String,
+    pub kind: String,
+    pub size: u64,
+    pub downloaded_at: String,
+    /// `downloaded`, or `size-mismatch` for a file kept despite not matching the listed size.
+    #[serde(default = "default_status")]
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// What the manifest is exported as besides `manifest.json`, and for which album.
+// Only the SQLite export, which needs the feature, records the album
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct ManifestExport {
+    pub format: ManifestFormat,
+    /// The SQLite database, shared by all albums.
+    pub database: PathBuf,
+    pub album_hash: String,
+    pub album_name: Option<String>,
+}
+
+impl ManifestExport {
+    pub fn new(args: &Args, album_hash: &str, album_name: Option<&str>) -> Self {
+        Self {
+            format: args.manifest_format,
+            database: args
+                .manifest_db
+                .clone()
+                .unwrap_or_else(|| workdir::tool_dir(&args.output).join(MANIFEST_SQLITE_NAME)),
+            album_hash: album_hash.to_string(),
+            album_name: album_name.map(str::to_string),
+        }
+    }
+}
+
+/// Entries written before the status was recorded were all plain downloads.
+fn default_status() -> String {
+    "downloaded".to_string()
+}
+
+pub struct Manifest {
+    dir: PathBuf,
+    export: ManifestExport,
+    /// Held while appending to or compacting the log.
+    log: Mutex<()>,
+}
+
+impl Manifest {
+    /// Opens the manifest in `dir` (the tool directory), first folding in
+    /// any log left behind by an interrupted run.
+    pub fn open(dir: PathBuf, export: ManifestExport) -> Result<Self> {
+        if export.format == ManifestFormat::Sqlite && !cfg!(feature = "sqlite") {
+            return Err(anyhow!(
+                "This build has no SQLite support; rebuild with `cargo build --release --features sqlite`"
+            ));
+        }
+        let manifest = Self { dir, export, log: Mutex::new(()) };
+        if manifest.log_path().exists() {
+            manifest.compact()?;
+        }
+        Ok(manifest)
+    }
+
+    /// Appends one entry. Lines are written whole under the lock, so
+    /// concurrent downloads never interleave.
+    pub fn record(&self, info: &DownloadInfo, saved: &SavedFile) {
+        let (width, height) = info
+            .size_info
+            .split_once('x')
+            .map_or((None, None), |(width, height)| (width.parse().ok(), height.parse().ok()));
+        let entry = ManifestEntry {
+            filename: saved.filename.clone(),
+            photo_guid: info.photo_guid.clone(),
+            checksum: info.checksum.clone(),
+            kind: kind_name(info.kind).to_string(),
+            size: saved.size,
+            downloaded_at: Utc::now().to_rfc3339(),
+            status: if saved.size_mismatch.is_some() { "size-mismatch" } else { "downloaded" }.to_string(),
+            caption: info.caption.clone(),
+            date_created: info.date_created.map(|date| date.to_rfc3339()),
+            width,
+            height,
+        };
+        let Ok(mut line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        line.push('\n');
+
+        let _log = self.log.lock().unwrap();
+        let _ = fs::create_dir_all(&self.dir);
+        if let Err(e) = append_line(&self.log_path(), &line) {
+            eprintln!("⚠️  Could not record {} in the manifest: {:#}", saved.filename, e);
+        }
+    }
+
+    /// Merges the log into `manifest.json` and removes the log. The export
+    /// is written either way, so one asked for on an album that's already
+    /// fully downloaded appears too.
+    pub fn compact(&self) -> Result<()> {
+        let _log = self.log.lock().unwrap();
+
+        let manifest_path = self.dir.join(MANIFEST_NAME);
+        let mut entries = read_manifest(&manifest_path)?;
+        let log_path = self.log_path();
+        if !log_path.exists() {
+            if manifest_path.exists() {
+                self.export(&entries.into_values().collect::<Vec<_>>())?;
+            }
+            return Ok(());
+        }
+
+        let file = File::open(&log_path)
+            .with_context(|| format!("Failed to read {}", log_path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Failed to read {}", log_path.display()))?;
+            // A crash can leave a half-written last line behind
+            if let Ok(entry) = serde_json::from_str::<ManifestEntry>(&line) {
+                entries.insert(entry.filename.clone(), entry);
+            }
+        }
+
+        self.save(entries.into_values().collect())?;
+        fs::remove_file(&log_path)
+            .with_context(|| format!("Failed to remove {}", log_path.display()))?;
+        Ok(())
+    }
+
+    /// Every entry of the compacted manifest.
+    pub fn entries(&self) -> Result<Vec<ManifestEntry>> {
+        Ok(read_manifest(&self.dir.join(MANIFEST_NAME))?.into_values().collect())
+    }
+
+    /// Updates the caption and capture date of every entry from fresh album
+    /// metadata, matched by photo GUID, without touching the files. Returns
+    /// the entries that changed, as updated, and how many belong to photos
+    /// no longer in the album.
+    pub fn refresh_metadata(&self, photos: &[Photo]) -> Result<(Vec<ManifestEntry>, usize)> {
+        self.compact()?;
+        let manifest_path = self.dir.join(MANIFEST_NAME);
+        if !manifest_path.exists() {
+            return Err(anyhow!("No manifest at {}; download the album first", manifest_path.display()));
+        }
+
+        let photos: HashMap<&str, &Photo> = photos.iter().map(|photo| (photo.photo_guid.as_str(), photo)).collect();
+        let mut entries: Vec<ManifestEntry> = read_manifest(&manifest_path)?.into_values().collect();
+        let mut changed = Vec::new();
+        let mut missing = 0;
+        for entry in &mut entries {
+            let Some(photo) = photos.get(entry.photo_guid.as_str()) else {
+                missing += 1;
+                continue;
+            };
+            let date_created = photo
+                .date_created
+                .as_deref()
+                .and_then(dates::parse_date_created)
+                .map(|date| date.to_rfc3339());
+            if entry.caption != photo.caption || entry.date_created != date_created {
+                entry.caption = photo.caption.clone();
+                entry.date_created = date_created;
+                changed.push(entry.clone());
+            }
+        }
+
+        if !changed.is_empty() {
+            self.save(entries)?;
+        }
+        Ok((changed, missing))
+    }
+
+    /// Writes `manifest.json` and, with --manifest-format, its export.
+    fn save(&self, entries: Vec<ManifestEntry>) -> Result<()> {
+        let json = serde_json::to_string_pretty(&entries)?;
+        atomic_write(&self.dir.join(MANIFEST_NAME), json.as_bytes())?;
+        self.export(&entries)
+    }
+
+    /// Writes the --manifest-format export of `entries`.
+    fn export(&self, entries: &[ManifestEntry]) -> Result<()> {
+        match self.export.format {
+            ManifestFormat::Json => {}
+            ManifestFormat::Csv => atomic_write(&self.dir.join(MANIFEST_CSV_NAME), to_csv(entries).as_bytes())?,
+            #[cfg(feature = "sqlite")]
+            ManifestFormat::Sqlite => sqlite::upsert(&self.export, entries)?,
+            #[cfg(not(feature = "sqlite"))]
+            ManifestFormat::Sqlite => unreachable!("rejected in Manifest::open"),
+        }
+        Ok(())
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_LOG_NAME)
+    }
+}
+
+/// The `(photo_guid, checksum)` pairs recorded in a manifest from anywhere,
+/// for --since-manifest. Accepts a compacted `manifest.json` or a
+/// `manifest.jsonl` log.
+pub fn load_known_assets(path: &Path) -> Result<HashSet<(String, String)>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let entries: Vec<ManifestEntry> = match serde_json::from_slice(&data) {
+        Ok(entries) => entries,
+        Err(_) => data
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("{} is not a manifest.json or manifest.jsonl file", path.display()))?,
+    };
+
+    Ok(entries.into_iter().map(|entry| (entry.photo_guid, entry.checksum)).collect())
+}
+
+/// Names of every file recorded in the manifest in `dir`, including entries
+/// still in the log, without writing anything.
+pub fn recorded_filenames(dir: &Path) -> Result<HashSet<String>> {
+    let mut names: HashSet<String> = read_manifest(&dir.join(MANIFEST_NAME))?.into_keys().collect();
+    let log_path = dir.join(MANIFEST_LOG_NAME);
+    match fs::read(&log_path) {
+        Ok(data) => names.extend(
+            data.split(|&b| b == b'\n')
+                .filter_map(|line| serde_json::from_slice::<ManifestEntry>(line).ok())
+                .map(|entry| entry.filename),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", log_path.display())),
+    }
+    Ok(names)
+}
+
+fn read_manifest(path: &Path) -> Result<BTreeMap<String, ManifestEntry>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let entries: Vec<ManifestEntry> = serde_json::from_slice(&data)
+        .with_context(|| format!("{} is not a valid manifest", path.display()))?;
+    Ok(entries.into_iter().map(|entry| (entry.filename.clone(), entry)).collect())
+}
+
+const CSV_HEADER: &str = "filename,photo_guid,checksum,kind,size,status,caption,date_created,width,height,downloaded_at";
+
+fn to_csv(entries: &[ManifestEntry]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push_str("\r\n");
+    for entry in entries {
+        let fields = [
+            csv_field(&entry.filename),
+            csv_field(&entry.photo_guid),
+            csv_field(&entry.checksum),
+            csv_field(&entry.kind),
+            entry.size.to_string(),
+            csv_field(&entry.status),
+            csv_field(entry.caption.as_deref().unwrap_or_default()),
+            csv_field(entry.date_created.as_deref().unwrap_or_default()),
+            entry.width.map(|w| w.to_string()).unwrap_or_default(),
+            entry.height.map(|h| h.to_string()).unwrap_or_default(),
+            csv_field(&entry.downloaded_at),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Quotes a field when it contains a separator, quote or line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use anyhow::{Context, Result};
+    use rusqlite::{params, Connection};
+
+    use super::{ManifestEntry, ManifestExport};
+
+    /// One row per downloaded asset. Rows are keyed by album, photo GUID and
+    /// checksum, so a later run updates the row for a file it saves again.
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS photos (
+        album TEXT NOT NULL,
+        album_name TEXT,
+        guid TEXT NOT NULL,
+        checksum TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        caption TEXT,
+        date_created TEXT,
+        width INTEGER,
+        height INTEGER,
+        size INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        downloaded_at TEXT NOT NULL,
+        PRIMARY KEY (album, guid, checksum)
+    )";
+
+    pub fn upsert(export: &ManifestExport, entries: &[ManifestEntry]) -> Result<()> {
+        let path = &export.database;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut db = Connection::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        db.execute(SCHEMA, [])?;
+
+        let tx = db.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO photos (album, album_name, guid, checksum, filename, kind, caption, date_created, width, height, size, status, downloaded_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+                 ON CONFLICT (album, guid, checksum) DO UPDATE SET
+                    album_name = excluded.album_name, filename = excluded.filename, kind = excluded.kind, caption = excluded.caption,
+                    date_created = excluded.date_created, width = excluded.width, height = excluded.height,
+                    size = excluded.size, status = excluded.status, downloaded_at = excluded.downloaded_at",
+            )?;
+            for entry in entries {
+                insert.execute(params![
+                    export.album_hash,
+                    export.album_name,
+                    entry.photo_guid,
+                    entry.checksum,
+                    entry.filename,
+                    entry.kind,
+                    entry.caption,
+                    entry.date_created,
+                    entry.width,
+                    entry.height,
+                    entry.size as i64,
+                    entry.status,
+                    entry.downloaded_at,
+                ])?;
+            }
+        }
+        tx.commit().with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn kind_name(kind: AssetKind) -> &'static str {
+    match kind {
+        AssetKind::Still => "photo",
+        AssetKind::Video => "video",
+        AssetKind::LiveMotion => "live-photo-video",
+    }
+}
+
+fn kind_from_name(name: &str) -> AssetKind {
+    match name {
+        "video" => AssetKind::Video,
+        "live-photo-video" => AssetKind::LiveMotion,
+        _ => AssetKind::Still,
+    }
+}
+
+impl ManifestEntry {
+    /// The entry as a download with what its XMP sidecar is written from
+    /// (caption, capture date, kind). It has no URL to download from.
+    pub fn sidecar_info(&self) -> DownloadInfo {
+        DownloadInfo {
+            photo_guid: self.photo_guid.clone(),
+            checksum: self.checksum.clone(),
+            download_url: String::new(),
+            filename: self.filename.clone(),
+            photo_stem: crate::file_stem(&self.filename).to_string(),
+            size_info: String::new(),
+            caption: self.caption.clone(),
+            date_created: self.date_created.as_deref().and_then(dates::parse_date_created),
+            hosts: Vec::new(),
+            file_size: Some(self.size),
+            url_expiry: None,
+            kind: kind_from_name(&self.kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::download_info;
+
+    fn export(format: ManifestFormat, database: &Path, album: &str) -> ManifestExport {
+        ManifestExport {
+            format,
+            database: database.to_path_buf(),
+            album_hash: album.to_string(),
+            album_name: Some(format!("Album {}", album)),
+        }
+    }
+
+    fn saved(filename: &str) -> SavedFile {
+        SavedFile { filename: filename.to_string(), size: 3, size_mismatch: None, verified: None, format: None }
+    }
+
+    fn record(dir: &Path, format: ManifestFormat, database: &Path, album: &str, filename: &str) {
+        let manifest = Manifest::open(dir.to_path_buf(), export(format, database, album)).unwrap();
+        manifest.record(&download_info(filename, filename, Some(3)), &saved(filename));
+        manifest.compact().unwrap();
+    }
+
+    #[test]
+    fn csv_is_exported_even_when_nothing_new_was_downloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join(MANIFEST_SQLITE_NAME);
+        record(dir.path(), ManifestFormat::Json, &database, "A", "IMG_0001.JPG");
+        assert!(!dir.path().join(MANIFEST_CSV_NAME).exists());
+
+        let manifest = Manifest::open(dir.path().to_path_buf(), export(ManifestFormat::Csv, &database, "A")).unwrap();
+        manifest.compact().unwrap();
+
+        let csv = fs::read_to_string(dir.path().join(MANIFEST_CSV_NAME)).unwrap();
+        assert!(csv.starts_with(CSV_HEADER));
+        assert!(csv.contains("IMG_0001.JPG,IMG_0001.JPG,ckIMG_0001.JPG,photo,3,downloaded"));
+    }
+
+    #[test]
+    fn a_log_line_cut_short_by_a_crash_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join(MANIFEST_SQLITE_NAME);
+        let manifest = Manifest::open(dir.path().to_path_buf(), export(ManifestFormat::Json, &database, "A")).unwrap();
+        manifest.record(&download_info("P1", "IMG_0001.JPG", Some(3)), &saved("IMG_0001.JPG"));
+        let mut log = fs::OpenOptions::new().append(true).open(dir.path().join(MANIFEST_LOG_NAME)).unwrap();
+        std::io::Write::write_all(&mut log, b"{\"filename\":\"IMG_00").unwrap();
+        manifest.record(&download_info("P2", "IMG_0002.JPG", Some(3)), &saved("IMG_0002.JPG"));
+        manifest.compact().unwrap();
+
+        let names: Vec<String> = manifest.entries().unwrap().into_iter().map(|entry| entry.filename).collect();
+        assert_eq!(names, ["IMG_0001.JPG", "IMG_0002.JPG"]);
+        assert!(!dir.path().join(MANIFEST_LOG_NAME).exists());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn albums_share_one_sqlite_database() {
+        let root = tempfile::tempdir().unwrap();
+        let database = root.path().join(MANIFEST_SQLITE_NAME);
+        let (first, second) = (root.path().join("first"), root.path().join("second"));
+        record(&first, ManifestFormat::Sqlite, &database, "A", "IMG_0001.JPG");
+        record(&second, ManifestFormat::Sqlite, &database, "B", "IMG_0001.JPG");
+        // Compacting again updates the rows instead of adding more
+        record(&second, ManifestFormat::Sqlite, &database, "B", "IMG_0002.JPG");
+
+        let db = rusqlite::Connection::open(&database).unwrap();
+        let mut query = db.prepare("SELECT album, album_name, filename FROM photos ORDER BY album, filename").unwrap();
+        let rows: Vec<(String, String, String)> = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("A".to_string(), "Album A".to_string(), "IMG_0001.JPG".to_string()),
+                ("B".to_string(), "Album B".to_string(), "IMG_0001.JPG".to_string()),
+                ("B".to_string(), "Album B".to_string(), "IMG_0002.JPG".to_string()),
+            ]
+        );
+    }
+}
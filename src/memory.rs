@@ -0,0 +1,121 @@
+// `--max-memory`: a soft cap on the memory held by downloads in flight, for
+// small machines. Each file is held in memory in full until it's written, so
+// a few big videos at high --concurrent can take more than a small VPS has.
+// Before a download starts it reserves an estimate of what it will hold; when
+// the reservations would go over the cap, it waits for running downloads to
+// finish, so fewer files run at once than --concurrent allows.
+//
+// The estimate goes by the size the album lists, so it's a heuristic, not a
+// guarantee: the allocator, TLS buffers and the rest of the process come on
+// top, and files of unknown size are guessed at. A file estimated above the
+// whole cap still downloads, on its own.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::size::format_size;
+use crate::DownloadInfo;
+
+/// Reservations are counted in KiB, so the semaphore's u32 permit counts
+/// reach terabytes.
+const UNIT: u64 = 1024;
+
+/// Assumed for a file whose size the album doesn't list.
+const UNKNOWN_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Held per download besides its body: buffers, the request, its state.
+const PER_DOWNLOAD_OVERHEAD: u64 = 1024 * 1024;
+
+pub struct MemoryBudget {
+    cap: u64,
+    /// The cap in units, rounded up; all the semaphore ever holds.
+    total_units: u32,
+    units: Semaphore,
+    /// Whether a download is waiting for memory right now, so pausing is
+    /// reported once per stretch rather than per file.
+    throttling: AtomicBool,
+    /// Downloads that had to wait for memory.
+    delayed: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: u64) -> Self {
+        let total_units = cap.div_ceil(UNIT).clamp(1, u32::MAX as u64) as u32;
+        Self {
+            cap,
+            total_units,
+            units: Semaphore::new(total_units as usize),
+            throttling: AtomicBool::new(false),
+            delayed: AtomicUsize::new(0),
+        }
+    }
+
+    /// What a download is expected to hold: its body, which may briefly be
+    /// there twice while the buffer grows or metadata is stripped, plus some
+    /// overhead.
+    fn estimate(info: &DownloadInfo) -> u64 {
+        info.file_size.unwrap_or(UNKNOWN_SIZE) * 2 + PER_DOWNLOAD_OVERHEAD
+    }
+
+    /// Waits until the download's estimate fits in what's left of the cap,
+    /// and reserves it until the returned permit is dropped. Pausing and
+    /// resuming are reported through `report`.
+    pub async fn admit(&self, info: &DownloadInfo, report: impl Fn(String)) -> SemaphorePermit<'_> {
+        let units = (Self::estimate(info).div_ceil(UNIT)).clamp(1, u32::MAX as u64) as u32;
+        // A file larger than the whole cap waits for everything else to finish
+        let units = units.min(self.total_units);
+
+        if let Ok(permit) = self.units.try_acquire_many(units) {
+            if self.throttling.swap(false, Ordering::Relaxed) {
+                report("🧠 Memory freed up, downloads continue at full --concurrent".to_string());
+            }
+            return permit;
+        }
+
+        self.delayed.fetch_add(1, Ordering::Relaxed);
+        if !self.throttling.swap(true, Ordering::Relaxed) {
+            report(format!(
+                "🧠 Downloads in flight would take more than --max-memory {}, starting fewer at once ({} needs about {})",
+                format_size(self.cap),
+                info.filename,
+                format_size(Self::estimate(info))
+            ));
+        }
+        self.units.acquire_many(units).await.expect("the memory semaphore is never closed")
+    }
+
+    /// How many downloads had to wait for memory.
+    pub fn delayed(&self) -> usize {
+        self.delayed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::download_info;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn a_file_above_a_cap_that_isnt_whole_kib_still_starts() {
+        // 1.5 KiB: rounding the semaphore down but the request up would
+        // ask for a permit more than there ever are
+        let budget = MemoryBudget::new(1536);
+        let info = download_info("P1", "big.mov", Some(1 << 30));
+        let permit = tokio::time::timeout(Duration::from_secs(1), budget.admit(&info, |_| {})).await;
+        assert!(permit.is_ok(), "a file larger than the cap waited forever");
+    }
+
+    #[tokio::test]
+    async fn a_file_above_the_cap_waits_for_the_others() {
+        let budget = MemoryBudget::new(4 * 1024 * 1024);
+        let small = download_info("P1", "small.jpg", Some(1024));
+        let big = download_info("P2", "big.mov", Some(1 << 30));
+        let held = budget.admit(&small, |_| {}).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(50), budget.admit(&big, |_| {})).await;
+        assert!(waiting.is_err());
+        drop(held);
+        assert!(tokio::time::timeout(Duration::from_secs(1), budget.admit(&big, |_| {})).await.is_ok());
+        assert_eq!(budget.delayed(), 1);
+    }
+}
@@ -0,0 +1,384 @@
+// Removal of embedded metadata (EXIF, XMP, IPTC, comments) for --strip-metadata.
+//
+// Only metadata containers are touched; the compressed pixel data and colour
+// profile are written back byte-for-byte. HEIC keeps its metadata as items
+// whose offsets are listed elsewhere in the file, so rather than removing
+// them (and rewriting every offset) their bytes are overwritten in place.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::png::Png;
+use img_parts::webp::WebP;
+use img_parts::ImageEXIF;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Once;
+
+static UNSUPPORTED_WARNING: Once = Once::new();
+
+/// JPEG segments that carry metadata. APP0 (JFIF), APP2 (ICC profile) and
+/// APP14 (Adobe colour transform) are kept because they affect rendering.
+const JPEG_METADATA_MARKERS: [u8; 4] = [markers::APP1, markers::APP12, markers::APP13, markers::COM];
+
+/// PNG ancillary chunks that carry metadata.
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"iTXt", b"zTXt", b"tIME"];
+
+/// An empty but valid Exif item: the offset to the TIFF header, then a
+/// big-endian TIFF header pointing at an IFD with no entries.
+const EMPTY_EXIF: [u8; 18] = [0, 0, 0, 0, b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0];
+
+/// An empty XMP packet; the rest of the item is padded with spaces.
+const EMPTY_XMP: &[u8] = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"/>"#;
+
+/// Returns `content` with its metadata removed. Formats that can't be
+/// stripped (videos, GIF) are returned unchanged after a one-time warning.
+pub fn strip_metadata(content: Bytes, extension: &str) -> Result<Bytes> {
+    match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => {
+            let mut jpeg = Jpeg::from_bytes(content).context("Failed to parse JPEG")?;
+            jpeg.segments_mut()
+                .retain(|segment| !JPEG_METADATA_MARKERS.contains(&segment.marker()));
+            Ok(jpeg.encoder().bytes())
+        }
+        "png" => {
+            let mut png = Png::from_bytes(content).context("Failed to parse PNG")?;
+            png.chunks_mut()
+                .retain(|chunk| !PNG_METADATA_CHUNKS.contains(&&chunk.kind()));
+            Ok(png.encoder().bytes())
+        }
+        "webp" => {
+            let mut webp = WebP::from_bytes(content).context("Failed to parse WebP")?;
+            webp.set_exif(None);
+            webp.remove_chunks_by_id(*b"XMP ");
+            Ok(webp.encoder().bytes())
+        }
+        "heic" | "heif" | "avif" => blank_heic_metadata(content).context("Failed to parse HEIC"),
+        _ => {
+            UNSUPPORTED_WARNING.call_once(|| {
+                eprintln!("⚠️  --strip-metadata only handles JPEG, HEIC, PNG and WebP; other files (videos, GIF) are saved unchanged");
+            });
+            Ok(content)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum HeicMetadata {
+    Exif,
+    Xmp,
+}
+
+/// Overwrites the Exif and XMP items of a HEIC file with empty ones of the
+/// same length, leaving every other byte where it was.
+fn blank_heic_metadata(content: Bytes) -> Result<Bytes> {
+    let meta = find_box(&content, 0..content.len(), b"meta")?.context("no meta box")?;
+    // meta is a full box: its children follow the version and flags
+    let children = meta.start + 4..meta.end;
+    let items = match find_box(&content, children.clone(), b"iinf")? {
+        Some(iinf) => metadata_items(&content, iinf)?,
+        None => HashMap::new(),
+    };
+    if items.is_empty() {
+        return Ok(content);
+    }
+    let iloc = find_box(&content, children.clone(), b"iloc")?.context("no iloc box")?;
+    let idat = find_box(&content, children, b"idat")?;
+
+    let mut data = content.to_vec();
+    for (id, extents) in item_extents(&content, iloc, idat)? {
+        let Some(&kind) = items.get(&id) else { continue };
+        let empty = match kind {
+            HeicMetadata::Exif => &EMPTY_EXIF[..],
+            HeicMetadata::Xmp => EMPTY_XMP,
+        };
+        let total: usize = extents.iter().map(|extent| extent.len()).sum();
+        let mut replacement = if total >= empty.len() { empty.to_vec() } else { Vec::new() };
+        replacement.resize(total, if kind == HeicMetadata::Xmp { b' ' } else { 0 });
+        let mut replacement = replacement.as_slice();
+        for extent in extents {
+            let (part, rest) = replacement.split_at(extent.len());
+            data[extent].copy_from_slice(part);
+            replacement = rest;
+        }
+    }
+    Ok(Bytes::from(data))
+}
+
+/// Big-endian reads from `data` that fail instead of running past `end`.
+struct BoxReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> BoxReader<'a> {
+    fn new(data: &'a [u8], range: Range<usize>) -> Self {
+        Self { data, pos: range.start, end: range.end }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.end).context("box is cut short")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// An unsigned integer of `n` bytes (0 to 8).
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        Ok(self.bytes(n)?.iter().fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+    }
+
+    /// A NUL-terminated string.
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let rest = &self.data[self.pos..self.end];
+        let len = rest.iter().position(|&byte| byte == 0).unwrap_or(rest.len());
+        let string = self.bytes(len)?;
+        self.pos = (self.pos + 1).min(self.end);
+        Ok(string)
+    }
+}
+
+/// The contents of each box in `range`, with its type.
+fn boxes(data: &[u8], range: Range<usize>) -> Result<Vec<([u8; 4], Range<usize>)>> {
+    let mut reader = BoxReader::new(data, range.clone());
+    let mut found = Vec::new();
+    while reader.pos < range.end {
+        let start = reader.pos;
+        let size = reader.uint(4)?;
+        let kind: [u8; 4] = reader.bytes(4)?.try_into()?;
+        let end = match size {
+            0 => range.end,
+            1 => start.checked_add(usize::try_from(reader.uint(8)?)?).context("box size overflows")?,
+            size => start + usize::try_from(size)?,
+        };
+        anyhow::ensure!(end >= reader.pos && end <= range.end, "box extends past its parent");
+        found.push((kind, reader.pos..end));
+        reader.pos = end;
+    }
+    Ok(found)
+}
+
+fn find_box(data: &[u8], range: Range<usize>, kind: &[u8; 4]) -> Result<Option<Range<usize>>> {
+    Ok(boxes(data, range)?.into_iter().find(|(found, _)| found == kind).map(|(_, contents)| contents))
+}
+
+/// The IDs of the Exif and XMP items listed in an `iinf` box.
+fn metadata_items(data: &[u8], iinf: Range<usize>) -> Result<HashMap<u64, HeicMetadata>> {
+    let mut reader = BoxReader::new(data, iinf.clone());
+    let version = reader.uint(1)?;
+    reader.bytes(3)?;
+    reader.uint(if version == 0 { 2 } else { 4 })?;
+
+    let mut items = HashMap::new();
+    for (kind, infe) in boxes(data, reader.pos..iinf.end)? {
+        if &kind != b"infe" {
+            continue;
+        }
+        let mut reader = BoxReader::new(data, infe);
+        let version = reader.uint(1)?;
+        reader.bytes(3)?;
+        let id = reader.uint(if version == 3 { 4 } else { 2 })?;
+        reader.uint(2)?; // protection index
+        let content_type = if version >= 2 {
+            match reader.bytes(4)? {
+                b"Exif" => {
+                    items.insert(id, HeicMetadata::Exif);
+                    continue;
+                }
+                b"mime" => reader.string()?,
+                _ => continue,
+            }
+        } else {
+            reader.string()?; // item name
+            reader.string()?
+        };
+        if content_type == b"application/rdf+xml" {
+            items.insert(id, HeicMetadata::Xmp);
+        }
+    }
+    Ok(items)
+}
+
+/// Where in the file each item listed in an `iloc` box is stored. Items
+/// stored in another file or built from other items are left out.
+fn item_extents(
+    data: &[u8],
+    iloc: Range<usize>,
+    idat: Option<Range<usize>>,
+) -> Result<Vec<(u64, Vec<Range<usize>>)>> {
+    let mut reader = BoxReader::new(data, iloc);
+    let version = reader.uint(1)?;
+    reader.bytes(3)?;
+    let sizes = reader.uint(1)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0xf) as usize);
+    let sizes = reader.uint(1)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version >= 1 { (sizes & 0xf) as usize } else { 0 };
+    let item_count = reader.uint(if version < 2 { 2 } else { 4 })?;
+
+    let mut items = Vec::new();
+    for _ in 0..item_count {
+        let id = reader.uint(if version < 2 { 2 } else { 4 })?;
+        let construction_method = if version >= 1 { reader.uint(2)? & 0xf } else { 0 };
+        let data_reference_index = reader.uint(2)?;
+        let base_offset = reader.uint(base_offset_size)?;
+        let extent_count = reader.uint(2)?;
+        let source = match (construction_method, data_reference_index) {
+            (0, 0) => Some(0..data.len()),
+            (1, _) => idat.clone(),
+            _ => None,
+        };
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            reader.uint(index_size)?;
+            let offset = reader.uint(offset_size)?;
+            let length = reader.uint(length_size)?;
+            let Some(source) = &source else { continue };
+            let start = usize::try_from(base_offset.checked_add(offset).context("item offset overflows")?)?
+                .checked_add(source.start)
+                .context("item offset overflows")?;
+            // A length of 0 means the rest of the source
+            let end = match length {
+                0 => source.end,
+                length => start.checked_add(usize::try_from(length)?).context("item length overflows")?,
+            };
+            anyhow::ensure!(start <= end && end <= source.end, "item extends past the end of the file");
+            extents.push(start..end);
+        }
+        if source.is_some() {
+            items.push((id, extents));
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, body: &[u8]) -> Vec<u8> {
+        boxed(kind, &[&[version, 0, 0, 0][..], body].concat())
+    }
+
+    fn infe(id: u16, item_type: &[u8; 4], content_type: &str) -> Vec<u8> {
+        let mut body = [&id.to_be_bytes()[..], &[0, 0], item_type].concat();
+        if !content_type.is_empty() {
+            body.extend_from_slice(content_type.as_bytes());
+            body.push(0);
+        }
+        full_box(b"infe", 2, &body)
+    }
+
+    const PIXELS: &[u8] = b"compressed pixels";
+    const EXIF: &[u8] = b"\0\0\0\0MM\0*\0\0\0\x08 GPS 37.33 -122.03, iPhone 15 Pro";
+    const XMP: &[u8] = b"<x:xmpmeta><rdf:RDF>CreatorTool iPhone</rdf:RDF></x:xmpmeta>";
+
+    /// A HEIC file whose image, Exif and XMP items are stored in mdat.
+    fn heic() -> Vec<u8> {
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let iinf = full_box(
+            b"iinf",
+            0,
+            &[
+                &3u16.to_be_bytes()[..],
+                &infe(1, b"hvc1", ""),
+                &infe(2, b"Exif", ""),
+                &infe(3, b"mime", "application/rdf+xml"),
+            ]
+            .concat(),
+        );
+        let iloc = |mdat: u32| {
+            let mut body = vec![0x44, 0x00];
+            body.extend_from_slice(&3u16.to_be_bytes());
+            let mut offset = mdat;
+            for (id, item) in [(1u16, PIXELS), (2, EXIF), (3, XMP)] {
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend_from_slice(&[0, 0, 0, 1]); // data reference, one extent
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(&(item.len() as u32).to_be_bytes());
+                offset += item.len() as u32;
+            }
+            full_box(b"iloc", 0, &body)
+        };
+        let meta = |mdat| full_box(b"meta", 0, &[full_box(b"hdlr", 0, b"\0\0\0\0pict"), iinf.clone(), iloc(mdat)].concat());
+        let mdat_start = (ftyp.len() + meta(0).len() + 8) as u32;
+        [ftyp, meta(mdat_start), boxed(b"mdat", &[PIXELS, EXIF, XMP].concat())].concat()
+    }
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|window| window == part)
+    }
+
+    #[test]
+    fn heic_metadata_is_blanked_in_place() {
+        let original = heic();
+        let stripped = strip_metadata(Bytes::from(original.clone()), "HEIC").unwrap();
+
+        assert_eq!(stripped.len(), original.len());
+        assert!(contains(&stripped, PIXELS));
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"CreatorTool"));
+        assert!(contains(&stripped, &EMPTY_EXIF));
+        assert!(contains(&stripped, EMPTY_XMP));
+        // Everything before the items' data is untouched
+        let mdat = original.len() - PIXELS.len() - EXIF.len() - XMP.len();
+        assert_eq!(stripped[..mdat + PIXELS.len()], original[..mdat + PIXELS.len()]);
+    }
+
+    #[test]
+    fn heic_without_metadata_items_is_unchanged() {
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let iinf = full_box(b"iinf", 0, &[&1u16.to_be_bytes()[..], &infe(1, b"hvc1", "")].concat());
+        let original = [ftyp, full_box(b"meta", 0, &iinf), boxed(b"mdat", PIXELS)].concat();
+        assert_eq!(strip_metadata(Bytes::from(original.clone()), "heic").unwrap(), original);
+    }
+
+    #[test]
+    fn malformed_heic_is_an_error() {
+        let mut broken = heic();
+        broken.truncate(40);
+        assert!(strip_metadata(Bytes::from(broken), "heic").is_err());
+    }
+
+    fn segment(marker: u8, body: &[u8]) -> Vec<u8> {
+        [&[0xFF, marker][..], &((body.len() + 2) as u16).to_be_bytes(), body].concat()
+    }
+
+    #[test]
+    fn jpeg_metadata_segments_are_removed() {
+        let jfif = segment(markers::APP0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        let icc = segment(markers::APP2, b"ICC_PROFILE\0\x01\x01profile");
+        let jpeg = [
+            &[0xFF, 0xD8][..],
+            &jfif,
+            &segment(markers::APP1, &[b"Exif\0\0", EXIF].concat()),
+            &icc,
+            &segment(markers::COM, b"taken at home"),
+            &segment(markers::SOS, &[1, 1, 0, 0, 0x3F, 0]),
+            PIXELS,
+            &[0xFF, 0xD9],
+        ]
+        .concat();
+
+        let stripped = strip_metadata(Bytes::from(jpeg), "JPG").unwrap();
+        assert!(contains(&stripped, &jfif));
+        assert!(contains(&stripped, &icc));
+        assert!(contains(&stripped, PIXELS));
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"taken at home"));
+    }
+
+    #[test]
+    fn videos_are_saved_unchanged() {
+        let video = Bytes::from_static(b"\0\0\0\x18ftypqt  not parsed");
+        assert_eq!(strip_metadata(video.clone(), "mov").unwrap(), video);
+    }
+}
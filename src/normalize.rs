@@ -0,0 +1,67 @@
+// Unicode normalization of output filenames. macOS filesystems store names
+// decomposed (NFD) while iCloud and most other systems use the composed form
+// (NFC), so "Café.jpg" can exist on disk in a form that doesn't compare equal
+// to the name we'd write. Names are written in the platform's form and
+// compared regardless of form, so incremental runs recognise their own files.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// `name` in the form this platform's filesystems use: NFD on macOS, NFC elsewhere.
+pub fn platform_form(name: &str) -> String {
+    if cfg!(target_os = "macos") {
+        name.nfd().collect()
+    } else {
+        name.nfc().collect()
+    }
+}
+
+/// Whether two names are the same once normalized.
+pub fn same_name(a: &str, b: &str) -> bool {
+    a == b || a.nfc().eq(b.nfc())
+}
+
+/// `name` composed (NFC), for keying names regardless of their form.
+pub fn composed(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// `name` composed and lower-cased, for telling whether two names would be
+/// the same file on a case-insensitive filesystem.
+pub fn folded(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "Café Été.jpg", composed as iCloud sends it and decomposed as macOS stores it.
+    const NFC: &str = "Caf\u{e9} \u{c9}t\u{e9}.jpg";
+    const NFD: &str = "Cafe\u{301} E\u{301}te\u{301}.jpg";
+
+    #[test]
+    fn accented_names_match_in_either_form() {
+        assert_ne!(NFC, NFD);
+        assert!(same_name(NFC, NFD));
+        assert!(same_name(NFD, NFC));
+        assert!(same_name(NFC, NFC));
+        assert!(!same_name(NFC, "Cafe Ete.jpg"));
+    }
+
+    #[test]
+    fn composed_and_folded_forms_agree() {
+        assert_eq!(composed(NFD), NFC);
+        assert_eq!(composed(NFC), NFC);
+        assert_eq!(folded(NFD), "caf\u{e9} \u{e9}t\u{e9}.jpg");
+        assert_eq!(folded(NFD), folded(&NFC.to_uppercase()));
+    }
+
+    #[test]
+    fn names_are_written_in_the_platform_form() {
+        let expected = if cfg!(target_os = "macos") { NFD } else { NFC };
+        assert_eq!(platform_form(NFC), expected);
+        assert_eq!(platform_form(NFD), expected);
+        // Names without accents are left alone
+        assert_eq!(platform_form("IMG_0001.JPG"), "IMG_0001.JPG");
+    }
+}
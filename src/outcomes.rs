@@ -0,0 +1,118 @@
+// Per-file outcomes for --summary-table. Only collected when the table was
+// asked for, so normal runs don't hold a record per file.
+
+use comfy_table::presets::UTF8_FULL_CONDENSED;
+use comfy_table::{ContentArrangement, Table};
+use std::sync::Mutex;
+
+use crate::caption::truncate_at_char_boundary;
+use crate::{size, DownloadInfo};
+
+/// Rows beyond this are summarised instead of printed.
+const MAX_ROWS: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum TableScope {
+    /// Only failed, size-mismatched and skipped files
+    Problems,
+    /// Every file
+    All,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    // Declaration order is the sort order: failures first
+    Failed,
+    SizeMismatch,
+    Skipped,
+    Downloaded,
+}
+
+struct Outcome {
+    status: Status,
+    filename: String,
+    size: Option<u64>,
+    resolution: String,
+    detail: String,
+}
+
+pub struct OutcomeTable {
+    scope: TableScope,
+    outcomes: Mutex<Vec<Outcome>>,
+}
+
+impl OutcomeTable {
+    pub fn new(scope: TableScope) -> Self {
+        Self {
+            scope,
+            outcomes: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record_downloaded(&self, info: &DownloadInfo, saved_as: &str) {
+        self.push(Status::Downloaded, info, saved_as, String::new());
+    }
+
+    pub fn record_failed(&self, info: &DownloadInfo, error: &anyhow::Error) {
+        self.push(Status::Failed, info, &info.filename, format!("{:#}", error));
+    }
+
+    pub fn record_size_mismatch(&self, info: &DownloadInfo, saved_as: &str, detail: &str) {
+        self.push(Status::SizeMismatch, info, saved_as, detail.to_string());
+    }
+
+    pub fn record_skipped(&self, info: &DownloadInfo, reason: &str) {
+        self.push(Status::Skipped, info, &info.filename, reason.to_string());
+    }
+
+    fn push(&self, status: Status, info: &DownloadInfo, filename: &str, detail: String) {
+        if self.scope == TableScope::Problems && status == Status::Downloaded {
+            return;
+        }
+        self.outcomes.lock().unwrap().push(Outcome {
+            status,
+            filename: filename.to_string(),
+            size: info.file_size,
+            resolution: info.size_info.clone(),
+            detail,
+        });
+    }
+
+    pub fn print(&self) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        if outcomes.is_empty() {
+            if self.scope == TableScope::Problems {
+                status!("\n✨ No failed, mismatched or skipped files");
+            }
+            return;
+        }
+        outcomes.sort_by(|a, b| a.status.cmp(&b.status).then_with(|| a.filename.cmp(&b.filename)));
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL_CONDENSED)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec!["", "File", "Size", "Resolution", "Details"]);
+
+        for outcome in outcomes.iter().take(MAX_ROWS) {
+            let status = match outcome.status {
+                Status::Downloaded => "✓",
+                Status::Failed => "✗",
+                Status::SizeMismatch => "⚠",
+                Status::Skipped => "skip",
+            };
+            table.add_row(vec![
+                status.to_string(),
+                outcome.filename.clone(),
+                outcome.size.map_or("?".to_string(), size::format_size),
+                outcome.resolution.clone(),
+                truncate_at_char_boundary(&outcome.detail, 120).to_string(),
+            ]);
+        }
+
+        status!("\n{}", table);
+        if outcomes.len() > MAX_ROWS {
+            status!("… and {} more", outcomes.len() - MAX_ROWS);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+// Human-readable status output. It normally goes to stdout, but modes that
+// use stdout for machine-readable data switch it to stderr so the data
+// stream stays clean.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends all further status output to stderr.
+pub fn redirect_to_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn status_line(args: fmt::Arguments<'_>) {
+    if TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// `println!` for status messages; see `redirect_to_stderr`.
+macro_rules! status {
+    () => {
+        $crate::output::status_line(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::status_line(format_args!($($arg)*))
+    };
+}
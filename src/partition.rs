@@ -0,0 +1,62 @@
+// The partition of a shared album, read from its token. The token's first
+// character says how many base62 digits follow that encode the partition:
+// one after an `A`, two after a `B`, so `A6...` is partition 6 and `B2l...`
+// is 2 × 62 + 47 = 171. Its API host is `p<NN>-sharedstreams.icloud.com`, so the first request
+// can go straight there instead of being redirected from the default host.
+//
+// Apple doesn't document any of this; a token that doesn't fit the pattern
+// decodes to nothing and the album starts at the default host as before.
+
+/// Digits of the base62 encoding iCloud tokens use.
+const BASE62: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// The partition number encoded at the start of an album token.
+pub fn from_token(token: &str) -> Option<u32> {
+    let digits = match token.chars().next()? {
+        'A' => 1,
+        'B' => 2,
+        _ => return None,
+    };
+    let encoded = token.get(1..1 + digits)?;
+    encoded
+        .chars()
+        .try_fold(0, |value, c| Some(value * 62 + BASE62.find(c)? as u32))
+}
+
+/// The sharedstreams API host of the album with `token`.
+pub fn host(token: &str) -> Option<String> {
+    from_token(token).map(|partition| format!("p{:02}-sharedstreams.icloud.com", partition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_digit_after_a() {
+        assert_eq!(from_token("A6GJqs8CbDvnQt"), Some(6));
+        assert_eq!(from_token("Az"), Some(61));
+        assert_eq!(host("A6GJqs8CbDvnQt").as_deref(), Some("p06-sharedstreams.icloud.com"));
+    }
+
+    #[test]
+    fn two_digits_after_b() {
+        assert_eq!(from_token("B2TGPDdmXjmHxW"), Some(153));
+        assert_eq!(from_token("B2l"), Some(171));
+        assert_eq!(from_token("B0a5"), Some(36));
+        assert_eq!(host("B2TGPDdmXjmHxW").as_deref(), Some("p153-sharedstreams.icloud.com"));
+        assert_eq!(host("B01xyz").as_deref(), Some("p01-sharedstreams.icloud.com"));
+    }
+
+    #[test]
+    fn tokens_that_dont_fit_the_pattern_decode_to_nothing() {
+        assert_eq!(from_token(""), None);
+        assert_eq!(from_token("A"), None);
+        assert_eq!(from_token("B2"), None);
+        assert_eq!(from_token("C2TGPDdmXjmHxW"), None);
+        assert_eq!(from_token("a6GJqs8CbDvnQt"), None);
+        assert_eq!(from_token("A-GJqs8CbDvnQt"), None);
+        assert_eq!(from_token("B2é"), None);
+        assert_eq!(host("C2TGPDdmXjmHxW"), None);
+    }
+}
@@ -0,0 +1,297 @@
+// `--parallel-parts`: downloads one large file as several byte ranges at
+// once, for multi-gigabyte videos where a single connection can't use the
+// available bandwidth. The file is preallocated on disk, next to where it
+// will end up, and each range is written at its offset as it arrives, so a
+// big video never has to fit in memory. The result then goes through the
+// same checks and saving as a normal download.
+//
+// The first range is requested on its own. A CDN that ignores `Range` answers
+// it with the whole file, which is then read as a normal download.
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::try_join_all;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::atomic::PendingFile;
+use crate::dashboard::Transfer;
+use crate::errors::DownloadStatus;
+use crate::filetype::SNIFF_BYTES;
+use crate::headers::RequestKind;
+use crate::http::{HttpClient, HttpResponse};
+use crate::{fetch_body, integrity, read_body, safepath, stats, DownloadInfo, FetchedBody, TRUNCATED_DOWNLOAD_ATTEMPTS};
+
+/// Files smaller than this are always downloaded in one piece.
+const MIN_PARALLEL_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Where a download in parts is written: `path`, which has to end up inside
+/// `root`.
+pub struct Spool {
+    pub root: PathBuf,
+    pub path: PathBuf,
+}
+
+pub enum PartsDownload {
+    /// Downloaded in one piece, into memory.
+    Whole(FetchedBody),
+    /// Downloaded in parts into a file on disk.
+    Spooled(SpooledFile),
+}
+
+/// A file downloaded in parts. It's removed when dropped, unless `persist`
+/// moved it into place first.
+pub struct SpooledFile {
+    file: PendingFile,
+    pub len: u64,
+    pub content_type: Option<String>,
+    /// The start of the file, for `filetype::detect_extension`.
+    pub head: Vec<u8>,
+}
+
+impl SpooledFile {
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Moves the file to `destination`, which must be on the same file system.
+    pub async fn persist(self, destination: &Path) -> Result<()> {
+        self.file.persist(destination).await
+    }
+
+    /// Reads the whole file, for what needs it in memory, and removes it.
+    pub async fn into_bytes(self) -> Result<bytes::Bytes> {
+        let content = tokio::fs::read(self.path())
+            .await
+            .with_context(|| format!("Failed to read back {}", self.path().display()))?;
+        Ok(content.into())
+    }
+}
+
+/// Downloads `info` in `parts` ranges into a new file at `spool`, or into memory
+/// when it's too small to split or the server doesn't do ranges.
+pub async fn fetch_in_parts(
+    client: &impl HttpClient,
+    info: &DownloadInfo,
+    parts: usize,
+    spool: &Spool,
+    transfer: Option<&Transfer<'_>>,
+) -> Result<PartsDownload> {
+    let listed_size = match info.file_size {
+        Some(size) if size >= MIN_PARALLEL_SIZE => size,
+        _ => return fetch_body(client, info, transfer).await.map(PartsDownload::Whole),
+    };
+    let part_size = listed_size.div_ceil(parts as u64);
+
+    let first = client
+        .get_range(&info.download_url, RequestKind::Download, 0..part_size)
+        .await
+        .context("Failed to start download")?;
+    if first.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return read_body(first, transfer).await.map(PartsDownload::Whole);
+    }
+
+    // The listed size is only a hint; the server's total is what gets split
+    let total = first
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse::<u64>().ok())
+        .ok_or_else(|| anyhow!("Range response without a usable Content-Range header"))?;
+    let content_type = first
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    if let Some(transfer) = transfer {
+        transfer.set_total(total);
+    }
+
+    // From here on the spool goes away again if anything fails
+    let mut spooled = SpooledFile { file: PendingFile::new(spool.path.clone()), len: total, content_type, head: Vec::new() };
+    let file = safepath::create_new_file(&spool.root, &spool.path).await?;
+    file.set_len(total).await.context("Failed to preallocate the download")?;
+
+    let ranges: Vec<(u64, u64)> = (0..total)
+        .step_by(part_size as usize)
+        .map(|start| (start, part_size.min(total - start)))
+        .collect();
+
+    // A part cut short is requested again on its own, so one bad connection
+    // doesn't cost the whole file
+    let mut first = Some(first);
+    let downloads = ranges.into_iter().map(|(start, len)| {
+        let mut response = first.take();
+        async move {
+            let mut attempt = 1;
+            loop {
+                let part = match response.take() {
+                    Some(response) => response,
+                    None => request_part(client, info, start, len).await?,
+                };
+                match write_part(part, &spool.path, start, len, transfer).await {
+                    Err(e) if attempt < TRUNCATED_DOWNLOAD_ATTEMPTS && e.is::<integrity::TruncatedBody>() => {
+                        integrity::before_retry(&e, attempt, transfer).await;
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            }
+        }
+    });
+    try_join_all(downloads).await?;
+    file.sync_all().await.context("Failed to sync the download")?;
+
+    let mut file = tokio::fs::File::open(&spool.path).await?;
+    (&mut file).take(SNIFF_BYTES as u64).read_to_end(&mut spooled.head).await?;
+    Ok(PartsDownload::Spooled(spooled))
+}
+
+async fn request_part(client: &impl HttpClient, info: &DownloadInfo, start: u64, len: u64) -> Result<HttpResponse> {
+    let end = start + len;
+    let response = client
+        .get_range(&info.download_url, RequestKind::Download, start..end)
+        .await
+        .with_context(|| format!("Failed to request bytes {}-{}", start, end - 1))?;
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        let request = format!("Request for bytes {}-{}", start, end - 1);
+        return Err(DownloadStatus { request, status: response.status(), trace: response.apple_trace() }.into());
+    }
+    Ok(response)
+}
+
+/// Writes one part at its offset in the spool, failing with `TruncatedBody`
+/// when it ends early.
+async fn write_part(
+    response: HttpResponse,
+    spool: &Path,
+    start: u64,
+    expected: u64,
+    transfer: Option<&Transfer<'_>>,
+) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(spool)
+        .await
+        .with_context(|| format!("Failed to open {}", spool.display()))?;
+    file.seek(SeekFrom::Start(start)).await?;
+
+    let mut received = 0u64;
+    let written = response
+        .write_into(&mut file, expected, |n| {
+            received += n as u64;
+            stats::count_bytes_downloaded(n as u64);
+            if let Some(transfer) = transfer {
+                transfer.advance(n);
+            }
+        })
+        .await;
+    match written {
+        Ok(n) if n == expected => {
+            file.flush().await?;
+            Ok(())
+        }
+        Ok(n) => Err(integrity::TruncatedBody { received: n, expected }.into()),
+        Err(_) if received < expected => Err(integrity::TruncatedBody { received, expected }.into()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, download_info, FakeClient};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Just above the size that gets split, with every byte telling where it is.
+    fn large_file() -> Vec<u8> {
+        (0..MIN_PARALLEL_SIZE + 1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn writes_each_range_at_its_offset_in_the_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = Arc::new(large_file());
+        let served = Arc::clone(&content);
+        let client = FakeClient::new(move |request| testing::partial(&request.url, &served, request.range.as_ref().unwrap()));
+        let info = download_info("P1", "big.mov", Some(content.len() as u64));
+        let spool = Spool { root: dir.path().to_path_buf(), path: dir.path().join(".big.mov.tmp") };
+
+        let Ok(PartsDownload::Spooled(file)) = fetch_in_parts(&client, &info, 4, &spool, None).await else {
+            panic!("expected the download to be spooled");
+        };
+        assert_eq!(file.len, content.len() as u64);
+        assert_eq!(file.head, content[..SNIFF_BYTES]);
+        assert_eq!(std::fs::read(file.path()).unwrap(), *content);
+        assert_eq!(client.count("files.test"), 4);
+
+        let destination = dir.path().join("big.mov");
+        file.persist(&destination).await.unwrap();
+        assert!(!spool.path.exists());
+        assert_eq!(std::fs::read(&destination).unwrap(), *content);
+    }
+
+    #[tokio::test]
+    async fn a_part_cut_short_is_requested_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = Arc::new(large_file());
+        let served = Arc::clone(&content);
+        let cut = AtomicBool::new(false);
+        let client = FakeClient::new(move |request| {
+            let range = request.range.clone().unwrap();
+            // The last part arrives with half its bytes missing, once
+            if range.start > 0 && range.end >= served.len() as u64 && !cut.swap(true, Ordering::SeqCst) {
+                let short = range.start..(range.start + range.end) / 2;
+                let response = testing::partial(&request.url, &served, &short);
+                return testing::with_content_length(response, range.end.min(served.len() as u64) - range.start);
+            }
+            testing::partial(&request.url, &served, &range)
+        });
+        let info = download_info("P1", "big.mov", Some(content.len() as u64));
+        let dashboard = crate::dashboard::Dashboard::new(1, None);
+        let transfer = dashboard.start("big.mov", None);
+
+        let spool = Spool { root: dir.path().to_path_buf(), path: dir.path().join("spool") };
+        let Ok(PartsDownload::Spooled(file)) = fetch_in_parts(&client, &info, 4, &spool, Some(&transfer)).await else {
+            panic!("expected the download to be spooled");
+        };
+        assert_eq!(std::fs::read(file.path()).unwrap(), *content);
+        assert_eq!(client.count("files.test"), 5);
+        // The bytes of the cut-off attempt don't count twice
+        assert_eq!(dashboard.received_bytes(), content.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn a_failed_download_leaves_no_spool_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = Arc::new(large_file());
+        let served = Arc::clone(&content);
+        let client = FakeClient::new(move |request| match request.range.as_ref().unwrap().start {
+            0 => testing::partial(&request.url, &served, request.range.as_ref().unwrap()),
+            _ => testing::status(&request.url, 500),
+        });
+        let info = download_info("P1", "big.mov", Some(content.len() as u64));
+        let spool = Spool { root: dir.path().to_path_buf(), path: dir.path().join("spool") };
+
+        assert!(fetch_in_parts(&client, &info, 4, &spool, None).await.is_err());
+        assert!(!spool.path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_server_without_ranges_is_read_as_one_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = Arc::new(large_file());
+        let served = Arc::clone(&content);
+        let client = FakeClient::new(move |request| testing::file(&request.url, &served, served.len() as u64));
+        let info = download_info("P1", "big.mov", Some(content.len() as u64));
+        let spool = Spool { root: dir.path().to_path_buf(), path: dir.path().join("spool") };
+
+        let Ok(PartsDownload::Whole(body)) = fetch_in_parts(&client, &info, 4, &spool, None).await else {
+            panic!("expected the download to be read into memory");
+        };
+        assert_eq!(body.content.len(), content.len());
+        assert!(!spool.path.exists());
+    }
+}
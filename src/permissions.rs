@@ -0,0 +1,102 @@
+// `--file-mode` / `--dir-mode`: explicit Unix permissions for downloaded files
+// and the directories created for them. Without them files and directories
+// get whatever the process umask allows, as before. The modes are applied
+// with `set_permissions` after creation, so they are exact rather than being
+// masked by the umask.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+
+use crate::Args;
+
+#[derive(Clone, Copy)]
+pub struct OutputPermissions {
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+}
+
+impl OutputPermissions {
+    pub fn from_args(args: &Args) -> Self {
+        Self { file_mode: args.file_mode, dir_mode: args.dir_mode }
+    }
+
+    /// Warns when modes were asked for on a platform that can't apply them.
+    pub fn warn_if_unsupported(&self) {
+        if !cfg!(unix) && (self.file_mode.is_some() || self.dir_mode.is_some()) {
+            eprintln!("⚠️  --file-mode and --dir-mode only apply on Unix and are ignored here");
+        }
+    }
+
+    /// `create_dir_all`, with `--dir-mode` applied to every directory it had to create.
+    pub async fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let missing: Vec<PathBuf> = match self.dir_mode {
+            Some(_) => path
+                .ancestors()
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .map(Path::to_path_buf)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        tokio::fs::create_dir_all(path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        if let Some(mode) = self.dir_mode {
+            for dir in &missing {
+                set_mode(dir, mode).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates (or truncates) a file, with `--file-mode` applied.
+    pub async fn create_file(&self, path: &Path) -> Result<File> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            options.mode(mode);
+        }
+        let file = options.open(path).await?;
+
+        // The mode given at creation is masked by the umask, and ignored for
+        // a file that already existed
+        if let Some(mode) = self.file_mode {
+            set_mode(path, mode).await?;
+        }
+        Ok(file)
+    }
+
+    /// Applies `--file-mode` to a file created some other way.
+    pub async fn apply_to_file(&self, path: &Path) -> Result<()> {
+        match self.file_mode {
+            Some(mode) => set_mode(path, mode).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Parses an octal mode such as `640`, `0750` or `0o644`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("'{}' is not an octal mode like 644 or 0750", value))
+}
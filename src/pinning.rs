@@ -0,0 +1,197 @@
+// `--pin-cert`: certificate pinning. A connection is only accepted when it
+// validates against the usual CAs *and* one of the certificates the server
+// presents (the leaf or an intermediate) has a pinned SHA-256 fingerprint, so
+// a certificate from some other trusted CA, as used by intercepting proxies,
+// is rejected.
+//
+// The default TLS stack can't run custom checks during the handshake, so
+// pinned connections use rustls with the system's CA certificates instead.
+// A pin mismatch is reported as such rather than as a generic TLS error.
+
+use anyhow::{anyhow, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::error::Error as StdError;
+use std::path::Path;
+use std::sync::Arc;
+
+pub type Fingerprint = [u8; 32];
+
+/// Parses a SHA-256 certificate fingerprint as printed by
+/// `openssl x509 -fingerprint -sha256`, with or without the colons.
+pub fn parse_fingerprint(value: &str) -> Result<Fingerprint, String> {
+    let hex: String = value
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let invalid = || format!("'{}' is not a SHA-256 fingerprint (64 hex digits, colons optional)", value);
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+/// The connection was refused because no certificate matched a pin.
+#[derive(Debug)]
+pub struct PinMismatch {
+    host: String,
+    /// Fingerprint of the leaf certificate the server presented.
+    presented: String,
+}
+
+impl std::fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Certificate pin mismatch for {}: it presented {} and no certificate in its chain matches \
+             --pin-cert. Either Apple has rotated its certificates (update the pins) or the connection is \
+             being intercepted",
+            self.host, self.presented
+        )
+    }
+}
+
+impl StdError for PinMismatch {}
+
+/// Looks through an error's sources for a `PinMismatch`. rustls errors
+/// don't expose what they wrap as a source, so they are unpacked by hand.
+pub fn find_pin_mismatch<'a>(error: &'a (dyn StdError + 'static)) -> Option<&'a PinMismatch> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(mismatch) = error.downcast_ref::<PinMismatch>() {
+            return Some(mismatch);
+        }
+        let inner = error.downcast_ref::<std::io::Error>().and_then(|io| io.get_ref());
+        if let Some(mismatch) = inner.and_then(|inner| find_pin_mismatch(inner)) {
+            return Some(mismatch);
+        }
+        if let Some(rustls::Error::Other(other)) = error.downcast_ref::<rustls::Error>() {
+            return other.0.downcast_ref::<PinMismatch>();
+        }
+        current = error.source();
+    }
+    None
+}
+
+/// A rustls configuration that checks `pins` on top of normal validation
+/// against the system CAs and `extra_ca`.
+pub fn tls_config(pins: &[Fingerprint], extra_ca: Option<&Path>) -> Result<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = Arc::new(root_store(extra_ca)?);
+    let inner = WebPkiServerVerifier::builder_with_provider(roots, Arc::clone(&provider))
+        .build()
+        .context("Failed to set up certificate verification")?;
+    let verifier = PinnedVerifier { inner, pins: pins.to_vec() };
+
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The system's CA certificates, found the way OpenSSL finds them.
+fn root_store(extra_ca: Option<&Path>) -> Result<RootCertStore> {
+    let probe = openssl_probe::probe();
+    let mut files: Vec<_> = probe.cert_file.into_iter().collect();
+    for dir in probe.cert_dir {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            files.extend(entries.flatten().map(|entry| entry.path()));
+        }
+    }
+    files.extend(extra_ca.map(Path::to_path_buf));
+
+    let mut roots = RootCertStore::empty();
+    for file in &files {
+        if let Ok(certificates) = CertificateDer::pem_file_iter(file) {
+            roots.add_parsable_certificates(certificates.flatten());
+        }
+    }
+    if let Some(path) = extra_ca {
+        // A DER file has no PEM blocks to find
+        if let Ok(der) = std::fs::read(path) {
+            roots.add_parsable_certificates([CertificateDer::from(der)]);
+        }
+    }
+
+    if roots.is_empty() {
+        return Err(anyhow!(
+            "--pin-cert couldn't find the system's CA certificates; point SSL_CERT_FILE at a CA bundle"
+        ));
+    }
+    Ok(roots)
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Fingerprint>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|certificate| self.pins.contains(&fingerprint(certificate)));
+        if !pinned {
+            let mismatch = PinMismatch {
+                host: server_name.to_str().into_owned(),
+                presented: format_fingerprint(&fingerprint(end_entity)),
+            };
+            return Err(rustls::Error::Other(rustls::OtherError(Arc::new(mismatch))));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn fingerprint(certificate: &CertificateDer<'_>) -> Fingerprint {
+    Sha256::digest(certificate.as_ref()).into()
+}
+
+fn format_fingerprint(fingerprint: &Fingerprint) -> String {
+    fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
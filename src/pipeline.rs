@@ -0,0 +1,505 @@
+// Streaming downloads. Instead of fetching every download URL before the
+// first download starts, URLs are fetched batch by batch and each batch's
+// downloads go into a bounded queue that the download tasks take from. For a
+// 50k-photo album downloads start after the first batch instead of after
+// thousands of requests, and only a queue's worth of URLs is held at once
+// (which also leaves them little time to expire).
+//
+// A few features need every download listed up front (see `can_stream`);
+// with those the URLs are all fetched first, as before. Either way the same
+// per-file filters run, through `Screening` and `ExistingFiles`.
+
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+use crate::batches::BatchFolders;
+use crate::conflicts::ConflictStrategy;
+use crate::dates::DateNaming;
+use crate::disposition::NamingSource;
+use crate::http::HttpClient;
+use crate::manifest::Manifest;
+use crate::outcomes::OutcomeTable;
+use crate::permissions::OutputPermissions;
+use crate::smartnames::SmartNames;
+use crate::{
+    create_subdirectories, existing, fetch_asset_urls_batch, manifest, normalize, record_skip, size,
+    Args, AssetKind, DerivativeSelection, DownloadInfo, Photo, URL_BATCH_SIZE,
+};
+
+/// Downloads waiting in the queue before URL fetching pauses: a couple of
+/// batches, so the next batch is usually ready when the downloads need it.
+pub const QUEUE_CAPACITY: usize = 2 * URL_BATCH_SIZE;
+
+/// Existing files checked at once; with --overwrite-policy if-different each
+/// check may read a whole file to hash it.
+const EXISTING_CHECKS: usize = 4;
+
+/// The order downloads are started in (`--order`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DownloadOrder {
+    /// The order of the album
+    #[default]
+    Album,
+    /// Smallest files first, for quick early progress
+    SmallestFirst,
+    /// Largest files first, so no huge file is left running alone at the end
+    LargestFirst,
+    /// Shuffled
+    Random,
+}
+
+impl DownloadOrder {
+    /// Sorts the downloads by their listed size. Files of unknown size go
+    /// last either way, in album order.
+    pub fn apply(self, infos: &mut [DownloadInfo]) {
+        match self {
+            DownloadOrder::Album => {}
+            DownloadOrder::SmallestFirst => infos.sort_by_key(|info| (info.file_size.is_none(), info.file_size)),
+            DownloadOrder::LargestFirst => {
+                infos.sort_by_key(|info| (info.file_size.is_none(), std::cmp::Reverse(info.file_size)))
+            }
+            DownloadOrder::Random => fastrand::shuffle(infos),
+        }
+    }
+}
+
+/// Where `download_photos` gets its downloads from.
+pub enum DownloadSource {
+    /// Every download, known up front.
+    Listed(Vec<DownloadInfo>),
+    /// Downloads queued while later URLs are still being fetched.
+    Streamed(mpsc::Receiver<DownloadInfo>),
+}
+
+/// Whether the album can be downloaded while its URLs are being fetched.
+/// Burst numbering, host comparison, the HEAD check, size prefetching,
+/// Content-Disposition naming, repair, the dashboard, the progress file,
+/// --max-total-size, --on-conflict overwrite and any --order but album order
+/// all need the complete list first.
+pub fn can_stream(args: &Args) -> bool {
+    let needs_list = args.burst_index
+        || args.order != DownloadOrder::Album
+        || args.compare_hosts.is_some()
+        || args.head_check
+        || args.prefetch_sizes
+        || args.naming == NamingSource::ContentDisposition
+        || args.repair
+        || args.tui
+        || args.progress_file.is_some()
+        || args.max_total_size.is_some()
+        || args.on_conflict == ConflictStrategy::Overwrite;
+    !needs_list
+}
+
+/// --max-total-size: keeps downloads, in their current order, until the next
+/// one would take the listed sizes past `cap`, and leaves out the rest. Files
+/// of unknown size can't be shown to fit and are left out too. Going by the
+/// listed sizes keeps the cut the same on every run, and the download never
+/// ends up larger than the cap.
+pub fn apply_total_size_cap(infos: &mut Vec<DownloadInfo>, cap: u64, outcome_table: Option<&OutcomeTable>) {
+    let mut planned = 0;
+    let mut full = false;
+    let mut unknown = 0;
+    let total = infos.len();
+    infos.retain(|info| {
+        let fits = match info.file_size {
+            Some(size) if !full && planned + size <= cap => {
+                planned += size;
+                true
+            }
+            Some(_) => {
+                full = true;
+                false
+            }
+            None => {
+                unknown += 1;
+                false
+            }
+        };
+        if !fits {
+            record_skip(outcome_table, info, "over --max-total-size");
+        }
+        fits
+    });
+
+    let left_out = total - infos.len();
+    if left_out > 0 {
+        status!(
+            "💾 Downloading {} of {} files ({} of the {} cap); {} left out by --max-total-size",
+            infos.len(),
+            total,
+            size::format_size(planned),
+            size::format_size(cap),
+            left_out
+        );
+    }
+    if unknown > 0 {
+        eprintln!("⚠️  {} files of unknown size were left out, as --max-total-size can't count them", unknown);
+    }
+}
+
+/// --smart-names, date naming, --group-by-batch and Unicode normalization of
+/// freshly fetched downloads.
+pub fn name_downloads(
+    date_naming: &DateNaming,
+    smart_names: Option<&SmartNames>,
+    batch_folders: Option<&BatchFolders>,
+    infos: &mut [DownloadInfo],
+    photos: &[Photo],
+) {
+    if let Some(smart_names) = smart_names {
+        for info in infos.iter_mut() {
+            smart_names.apply(info);
+        }
+    }
+    if date_naming.is_enabled() {
+        let album_order: HashMap<&str, usize> = if date_naming.burst_index {
+            photos.iter().enumerate().map(|(i, photo)| (photo.photo_guid.as_str(), i)).collect()
+        } else {
+            HashMap::new()
+        };
+        date_naming.apply_all(infos, &album_order);
+    }
+    if let Some(batch_folders) = batch_folders {
+        for info in infos.iter_mut() {
+            batch_folders.apply(info);
+        }
+    }
+    for info in infos {
+        info.filename = normalize::platform_form(&info.filename);
+    }
+}
+
+/// The filters that don't depend on file names: --flatten-live-photos, the
+/// size limits, --exclude-videos-over and --since-manifest.
+pub struct Screening<'a> {
+    args: &'a Args,
+    outcome_table: Option<&'a OutcomeTable>,
+    /// Durations of the album's videos, with --exclude-videos-over.
+    durations: HashMap<&'a str, Option<f64>>,
+    known: Option<HashSet<(String, String)>>,
+    outside_size_limits: usize,
+    too_long: usize,
+    already_known: usize,
+    admitted: usize,
+}
+
+impl<'a> Screening<'a> {
+    pub fn new(args: &'a Args, photos: &'a [Photo], outcome_table: Option<&'a OutcomeTable>) -> Result<Self> {
+        let durations: HashMap<&str, Option<f64>> = match args.exclude_videos_over {
+            Some(_) => photos
+                .iter()
+                .filter(|photo| photo.is_video())
+                .map(|photo| (photo.photo_guid.as_str(), photo.duration_secs()))
+                .collect(),
+            None => HashMap::new(),
+        };
+        if !durations.is_empty() && durations.values().all(Option::is_none) {
+            return Err(anyhow::anyhow!(
+                "--exclude-videos-over can't be used with this album: its metadata doesn't include video durations"
+            ));
+        }
+        let known = args.since_manifest.as_deref().map(manifest::load_known_assets).transpose()?;
+
+        Ok(Self {
+            args,
+            outcome_table,
+            durations,
+            known,
+            outside_size_limits: 0,
+            too_long: 0,
+            already_known: 0,
+            admitted: 0,
+        })
+    }
+
+    /// Whether `info` passes the filters; skips are recorded.
+    pub fn admit(&mut self, info: &DownloadInfo) -> bool {
+        let args = self.args;
+        if args.flatten_live_photos && info.kind == AssetKind::LiveMotion {
+            return false;
+        }
+
+        let size_reason = match info.file_size {
+            Some(size) if args.max_file_size.is_some_and(|max| size > max) => Some("larger than --max-file-size"),
+            Some(size) if args.min_file_size.is_some_and(|min| size < min) => Some("smaller than --min-file-size"),
+            None if args.strict_size && (args.max_file_size.is_some() || args.min_file_size.is_some()) => {
+                Some("size unknown (--strict-size)")
+            }
+            _ => None,
+        };
+        if let Some(reason) = size_reason {
+            record_skip(self.outcome_table, info, reason);
+            self.outside_size_limits += 1;
+            return false;
+        }
+
+        if let Some(limit) = args.exclude_videos_over {
+            let too_long = info.kind == AssetKind::Video
+                && self
+                    .durations
+                    .get(info.photo_guid.as_str())
+                    .copied()
+                    .flatten()
+                    .is_some_and(|duration| duration > limit);
+            if too_long {
+                record_skip(self.outcome_table, info, "longer than --exclude-videos-over");
+                self.too_long += 1;
+                return false;
+            }
+        }
+
+        if let Some(known) = &self.known {
+            if known.contains(&(info.photo_guid.clone(), info.checksum.clone())) {
+                record_skip(self.outcome_table, info, "in --since-manifest");
+                self.already_known += 1;
+                return false;
+            }
+        }
+
+        self.admitted += 1;
+        true
+    }
+
+    /// Whether the filters turned away every file they saw.
+    pub fn left_nothing(&self) -> bool {
+        self.admitted == 0 && self.outside_size_limits + self.too_long + self.already_known > 0
+    }
+
+    /// Reports what the filters left out.
+    pub fn report(&self) {
+        if self.outside_size_limits > 0 {
+            status!("📏 Skipping {} files outside the size limits", self.outside_size_limits);
+        }
+        if let Some(limit) = self.args.exclude_videos_over {
+            if self.too_long > 0 {
+                status!("🎬 Skipping {} videos longer than {}s", self.too_long, limit);
+            }
+            let unknown = self.durations.values().filter(|d| d.is_none()).count();
+            if unknown > 0 {
+                status!("⚠️  {} videos have no duration in the album metadata and will be downloaded", unknown);
+            }
+        }
+        if let Some(path) = &self.args.since_manifest {
+            status!("📒 {} files already in {}, {} new", self.already_known, path.display(), self.admitted);
+        }
+    }
+}
+
+/// --overwrite-policy and --if-newer.
+pub struct ExistingFiles<'a> {
+    policy: existing::OverwritePolicy,
+    /// With --if-newer, the checksum the manifest recorded for each file.
+    recorded: Option<HashMap<String, String>>,
+    comparable: bool,
+    output_dir: &'a str,
+    outcome_table: Option<&'a OutcomeTable>,
+    /// Folded names of other photos' files that --on-conflict overwrite replaces.
+    overwrites: HashSet<String>,
+    skipped: usize,
+    upgraded: usize,
+    replaced: usize,
+    updated: usize,
+}
+
+impl<'a> ExistingFiles<'a> {
+    /// `None` when existing files are overwritten anyway: with
+    /// --overwrite-policy always, or when writing an archive.
+    pub fn new(
+        args: &'a Args,
+        output_dir: &'a str,
+        archive: bool,
+        manifest: Option<&Manifest>,
+        outcome_table: Option<&'a OutcomeTable>,
+    ) -> Option<Self> {
+        if archive || args.overwrite_policy == existing::OverwritePolicy::Always {
+            return None;
+        }
+        let comparable = !args.strip_metadata;
+        if args.overwrite_policy == existing::OverwritePolicy::IfDifferent && !comparable {
+            eprintln!("⚠️  --strip-metadata changes every file, so --overwrite-policy if-different keeps existing files");
+        }
+        let recorded = args.if_newer.then(|| match manifest.map(Manifest::entries).transpose() {
+            Ok(entries) => entries
+                .into_iter()
+                .flatten()
+                .map(|entry| (entry.filename, entry.checksum))
+                .collect(),
+            Err(e) => {
+                eprintln!("⚠️  Could not read the manifest, so --if-newer can't tell which files changed: {:#}", e);
+                HashMap::new()
+            }
+        });
+        Some(Self {
+            policy: args.overwrite_policy,
+            recorded,
+            comparable,
+            output_dir,
+            outcome_table,
+            overwrites: HashSet::new(),
+            skipped: 0,
+            upgraded: 0,
+            replaced: 0,
+            updated: 0,
+        })
+    }
+
+    /// Files to replace whatever the policy, as they belong to other photos.
+    pub fn overwrite(&mut self, names: HashSet<String>) {
+        self.overwrites.extend(names);
+    }
+
+    /// The downloads in `infos` that still need downloading given what's on
+    /// disk. Several files are checked at a time.
+    pub async fn filter(&mut self, infos: Vec<DownloadInfo>) -> Vec<DownloadInfo> {
+        let this = &*self;
+        let actions: Vec<existing::ExistingAction> = stream::iter(&infos)
+            .map(|info| async move {
+                if this.overwrites.contains(&normalize::folded(&info.filename)) {
+                    return existing::ExistingAction::Download;
+                }
+                let recorded = this.recorded.as_ref();
+                existing::existing_action(info, this.output_dir, this.policy, recorded, this.comparable).await
+            })
+            .buffered(EXISTING_CHECKS)
+            .collect()
+            .await;
+        infos
+            .into_iter()
+            .zip(actions)
+            .filter(|(info, action)| self.admit(info, action))
+            .map(|(info, _)| info)
+            .collect()
+    }
+
+    fn admit(&mut self, info: &DownloadInfo, action: &existing::ExistingAction) -> bool {
+        match action {
+            existing::ExistingAction::Download => true,
+            existing::ExistingAction::Skip => {
+                record_skip(self.outcome_table, info, "already exists");
+                self.skipped += 1;
+                false
+            }
+            existing::ExistingAction::Upgrade { existing } => {
+                status!("   ⬆️  {} ({} -> {})",
+                    info.filename,
+                    size::format_size(*existing),
+                    info.file_size.map_or("?".to_string(), size::format_size)
+                );
+                self.upgraded += 1;
+                true
+            }
+            existing::ExistingAction::Differs { existing } => {
+                match info.file_size {
+                    Some(expected) if expected != *existing => status!("   ♻️  {} ({} -> {})",
+                        info.filename,
+                        size::format_size(*existing),
+                        size::format_size(expected)
+                    ),
+                    _ => status!("   ♻️  {} (checksum differs)", info.filename),
+                }
+                self.replaced += 1;
+                true
+            }
+            existing::ExistingAction::Update { recorded } => {
+                status!("   🔄 {} (checksum {} -> {})", info.filename, recorded, info.checksum);
+                self.updated += 1;
+                true
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        if self.skipped > 0 {
+            status!("⏭️  Skipping {} files that already exist", self.skipped);
+        }
+        if self.upgraded > 0 {
+            status!("⬆️  Upgrading {} files to a larger version", self.upgraded);
+        }
+        if self.replaced > 0 {
+            status!("♻️  Replacing {} files that differ from the album", self.replaced);
+        }
+        if self.updated > 0 {
+            status!("🔄 Updating {} files that changed in the album since they were downloaded", self.updated);
+        }
+    }
+}
+
+/// The sending end of a streamed download.
+pub struct Queue<'a> {
+    sender: mpsc::Sender<DownloadInfo>,
+    /// Output directory and permissions for creating subdirectories; `None` with --tar.
+    directories: Option<(&'a str, &'a OutputPermissions)>,
+    existing: Option<ExistingFiles<'a>>,
+    created: HashSet<PathBuf>,
+    queued: usize,
+}
+
+impl<'a> Queue<'a> {
+    pub fn new(
+        sender: mpsc::Sender<DownloadInfo>,
+        directories: Option<(&'a str, &'a OutputPermissions)>,
+        existing: Option<ExistingFiles<'a>>,
+    ) -> Self {
+        Self { sender, directories, existing, created: HashSet::new(), queued: 0 }
+    }
+
+    /// Closes the queue, which tells the downloads there's no more. Returns
+    /// how many downloads were queued and the existing-file checks, for
+    /// their report.
+    pub fn close(self) -> (usize, Option<ExistingFiles<'a>>) {
+        (self.queued, self.existing)
+    }
+
+    /// Queues a batch, after leaving out files that don't need downloading
+    /// again and creating any subdirectories it's the first to need. Returns
+    /// false once the downloads have stopped.
+    async fn push(&mut self, infos: Vec<DownloadInfo>) -> Result<bool> {
+        let infos = match &mut self.existing {
+            Some(existing) => existing.filter(infos).await,
+            None => infos,
+        };
+        if let Some((output_dir, permissions)) = self.directories {
+            let created = &mut self.created;
+            let first_in_dir = infos.iter().filter(|info| {
+                Path::new(&info.filename)
+                    .parent()
+                    .is_some_and(|dir| !dir.as_os_str().is_empty() && created.insert(dir.to_path_buf()))
+            });
+            create_subdirectories(first_in_dir, output_dir, permissions).await?;
+        }
+
+        for info in infos {
+            if self.sender.send(info).await.is_err() {
+                return Ok(false);
+            }
+            self.queued += 1;
+        }
+        Ok(true)
+    }
+}
+
+/// Fetches the download URLs of `photos` batch by batch, runs each batch
+/// through `prepare` and queues what's left, followed by `recovered`.
+/// Returns early, without an error, once the downloads have stopped.
+pub async fn fetch_into(
+    queue: &mut Queue<'_>,
+    client: &impl HttpClient,
+    hash: &str,
+    photos: &[Photo],
+    selection: &DerivativeSelection,
+    recovered: Vec<DownloadInfo>,
+    mut prepare: impl FnMut(Vec<DownloadInfo>) -> Result<Vec<DownloadInfo>>,
+) -> Result<()> {
+    for batch in photos.chunks(URL_BATCH_SIZE) {
+        let infos = fetch_asset_urls_batch(client, hash, batch, selection).await?;
+        if !queue.push(prepare(infos)?).await? {
+            return Ok(());
+        }
+    }
+    queue.push(prepare(recovered)?).await?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+// `--probe`: a connectivity self-test for bug reports. Runs each step of a
+// download once (host lookup, album metadata, one batch of download URLs,
+// one small download) and prints a checklist with the error of the first
+// step that fails. Nothing is written to disk.
+
+use anyhow::{anyhow, Result};
+use std::time::Instant;
+
+use crate::http::HttpClient;
+use crate::size::format_size;
+use crate::{
+    fetch_asset_urls_batch, fetch_body, fetch_webstream, resolve_album_hash, sharedstreams, DerivativeSelection,
+    Args,
+};
+
+/// Download URLs are requested for at most this many photos.
+const PROBE_BATCH_SIZE: usize = 25;
+
+/// Downloads tried, smallest first, before the download step counts as failed.
+const PROBE_DOWNLOAD_ATTEMPTS: usize = 3;
+
+pub async fn run(client: &impl HttpClient, args: &Args) -> Result<()> {
+    let [url] = args.url.as_slice() else {
+        return Err(anyhow!("--probe takes exactly one --url"));
+    };
+
+    status!("\n🩺 Probing {}", url);
+    status!("   {} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    let mut checklist = Checklist::default();
+    if let Err(e) = probe(client, url, &mut checklist).await {
+        checklist.fail(&e);
+        checklist.skip_remaining();
+        return Err(anyhow!("Probe failed; see the checklist above"));
+    }
+
+    status!("\n✅ Everything works; a normal download should too");
+    Ok(())
+}
+
+async fn probe(client: &impl HttpClient, url: &str, checklist: &mut Checklist) -> Result<()> {
+    let hash = resolve_album_hash(client, url).await?;
+    checklist.pass(format!("album {}", hash));
+
+    let host = sharedstreams::host_for(&hash);
+    let addresses: Vec<String> = tokio::net::lookup_host((host.as_str(), 443))
+        .await
+        .map_err(|e| anyhow!("{}: {}", host, e))?
+        .map(|address| address.ip().to_string())
+        .collect();
+    checklist.pass(format!("{} → {}", host, addresses.join(", ")));
+
+    let started = Instant::now();
+    let webstream = fetch_webstream(client, &hash).await?;
+    checklist.pass(format!(
+        "'{}', {} photos ({} ms)",
+        webstream.stream_name.as_deref().unwrap_or("Unknown Album"),
+        webstream.photos.len(),
+        started.elapsed().as_millis()
+    ));
+    if webstream.photos.is_empty() {
+        return Err(anyhow!("The album has no photos, so downloads can't be tested"));
+    }
+
+    let started = Instant::now();
+    let batch = &webstream.photos[..webstream.photos.len().min(PROBE_BATCH_SIZE)];
+    let mut infos = fetch_asset_urls_batch(client, &hash, batch, &DerivativeSelection::Best).await?;
+    if infos.is_empty() {
+        return Err(anyhow!("webasseturls returned no usable URLs for {} photos", batch.len()));
+    }
+    checklist.pass(format!("{} URLs for {} photos ({} ms)", infos.len(), batch.len(), started.elapsed().as_millis()));
+
+    infos.sort_by_key(|info| info.file_size.unwrap_or(u64::MAX));
+    let mut last_error = None;
+    for info in infos.iter().take(PROBE_DOWNLOAD_ATTEMPTS) {
+        let started = Instant::now();
+        match fetch_body(client, info, None).await {
+            Ok(body) => {
+                let host = reqwest::Url::parse(&info.download_url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                checklist.pass(format!(
+                    "{} ({}) from {} in {} ms",
+                    info.filename,
+                    format_size(body.content.len() as u64),
+                    host,
+                    started.elapsed().as_millis()
+                ));
+                return Ok(());
+            }
+            Err(e) => last_error = Some(e.context(format!("Downloading {}", info.filename))),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("Nothing to download")))
+}
+
+const STEPS: [&str; 5] = ["Album link", "Host lookup", "Album metadata", "Download URLs", "Download"];
+
+/// Prints one line per step of `STEPS` as it finishes, in order.
+#[derive(Default)]
+struct Checklist {
+    current: usize,
+}
+
+impl Checklist {
+    fn pass(&mut self, detail: String) {
+        status!("✅ {}: {}", STEPS[self.current], detail);
+        self.current += 1;
+    }
+
+    fn fail(&mut self, error: &anyhow::Error) {
+        status!("❌ {}: {:#}", STEPS[self.current], error);
+        self.current += 1;
+    }
+
+    fn skip_remaining(&mut self) {
+        for step in &STEPS[self.current.min(STEPS.len())..] {
+            status!("⏭️  {}: skipped", step);
+        }
+        self.current = STEPS.len();
+    }
+}
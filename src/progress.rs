@@ -0,0 +1,125 @@
+// Progress display. On a terminal progress is an indicatif bar; under
+// systemd, cron or in a pipe its escape sequences would garble the log, so
+// there (and with --no-progress) a plain "500/2000 photos downloaded" line is
+// printed every few seconds instead, and no bar is ever created.
+
+use anyhow::Result;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a plain progress line is printed at most.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+static BARS: AtomicBool = AtomicBool::new(true);
+
+/// Decides, once at startup, whether progress bars may be drawn.
+pub fn configure(no_progress: bool) {
+    let interactive = std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    BARS.store(interactive && !no_progress, Ordering::Relaxed);
+}
+
+pub fn bars_enabled() -> bool {
+    BARS.load(Ordering::Relaxed)
+}
+
+pub enum Progress {
+    Bar(ProgressBar),
+    Plain(PlainProgress),
+}
+
+pub struct PlainProgress {
+    what: &'static str,
+    total: AtomicU64,
+    done: AtomicU64,
+    last_printed: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    /// A bar or plain progress lines counting to `total`; `what` names what
+    /// is counted, e.g. "photos downloaded".
+    pub fn new(total: u64, what: &'static str) -> Result<Self> {
+        if !bars_enabled() {
+            return Ok(Progress::Plain(PlainProgress {
+                what,
+                total: AtomicU64::new(total),
+                done: AtomicU64::new(0),
+                last_printed: Mutex::new(Some(Instant::now())),
+            }));
+        }
+        let bar = ProgressBar::new(total);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(&format!("{{spinner:.green}} [{{elapsed_precise}}] [{{bar:40.cyan/blue}}] {{pos}}/{{len}} {}", what))?
+                .progress_chars("#>-"),
+        );
+        Ok(Progress::Bar(bar))
+    }
+
+    /// Counts without showing anything, for when the --tui dashboard is up.
+    pub fn hidden(total: u64) -> Self {
+        Progress::Bar(ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::hidden()))
+    }
+
+    pub fn inc(&self, n: u64) {
+        match self {
+            Progress::Bar(bar) => bar.inc(n),
+            Progress::Plain(plain) => {
+                plain.done.fetch_add(n, Ordering::Relaxed);
+                let mut last_printed = plain.last_printed.lock().unwrap();
+                if last_printed.is_some_and(|at| at.elapsed() >= PLAIN_INTERVAL) {
+                    *last_printed = Some(Instant::now());
+                    plain.print();
+                }
+            }
+        }
+    }
+
+    /// Raises the total, for counts that only become known as work arrives.
+    pub fn inc_length(&self, n: u64) {
+        match self {
+            Progress::Bar(bar) => bar.inc_length(n),
+            Progress::Plain(plain) => {
+                plain.total.fetch_add(n, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        match self {
+            Progress::Bar(bar) => bar.length().unwrap_or(0),
+            Progress::Plain(plain) => plain.total.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Leaves the bar at 100%, or prints the final count.
+    pub fn finish(&self) {
+        match self {
+            Progress::Bar(bar) => bar.finish(),
+            Progress::Plain(plain) => plain.print_last(),
+        }
+    }
+
+    /// Leaves the bar where it stopped, or prints the final count.
+    pub fn abandon(&self) {
+        match self {
+            Progress::Bar(bar) => bar.abandon(),
+            Progress::Plain(plain) => plain.print_last(),
+        }
+    }
+}
+
+impl PlainProgress {
+    fn print(&self) {
+        status!("⏳ {}/{} {}", self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed), self.what);
+    }
+
+    /// Prints the count once more, unless that's already been done.
+    fn print_last(&self) {
+        if self.last_printed.lock().unwrap().take().is_some() {
+            self.print();
+        }
+    }
+}
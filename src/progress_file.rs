@@ -0,0 +1,142 @@
+// `--progress-file`: live progress as a small JSON file, rewritten every
+// second during the download phase, for dashboards and watchdogs that poll
+// long unattended runs:
+//
+//     {"album":"B0a5oqs3qGvR6Re","state":"downloading","completed":120,"total":800,...}
+//
+// Each snapshot replaces the previous one with a rename, so a reader never
+// sees half of one. The last snapshot of an album has state "finished" or
+// "interrupted".
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::failures::DownloadCounters;
+use crate::atomic::atomic_write;
+use crate::stats;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The rate is averaged over this long, so one slow file doesn't swing the ETA.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    album: &'a str,
+    state: &'a str,
+    completed: usize,
+    succeeded: usize,
+    failed: usize,
+    total: usize,
+    bytes_downloaded: u64,
+    /// Sum of the sizes listed in the album, when every file has one.
+    bytes_total: Option<u64>,
+    bytes_per_sec: f64,
+    eta_secs: Option<u64>,
+    elapsed_secs: u64,
+    /// Seconds since the Unix epoch, so a stale file can be told from a live one.
+    updated_at: u64,
+}
+
+pub struct ProgressFile {
+    path: PathBuf,
+    album: String,
+    total: usize,
+    bytes_total: Option<u64>,
+    started: Instant,
+    bytes_at_start: u64,
+    /// (when, bytes downloaded by then) over the last `RATE_WINDOW`.
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl ProgressFile {
+    pub fn new(path: PathBuf, album: &str, total: usize, bytes_total: Option<u64>) -> Self {
+        let bytes_at_start = stats::bytes_downloaded();
+        Self {
+            path,
+            album: album.to_string(),
+            total,
+            bytes_total,
+            started: Instant::now(),
+            bytes_at_start,
+            samples: Mutex::new(VecDeque::from([(Instant::now(), 0)])),
+        }
+    }
+
+    /// Rewrites the file every second; never returns, so race it against the downloads.
+    pub async fn run(&self, counters: &DownloadCounters) {
+        let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+        let mut warned = false;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.write("downloading", counters) {
+                // One warning is enough; the run matters more than its status file
+                if !warned {
+                    eprintln!("⚠️  Could not write progress file: {:#}", e);
+                    warned = true;
+                }
+            }
+        }
+    }
+
+    /// Writes the album's final snapshot.
+    pub fn finish(&self, counters: &DownloadCounters, interrupted: bool) {
+        let state = if interrupted { "interrupted" } else { "finished" };
+        if let Err(e) = self.write(state, counters) {
+            eprintln!("⚠️  Could not write progress file: {:#}", e);
+        }
+    }
+
+    fn write(&self, state: &str, counters: &DownloadCounters) -> Result<()> {
+        let now = Instant::now();
+        let bytes = stats::bytes_downloaded() - self.bytes_at_start;
+
+        let bytes_per_sec = {
+            let mut samples = self.samples.lock().unwrap();
+            samples.push_back((now, bytes));
+            while samples.len() > 2 && now.duration_since(samples[1].0) >= RATE_WINDOW {
+                samples.pop_front();
+            }
+            let (oldest_at, oldest_bytes) = samples[0];
+            let span = now.duration_since(oldest_at).as_secs_f64();
+            if span > 0.0 { (bytes - oldest_bytes) as f64 / span } else { 0.0 }
+        };
+
+        let (succeeded, failed) = (counters.succeeded(), counters.failed());
+        let completed = succeeded + failed;
+        let elapsed = now.duration_since(self.started);
+        let eta_secs = match self.bytes_total {
+            Some(bytes_total) if bytes_per_sec > 0.0 => {
+                Some((bytes_total.saturating_sub(bytes) as f64 / bytes_per_sec) as u64)
+            }
+            // Without listed sizes, go by the average time per file so far
+            None if completed > 0 => {
+                Some(elapsed.as_secs() * (self.total.saturating_sub(completed)) as u64 / completed as u64)
+            }
+            _ => None,
+        };
+
+        let snapshot = Snapshot {
+            album: &self.album,
+            state,
+            completed,
+            succeeded,
+            failed,
+            total: self.total,
+            bytes_downloaded: bytes,
+            bytes_total: self.bytes_total,
+            bytes_per_sec,
+            eta_secs: if state == "downloading" { eta_secs } else { None },
+            elapsed_secs: elapsed.as_secs(),
+            updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+
+        let mut json = serde_json::to_vec(&snapshot)?;
+        json.push(b'\n');
+        atomic_write(&self.path, &json)
+    }
+}
@@ -0,0 +1,104 @@
+// `--max-rate-per-file`: caps how fast any one file downloads, so a single
+// transfer can't saturate a shared or asymmetric link however few downloads
+// run at once. Each file gets a token bucket that its body reads draw from;
+// the parts of a --parallel-parts download share their file's bucket. The
+// client hands the buckets out (see `http::FileRateLimits`), keyed by
+// download URL.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::size;
+
+/// How much a file may get ahead of its rate, as a fraction of a second's
+/// worth of bytes. Small, so the cap holds over short stretches too.
+pub const BURST_SECONDS: f64 = 0.25;
+
+/// Parses a rate like `2MB`, `500KB/s` or `65536` (bytes per second).
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let trimmed = trimmed.strip_suffix("/s").unwrap_or(trimmed);
+    match size::parse_size(trimmed)? {
+        0 => Err(format!("'{}' is not a rate above zero", value)),
+        rate => Ok(rate),
+    }
+}
+
+pub struct Throttle {
+    bytes_per_sec: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be read right away; negative while over the rate.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        let burst = bytes_per_sec * BURST_SECONDS;
+        Self {
+            bytes_per_sec,
+            burst,
+            bucket: Mutex::new(Bucket { tokens: burst, refilled: Instant::now() }),
+        }
+    }
+
+    /// Accounts for `n` bytes just read, waiting as long as it takes for the
+    /// file to be back under its rate.
+    pub async fn consume(&self, n: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst) - n as f64;
+            bucket.refilled = now;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_sizes_per_second() {
+        assert_eq!(parse_rate("65536"), Ok(65536));
+        assert_eq!(parse_rate("2MB"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("500KB/s"), Ok(500 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_file_is_held_to_its_rate() {
+        let rate = 64 * 1024;
+        let throttle = Throttle::new(rate);
+        let started = Instant::now();
+
+        // A second and a quarter's worth, less the quarter second of burst
+        for _ in 0..20 {
+            throttle.consume(4096).await;
+        }
+
+        let elapsed = started.elapsed().as_secs_f64();
+        let allowed = (20.0 * 4096.0 - rate as f64 * BURST_SECONDS) / rate as f64;
+        assert!(elapsed >= allowed * 0.95, "read 80KB at 64KB/s in {:.2}s", elapsed);
+        assert!(elapsed < allowed + 1.0, "waited {:.2}s", elapsed);
+    }
+
+    #[tokio::test]
+    async fn reads_within_the_burst_are_not_held_up() {
+        let throttle = Throttle::new(64 * 1024);
+        let started = Instant::now();
+        throttle.consume(8 * 1024).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+}
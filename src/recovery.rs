@@ -0,0 +1,125 @@
+// Photos that show up in the webstream with an empty `derivatives` map would
+// otherwise vanish without a trace: there's no checksum to ask webasseturls
+// about. They're usually still being processed on Apple's side, so ask again
+// before giving up, and record whatever stays unresolved in the failures file.
+
+use anyhow::anyhow;
+use std::collections::HashMap;
+
+use crate::dates;
+use crate::failures::FailureLog;
+use crate::http::HttpClient;
+use crate::{
+    asset_download_url, asset_hosts, expiry, fetch_webstream, file_stem, filename_from_url_path, request_asset_urls,
+    AssetKind, DownloadInfo, Photo,
+};
+
+/// Fills in derivatives for photos that had none, and returns download infos
+/// for any that could only be resolved through webasseturls directly.
+pub async fn recover_missing_derivatives(
+    client: &impl HttpClient,
+    hash: &str,
+    photos: &mut [Photo],
+    failure_log: &FailureLog,
+) -> Vec<DownloadInfo> {
+    let missing: Vec<usize> = photos
+        .iter()
+        .enumerate()
+        .filter(|(_, photo)| photo.derivatives.is_empty())
+        .map(|(i, _)| i)
+        .collect();
+
+    if missing.is_empty() {
+        return Vec::new();
+    }
+
+    status!("🔄 {} photos have no downloadable versions yet, asking iCloud again...", missing.len());
+
+    // A fresh webstream often has the derivatives once processing has finished
+    match fetch_webstream(client, hash).await {
+        Ok(fresh) => {
+            let mut fresh_photos: HashMap<String, Photo> = fresh.photos
+                .into_iter()
+                .filter(|photo| !photo.derivatives.is_empty())
+                .map(|photo| (photo.photo_guid.clone(), photo))
+                .collect();
+
+            for &i in &missing {
+                if let Some(fresh_photo) = fresh_photos.remove(&photos[i].photo_guid) {
+                    photos[i].derivatives = fresh_photo.derivatives;
+                }
+            }
+        }
+        Err(e) => eprintln!("⚠️  Could not re-fetch album metadata: {:#}", e),
+    }
+
+    // webasseturls items are keyed by checksum and don't name their photo, so
+    // asking for one GUID at a time is the only way to attribute them
+    let mut recovered = Vec::new();
+    let mut unresolved = 0;
+    for &i in &missing {
+        let photo = &photos[i];
+        if !photo.derivatives.is_empty() {
+            continue;
+        }
+
+        match fetch_single_asset(client, hash, photo).await {
+            Ok(Some(info)) => recovered.push(info),
+            Ok(None) => {
+                unresolved += 1;
+                failure_log.record(&photo.photo_guid, "-", &anyhow!("No downloadable versions available"));
+            }
+            Err(e) => {
+                unresolved += 1;
+                failure_log.record(&photo.photo_guid, "-", &e);
+            }
+        }
+    }
+
+    let resolved = missing.len() - unresolved;
+    if resolved > 0 {
+        status!("✅ Recovered {} of {} photos", resolved, missing.len());
+    }
+    if unresolved > 0 {
+        status!("⚠️  {} photos still have no downloadable versions and were recorded as failures", unresolved);
+    }
+
+    recovered
+}
+
+/// Asks webasseturls about a single photo and uses the largest asset it
+/// returns. `None` if nothing came back.
+async fn fetch_single_asset(
+    client: &impl HttpClient,
+    hash: &str,
+    photo: &Photo,
+) -> anyhow::Result<Option<DownloadInfo>> {
+    let response = request_asset_urls(client, hash, vec![photo.photo_guid.clone()]).await?;
+
+    // Without derivative metadata there's no size to compare, so the longest
+    // path is the best guess at the full-size rendition
+    let Some((checksum, asset_url)) = response.items
+        .iter()
+        .max_by_key(|(_, asset_url)| asset_url.url_path.len())
+    else {
+        return Ok(None);
+    };
+
+    let kind = if photo.is_video() { AssetKind::Video } else { AssetKind::Still };
+    let filename = filename_from_url_path(&asset_url.url_path)
+        .unwrap_or_else(|| format!("{}.{}", photo.photo_guid, kind.default_extension()));
+    Ok(Some(DownloadInfo {
+        photo_guid: photo.photo_guid.clone(),
+        checksum: checksum.clone(),
+        download_url: asset_download_url(&response, asset_url)?,
+        photo_stem: file_stem(&filename).to_string(),
+        filename,
+        size_info: "?x?".to_string(),
+        caption: photo.caption.clone(),
+        date_created: photo.date_created.as_deref().and_then(dates::parse_date_created),
+        hosts: asset_hosts(&response, asset_url),
+        file_size: None,
+        url_expiry: asset_url.url_expiry.as_deref().and_then(expiry::parse_url_expiry),
+        kind,
+    }))
+}
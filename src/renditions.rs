@@ -0,0 +1,124 @@
+// `--list-derivatives`: every rendition iCloud offers for each photo, with
+// its key, pixel size and file size, straight from the album metadata. Meant
+// for picking `--derivatives` keys and for checking whether an album exposes
+// originals; nothing is downloaded and no download URLs are fetched.
+
+use anyhow::Result;
+use comfy_table::presets::UTF8_FULL_CONDENSED;
+use comfy_table::{ContentArrangement, Table};
+use serde::Serialize;
+
+use crate::size::format_size;
+use crate::{derivative_rank, live_photo_motion, selected_derivatives, Derivative, DerivativeSelection, Photo};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListFormat {
+    /// A table on the terminal
+    Table,
+    /// A JSON array on stdout, one object per photo
+    Json,
+}
+
+#[derive(Serialize)]
+struct PhotoRenditions<'a> {
+    photo_guid: &'a str,
+    date_created: Option<&'a str>,
+    width: Option<u32>,
+    height: Option<u32>,
+    derivatives: Vec<Rendition<'a>>,
+}
+
+#[derive(Serialize)]
+struct Rendition<'a> {
+    key: &'a str,
+    video: bool,
+    width: Option<u32>,
+    height: Option<u32>,
+    file_size: Option<u64>,
+    /// Whether this run's options would download it.
+    selected: bool,
+}
+
+/// Prints the renditions of `photos` in `format`. `selected` marks the ones
+/// the current `--derivatives` and `--flatten-live-photos` would download.
+pub fn print(photos: &[Photo], selection: &DerivativeSelection, include_motion: bool, format: ListFormat) -> Result<()> {
+    let listed: Vec<PhotoRenditions> = photos
+        .iter()
+        .map(|photo| renditions(photo, selection, include_motion))
+        .collect();
+    match format {
+        ListFormat::Table => print_table(&listed),
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&listed)?),
+    }
+    Ok(())
+}
+
+fn renditions<'a>(photo: &'a Photo, selection: &DerivativeSelection, include_motion: bool) -> PhotoRenditions<'a> {
+    let mut chosen: Vec<&Derivative> = selected_derivatives(photo, selection)
+        .into_iter()
+        .map(|(_, derivative)| derivative)
+        .collect();
+    if include_motion {
+        chosen.extend(live_photo_motion(photo).map(|(_, derivative)| derivative));
+    }
+
+    let mut derivatives: Vec<Rendition> = photo
+        .derivatives
+        .iter()
+        .map(|(key, derivative)| Rendition {
+            key,
+            video: derivative.is_video(key),
+            width: derivative.width,
+            height: derivative.height,
+            file_size: derivative.file_size_bytes(),
+            selected: chosen.iter().any(|c| std::ptr::eq(*c, derivative)),
+        })
+        .collect();
+    // Stills before videos, largest first
+    derivatives.sort_by(|a, b| {
+        (a.video, derivative_rank(b.key), a.key).cmp(&(b.video, derivative_rank(a.key), b.key))
+    });
+
+    PhotoRenditions {
+        photo_guid: &photo.photo_guid,
+        date_created: photo.date_created.as_deref(),
+        width: photo.width,
+        height: photo.height,
+        derivatives,
+    }
+}
+
+fn print_table(listed: &[PhotoRenditions]) {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["#", "Photo", "Key", "Kind", "Dimensions", "File size", "Selected"]);
+    for (index, photo) in listed.iter().enumerate() {
+        if photo.derivatives.is_empty() {
+            table.add_row(vec![(index + 1).to_string(), photo.photo_guid.to_string(), "(none)".to_string()]);
+        }
+        for (i, rendition) in photo.derivatives.iter().enumerate() {
+            // The photo is named on its first row only
+            let (number, guid) = match i {
+                0 => ((index + 1).to_string(), photo.photo_guid.to_string()),
+                _ => (String::new(), String::new()),
+            };
+            let dimensions = match (rendition.width, rendition.height) {
+                (Some(width), Some(height)) => format!("{}×{}", width, height),
+                _ => "?".to_string(),
+            };
+            table.add_row(vec![
+                number,
+                guid,
+                rendition.key.to_string(),
+                if rendition.video { "video" } else { "image" }.to_string(),
+                dimensions,
+                rendition.file_size.map_or("?".to_string(), format_size),
+                if rendition.selected { "✓" } else { "" }.to_string(),
+            ]);
+        }
+    }
+    status!("\n🧾 Renditions of {} photos:", listed.len());
+    status!("{}", table);
+}
@@ -0,0 +1,182 @@
+// --repair: find files from a previous run that are missing, empty or the
+// wrong size, so only those get downloaded again. Photos whose files the
+// manifest records and that check out on disk are settled before any URLs
+// are requested, so a mostly intact album costs few webasseturls requests.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::existing::DirListing;
+use crate::manifest::ManifestEntry;
+use crate::{live_photo_motion, selected_derivatives, size, DerivativeSelection, DownloadInfo, Photo};
+
+pub enum RepairReason {
+    Missing,
+    Empty,
+    SizeMismatch { expected: u64, actual: u64 },
+}
+
+impl std::fmt::Display for RepairReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepairReason::Missing => write!(f, "missing"),
+            RepairReason::Empty => write!(f, "empty"),
+            RepairReason::SizeMismatch { expected, actual } => write!(
+                f,
+                "size mismatch (expected {}, found {})",
+                size::format_size(*expected),
+                size::format_size(*actual)
+            ),
+        }
+    }
+}
+
+/// GUIDs of the photos whose every file is recorded in the manifest and is
+/// on disk, non-empty and (with `check_sizes`) of the listed size. Only the
+/// other photos need download URLs.
+pub fn intact_photos(
+    photos: &[Photo],
+    selection: &DerivativeSelection,
+    include_live_motion: bool,
+    entries: &[ManifestEntry],
+    listing: &mut DirListing,
+    output_dir: &str,
+    check_sizes: bool,
+) -> HashSet<String> {
+    let recorded: HashMap<(&str, &str), &str> = entries
+        .iter()
+        .map(|entry| ((entry.photo_guid.as_str(), entry.checksum.as_str()), entry.filename.as_str()))
+        .collect();
+
+    photos
+        .iter()
+        .filter(|photo| {
+            let mut derivatives: Vec<_> =
+                selected_derivatives(photo, selection).into_iter().map(|(_, derivative)| derivative).collect();
+            if include_live_motion {
+                derivatives.extend(live_photo_motion(photo).map(|(_, motion)| motion));
+            }
+            derivatives.iter().all(|derivative| {
+                    let Some(filename) = recorded.get(&(photo.photo_guid.as_str(), derivative.checksum.as_str())) else {
+                        return false;
+                    };
+                    let actual = listing.exact_size(output_dir, filename);
+                    check_size(actual, derivative.file_size_bytes(), check_sizes).is_none()
+                })
+        })
+        .map(|photo| photo.photo_guid.clone())
+        .collect()
+}
+
+/// Returns the downloads whose local file needs repairing, with the reason.
+/// Sizes are only compared when `check_sizes` is set, since options like
+/// --strip-metadata legitimately change them.
+pub fn find_damaged(
+    infos: Vec<DownloadInfo>,
+    listing: &mut DirListing,
+    output_dir: &str,
+    check_sizes: bool,
+) -> Vec<(DownloadInfo, RepairReason)> {
+    infos
+        .into_iter()
+        .filter_map(|info| {
+            let actual = listing.file_size(&info, output_dir);
+            let reason = check_size(actual, info.file_size, check_sizes)?;
+            Some((info, reason))
+        })
+        .collect()
+}
+
+fn check_size(actual: Option<u64>, expected: Option<u64>, check_sizes: bool) -> Option<RepairReason> {
+    let Some(actual) = actual else {
+        return Some(RepairReason::Missing);
+    };
+
+    if actual == 0 {
+        return Some(RepairReason::Empty);
+    }
+
+    match expected {
+        Some(expected) if check_sizes && expected != actual => {
+            Some(RepairReason::SizeMismatch { expected, actual })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::download_info;
+    use std::fs;
+
+    fn entry(guid: &str, filename: &str) -> ManifestEntry {
+        ManifestEntry {
+            filename: filename.to_string(),
+            photo_guid: guid.to_string(),
+            checksum: format!("ck{}", guid),
+            kind: "photo".to_string(),
+            size: 5,
+            downloaded_at: "2024-05-01T12:00:00+00:00".to_string(),
+            status: "downloaded".to_string(),
+            caption: None,
+            date_created: None,
+            width: None,
+            height: None,
+        }
+    }
+
+    #[test]
+    fn photos_the_manifest_vouches_for_need_no_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("IMG_0001.JPG"), b"12345").unwrap();
+        fs::write(dir.path().join("IMG_0002.JPG"), b"12").unwrap();
+        let photos: Vec<Photo> = ["P1", "P2", "P3"]
+            .iter()
+            .map(|guid| serde_json::from_value(crate::testing::photo(guid, 5)).unwrap())
+            .collect();
+        // P3 was never downloaded
+        let entries = [entry("P1", "IMG_0001.JPG"), entry("P2", "IMG_0002.JPG")];
+
+        let intact = intact_photos(
+            &photos,
+            &DerivativeSelection::Best,
+            true,
+            &entries,
+            &mut DirListing::default(),
+            output_dir,
+            true,
+        );
+        assert_eq!(intact, HashSet::from(["P1".to_string()]));
+    }
+
+    #[test]
+    fn damaged_files_are_found_from_one_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("good.JPG"), b"12345").unwrap();
+        fs::write(dir.path().join("empty.JPG"), b"").unwrap();
+        fs::write(dir.path().join("short.JPG"), b"12").unwrap();
+        // Renamed by extension correction, and saved in decomposed form
+        fs::write(dir.path().join("renamed.JPG"), b"12345").unwrap();
+        fs::write(dir.path().join("Cafe\u{301}.JPG"), b"12345").unwrap();
+
+        let infos = ["good.JPG", "empty.JPG", "short.JPG", "missing.JPG", "renamed.HEIC", "Caf\u{e9}.JPG"]
+            .iter()
+            .map(|name| download_info(name, name, Some(5)))
+            .collect();
+        let mut listing = DirListing::default();
+        let damaged = find_damaged(infos, &mut listing, output_dir, true);
+
+        let found: Vec<(&str, String)> =
+            damaged.iter().map(|(info, reason)| (info.filename.as_str(), reason.to_string())).collect();
+        assert_eq!(
+            found,
+            [
+                ("empty.JPG", "empty".to_string()),
+                ("short.JPG", RepairReason::SizeMismatch { expected: 5, actual: 2 }.to_string()),
+                ("missing.JPG", "missing".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// Checks on where a downloaded file is about to be written. A symlink left in
+// the output directory could otherwise redirect a write to anywhere on disk,
+// and opening a FIFO or device for writing can block forever or clobber
+// something that isn't a file. Such destinations are refused with an error
+// naming the path rather than handled specially.
+//
+// Downloads are written to a new temporary file and renamed into place, and
+// a rename replaces whatever is at the destination instead of writing
+// through it. The temporary file is created with `create_new`, which never
+// follows a symlink, and the opened handle is then compared with what's at
+// the resolved path, so a directory swapped for a symlink after
+// `check_destination` looked at it is caught too.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::Metadata;
+use std::path::Path;
+use tokio::fs::File;
+
+/// Refuses an output directory that exists but isn't a directory.
+pub fn check_output_dir(output_dir: &Path) -> Result<()> {
+    match std::fs::metadata(output_dir) {
+        Ok(meta) if !meta.is_dir() => Err(anyhow!(
+            "Output path {} exists but is {}, not a directory",
+            output_dir.display(),
+            describe(&meta)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses to write `path` unless it is (or will be) a regular file whose
+/// real location is inside `output_dir`. Call after creating its parent.
+pub async fn check_destination(output_dir: &Path, path: &Path) -> Result<()> {
+    let root = tokio::fs::canonicalize(output_dir)
+        .await
+        .with_context(|| format!("Failed to resolve {}", output_dir.display()))?;
+    let parent = path.parent().unwrap_or(output_dir);
+    let real_parent = tokio::fs::canonicalize(parent)
+        .await
+        .with_context(|| format!("Failed to resolve {}", parent.display()))?;
+    if !real_parent.starts_with(&root) {
+        return Err(anyhow!(
+            "{} resolves to {}, outside the output directory; refusing to write there",
+            parent.display(),
+            real_parent.display()
+        ));
+    }
+
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.file_type().is_symlink() => Err(anyhow!(
+            "{} is a symlink; refusing to write through it",
+            path.display()
+        )),
+        Ok(meta) if !meta.is_file() => Err(anyhow!(
+            "{} is {}; refusing to overwrite it",
+            path.display(),
+            describe(&meta)
+        )),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to inspect {}", path.display())),
+    }
+}
+
+/// Creates `path` as a new, empty file, making sure that what was opened is
+/// a regular file inside `root`. A file already there (left by a run that
+/// stopped mid-download, or a symlink) is removed first, never followed.
+pub async fn create_new_file(root: &Path, path: &Path) -> Result<File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    let opened = match options.open(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            tokio::fs::remove_file(path)
+                .await
+                .with_context(|| format!("Failed to remove the leftover {}", path.display()))?;
+            options.open(path).await
+        }
+        result => result,
+    };
+    let file = opened.with_context(|| format!("Failed to create {}", path.display()))?;
+    if let Err(e) = check_opened(root, path, &file).await {
+        drop(file);
+        let _ = tokio::fs::remove_file(path).await;
+        return Err(e);
+    }
+    Ok(file)
+}
+
+/// Whether `file`, just opened at `path`, is the file that `path` resolves
+/// to inside `root`.
+async fn check_opened(root: &Path, path: &Path, file: &File) -> Result<()> {
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .with_context(|| format!("Failed to resolve {}", root.display()))?;
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let real_parent = tokio::fs::canonicalize(parent)
+        .await
+        .with_context(|| format!("Failed to resolve {}", parent.display()))?;
+    if !real_parent.starts_with(&root) {
+        return Err(anyhow!(
+            "{} resolves to {}, outside the output directory; refusing to write there",
+            parent.display(),
+            real_parent.display()
+        ));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let opened = file.metadata().await?;
+        let real_path = real_parent.join(path.file_name().unwrap_or_default());
+        let there = tokio::fs::symlink_metadata(&real_path)
+            .await
+            .with_context(|| format!("Failed to inspect {}", real_path.display()))?;
+        if (opened.dev(), opened.ino()) != (there.dev(), there.ino()) || !there.is_file() {
+            return Err(anyhow!("{} changed while it was being created; refusing to write there", path.display()));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+fn describe(meta: &Metadata) -> &'static str {
+    let file_type = meta.file_type();
+    if file_type.is_dir() {
+        return "a directory";
+    }
+    if file_type.is_symlink() {
+        return "a symlink";
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return "a named pipe (FIFO)";
+        }
+        if file_type.is_socket() {
+            return "a socket";
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return "a device";
+        }
+    }
+    if file_type.is_file() {
+        "a file"
+    } else {
+        "a special file"
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    fn mkfifo(path: &Path) {
+        let status = std::process::Command::new("mkfifo").arg(path).status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn output_dir_must_be_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("pipe");
+        mkfifo(&fifo);
+        fs::write(dir.path().join("file"), b"x").unwrap();
+
+        assert!(check_output_dir(dir.path()).is_ok());
+        assert!(check_output_dir(&dir.path().join("not yet")).is_ok());
+        let error = check_output_dir(&fifo).unwrap_err().to_string();
+        assert!(error.contains("a named pipe (FIFO)"), "{}", error);
+        assert!(check_output_dir(&dir.path().join("file")).is_err());
+    }
+
+    #[tokio::test]
+    async fn destinations_that_escape_or_arent_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        symlink(outside.path().join("target.jpg"), root.join("link.jpg")).unwrap();
+        symlink(outside.path(), root.join("escape")).unwrap();
+        fs::create_dir(root.join("2024")).unwrap();
+        symlink(root.join("2024"), root.join("inside")).unwrap();
+        mkfifo(&root.join("pipe.jpg"));
+
+        assert!(check_destination(root, &root.join("new.jpg")).await.is_ok());
+        assert!(check_destination(root, &root.join("inside/new.jpg")).await.is_ok());
+        let error = check_destination(root, &root.join("link.jpg")).await.unwrap_err().to_string();
+        assert!(error.contains("is a symlink"), "{}", error);
+        let error = check_destination(root, &root.join("escape/new.jpg")).await.unwrap_err().to_string();
+        assert!(error.contains("outside the output directory"), "{}", error);
+        let error = check_destination(root, &root.join("pipe.jpg")).await.unwrap_err().to_string();
+        assert!(error.contains("a named pipe (FIFO)"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn a_symlink_at_the_new_file_is_replaced_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("target.jpg");
+        fs::write(&target, b"keep me").unwrap();
+        let path = dir.path().join(".photo.jpg.tmp");
+        symlink(&target, &path).unwrap();
+
+        create_new_file(dir.path(), &path).await.unwrap();
+
+        assert!(fs::symlink_metadata(&path).unwrap().is_file());
+        assert_eq!(fs::read(&target).unwrap(), b"keep me");
+    }
+
+    #[tokio::test]
+    async fn a_directory_swapped_for_a_symlink_after_the_check_is_caught() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("2024")).unwrap();
+        assert!(check_destination(root, &root.join("2024/photo.jpg")).await.is_ok());
+
+        fs::remove_dir(root.join("2024")).unwrap();
+        symlink(outside.path(), root.join("2024")).unwrap();
+        let error = create_new_file(root, &root.join("2024/.photo.jpg.tmp")).await.unwrap_err().to_string();
+
+        assert!(error.contains("outside the output directory"), "{}", error);
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+// `--selftest-fixtures`: runs the download pipeline end to end against
+// fixtures built into the binary, without network access, to check that a
+// build works and to reproduce the tool's behaviour exactly in CI.
+//
+// A `FixtureClient` stands in for iCloud and its CDN: it answers webstream
+// and webasseturls from the fixture and serves made-up file contents of the
+// listed sizes. Each fixture is downloaded with default options into its own
+// temporary directory, and what ends up there is compared with what the
+// fixture expects. The directories are removed when everything passed and
+// kept for a look otherwise.
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use clap::Parser;
+use comfy_table::presets::UTF8_FULL_CONDENSED;
+use comfy_table::{ContentArrangement, Table};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::future::{ready, Future};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::headers::RequestKind;
+use crate::http::{HttpClient, HttpResponse};
+use crate::size::format_size;
+use crate::{download_album, failures, fetch_download_urls, fetch_webstream, workdir, Args, DerivativeSelection};
+
+const FIXTURES: [(&str, &str); 5] = [
+    ("photo", include_str!("../fixtures/selftest/photo.json")),
+    ("video", include_str!("../fixtures/selftest/video.json")),
+    ("live-photo", include_str!("../fixtures/selftest/live-photo.json")),
+    ("missing-derivative", include_str!("../fixtures/selftest/missing-derivative.json")),
+    ("empty", include_str!("../fixtures/selftest/empty.json")),
+];
+
+/// The album every fixture is served as.
+const ALBUM_HASH: &str = "B0SelfTestAlbum";
+
+/// Where download URLs point. `.invalid` never resolves, so a request that
+/// slipped past the fixture client couldn't reach anything either.
+const FILE_HOST: &str = "selftest.invalid";
+
+const STAGES: [&str; 4] = ["Album metadata", "Download URLs", "Download", "Files"];
+
+#[derive(Deserialize)]
+struct Fixture {
+    description: String,
+    /// The webstream response, as iCloud sends it.
+    webstream: serde_json::Value,
+    /// What webasseturls has URLs for.
+    assets: Vec<Asset>,
+    expect: Expectation,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    #[serde(rename = "photoGuid")]
+    photo_guid: String,
+    checksum: String,
+    /// The file name at the end of the URL path.
+    name: String,
+    /// Defaults to the `fileSize` of the derivative with this checksum.
+    size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Expectation {
+    /// Every file that should be in the output directory afterwards.
+    files: Vec<ExpectedFile>,
+    /// Photos that should be in the failures file.
+    failed: usize,
+}
+
+#[derive(Deserialize)]
+struct ExpectedFile {
+    name: String,
+    size: u64,
+}
+
+/// Answers requests for one fixture.
+#[derive(Clone)]
+struct FixtureClient {
+    fixture: Arc<Fixture>,
+    /// Size of each asset, by checksum.
+    sizes: Arc<HashMap<String, u64>>,
+}
+
+impl FixtureClient {
+    fn new(fixture: Arc<Fixture>) -> Result<Self> {
+        let listed_sizes: HashMap<&str, u64> = fixture.webstream["photos"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|photo| photo["derivatives"].as_object())
+            .flat_map(|derivatives| derivatives.values())
+            .filter_map(|derivative| {
+                let size = derivative["fileSize"].as_str()?.parse().ok()?;
+                Some((derivative["checksum"].as_str()?, size))
+            })
+            .collect();
+        let sizes = fixture
+            .assets
+            .iter()
+            .map(|asset| {
+                let size = asset
+                    .size
+                    .or_else(|| listed_sizes.get(asset.checksum.as_str()).copied())
+                    .ok_or_else(|| anyhow!("The fixture has no size for asset {}", asset.checksum))?;
+                Ok((asset.checksum.clone(), size))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { fixture, sizes: Arc::new(sizes) })
+    }
+
+    fn api(&self, url: &str, body: serde_json::Value) -> HttpResponse {
+        if url.ends_with("/webstream") {
+            return json_response(url, &self.fixture.webstream);
+        }
+        if !url.ends_with("/webasseturls") {
+            return not_found(url);
+        }
+
+        let wanted: Vec<&str> = body["photoGuids"].as_array().into_iter().flatten().filter_map(|v| v.as_str()).collect();
+        let items: serde_json::Map<String, serde_json::Value> = self
+            .fixture
+            .assets
+            .iter()
+            .filter(|asset| wanted.contains(&asset.photo_guid.as_str()))
+            .map(|asset| {
+                let item = json!({
+                    "url_location": "selftest",
+                    "url_path": format!("/{}/{}", asset.checksum, asset.name),
+                });
+                (asset.checksum.clone(), item)
+            })
+            .collect();
+        json_response(
+            url,
+            &json!({
+                "locations": { "selftest": { "scheme": "https", "hosts": [FILE_HOST] } },
+                "items": items,
+            }),
+        )
+    }
+
+    /// The file a download URL points to; just its headers for a HEAD request.
+    fn file(&self, url: &str, head: bool) -> HttpResponse {
+        let checksum = url
+            .strip_prefix(&format!("https://{}/", FILE_HOST))
+            .and_then(|path| path.split('/').next());
+        let Some((checksum, &size)) = checksum.and_then(|checksum| Some((checksum, self.sizes.get(checksum)?))) else {
+            return not_found(url);
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+        let body = match head {
+            true => Bytes::new(),
+            false => format!("{} ", checksum).into_bytes().into_iter().cycle().take(size as usize).collect(),
+        };
+        HttpResponse::canned(StatusCode::OK, url, headers, body)
+    }
+}
+
+impl HttpClient for FixtureClient {
+    fn post_json<B: Serialize + Sync>(
+        &self,
+        url: &str,
+        _kind: RequestKind,
+        body: &B,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        ready(serde_json::to_value(body).map(|body| self.api(url, body)).map_err(Into::into))
+    }
+
+    fn get(&self, url: &str, _kind: RequestKind) -> impl Future<Output = Result<HttpResponse>> + Send {
+        ready(Ok(self.file(url, false)))
+    }
+
+    fn head(&self, url: &str, _kind: RequestKind) -> impl Future<Output = Result<HttpResponse>> + Send {
+        ready(Ok(self.file(url, true)))
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        _kind: RequestKind,
+        _range: std::ops::Range<u64>,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        // Answered like a server without range support: the whole file
+        ready(Ok(self.file(url, false)))
+    }
+}
+
+fn json_response(url: &str, body: &serde_json::Value) -> HttpResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    HttpResponse::canned(StatusCode::OK, url, headers, Bytes::from(body.to_string()))
+}
+
+fn not_found(url: &str) -> HttpResponse {
+    HttpResponse::canned(StatusCode::NOT_FOUND, url, HeaderMap::new(), Bytes::new())
+}
+
+/// How far one fixture got: the details of the stages that passed, and the
+/// error of the one that failed.
+struct FixtureReport {
+    name: &'static str,
+    passed: Vec<String>,
+    error: Option<anyhow::Error>,
+}
+
+pub async fn run() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("icloud-dl-selftest-{}", std::process::id()));
+    status!("\n🧪 Running the pipeline against {} built-in fixtures, without network access", FIXTURES.len());
+
+    let mut reports = Vec::new();
+    for (name, json) in FIXTURES {
+        let fixture: Fixture = serde_json::from_str(json).with_context(|| format!("The {} fixture is invalid", name))?;
+        status!("\n━━ Fixture {}: {}", name, fixture.description);
+        let mut passed = Vec::new();
+        let error = run_fixture(Arc::new(fixture), &root.join(name), &mut passed).await.err();
+        reports.push(FixtureReport { name, passed, error });
+    }
+
+    print_report(&reports);
+    let failed = reports.iter().filter(|report| report.error.is_some()).count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} fixtures failed; their downloads are in {}",
+            failed,
+            reports.len(),
+            root.display()
+        ));
+    }
+    if let Err(e) = fs::remove_dir_all(&root) {
+        eprintln!("⚠️  Could not remove {}: {}", root.display(), e);
+    }
+    status!("\n✅ All {} fixtures passed", reports.len());
+    Ok(())
+}
+
+/// Runs the stages in order, adding a detail to `passed` for each one that
+/// passes, up to the first that fails.
+async fn run_fixture(fixture: Arc<Fixture>, output_dir: &Path, passed: &mut Vec<String>) -> Result<()> {
+    let client = FixtureClient::new(Arc::clone(&fixture))?;
+
+    let webstream = fetch_webstream(&client, ALBUM_HASH).await?;
+    passed.push(format!("{} photos", webstream.photos.len()));
+
+    let infos = fetch_download_urls(&client, ALBUM_HASH, &webstream.photos, &DerivativeSelection::Best).await?;
+    passed.push(format!("{} URLs", infos.len()));
+
+    let output = output_dir.to_string_lossy().into_owned();
+    let url = format!("https://www.icloud.com/sharedalbum/#{}", ALBUM_HASH);
+    let args = Args::try_parse_from(["icloud-photo-download", "--url", &url, "--output", &output, "--yes"])?;
+    download_album(&client, &args, ALBUM_HASH, None, false, None, None).await?;
+    passed.push("done".to_string());
+
+    passed.push(check_files(&output, &fixture.expect)?);
+    Ok(())
+}
+
+/// Compares the output directory with what the fixture expects.
+fn check_files(output_dir: &str, expect: &Expectation) -> Result<String> {
+    let mut found: BTreeMap<String, u64> = BTreeMap::new();
+    if Path::new(output_dir).exists() {
+        for entry in fs::read_dir(output_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() && !name.starts_with('.') {
+                found.insert(name, entry.metadata()?.len());
+            }
+        }
+    }
+    let expected: BTreeMap<String, u64> = expect.files.iter().map(|file| (file.name.clone(), file.size)).collect();
+    if found != expected {
+        return Err(anyhow!("expected {} but found {}", describe_files(&expected), describe_files(&found)));
+    }
+
+    let failures_path = workdir::tool_dir(output_dir).join(failures::FAILURES_FILE_NAME);
+    let failed = match failures_path.exists() {
+        true => failures::read_failed_guids(&failures_path)?.len(),
+        false => 0,
+    };
+    if failed != expect.failed {
+        return Err(anyhow!("expected {} recorded failures but found {}", expect.failed, failed));
+    }
+
+    Ok(match failed {
+        0 => format!("{} files", found.len()),
+        _ => format!("{} files, {} failures", found.len(), failed),
+    })
+}
+
+fn describe_files(files: &BTreeMap<String, u64>) -> String {
+    if files.is_empty() {
+        return "no files".to_string();
+    }
+    files
+        .iter()
+        .map(|(name, size)| format!("{} ({})", name, format_size(*size)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_report(reports: &[FixtureReport]) {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(std::iter::once("Fixture").chain(STAGES));
+    for report in reports {
+        let mut row = vec![report.name.to_string()];
+        row.extend(report.passed.iter().map(|detail| format!("✅ {}", detail)));
+        if report.error.is_some() {
+            row.push("❌".to_string());
+        }
+        row.resize(STAGES.len() + 1, "⏭️".to_string());
+        table.add_row(row);
+    }
+    status!("\n🧪 Self-test results:");
+    status!("{}", table);
+
+    for report in reports {
+        if let Some(e) = &report.error {
+            let stage = STAGES[report.passed.len().min(STAGES.len() - 1)];
+            status!("❌ {} – {}: {:#}", report.name, stage, e);
+        }
+    }
+}
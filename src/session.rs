@@ -0,0 +1,66 @@
+// Session state kept for the length of a run. The shared album endpoints
+// don't ask for a session today, but Apple's other web endpoints do, through
+// cookies and token headers handed out by an earlier request. Should the
+// album endpoints start doing the same, the tool keeps working: before the
+// first album the web app's page is requested once (`handshake`), the client
+// keeps every cookie it's given in a cookie store, and the token headers
+// below are remembered from any response and sent with every later request.
+// When nothing is handed out, nothing changes.
+//
+// `--no-session` turns all of this off.
+
+use anyhow::{anyhow, Result};
+use reqwest::header::{HeaderMap, SET_COOKIE};
+use std::sync::{Arc, RwLock};
+
+use crate::headers::RequestKind;
+use crate::http::HttpClient;
+
+/// The page the browser loads before talking to the album endpoints.
+const HANDSHAKE_URL: &str = "https://www.icloud.com/sharedalbum/";
+
+/// Response headers that carry a session or CSRF token on Apple's web
+/// endpoints, to be sent back under the same name.
+const TOKEN_HEADERS: &[&str] = &["scnt", "x-apple-id-session-id", "x-apple-session-token", "x-csrf-token"];
+
+/// Tokens received so far, shared by every clone of the client.
+#[derive(Clone, Default)]
+pub struct SessionTokens(Arc<RwLock<Vec<(String, String)>>>);
+
+impl SessionTokens {
+    /// Remembers the tokens in `headers`, replacing older values.
+    pub fn capture(&self, headers: &HeaderMap) {
+        let received: Vec<(String, String)> = TOKEN_HEADERS
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), headers.get(name)?.to_str().ok()?.to_string())))
+            .collect();
+        if received.is_empty() {
+            return;
+        }
+        let mut tokens = self.0.write().unwrap();
+        for (name, value) in received {
+            tokens.retain(|(known, _)| *known != name);
+            tokens.push((name, value));
+        }
+    }
+
+    /// The tokens to send, as header name and value.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// Loads the web app's page so any cookies and tokens it hands out are in
+/// place before the first album request.
+pub async fn handshake(client: &impl HttpClient) -> Result<()> {
+    let response = client.get(HANDSHAKE_URL, RequestKind::Page).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} answered {}", HANDSHAKE_URL, response.status()));
+    }
+    let cookies = response.headers().get_all(SET_COOKIE).iter().count();
+    let tokens = TOKEN_HEADERS.iter().filter(|&&name| response.headers().contains_key(name)).count();
+    if cookies + tokens > 0 {
+        status!("🤝 iCloud started a session ({} cookies, {} tokens); it's sent with every request", cookies, tokens);
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// The host the sharedstreams API requests go to.
+//
+// Shared albums are spread over numbered partitions, each served from its own
+// host: `p<NN>-sharedstreams.icloud.com`, with NN from 01 to a bit over 170.
+// Requests start at the host of the partition the album token encodes (see
+// `partition`), or at `p153` for a token that doesn't decode. A host that
+// doesn't hold the album answers with the non-standard status 330 and names
+// the right one in `X-Apple-MMe-Host`, in both a header and the JSON body;
+// that shows up as
+// `AlbumError::WrongHost`. The webstream request follows it once, and the
+// album's later requests go straight to the host it named. Accounts run by
+// Apple's partner in mainland China live under `icloud.com.cn` instead
+// (`p<NN>-sharedstreams.icloud.com.cn`), which no redirect leads to.
+//
+// `--host-override` sends every request to a given host instead, for albums
+// the default host doesn't reach. Redirects aren't followed then.
+
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::partition;
+
+/// Where requests go when the token names no partition.
+pub const DEFAULT_HOST: &str = "p153-sharedstreams.icloud.com";
+
+/// Status a sharedstreams host answers with when another host holds the album.
+pub const WRONG_HOST_STATUS: u16 = 330;
+
+/// Header (and body field) of a 330 response that names the album's host.
+const WRONG_HOST_HEADER: &str = "X-Apple-MMe-Host";
+
+static HOST_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Hosts that 330 responses named, by album hash.
+static DISCOVERED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Sends all further requests to `host`.
+pub fn set_override(host: String) {
+    let _ = HOST_OVERRIDE.set(host);
+}
+
+/// Where requests for the album `hash` go: --host-override, else the host a
+/// redirect named for the album, else the one its token encodes, else the
+/// default.
+pub fn host_for(hash: &str) -> String {
+    if let Some(host) = HOST_OVERRIDE.get() {
+        return host.clone();
+    }
+    discovered()
+        .lock()
+        .unwrap()
+        .get(hash)
+        .cloned()
+        .or_else(|| partition::host(hash))
+        .unwrap_or_else(|| DEFAULT_HOST.to_string())
+}
+
+/// Sends the album's further requests to `host`, which a 330 named. Returns
+/// false, changing nothing, when the redirect isn't to be followed: with
+/// --host-override, or when the album was already redirected once, so two
+/// hosts that point at each other can't send requests back and forth.
+pub fn follow_redirect(hash: &str, host: &str) -> bool {
+    if HOST_OVERRIDE.get().is_some() {
+        return false;
+    }
+    let mut discovered = discovered().lock().unwrap();
+    if discovered.contains_key(hash) {
+        return false;
+    }
+    discovered.insert(hash.to_string(), host.to_string());
+    true
+}
+
+fn discovered() -> &'static Mutex<HashMap<String, String>> {
+    DISCOVERED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// URL of a sharedstreams endpoint (`webstream`, `webasseturls`) for an album.
+pub fn api_url(hash: &str, endpoint: &str) -> String {
+    format!("https://{}/{}/sharedstreams/{}", host_for(hash), hash, endpoint)
+}
+
+/// Accepts a host name, tolerating a pasted `https://` and trailing slash.
+pub fn parse_host(value: &str) -> Result<String, String> {
+    let host = value.trim();
+    let host = host.strip_prefix("https://").unwrap_or(host).trim_end_matches('/');
+    let valid = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(host.to_ascii_lowercase())
+    } else {
+        Err(format!("'{}' is not a host name like p42-sharedstreams.icloud.com", value))
+    }
+}
+
+/// The host a status 330 response points to, from its body or else its header.
+pub fn host_from_wrong_host_response(headers: &HeaderMap, body: &str) -> Option<String> {
+    let from_body = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value.get(WRONG_HOST_HEADER)?.as_str().map(str::to_string));
+    let from_header = || headers.get(WRONG_HOST_HEADER)?.to_str().ok().map(str::to_string);
+    parse_host(&from_body.or_else(from_header)?).ok()
+}
@@ -0,0 +1,71 @@
+// `--smart-names`: a captioned photo's files are named after its caption
+// ("Beach at sunset.jpg") instead of the camera's name (IMG_0001.JPG); photos
+// without a caption keep their original name. Photos sharing a caption are
+// numbered in album order ("Beach_1.jpg", "Beach_2.jpg"), compared without
+// regard to case so they don't collide on case-insensitive filesystems. Long
+// captions are cut at a character boundary, leaving room for the number,
+// the derivative suffix, the extension and a date prefix.
+//
+// Runs before date naming, so --date-prefix and --folder-by-date apply on
+// top: "2024-05-01_Beach_1.jpg".
+
+use std::collections::HashMap;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::{limit_filename_length, DownloadInfo, Photo};
+
+/// Longest caption kept in a name, in bytes of UTF-8.
+const MAX_CAPTION_BYTES: usize = 180;
+
+pub struct SmartNames {
+    /// Photo GUID to the stem its files get.
+    stems: HashMap<String, String>,
+}
+
+impl SmartNames {
+    /// Works out the names for the whole album up front, so numbering is
+    /// the same however the downloads are batched.
+    pub fn new(photos: &[Photo]) -> Self {
+        let captioned: Vec<(&str, String)> = photos
+            .iter()
+            .filter_map(|photo| {
+                let caption = photo.caption.as_deref()?;
+                let stem = render_caption(caption, CaptionContext::Filename { max_bytes: MAX_CAPTION_BYTES });
+                (!stem.is_empty()).then_some((photo.photo_guid.as_str(), stem))
+            })
+            .collect();
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for (_, stem) in &captioned {
+            *counts.entry(stem.to_lowercase()).or_default() += 1;
+        }
+
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        let stems = captioned
+            .into_iter()
+            .map(|(guid, stem)| {
+                let key = stem.to_lowercase();
+                let stem = if counts[&key] > 1 {
+                    let index = seen.entry(key).or_default();
+                    *index += 1;
+                    format!("{}_{}", stem, index)
+                } else {
+                    stem
+                };
+                (guid.to_string(), stem)
+            })
+            .collect();
+        Self { stems }
+    }
+
+    /// Renames a captioned photo's file, keeping whatever follows the
+    /// photo's stem (derivative suffix, extension).
+    pub fn apply(&self, info: &mut DownloadInfo) {
+        let Some(stem) = self.stems.get(&info.photo_guid) else {
+            return;
+        };
+        if let Some(rest) = info.filename.strip_prefix(info.photo_stem.as_str()) {
+            info.filename = limit_filename_length(&format!("{}{}", stem, rest));
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Timing breakdown for --stats: how long each phase took, how many requests
+// were retried and how per-file download times were distributed. Helps tell
+// a slow API from a slow CDN.
+//
+// --stats-json writes the same data plus run-wide counters (retries, URL
+// refreshes, HTTP 429s, bytes received) as one JSON object per album. The
+// counters are bumped from wherever the event happens, like the clock
+// offset in `clock`, and each album reports its share of them.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::atomic::append_line;
+
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static URL_REFRESHES: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+static BYTES_DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+// Totals over every album of the run, for the RESULT line
+static SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// A request or download that is being tried again.
+pub fn count_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A download URL that was fetched again because it had expired or was about to.
+pub fn count_url_refresh() {
+    URL_REFRESHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// An HTTP 429 response.
+pub fn count_rate_limited() {
+    RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_bytes_downloaded(bytes: u64) {
+    BYTES_DOWNLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// A file left out by a filter or because it already exists.
+pub fn count_skipped() {
+    SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Bytes received over the whole run so far.
+pub fn bytes_downloaded() -> u64 {
+    BYTES_DOWNLOADED.load(Ordering::Relaxed)
+}
+
+/// Files downloaded successfully over the whole run.
+pub fn run_succeeded() -> u64 {
+    SUCCEEDED.load(Ordering::Relaxed)
+}
+
+/// Downloads that failed over the whole run.
+pub fn run_failed() -> u64 {
+    FAILED.load(Ordering::Relaxed)
+}
+
+/// Files left out over the whole run.
+pub fn run_skipped() -> u64 {
+    SKIPPED.load(Ordering::Relaxed)
+}
+
+/// Prints the one-line run summary for wrapper scripts. Always goes to
+/// stderr, whatever else the output mode is.
+pub fn print_result_line() {
+    eprintln!(
+        "RESULT ok={} fail={} skip={} bytes={}",
+        SUCCEEDED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        SKIPPED.load(Ordering::Relaxed),
+        BYTES_DOWNLOADED.load(Ordering::Relaxed)
+    );
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    retries: u64,
+    url_refreshes: u64,
+    rate_limited: u64,
+    bytes_downloaded: u64,
+}
+
+impl Counters {
+    fn now() -> Self {
+        Self {
+            retries: RETRIES.load(Ordering::Relaxed),
+            url_refreshes: URL_REFRESHES.load(Ordering::Relaxed),
+            rate_limited: RATE_LIMITED.load(Ordering::Relaxed),
+            bytes_downloaded: BYTES_DOWNLOADED.load(Ordering::Relaxed),
+        }
+    }
+
+    fn since(self, start: Self) -> Self {
+        Self {
+            retries: self.retries - start.retries,
+            url_refreshes: self.url_refreshes - start.url_refreshes,
+            rate_limited: self.rate_limited - start.rate_limited,
+            bytes_downloaded: self.bytes_downloaded - start.bytes_downloaded,
+        }
+    }
+}
+
+pub struct RunStats {
+    started: Instant,
+    counters_at_start: Counters,
+    phases: Vec<(&'static str, Duration)>,
+    // Filled in concurrently by the download tasks
+    download_durations: Mutex<Vec<Duration>>,
+    /// Succeeded and failed downloads, once the download phase is over.
+    results: Mutex<(usize, usize)>,
+}
+
+/// What --stats-json writes for one album.
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    album: &'a str,
+    succeeded: usize,
+    failed: usize,
+    wall_time_secs: f64,
+    phases_secs: BTreeMap<&'static str, f64>,
+    retries: u64,
+    url_refreshes: u64,
+    rate_limited_responses: u64,
+    bytes_downloaded: u64,
+    /// Downloads in flight on average during the download phase.
+    average_concurrency: f64,
+    download_secs: Option<Percentiles>,
+}
+
+#[derive(Serialize)]
+struct Percentiles {
+    min: f64,
+    median: f64,
+    p95: f64,
+    max: f64,
+}
+
+impl Default for RunStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            counters_at_start: Counters::now(),
+            phases: Vec::new(),
+            download_durations: Mutex::new(Vec::new()),
+            results: Mutex::new((0, 0)),
+        }
+    }
+}
+
+/// Removes the --stats-json file left by a previous run; each album of
+/// this run then appends its line.
+pub fn remove_stale_json(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove old {}", path.display())),
+    }
+}
+
+impl RunStats {
+    pub fn record_phase(&mut self, name: &'static str, duration: Duration) {
+        self.phases.push((name, duration));
+    }
+
+    pub fn record_download(&self, duration: Duration) {
+        self.download_durations.lock().unwrap().push(duration);
+    }
+
+    pub fn record_results(&self, succeeded: usize, failed: usize) {
+        *self.results.lock().unwrap() = (succeeded, failed);
+        SUCCEEDED.fetch_add(succeeded as u64, Ordering::Relaxed);
+        FAILED.fetch_add(failed as u64, Ordering::Relaxed);
+    }
+
+    /// Succeeded and failed downloads of this album.
+    pub fn results(&self) -> (usize, usize) {
+        *self.results.lock().unwrap()
+    }
+
+    pub fn print(&self) {
+        status!("\n⏱️  Timing breakdown");
+        for (name, duration) in &self.phases {
+            status!("   {:<16} {}", name, format_duration(*duration));
+        }
+        let counters = Counters::now().since(self.counters_at_start);
+        status!(
+            "   Retries: {} | URL refreshes: {} | HTTP 429s: {}",
+            counters.retries,
+            counters.url_refreshes,
+            counters.rate_limited
+        );
+
+        let mut durations = self.download_durations.lock().unwrap().clone();
+        if durations.is_empty() {
+            return;
+        }
+        durations.sort();
+
+        status!(
+            "   Per file ({}): min {} | median {} | p95 {} | max {}",
+            durations.len(),
+            format_duration(durations[0]),
+            format_duration(percentile(&durations, 50)),
+            format_duration(percentile(&durations, 95)),
+            format_duration(durations[durations.len() - 1])
+        );
+    }
+
+    /// Appends this album's stats to `path` as one line of JSON.
+    pub fn write_json(&self, path: &Path, album: &str) -> Result<()> {
+        let (succeeded, failed) = *self.results.lock().unwrap();
+        let counters = Counters::now().since(self.counters_at_start);
+
+        let mut durations = self.download_durations.lock().unwrap().clone();
+        durations.sort();
+        let download_phase = self
+            .phases
+            .iter()
+            .find(|(name, _)| *name == "Download")
+            .map(|(_, duration)| duration.as_secs_f64())
+            .unwrap_or_default();
+        let busy: f64 = durations.iter().map(Duration::as_secs_f64).sum();
+
+        let report = StatsReport {
+            album,
+            succeeded,
+            failed,
+            wall_time_secs: self.started.elapsed().as_secs_f64(),
+            phases_secs: self.phases.iter().map(|(name, duration)| (*name, duration.as_secs_f64())).collect(),
+            retries: counters.retries,
+            url_refreshes: counters.url_refreshes,
+            rate_limited_responses: counters.rate_limited,
+            bytes_downloaded: counters.bytes_downloaded,
+            average_concurrency: if download_phase > 0.0 { busy / download_phase } else { 0.0 },
+            download_secs: (!durations.is_empty()).then(|| Percentiles {
+                min: durations[0].as_secs_f64(),
+                median: percentile(&durations, 50).as_secs_f64(),
+                p95: percentile(&durations, 95).as_secs_f64(),
+                max: durations[durations.len() - 1].as_secs_f64(),
+            }),
+        };
+
+        let mut line = serde_json::to_string(&report)?;
+        line.push('\n');
+        append_line(path, &line)
+    }
+}
+
+/// Nearest-rank percentile of an already sorted, non-empty slice.
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 1.0 {
+        format!("{:.0}ms", secs * 1000.0)
+    } else if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        format!("{}m{:02}s", duration.as_secs() / 60, duration.as_secs() % 60)
+    }
+}
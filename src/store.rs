@@ -0,0 +1,175 @@
+// `--content-store`: deduplication across albums. Each asset is stored once
+// as `<store>/<checksum>`, named after iCloud's checksum for it, and the
+// album directories get links to it (`--link-mode`). A photo shared into
+// several albums is then downloaded and stored once.
+//
+// The store directory is its own index: an asset is there exactly when its
+// file is, since files are written under a temporary name and renamed into
+// place once complete. Two downloads of the same checksum in one run are
+// serialized, so the second one finds the first one's file instead of
+// downloading it again.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::atomic::temporary_path;
+use crate::filetype::SNIFF_BYTES;
+use crate::permissions::OutputPermissions;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Hard links; the store has to be on the same filesystem as the albums
+    Hardlink,
+    /// Symbolic links to the stored file
+    Symlink,
+    /// Plain copies, which save downloads but not space
+    Copy,
+}
+
+/// An asset already in the store.
+pub struct StoredAsset {
+    path: PathBuf,
+    pub size: u64,
+    /// The start of the file, for `filetype::detect_extension`.
+    pub head: Vec<u8>,
+}
+
+pub struct ContentStore {
+    dir: PathBuf,
+    link_mode: LinkMode,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    reused_files: AtomicUsize,
+    reused_bytes: AtomicU64,
+}
+
+impl ContentStore {
+    pub fn new(dir: PathBuf, link_mode: LinkMode) -> Self {
+        Self {
+            dir,
+            link_mode,
+            locks: Mutex::new(HashMap::new()),
+            reused_files: AtomicUsize::new(0),
+            reused_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Held while an asset is looked up, downloaded and linked, so the same
+    /// checksum is never fetched twice at once.
+    pub async fn lock(&self, checksum: &str) -> OwnedMutexGuard<()> {
+        let lock = self.locks.lock().unwrap().entry(checksum.to_string()).or_default().clone();
+        lock.lock_owned().await
+    }
+
+    pub async fn find(&self, checksum: &str) -> Result<Option<StoredAsset>> {
+        let path = self.path_for(checksum);
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+        };
+        let size = file.metadata().await?.len();
+        let mut head = Vec::with_capacity(SNIFF_BYTES);
+        (&mut file).take(SNIFF_BYTES as u64).read_to_end(&mut head).await?;
+        Ok(Some(StoredAsset { path, size, head }))
+    }
+
+    /// Links a stored asset to `destination`, counting it as a download saved.
+    pub async fn reuse(&self, asset: &StoredAsset, destination: &Path) -> Result<()> {
+        self.link(&asset.path, destination).await?;
+        self.reused_files.fetch_add(1, Ordering::Relaxed);
+        self.reused_bytes.fetch_add(asset.size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Adds a downloaded asset to the store and links it to `destination`.
+    pub async fn save(
+        &self,
+        checksum: &str,
+        content: &[u8],
+        destination: &Path,
+        permissions: &OutputPermissions,
+    ) -> Result<()> {
+        permissions.create_dir_all(&self.dir).await.context("Failed to create the content store")?;
+        let path = self.path_for(checksum);
+        let tmp_path = temporary_path(&path);
+        let mut file = permissions
+            .create_file(&tmp_path)
+            .await
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        file.write_all(content).await.context("Failed to write file")?;
+        file.sync_all().await.context("Failed to sync file")?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .with_context(|| format!("Failed to move {} into the content store", tmp_path.display()))?;
+
+        self.link(&path, destination).await
+    }
+
+    /// Whether `path` is a symlink this store made, which may be replaced.
+    pub fn is_store_link(&self, path: &Path) -> bool {
+        let (Ok(target), Ok(dir)) = (std::fs::read_link(path), std::fs::canonicalize(&self.dir)) else {
+            return false;
+        };
+        target.starts_with(dir)
+    }
+
+    /// Files and bytes taken from the store instead of downloaded since the
+    /// last call.
+    pub fn take_savings(&self) -> (usize, u64) {
+        (self.reused_files.swap(0, Ordering::Relaxed), self.reused_bytes.swap(0, Ordering::Relaxed))
+    }
+
+    fn path_for(&self, checksum: &str) -> PathBuf {
+        // Checksums are alphanumeric; anything else is kept out of the path
+        let name: String = checksum
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.dir.join(name)
+    }
+
+    /// Replaces `destination` with a link to `stored`, via a temporary name
+    /// so an existing file is swapped out in one step.
+    async fn link(&self, stored: &Path, destination: &Path) -> Result<()> {
+        let tmp_path = temporary_path(destination);
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        match self.link_mode {
+            LinkMode::Hardlink => tokio::fs::hard_link(stored, &tmp_path).await.with_context(|| {
+                format!(
+                    "Failed to hard-link {} (the content store must be on the same filesystem as the output; \
+                     try --link-mode symlink or copy)",
+                    stored.display()
+                )
+            })?,
+            LinkMode::Symlink => {
+                let target = tokio::fs::canonicalize(stored).await?;
+                symlink(&target, &tmp_path)
+                    .await
+                    .with_context(|| format!("Failed to symlink {}", target.display()))?;
+            }
+            LinkMode::Copy => {
+                tokio::fs::copy(stored, &tmp_path)
+                    .await
+                    .with_context(|| format!("Failed to copy {}", stored.display()))?;
+            }
+        }
+        tokio::fs::rename(&tmp_path, destination)
+            .await
+            .with_context(|| format!("Failed to write {}", destination.display()))
+    }
+}
+
+#[cfg(unix)]
+async fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+#[cfg(windows)]
+async fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink_file(target, link).await
+}
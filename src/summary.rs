@@ -0,0 +1,126 @@
+// `--summary-json`: one small JSON object per run for monitoring, written
+// at the end of every run, including runs that failed part of the way. It
+// holds run-level totals and the albums' failures, and none of the per-photo
+// detail of the manifest. The file is replaced in one step, so a monitor
+// never reads half of one.
+//
+// The schema is kept stable: fields may be added, but existing ones keep
+// their name and meaning. `version` changes if that ever has to break.
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::atomic::atomic_write;
+use crate::failures;
+use crate::stats;
+
+const SCHEMA_VERSION: u32 = 1;
+
+pub struct RunSummary {
+    started_at: DateTime<Utc>,
+    started: Instant,
+    albums: Mutex<Vec<AlbumSummary>>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    version: u32,
+    started_at: String,
+    finished_at: String,
+    duration_secs: f64,
+    /// `ok`, or `failed` when any album or download failed.
+    status: &'static str,
+    error: Option<String>,
+    succeeded: u64,
+    failed: u64,
+    skipped: u64,
+    bytes_downloaded: u64,
+    albums: &'a [AlbumSummary],
+}
+
+#[derive(Serialize)]
+struct AlbumSummary {
+    /// The album hash, or the URL as given if it couldn't be resolved.
+    album: String,
+    name: Option<String>,
+    succeeded: usize,
+    failed: usize,
+    error: Option<String>,
+    /// The album's entries in its failures file.
+    failures: Vec<failures::FailureRecord>,
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        Self {
+            started_at: Utc::now(),
+            started: Instant::now(),
+            albums: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl RunSummary {
+    /// Records an album that got as far as downloading. `failures_path` is
+    /// its failures file.
+    pub fn record_album(&self, album: &str, name: Option<&str>, results: (usize, usize), failures_path: &Path) {
+        let failures = failures::read_failures(failures_path).unwrap_or_else(|e| {
+            eprintln!("⚠️  Could not read the failures for the run summary: {:#}", e);
+            Vec::new()
+        });
+        self.albums.lock().unwrap().push(AlbumSummary {
+            album: album.to_string(),
+            name: name.map(str::to_string),
+            succeeded: results.0,
+            failed: results.1,
+            error: None,
+            failures,
+        });
+    }
+
+    /// Records how an album ended. An album that failed before downloading
+    /// anything gets its own entry here.
+    pub fn record_outcome(&self, album: &str, outcome: &Result<()>) {
+        let Err(e) = outcome else {
+            return;
+        };
+        let mut albums = self.albums.lock().unwrap();
+        match albums.last_mut().filter(|last| last.album == album && last.error.is_none()) {
+            Some(last) => last.error = Some(format!("{:#}", e)),
+            None => albums.push(AlbumSummary {
+                album: album.to_string(),
+                name: None,
+                succeeded: 0,
+                failed: 0,
+                error: Some(format!("{:#}", e)),
+                failures: Vec::new(),
+            }),
+        }
+    }
+
+    /// Writes the summary of the run to `path`, replacing any earlier one.
+    pub fn write(&self, path: &Path, outcome: &Result<()>) -> Result<()> {
+        let albums = self.albums.lock().unwrap();
+        let failed = stats::run_failed();
+        let report = Report {
+            version: SCHEMA_VERSION,
+            started_at: self.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            finished_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            status: if outcome.is_err() || failed > 0 { "failed" } else { "ok" },
+            error: outcome.as_ref().err().map(|e| format!("{:#}", e)),
+            succeeded: stats::run_succeeded(),
+            failed,
+            skipped: stats::run_skipped(),
+            bytes_downloaded: stats::bytes_downloaded(),
+            albums: &albums,
+        };
+        let mut json = serde_json::to_vec_pretty(&report)?;
+        json.push(b'\n');
+        atomic_write(path, &json)
+    }
+}
@@ -0,0 +1,213 @@
+// Test helpers: `FakeClient`, an in-memory `HttpClient` that answers each
+// request through a closure and records what was asked, so the fetch and
+// download code can be run without a network.
+
+use anyhow::Result;
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::future::{ready, Future};
+use std::sync::{Arc, Mutex};
+
+use crate::headers::RequestKind;
+use crate::http::{HttpClient, HttpResponse};
+use crate::{AssetKind, DownloadInfo};
+
+/// A request as the fake client saw it.
+#[derive(Clone, Debug)]
+pub struct FakeRequest {
+    pub method: &'static str,
+    pub url: String,
+    /// The JSON body of a POST.
+    pub body: Option<serde_json::Value>,
+    pub range: Option<std::ops::Range<u64>>,
+}
+
+type Handler = dyn Fn(&FakeRequest) -> HttpResponse + Send + Sync;
+
+#[derive(Clone)]
+pub struct FakeClient {
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<FakeRequest>>>,
+}
+
+impl FakeClient {
+    pub fn new(handler: impl Fn(&FakeRequest) -> HttpResponse + Send + Sync + 'static) -> Self {
+        Self { handler: Arc::new(handler), requests: Arc::default() }
+    }
+
+    /// Every request so far, in order.
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// How many requests went to a URL containing `part`.
+    pub fn count(&self, part: &str) -> usize {
+        self.requests.lock().unwrap().iter().filter(|request| request.url.contains(part)).count()
+    }
+
+    fn answer(&self, request: FakeRequest) -> HttpResponse {
+        let response = (self.handler)(&request);
+        self.requests.lock().unwrap().push(request);
+        response
+    }
+}
+
+impl HttpClient for FakeClient {
+    fn post_json<B: Serialize + Sync>(
+        &self,
+        url: &str,
+        _kind: RequestKind,
+        body: &B,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        let body = serde_json::to_value(body).ok();
+        ready(Ok(self.answer(FakeRequest { method: "POST", url: url.to_string(), body, range: None })))
+    }
+
+    fn get(&self, url: &str, _kind: RequestKind) -> impl Future<Output = Result<HttpResponse>> + Send {
+        ready(Ok(self.answer(FakeRequest { method: "GET", url: url.to_string(), body: None, range: None })))
+    }
+
+    fn head(&self, url: &str, _kind: RequestKind) -> impl Future<Output = Result<HttpResponse>> + Send {
+        ready(Ok(self.answer(FakeRequest { method: "HEAD", url: url.to_string(), body: None, range: None })))
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        _kind: RequestKind,
+        range: std::ops::Range<u64>,
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        let request = FakeRequest { method: "GET", url: url.to_string(), body: None, range: Some(range) };
+        ready(Ok(self.answer(request)))
+    }
+}
+
+pub fn json(url: &str, body: serde_json::Value) -> HttpResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    HttpResponse::canned(StatusCode::OK, url, headers, Bytes::from(body.to_string()))
+}
+
+/// A file download; its Content-Length is `declared`, which a test can set
+/// above the body's length to cut it short.
+pub fn file(url: &str, body: &[u8], declared: u64) -> HttpResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(declared));
+    HttpResponse::canned(StatusCode::OK, url, headers, Bytes::copy_from_slice(body))
+}
+
+/// The bytes of `content` in `range` as a 206 response.
+pub fn partial(url: &str, content: &[u8], range: &std::ops::Range<u64>) -> HttpResponse {
+    let end = range.end.min(content.len() as u64);
+    let body = &content[range.start as usize..end as usize];
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    let content_range = format!("bytes {}-{}/{}", range.start, end - 1, content.len());
+    headers.insert(reqwest::header::CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+    HttpResponse::canned(StatusCode::PARTIAL_CONTENT, url, headers, Bytes::copy_from_slice(body))
+}
+
+/// `response` claiming a Content-Length of `declared`, e.g. to cut it short.
+pub fn with_content_length(response: HttpResponse, declared: u64) -> HttpResponse {
+    response.with_header(CONTENT_LENGTH, HeaderValue::from(declared))
+}
+
+pub fn status(url: &str, status: u16) -> HttpResponse {
+    HttpResponse::canned(StatusCode::from_u16(status).unwrap(), url, HeaderMap::new(), Bytes::new())
+}
+
+/// A webstream photo with one still derivative of `size` bytes, whose
+/// checksum is `ck<guid>`.
+pub fn photo(guid: &str, size: usize) -> serde_json::Value {
+    serde_json::json!({
+        "photoGuid": guid,
+        "dateCreated": "2024-05-01T12:00:00Z",
+        "width": "10",
+        "height": "10",
+        "derivatives": {
+            "1": { "fileSize": size.to_string(), "checksum": format!("ck{}", guid), "width": "10", "height": "10" }
+        }
+    })
+}
+
+/// A webasseturls answer with a URL on `files.test` for each checksum,
+/// ending in `<name>`.
+pub fn asset_urls(items: &[(&str, &str)]) -> serde_json::Value {
+    let items: serde_json::Map<String, serde_json::Value> = items
+        .iter()
+        .map(|(checksum, name)| {
+            let item = serde_json::json!({ "url_location": "test", "url_path": format!("/{}/{}", checksum, name) });
+            (checksum.to_string(), item)
+        })
+        .collect();
+    serde_json::json!({
+        "locations": { "test": { "scheme": "https", "hosts": ["files.test"] } },
+        "items": items,
+    })
+}
+
+/// A photo of a fake album: its GUID, the file name in its URL, and its
+/// contents.
+#[derive(Clone)]
+pub struct FakePhoto {
+    pub guid: String,
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+impl FakePhoto {
+    pub fn new(guid: &str, name: &str, content: &[u8]) -> Self {
+        Self { guid: guid.to_string(), name: name.to_string(), content: content.to_vec() }
+    }
+}
+
+/// Answers like iCloud would for an album of `photos`: the webstream,
+/// webasseturls for the GUIDs asked for, and the files themselves.
+pub fn album(photos: Vec<FakePhoto>) -> impl Fn(&FakeRequest) -> HttpResponse + Send + Sync + 'static {
+    move |request| {
+        if request.url.ends_with("/webstream") {
+            let listed: Vec<serde_json::Value> = photos.iter().map(|p| photo(&p.guid, p.content.len())).collect();
+            return json(&request.url, serde_json::json!({ "streamName": "Fake", "photos": listed }));
+        }
+        if request.url.ends_with("/webasseturls") {
+            let wanted = request.body.as_ref().map(|body| body["photoGuids"].clone()).unwrap_or_default();
+            let items: Vec<(String, &str)> = photos
+                .iter()
+                .filter(|p| wanted.as_array().is_some_and(|guids| guids.iter().any(|g| g == p.guid.as_str())))
+                .map(|p| (format!("ck{}", p.guid), p.name.as_str()))
+                .collect();
+            let items: Vec<(&str, &str)> = items.iter().map(|(checksum, name)| (checksum.as_str(), *name)).collect();
+            return json(&request.url, asset_urls(&items));
+        }
+        match photos.iter().find(|p| request.url.starts_with(&format!("https://files.test/ck{}/", p.guid))) {
+            Some(p) => match &request.range {
+                Some(range) => partial(&request.url, &p.content, range),
+                None => file(&request.url, &p.content, p.content.len() as u64),
+            },
+            None => status(&request.url, 404),
+        }
+    }
+}
+
+/// A still on `files.test`, named `filename`, whose checksum is `ck<guid>`.
+pub fn download_info(guid: &str, filename: &str, file_size: Option<u64>) -> DownloadInfo {
+    let checksum = format!("ck{}", guid);
+    DownloadInfo {
+        photo_guid: guid.to_string(),
+        download_url: format!("https://files.test/{}/{}", checksum, filename),
+        checksum,
+        filename: filename.to_string(),
+        photo_stem: crate::file_stem(filename).to_string(),
+        size_info: "1".to_string(),
+        caption: None,
+        date_created: None,
+        hosts: vec!["files.test".to_string()],
+        file_size,
+        url_expiry: None,
+        kind: AssetKind::Still,
+    }
+}
@@ -0,0 +1,157 @@
+// `--reencode-videos`: re-encodes downloaded videos with ffmpeg, to save
+// space or for players that can't handle HEVC.
+//
+// Transcoding is CPU-bound and much slower than downloading, so it doesn't
+// run inside the download tasks: each saved video is handed over and
+// transcoded in the background, at most --reencode-jobs at a time, while the
+// downloads carry on. The output keeps the original's name and container; the
+// original is replaced once the transcode has finished, unless
+// --keep-original is given. Live Photo videos are left alone, as re-encoding
+// them breaks the pairing with their still.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::Args;
+
+/// Extensions of the video files ffmpeg is given.
+const VIDEO_EXTENSIONS: [&str; 3] = ["mov", "mp4", "m4v"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReencodePreset {
+    /// H.264 at CRF 23 with AAC audio; plays almost anywhere
+    H264,
+    /// HEVC at CRF 28 with AAC audio; about half the size of H.264
+    Hevc,
+    /// H.264 at CRF 23, scaled down to at most 720 lines
+    #[value(name = "h264-720p")]
+    H264Small,
+}
+
+impl ReencodePreset {
+    fn codec_args(self) -> &'static [&'static str] {
+        match self {
+            ReencodePreset::H264 => &["-c:v", "libx264", "-crf", "23", "-preset", "medium"],
+            // The hvc1 tag is what Apple players look for
+            ReencodePreset::Hevc => &["-c:v", "libx265", "-crf", "28", "-preset", "medium", "-tag:v", "hvc1"],
+            ReencodePreset::H264Small => {
+                &["-c:v", "libx264", "-crf", "23", "-preset", "medium", "-vf", "scale=-2:'min(720,ih)'"]
+            }
+        }
+    }
+}
+
+pub struct Transcoder {
+    preset: ReencodePreset,
+    keep_original: bool,
+    jobs: Arc<Semaphore>,
+    tasks: Mutex<JoinSet<()>>,
+    succeeded: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl Transcoder {
+    /// `None` without --reencode-videos. Fails up front when ffmpeg can't be
+    /// run, rather than once per video.
+    pub async fn from_args(args: &Args) -> Result<Option<Self>> {
+        let Some(preset) = args.reencode_videos else {
+            return Ok(None);
+        };
+        let found = Command::new("ffmpeg")
+            .arg("-version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|status| status.success());
+        if !found {
+            return Err(anyhow!("--reencode-videos needs ffmpeg, which wasn't found on the PATH"));
+        }
+
+        Ok(Some(Self {
+            preset,
+            keep_original: args.keep_original,
+            jobs: Arc::new(Semaphore::new(args.reencode_jobs.max(1))),
+            tasks: Mutex::new(JoinSet::new()),
+            succeeded: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicUsize::new(0)),
+        }))
+    }
+
+    pub fn is_video(filename: &str) -> bool {
+        filename
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| VIDEO_EXTENSIONS.iter().any(|video| ext.eq_ignore_ascii_case(video)))
+    }
+
+    /// Queues a saved video; `name` is its path relative to the output directory.
+    pub fn submit(&self, path: PathBuf, name: String) {
+        let (preset, keep_original) = (self.preset, self.keep_original);
+        let jobs = Arc::clone(&self.jobs);
+        let (succeeded, failed) = (Arc::clone(&self.succeeded), Arc::clone(&self.failed));
+        self.tasks.lock().unwrap().spawn(async move {
+            let Ok(_permit) = jobs.acquire().await else {
+                return;
+            };
+            match transcode(&path, preset, keep_original).await {
+                Ok(()) => succeeded.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    eprintln!("⚠️  Could not re-encode {}: {:#}", name, e);
+                    failed.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+        });
+    }
+
+    /// Waits for the queued transcodes. Returns how many succeeded and failed.
+    pub async fn finish(self) -> (usize, usize) {
+        let mut tasks = self.tasks.into_inner().unwrap();
+        if !tasks.is_empty() {
+            status!("🎞️  Waiting for {} video re-encodes...", tasks.len());
+        }
+        while tasks.join_next().await.is_some() {}
+        (self.succeeded.load(Ordering::Relaxed), self.failed.load(Ordering::Relaxed))
+    }
+}
+
+async fn transcode(path: &Path, preset: ReencodePreset, keep_original: bool) -> Result<()> {
+    let output = reencoded_path(path);
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-nostdin", "-y", "-i"])
+        .arg(path)
+        .args(["-map_metadata", "0"])
+        .args(preset.codec_args())
+        .args(["-c:a", "aac", "-b:a", "128k", "-movflags", "+faststart"])
+        .arg(&output)
+        .output()
+        .await
+        .context("Failed to run ffmpeg")?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(&output).await;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(anyhow!("ffmpeg exited with {}: {}", result.status, stderr.trim()));
+    }
+
+    if !keep_original {
+        tokio::fs::rename(&output, path)
+            .await
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// `IMG_0001.MOV` -> `IMG_0001.reencoded.MOV`, keeping the extension so
+/// ffmpeg picks the same container.
+fn reencoded_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.reencoded.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.reencoded", stem),
+    };
+    path.with_file_name(name)
+}
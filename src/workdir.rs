@@ -0,0 +1,183 @@
+// Output-directory housekeeping: the hidden directory that holds the files
+// the tool writes for itself, and a guard against downloading into a folder
+// that is clearly full of someone's other files. The guard runs before
+// anything is written, so answering no leaves the folder as it was.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use crate::manifest;
+use crate::permissions::OutputPermissions;
+
+/// Subdirectory of the output directory for the tool's own files (failure
+/// logs and the like), so they don't mix with the photos.
+pub const TOOL_DIR_NAME: &str = ".icloud-dl";
+
+/// More unrelated files than this and we ask before writing into the directory.
+const UNRELATED_FILE_THRESHOLD: usize = 20;
+
+/// Tells Android's media scanner to leave a directory out of the gallery.
+pub const NOMEDIA_FILE_NAME: &str = ".nomedia";
+
+pub fn tool_dir(output_dir: &str) -> PathBuf {
+    Path::new(output_dir).join(TOOL_DIR_NAME)
+}
+
+/// Puts an empty `.nomedia` in `output_dir` unless there is one already.
+/// Returns whether it was created.
+pub async fn write_nomedia(output_dir: &str, permissions: &OutputPermissions) -> Result<bool> {
+    let path = Path::new(output_dir).join(NOMEDIA_FILE_NAME);
+    if path.exists() {
+        return Ok(false);
+    }
+    permissions
+        .create_file(&path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(true)
+}
+
+/// Warns and asks for confirmation when `output_dir` already holds many files
+/// the tool didn't download there, e.g. when pointed at ~/Pictures by mistake.
+/// Files recorded in the directory's manifest, by any run and any --range
+/// shard, belong to it. Without a terminal to ask on (cron, scripts) the
+/// warning is all there is and the run goes ahead.
+pub fn confirm_output_dir(output_dir: &str, assume_yes: bool, can_prompt: bool) -> Result<()> {
+    if assume_yes || !may_need_confirmation(output_dir) {
+        return Ok(());
+    }
+    let recorded = manifest::recorded_filenames(&tool_dir(output_dir))?;
+    let unrelated = count_unrelated_files(output_dir, &recorded);
+    if unrelated <= UNRELATED_FILE_THRESHOLD {
+        return Ok(());
+    }
+
+    eprintln!(
+        "⚠️  {} already contains {} files that this tool didn't download",
+        output_dir, unrelated
+    );
+    if !can_prompt || !std::io::stdin().is_terminal() {
+        eprintln!("   Downloading into it anyway, as there's no terminal to ask on");
+        return Ok(());
+    }
+
+    eprint!("Download into it anyway? [y/N] ");
+    std::io::stderr().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Failed to read confirmation")?;
+
+    if matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+        Ok(())
+    } else {
+        Err(anyhow!("Aborted: {} was not confirmed as the output directory", output_dir))
+    }
+}
+
+/// Whether `output_dir` holds enough files for `confirm_output_dir` to
+/// possibly ask, before reading the manifest.
+fn may_need_confirmation(output_dir: &str) -> bool {
+    visible_files(output_dir).nth(UNRELATED_FILE_THRESHOLD).is_some()
+}
+
+/// Counts visible files whose stem doesn't match any recorded file. Stems
+/// are compared so files renamed by extension correction still match.
+fn count_unrelated_files(output_dir: &str, recorded: &HashSet<String>) -> usize {
+    let recorded_stems: HashSet<&str> = recorded.iter().map(|name| file_stem(name)).collect();
+    visible_files(output_dir)
+        .filter(|name| !recorded_stems.contains(file_stem(name)))
+        .count()
+}
+
+/// Names of the regular, non-hidden files directly in `output_dir`.
+fn visible_files(output_dir: &str) -> impl Iterator<Item = String> {
+    fs::read_dir(output_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+}
+
+fn file_stem(filename: &str) -> &str {
+    let name = filename.rsplit_once('/').map_or(filename, |(_, name)| name);
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(filename: &str) -> String {
+        serde_json::json!({
+            "filename": filename,
+            "photo_guid": "guid",
+            "checksum": "ck",
+            "kind": "photo",
+            "size": 3,
+            "downloaded_at": "2024-05-01T12:00:00+00:00",
+        })
+        .to_string()
+    }
+
+    fn fill(dir: &Path, prefix: &str, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| {
+                let name = format!("{}_{:04}.JPG", prefix, i);
+                fs::write(dir.join(&name), b"x").unwrap();
+                name
+            })
+            .collect()
+    }
+
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> =
+            fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn files_recorded_by_other_shards_belong_to_the_album() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        let compacted = fill(dir.path(), "IMG", 15);
+        let logged = fill(dir.path(), "SHARD", 15);
+        fs::create_dir(tool_dir(output_dir)).unwrap();
+        let manifest = format!("[{}]", compacted.iter().map(|name| entry(name)).collect::<Vec<_>>().join(","));
+        fs::write(tool_dir(output_dir).join(manifest::MANIFEST_NAME), manifest).unwrap();
+        let log: String = logged.iter().map(|name| entry(name) + "\n").collect();
+        fs::write(tool_dir(output_dir).join(manifest::MANIFEST_LOG_NAME), log).unwrap();
+
+        let recorded = manifest::recorded_filenames(&tool_dir(output_dir)).unwrap();
+        assert_eq!(count_unrelated_files(output_dir, &recorded), 0);
+    }
+
+    #[test]
+    fn unrecorded_visible_files_are_unrelated() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        fill(dir.path(), "DSC", 25);
+        fs::write(dir.path().join(".DS_Store"), b"x").unwrap();
+
+        assert!(may_need_confirmation(output_dir));
+        assert_eq!(count_unrelated_files(output_dir, &HashSet::new()), 25);
+    }
+
+    #[test]
+    fn without_a_terminal_the_run_goes_ahead_and_nothing_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        fill(dir.path(), "DSC", 25);
+        let before = listing(dir.path());
+
+        confirm_output_dir(output_dir, false, false).unwrap();
+        assert_eq!(listing(dir.path()), before);
+    }
+}
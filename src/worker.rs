@@ -0,0 +1,124 @@
+// --json-lines-input: a long-running worker that reads one JSON job per line
+// on stdin and writes one JSON result per line on stdout. Lets an
+// orchestrator queue many albums through a single process and HTTP client.
+//
+// Job:    {"id": 7, "url": "https://www.icloud.com/sharedalbum/#...", "output": "./a", "concurrent": 8}
+// Result: {"id": 7, "url": "...", "output": "./a", "ok": true, "error": null, "duration_secs": 12.3}
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::http::HttpClient;
+use crate::existing::OverwritePolicy;
+use crate::{download_album, resolve_album_hash, Args};
+
+/// Per-job settings; anything left out falls back to the command-line flags.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    url: String,
+    output: Option<String>,
+    concurrent: Option<usize>,
+    dry_run: Option<bool>,
+    overwrite_policy: Option<OverwritePolicy>,
+    /// Older spellings of `overwrite_policy`
+    skip_existing: Option<bool>,
+    replace_existing_smaller: Option<bool>,
+    flatten_live_photos: Option<bool>,
+    strip_metadata: Option<bool>,
+    derivatives: Option<Vec<String>>,
+    yes: Option<bool>,
+    album_name: Option<String>,
+}
+
+impl Job {
+    fn apply_to(&self, base: &Args) -> Args {
+        let mut args = base.clone();
+        args.url = vec![self.url.clone()];
+        if let Some(output) = &self.output {
+            args.output = output.clone();
+        }
+        if let Some(concurrent) = self.concurrent {
+            args.concurrent = concurrent;
+        }
+        if let Some(derivatives) = &self.derivatives {
+            args.derivatives = Some(derivatives.clone());
+        }
+        args.dry_run = self.dry_run.unwrap_or(args.dry_run);
+        if self.skip_existing == Some(true) {
+            args.overwrite_policy = OverwritePolicy::Never;
+        }
+        if self.replace_existing_smaller == Some(true) {
+            args.overwrite_policy = OverwritePolicy::IfLarger;
+        }
+        args.overwrite_policy = self.overwrite_policy.unwrap_or(args.overwrite_policy);
+        args.flatten_live_photos = self.flatten_live_photos.unwrap_or(args.flatten_live_photos);
+        args.strip_metadata = self.strip_metadata.unwrap_or(args.strip_metadata);
+        args.yes = self.yes.unwrap_or(args.yes);
+        args
+    }
+}
+
+#[derive(Serialize)]
+struct JobResult {
+    id: Option<serde_json::Value>,
+    url: Option<String>,
+    output: Option<String>,
+    ok: bool,
+    error: Option<String>,
+    duration_secs: f64,
+}
+
+pub async fn run(client: &impl HttpClient, base_args: &Args) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await.context("Failed to read job from stdin")? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = match serde_json::from_str::<Job>(&line) {
+            Ok(job) => {
+                let args = job.apply_to(base_args);
+                let outcome = match resolve_album_hash(client, &job.url).await {
+                    Ok(hash) => download_album(client, &args, &hash, job.album_name.as_deref(), false, None, None).await,
+                    Err(e) => Err(e),
+                };
+                JobResult {
+                    id: job.id,
+                    url: Some(job.url),
+                    output: Some(args.output),
+                    ok: outcome.is_ok(),
+                    error: outcome.err().map(|e| format!("{:#}", e)),
+                    duration_secs: started.elapsed().as_secs_f64(),
+                }
+            }
+            Err(e) => JobResult {
+                id: None,
+                url: None,
+                output: None,
+                ok: false,
+                error: Some(format!("Malformed job: {}", e)),
+                duration_secs: 0.0,
+            },
+        };
+
+        emit(&result)?;
+    }
+
+    Ok(())
+}
+
+fn emit(result: &JobResult) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer(&mut stdout, result)?;
+    writeln!(stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+// `--xmp-sidecars`: a `NAME.xmp` next to each photo and video with its
+// caption and capture date, which Apple Photos, Lightroom and digiKam read on
+// import. The caption becomes the description (Photos' "Caption"). A Live
+// Photo's still and video share one sidecar, as they share a name.
+//
+// Sidecars are written for files as they're downloaded, and photos with
+// neither a caption nor a capture date don't get one. With
+// --album-metadata-only-refresh, the sidecars of files whose caption or date
+// changed are written again from the refreshed manifest.
+
+use anyhow::{Context, Result};
+use chrono::SecondsFormat;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::caption::{render_caption, CaptionContext};
+use crate::manifest::ManifestEntry;
+use crate::permissions::OutputPermissions;
+use crate::{AssetKind, DownloadInfo};
+
+/// The sidecar for a media file: the same name with `.xmp` as extension.
+pub fn sidecar_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("xmp")
+}
+
+/// Writes the sidecar for a saved file. Returns whether one was written.
+pub async fn write_sidecar(info: &DownloadInfo, file_path: &Path, permissions: &OutputPermissions) -> Result<bool> {
+    // The still's sidecar covers the video too
+    if info.kind == AssetKind::LiveMotion {
+        return Ok(false);
+    }
+    let Some(xmp) = render(info) else {
+        return Ok(false);
+    };
+
+    let path = sidecar_path(file_path);
+    let mut file = permissions.create_file(&path).await?;
+    file.write_all(xmp.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(true)
+}
+
+/// What `refresh_sidecars` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Refreshed {
+    pub rewritten: usize,
+    /// Sidecars removed as their photo no longer has a caption or date.
+    pub removed: usize,
+    /// Files no longer on disk, whose sidecars were left alone.
+    pub missing: usize,
+}
+
+impl Refreshed {
+    pub fn report(&self) {
+        if self.rewritten > 0 {
+            status!("📝 Rewrote {} XMP sidecars", self.rewritten);
+        }
+        if self.removed > 0 {
+            status!("   Removed {} sidecars whose photos no longer have a caption or date", self.removed);
+        }
+        if self.missing > 0 {
+            status!("   {} files are no longer on disk; their sidecars were left as they were", self.missing);
+        }
+    }
+}
+
+/// Writes the sidecars of refreshed manifest `entries` again, for the files
+/// still in `output_dir`.
+pub async fn refresh_sidecars(entries: &[ManifestEntry], output_dir: &Path, permissions: &OutputPermissions) -> Refreshed {
+    let mut refreshed = Refreshed::default();
+    for entry in entries {
+        let info = entry.sidecar_info();
+        if info.kind == AssetKind::LiveMotion {
+            continue;
+        }
+        let file_path = output_dir.join(&entry.filename);
+        if !file_path.is_file() {
+            refreshed.missing += 1;
+            continue;
+        }
+        match write_sidecar(&info, &file_path, permissions).await {
+            Ok(true) => refreshed.rewritten += 1,
+            Ok(false) => {
+                if tokio::fs::remove_file(sidecar_path(&file_path)).await.is_ok() {
+                    refreshed.removed += 1;
+                }
+            }
+            Err(e) => eprintln!("⚠️  Could not rewrite the sidecar of {}: {:#}", entry.filename, e),
+        }
+    }
+    refreshed
+}
+
+fn render(info: &DownloadInfo) -> Option<String> {
+    let caption = info
+        .caption
+        .as_deref()
+        .map(|caption| render_caption(caption, CaptionContext::Xml))
+        .filter(|caption| !caption.is_empty());
+    let date = info.date_created.map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true));
+    if caption.is_none() && date.is_none() {
+        return None;
+    }
+
+    let mut properties = String::new();
+    if let Some(caption) = caption {
+        properties.push_str(&format!(
+            "   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n",
+            caption
+        ));
+    }
+    if let Some(date) = date {
+        properties.push_str(&format!("   <photoshop:DateCreated>{}</photoshop:DateCreated>\n", date));
+        properties.push_str(&format!("   <exif:DateTimeOriginal>{}</exif:DateTimeOriginal>\n", date));
+    }
+
+    Some(format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         \x20<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         \x20 <rdf:Description rdf:about=\"\"\n\
+         \x20   xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n\
+         \x20   xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"\n\
+         \x20   xmlns:exif=\"http://ns.adobe.com/exif/1.0/\">\n\
+         {}\
+         \x20 </rdf:Description>\n\
+         \x20</rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>\n",
+        properties
+    ))
+}